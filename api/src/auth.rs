@@ -1,14 +1,18 @@
+use crate::config::Config;
+use crate::github::DeviceFlowPoll;
+use crate::github::GitHub;
 use crate::github::GitHubNodeId;
-use crate::hasura;
+use crate::github::GitHubUserInfo;
+use crate::storage::SessionRecord;
+use crate::storage::Storage;
+use crate::GitHubAuth;
 use crate::GitHubUserId;
-use crate::RUNNING_ON_RENDER;
 use anyhow::anyhow;
 use anyhow::ensure;
 use chrono::prelude::Utc;
 use chrono::Duration;
 use cookie::Cookie;
 use cookie::SameSite;
-use hyper;
 use hyper::header;
 use hyper::Body;
 use hyper::Request;
@@ -20,41 +24,82 @@ use serde_json::json;
 use std::borrow::Cow;
 use std::collections::HashMap;
 
-const APPLICATION_JSON: &str = "application/json";
 const SESSION_TOKEN_COOKIE_NAME: &str = "cf_session_token";
 const USER_INFO_COOKIE_NAME: &str = "cf_user_info";
+/// When a session is due for rotation, responses to requests that authenticated with `Authorization: Bearer <token>`
+/// carry its new token in this header. Requests that used the cookie get a new cookie instead.
+pub const SESSION_TOKEN_HEADER: &str = "x-cuddlefish-session-token";
+/// How long a session token keeps working after it has been rotated out, so that requests already in flight with it
+/// don't fail.
+const ROTATION_GRACE_SECS: i64 = 60;
+
+fn query_params(req: &Request<Body>) -> HashMap<String, String> {
+  // See https://users.rust-lang.org/t/using-hyper-how-to-get-url-query-string-params/23768/3?u=samuela.
+  req
+    .uri()
+    .query()
+    .map(|v| {
+      url::form_urlencoded::parse(v.as_bytes())
+        .into_owned()
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Where to send the user once they're done logging in or out: the `return_to` query param if there is one, or else
+/// FRONTEND_URL. Returns None if `return_to` isn't somewhere that we're willing to go.
+fn return_to(config: &Config, req: &Request<Body>) -> Option<String> {
+  match query_params(req).remove("return_to") {
+    Some(return_to) if config.return_to_allowed(&return_to) => Some(return_to),
+    Some(return_to) => {
+      log::warn!("rejecting return_to {:?}", return_to);
+      None
+    }
+    None => Some(config.frontend_url.clone()),
+  }
+}
+
+fn bad_request() -> Response<Body> {
+  Response::builder()
+    .status(StatusCode::BAD_REQUEST)
+    .body(Body::empty())
+    .expect("building response failed")
+}
+
+pub async fn login_route(
+  config: &Config,
+  github: &dyn GitHub,
+  req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+  let return_to = match return_to(config, &req) {
+    Some(return_to) => return_to,
+    None => return Ok(bad_request()),
+  };
 
-pub async fn login_route(_: Request<Body>) -> Result<Response<Body>, hyper::Error> {
   // See https://docs.github.com/en/free-pro-team@latest/developers/apps/authorizing-oauth-apps#1-request-a-users-github-identity.
   // We use a local token since there's really no need for the client to be able
-  // to read anything in it.
+  // to read anything in it. That also keeps anyone from swapping in their own
+  // return_to along the way.
   let state = paseto::tokens::PasetoBuilder::new()
-    .set_encryption_key(&*crate::API_PASETO_SECRET_KEY.as_bytes())
+    .set_encryption_key(config.api_paseto_secret_key.as_bytes())
     .set_expiration(&(Utc::now() + Duration::minutes(15)))
     .set_not_before(&Utc::now())
+    .set_claim("return_to", json!(return_to))
     .build()
     .expect("failed to construct paseto token");
 
   // See https://serverfault.com/questions/391181/examples-of-302-vs-303 for a
   // breakdown of all possible HTTP redirects.
-
-  // Can't use RENDER_EXTERNAL_URL because it's https://cf-api.onrender.com, and
-  // we can't do any kind of callback_url wildcard in the GitHub app. This won't
-  // work with PR preview environments, so we'll have to figure that out later.
-  let callback_url = if *RUNNING_ON_RENDER {
-    "https://api.cuddlefish.app/oauth/callback/github"
-  } else {
-    "http://localhost:3001/oauth/callback/github"
-  };
   Ok::<_, hyper::Error>(
     Response::builder()
       .status(StatusCode::TEMPORARY_REDIRECT)
       .header(
         header::LOCATION,
         format!(
-          "https://github.com/login/oauth/authorize?client_id={}&redirect_uri={}&state={}",
-          &*crate::GITHUB_OAUTH_CLIENT_ID,
-          callback_url,
+          "{}?client_id={}&redirect_uri={}&state={}",
+          github.oauth_authorize_url(),
+          config.github_oauth_client_id,
+          config.github_callback_url(),
           state
         ),
       )
@@ -63,77 +108,28 @@ pub async fn login_route(_: Request<Body>) -> Result<Response<Body>, hyper::Erro
   )
 }
 
-fn cookie<'c, V>(name: &'c str, value: V, http_only: bool) -> Cookie<'c>
+fn cookie<'c, V>(config: &Config, name: &'c str, value: V, http_only: bool) -> Cookie<'c>
 where
   V: Into<Cow<'c, str>>,
 {
   let mut builder = Cookie::build(name, value)
-    // Only send this cookie over HTTPS when running in prod.
-    .secure(*RUNNING_ON_RENDER)
+    // Only send this cookie over HTTPS when we're served over HTTPS.
+    .secure(config.secure_cookies())
     // Cookie is not accessible via JavaScript when http_only is true.
     .http_only(http_only)
     // Only send this cookie for requests originating from our domain.
     .same_site(SameSite::Strict)
     // Must set this otherwise the path is /oauth/callback.
     .path("/");
-  if *RUNNING_ON_RENDER {
+  if let Some(domain) = &config.cookie_domain {
     // See https://developer.mozilla.org/en-US/docs/Web/HTTP/Cookies#define_where_cookies_are_sent.
     // If we didn't set this then the cookie would only be sent to
     // api.cuddlefish.app, and not to other *.cuddlefish.app subdomains.
-    builder = builder.domain("cuddlefish.app");
+    builder = builder.domain(domain.clone());
   }
   builder.finish()
 }
 
-#[derive(Deserialize, Debug)]
-struct GitHubUserInfoResp {
-  /// The user's GitHub username, eg. "samuela".
-  login: String,
-  /// The "databaseId" in GitHub API speak.
-  id: u32,
-  /// The "node id" in GitHub API speak.
-  node_id: String,
-  // TODO what happens with users that don't have a name set?
-  name: String,
-  // This comes in as null sometimes. Serde seems to handle correctly.
-  company: Option<String>,
-  blog: Option<String>,
-  location: Option<String>,
-  email: Option<String>,
-  hireable: bool,
-  bio: Option<String>,
-  twitter_username: Option<String>,
-}
-
-async fn get_github_user_info(github_access_token: &str) -> anyhow::Result<GitHubUserInfoResp> {
-  // TODO: use the gql way
-  let user_info_response = reqwest::Client::new()
-    .get("https://api.github.com/user")
-    .header(
-      header::AUTHORIZATION,
-      format!("token {}", github_access_token),
-    )
-    // Setting a user agent is mandatory when calling api.github.com.
-    .header(header::USER_AGENT, "cuddlefish")
-    .send()
-    .await
-    // Turn error status codes into rust errors.
-    .and_then(|resp| resp.error_for_status())?;
-  trace!("user_info_response = {:?}", user_info_response);
-
-  // Deserialize the user info response.
-  let response_body = user_info_response.text().await?;
-  trace!("response body = {:?}", response_body);
-  let user_info: GitHubUserInfoResp = serde_json::from_str(&response_body)?;
-
-  // Slightly more efficient, harder to debug with:
-  // let user_info: GitHubUserInfoResp = user_info_response.json().await?;
-
-  trace!("user_info = {:?}", user_info);
-
-  Ok(user_info)
-}
-
 // We generally pass these around once we have verified this user is legit.
 #[derive(Deserialize, Debug)]
 struct CuddlefishSessionToken {
@@ -141,42 +137,42 @@ struct CuddlefishSessionToken {
 }
 
 async fn start_session_from_github(
-  user_info: &GitHubUserInfoResp,
+  config: &Config,
+  storage: &dyn Storage,
+  user_info: &GitHubUserInfo,
   github_access_token: &str,
 ) -> anyhow::Result<CuddlefishSessionToken> {
   let gh_user_id = GitHubUserId(GitHubNodeId(user_info.node_id.to_string()));
   // upsert user info
-  hasura::upsert_user(
-    &gh_user_id,
-    user_info.id,
-    &user_info.name,
-    &user_info.login,
-    user_info.email.as_ref().map(|s| s.to_string()),
-    &github_access_token,
-  )
-  .await?;
+  storage
+    .upsert_user(
+      &gh_user_id,
+      user_info.id,
+      &user_info.name,
+      &user_info.login,
+      user_info.email.as_ref().map(|s| s.to_string()),
+      github_access_token,
+    )
+    .await?;
   trace!("upsert_user was successful");
 
-  // create new user session in hasura
-  let cf_session_token = hasura::start_user_session(&gh_user_id).await?;
+  // create new user session in the database
+  let cf_session_token = storage
+    .start_user_session(&gh_user_id, config.session_ttl)
+    .await?;
 
   Ok(CuddlefishSessionToken {
     session_token: cf_session_token,
   })
 }
 
-async fn github_callback_route_inner(req: Request<Body>) -> anyhow::Result<Response<Body>> {
-  // See https://users.rust-lang.org/t/using-hyper-how-to-get-url-query-string-params/23768/3?u=samuela.
-
-  let query_params = req
-    .uri()
-    .query()
-    .map(|v| {
-      url::form_urlencoded::parse(v.as_bytes())
-        .into_owned()
-        .collect()
-    })
-    .unwrap_or_else(HashMap::new);
+async fn github_callback_route_inner(
+  config: &Config,
+  storage: &dyn Storage,
+  github: &dyn GitHub,
+  req: Request<Body>,
+) -> anyhow::Result<Response<Body>> {
+  let query_params = query_params(&req);
 
   let code = query_params
     .get("code")
@@ -184,8 +180,6 @@ async fn github_callback_route_inner(req: Request<Body>) -> anyhow::Result<Respo
   let state = query_params
     .get("state")
     .ok_or_else(|| anyhow!("no `state` query param"))?;
-  trace!("code = {}", code);
-  trace!("state = {}", state);
 
   // TODO: test calling this endpoint with an invalid/expired paseto token.
 
@@ -214,49 +208,40 @@ async fn github_callback_route_inner(req: Request<Body>) -> anyhow::Result<Respo
   // been deprecated and doesn't play nicely with anyhow. Looks like they have
   // already migrated to thiserror which should come out in the next release.
   // Fingers crossed...
-  paseto::tokens::validate_local_token(
-    &state,
+  let claims = paseto::tokens::validate_local_token(
+    state,
     None,
-    &*crate::API_PASETO_SECRET_KEY.as_bytes(),
+    config.api_paseto_secret_key.as_bytes(),
     &paseto::TimeBackend::Chrono,
   )
-  .or_else(|_| Err(anyhow!("paseto validation failed")))?;
+  .map_err(|_| anyhow!("paseto validation failed"))?;
   trace!("paseto::tokens::validate_local_token was successful");
+  // We checked this against the allowlist in `login_route`, but the allowlist may have changed since.
+  let return_to = claims["return_to"]
+    .as_str()
+    .filter(|return_to| config.return_to_allowed(return_to))
+    .unwrap_or(&config.frontend_url)
+    .to_string();
 
   // Trade in code for an access token from GitHub.
-  let access_token_response = reqwest::Client::new()
-    .post("https://github.com/login/oauth/access_token")
-    .header(header::ACCEPT, APPLICATION_JSON)
-    .query(&[
-      ("client_id", &*crate::GITHUB_OAUTH_CLIENT_ID),
-      ("client_secret", &*crate::GITHUB_OAUTH_CLIENT_SECRET),
-      ("code", code),
-      ("state", state),
-    ])
-    .send()
-    .await
-    // Turn error status codes into rust errors.
-    .and_then(|resp| resp.error_for_status())?;
-  trace!("access_token_response = {:?}", access_token_response);
-
-  #[derive(Deserialize)]
-  struct AccessTokenResp {
-    access_token: String,
-  }
-  // Deserialize the access token response into an access token.
-  let access_token = access_token_response
-    .json::<AccessTokenResp>()
-    .await?
-    .access_token;
-  trace!("access_token = {}", access_token);
+  let access_token = github
+    .exchange_oauth_code(
+      &config.github_oauth_client_id,
+      &config.github_oauth_client_secret,
+      code,
+      state,
+    )
+    .await?;
 
-  let user_info = get_github_user_info(&access_token).await?;
-  let cf_session_token = start_session_from_github(&user_info, &access_token).await?;
+  let user_info = github.user_info(&access_token).await?;
+  let cf_session_token =
+    start_session_from_github(config, storage, &user_info, &access_token).await?;
 
   // set cookie in response with session token
   // TODO: update the logout route to make sure that it's deleting the right stuff.
   // TODO: should use __Host- prefix here?
   let session_token_cookie = cookie(
+    config,
     SESSION_TOKEN_COOKIE_NAME,
     cf_session_token.session_token,
     true,
@@ -264,6 +249,7 @@ async fn github_callback_route_inner(req: Request<Body>) -> anyhow::Result<Respo
 
   // TODO base64 or JWT encode this value... otherwise all kinds of naughty things can happen.
   let user_cookie = cookie(
+    config,
     USER_INFO_COOKIE_NAME,
     json!({
       "github_login": user_info.login,
@@ -274,49 +260,164 @@ async fn github_callback_route_inner(req: Request<Body>) -> anyhow::Result<Respo
     false,
   );
 
-  trace!("session_token_cookie = {}", session_token_cookie);
   trace!("user_cookie = {}", user_cookie);
 
-  // Return a response setting the cookie and redirecting to the homepage.
-  let homepage_url = if *RUNNING_ON_RENDER {
-    // TODO this won't work with render's PR previews.
-    "https://cuddlefish.app/"
-  } else {
-    "http://localhost:3000/"
-  };
+  // Return a response setting the cookie and redirecting to wherever the user started logging in from.
   Ok(
     Response::builder()
       .status(StatusCode::TEMPORARY_REDIRECT)
       .header(header::SET_COOKIE, session_token_cookie.to_string())
       .header(header::SET_COOKIE, user_cookie.to_string())
-      .header(header::LOCATION, homepage_url)
+      .header(header::LOCATION, return_to)
       .body(Body::empty())
       .expect("building response failed"),
   )
 }
-pub async fn github_callback_route(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+pub async fn github_callback_route(
+  config: &Config,
+  storage: &dyn Storage,
+  github: &dyn GitHub,
+  req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
   // See https://docs.github.com/en/free-pro-team@latest/developers/apps/authorizing-oauth-apps#2-users-are-redirected-back-to-your-site-by-github.
   // TODO simplify
-  match github_callback_route_inner(req).await {
+  match github_callback_route_inner(config, storage, github, req).await {
     Ok(resp) => Ok(resp),
-    Err(_) => Ok(
-      Response::builder()
-        .status(StatusCode::BAD_REQUEST)
-        .body(Body::empty())
-        .expect("building response failed"),
-    ),
+    Err(_) => Ok(bad_request()),
+  }
+}
+// The device flow is for clients like the VSCode extension and CLIs, which can't easily get a redirect back or hold on
+// to cookies. They POST /login/device to get a code to show the user, and then POST {"device_code": ...} to
+// /login/device/token every `interval` seconds until the user has entered the code on GitHub. At that point they get a
+// session token to send along as `Authorization: Bearer <token>`. Until then, the responses are the same errors as in
+// https://datatracker.ietf.org/doc/html/rfc8628#section-3.5.
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header(header::CONTENT_TYPE, "application/json")
+    .body(Body::from(body.to_string()))
+    .expect("building response failed")
+}
+
+pub async fn device_login_route(
+  config: &Config,
+  github: &dyn GitHub,
+  _: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+  Ok(
+    match github
+      .start_device_flow(&config.github_oauth_client_id)
+      .await
+    {
+      Ok(device_code) => json_response(
+        StatusCode::OK,
+        json!({
+          "device_code": device_code.device_code,
+          "user_code": device_code.user_code,
+          "verification_uri": device_code.verification_uri,
+          "expires_in": device_code.expires_in,
+          "interval": device_code.interval,
+        }),
+      ),
+      Err(e) => {
+        log::error!("starting device flow failed: {:?}", e);
+        json_response(StatusCode::BAD_GATEWAY, json!({ "error": "server_error" }))
+      }
+    },
+  )
+}
+
+async fn device_token_route_inner(
+  config: &Config,
+  storage: &dyn Storage,
+  github: &dyn GitHub,
+  req: Request<Body>,
+) -> anyhow::Result<Response<Body>> {
+  #[derive(Deserialize)]
+  struct DeviceTokenReq {
+    device_code: String,
+  }
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let device_code = match serde_json::from_slice::<DeviceTokenReq>(&body) {
+    Ok(body) => body.device_code,
+    Err(_) => {
+      return Ok(json_response(
+        StatusCode::BAD_REQUEST,
+        json!({ "error": "invalid_request" }),
+      ))
+    }
+  };
+
+  let access_token = match github
+    .poll_device_flow(&config.github_oauth_client_id, &device_code)
+    .await?
+  {
+    DeviceFlowPoll::Authorized { access_token } => access_token,
+    DeviceFlowPoll::Pending => {
+      return Ok(json_response(
+        StatusCode::BAD_REQUEST,
+        json!({ "error": "authorization_pending" }),
+      ))
+    }
+    DeviceFlowPoll::SlowDown { interval } => {
+      return Ok(json_response(
+        StatusCode::BAD_REQUEST,
+        json!({ "error": "slow_down", "interval": interval }),
+      ))
+    }
+    DeviceFlowPoll::Failed { error } => {
+      return Ok(json_response(
+        StatusCode::BAD_REQUEST,
+        json!({ "error": error }),
+      ))
+    }
+  };
+
+  let user_info = github.user_info(&access_token).await?;
+  let cf_session_token =
+    start_session_from_github(config, storage, &user_info, &access_token).await?;
+  Ok(json_response(
+    StatusCode::OK,
+    json!({
+      "session_token": cf_session_token.session_token,
+      "github_login": user_info.login,
+      "github_id": user_info.id,
+      "name": user_info.name,
+    }),
+  ))
+}
+pub async fn device_token_route(
+  config: &Config,
+  storage: &dyn Storage,
+  github: &dyn GitHub,
+  req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+  match device_token_route_inner(config, storage, github, req).await {
+    Ok(resp) => Ok(resp),
+    Err(e) => {
+      log::error!("device flow login failed: {:?}", e);
+      Ok(server_error())
+    }
   }
 }
-pub async fn logout_route(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+
+pub async fn logout_route(
+  config: &Config,
+  storage: &dyn Storage,
+  req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+  let return_to = match return_to(config, &req) {
+    Some(return_to) => return_to,
+    None => return Ok(bad_request()),
+  };
   if let Ok(cookies) = parse_cookies(&req) {
-    trace!("got cookies: {:#?}", cookies);
     if let Some(session_token) = cookies.get(SESSION_TOKEN_COOKIE_NAME) {
-      trace!("got session_token: {}", session_token);
       // Try ending the user session...
-      if let Err(_) = hasura::end_user_session(session_token).await {
+      if storage.end_user_session(session_token).await.is_err() {
         // If we get an Err from end_user_session it means we got some kind of
-        // error talking to hasura.
-        trace!("hasura::end_user_session failed");
+        // error talking to the database.
+        trace!("end_user_session failed");
         return Ok(
           Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -329,24 +430,211 @@ pub async fn logout_route(req: Request<Body>) -> Result<Response<Body>, hyper::E
 
   // The path and domain on the cookie must match in order for the delete to
   // work! See https://stackoverflow.com/a/53573622/3880977.
-  let session_token_cookie = cookie(SESSION_TOKEN_COOKIE_NAME, "", true);
-  let user_cookie = cookie(USER_INFO_COOKIE_NAME, "", false);
-  let redirect_location = if *RUNNING_ON_RENDER {
-    "https://cuddlefish.app/"
-  } else {
-    "http://localhost:3000/"
-  };
+  let session_token_cookie = cookie(config, SESSION_TOKEN_COOKIE_NAME, "", true);
+  let user_cookie = cookie(config, USER_INFO_COOKIE_NAME, "", false);
   Ok(
     Response::builder()
       .status(StatusCode::TEMPORARY_REDIRECT)
       .header(header::SET_COOKIE, session_token_cookie.to_string())
       .header(header::SET_COOKIE, user_cookie.to_string())
-      .header(header::LOCATION, redirect_location)
+      .header(header::LOCATION, return_to)
       .body(Body::empty())
       .expect("building response failed"),
   )
 }
 
+// Logged in users can list their sessions with GET /sessions, end one of their other sessions with POST
+// /sessions/revoke {"public_id": ...}, and end all of them but the one that they're using with POST
+// /sessions/revoke_others. Like the Hasura webhook, these take the session token as a bearer token or in our cookie.
+
+/// Rotate `session`'s token if it's due, and hand the new one to the client along with `resp`, the same way that it
+/// sent us the old one. Rotating isn't worth failing the request over, since the old token keeps working anyway.
+pub async fn with_rotated_token(
+  config: &Config,
+  storage: &dyn Storage,
+  session: &Session,
+  from_cookie: bool,
+  mut resp: Response<Body>,
+) -> Response<Body> {
+  match rotate_session_if_due(config, storage, session).await {
+    Ok(Some(new_token)) => {
+      trace!("rotated session {}", session.record.public_id);
+      if from_cookie {
+        let session_token_cookie = cookie(config, SESSION_TOKEN_COOKIE_NAME, new_token, true);
+        resp.headers_mut().append(
+          header::SET_COOKIE,
+          header::HeaderValue::from_str(&session_token_cookie.to_string())
+            .expect("cookies are valid header values"),
+        );
+      } else {
+        resp.headers_mut().insert(
+          SESSION_TOKEN_HEADER,
+          header::HeaderValue::from_str(&new_token)
+            .expect("session tokens are valid header values"),
+        );
+      }
+    }
+    Ok(None) => {}
+    Err(e) => log::warn!(
+      "rotating session {} failed: {:?}",
+      session.record.public_id,
+      e
+    ),
+  }
+  resp
+}
+
+/// The session that `req` authenticated with and whether it came from the cookie, or else the response to send back.
+async fn require_session(
+  config: &Config,
+  storage: &dyn Storage,
+  req: &Request<Body>,
+) -> Result<(Session, bool), Response<Body>> {
+  let unauthorized = || json_response(StatusCode::UNAUTHORIZED, json!({ "error": "unauthorized" }));
+  let (token, from_cookie) = session_token(req).map_err(|_| unauthorized())?;
+  match storage
+    .lookup_user_session(&token, config.session_ttl)
+    .await
+  {
+    Ok(Some((auth, record))) => Ok((
+      Session {
+        token,
+        auth,
+        record,
+      },
+      from_cookie,
+    )),
+    Ok(None) => Err(unauthorized()),
+    Err(e) => {
+      log::error!("looking up session failed: {:?}", e);
+      Err(server_error())
+    }
+  }
+}
+
+fn server_error() -> Response<Body> {
+  json_response(
+    StatusCode::INTERNAL_SERVER_ERROR,
+    json!({ "error": "server_error" }),
+  )
+}
+
+pub async fn sessions_route(
+  config: &Config,
+  storage: &dyn Storage,
+  req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+  let (session, from_cookie) = match require_session(config, storage, &req).await {
+    Ok(x) => x,
+    Err(resp) => return Ok(resp),
+  };
+  let resp = match storage.user_sessions(&session.auth.github_node_id).await {
+    Ok(sessions) => json_response(
+      StatusCode::OK,
+      json!({
+        "sessions": sessions
+          .iter()
+          .map(|s| json!({
+            "public_id": s.public_id,
+            "created_at": s.created_at.to_rfc3339(),
+            "last_used_at": s.last_used_at.to_rfc3339(),
+            "expires_at": s.expires_at.to_rfc3339(),
+            "current": s.public_id == session.record.public_id,
+          }))
+          .collect::<Vec<_>>(),
+      }),
+    ),
+    Err(e) => {
+      log::error!("listing sessions failed: {:?}", e);
+      server_error()
+    }
+  };
+  Ok(with_rotated_token(config, storage, &session, from_cookie, resp).await)
+}
+
+async fn revoke_session_route_inner(
+  storage: &dyn Storage,
+  session: &Session,
+  req: Request<Body>,
+) -> anyhow::Result<Response<Body>> {
+  #[derive(Deserialize)]
+  struct RevokeReq {
+    public_id: String,
+  }
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let public_id = match serde_json::from_slice::<RevokeReq>(&body) {
+    // The current session ends with /logout, which also clears the cookies.
+    Ok(body) if body.public_id != session.record.public_id => body.public_id,
+    _ => {
+      return Ok(json_response(
+        StatusCode::BAD_REQUEST,
+        json!({ "error": "invalid_request" }),
+      ))
+    }
+  };
+  if storage
+    .end_user_session_by_public_id(&session.auth.github_node_id, &public_id)
+    .await?
+  {
+    Ok(json_response(StatusCode::OK, json!({ "revoked": 1 })))
+  } else {
+    Ok(json_response(
+      StatusCode::NOT_FOUND,
+      json!({ "error": "not_found" }),
+    ))
+  }
+}
+pub async fn revoke_session_route(
+  config: &Config,
+  storage: &dyn Storage,
+  req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+  let (session, from_cookie) = match require_session(config, storage, &req).await {
+    Ok(x) => x,
+    Err(resp) => return Ok(resp),
+  };
+  let resp = match revoke_session_route_inner(storage, &session, req).await {
+    Ok(resp) => resp,
+    Err(e) => {
+      log::error!("revoking session failed: {:?}", e);
+      server_error()
+    }
+  };
+  Ok(with_rotated_token(config, storage, &session, from_cookie, resp).await)
+}
+
+async fn revoke_other_sessions(storage: &dyn Storage, session: &Session) -> anyhow::Result<usize> {
+  let mut revoked = 0;
+  for other in storage.user_sessions(&session.auth.github_node_id).await? {
+    if other.public_id != session.record.public_id
+      && storage
+        .end_user_session_by_public_id(&session.auth.github_node_id, &other.public_id)
+        .await?
+    {
+      revoked += 1;
+    }
+  }
+  Ok(revoked)
+}
+pub async fn revoke_other_sessions_route(
+  config: &Config,
+  storage: &dyn Storage,
+  req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+  let (session, from_cookie) = match require_session(config, storage, &req).await {
+    Ok(x) => x,
+    Err(resp) => return Ok(resp),
+  };
+  let resp = match revoke_other_sessions(storage, &session).await {
+    Ok(revoked) => json_response(StatusCode::OK, json!({ "revoked": revoked })),
+    Err(e) => {
+      log::error!("revoking other sessions failed: {:?}", e);
+      server_error()
+    }
+  };
+  Ok(with_rotated_token(config, storage, &session, from_cookie, resp).await)
+}
+
 fn parse_cookies(req: &Request<Body>) -> anyhow::Result<HashMap<&str, &str>> {
   let cookies_raw = req
     .headers()
@@ -372,28 +660,86 @@ fn parse_cookies(req: &Request<Body>) -> anyhow::Result<HashMap<&str, &str>> {
 // We respond with { "X-Hasura-User-Id": "<github_node_id>", "X-Hasura-Role": "user" } for authenticated users and
 // { "X-Hasura-Role": "anonymous" } for anonymous requests.
 
-async fn hasura_auth_webhook_inner(req: Request<Body>) -> anyhow::Result<GitHubUserId> {
-  // Note: there's some redundancy here with `main::lookup_github_auth_from_header`.
-  let session_token = if let Some(header_value) = req.headers().get(header::AUTHORIZATION) {
+/// A session that a request authenticated with.
+pub struct Session {
+  pub token: String,
+  pub auth: GitHubAuth,
+  pub record: SessionRecord,
+}
+
+/// Look up an unexpired session, and push its expiry back.
+pub async fn lookup_session(
+  storage: &dyn Storage,
+  session_ttl: Duration,
+  session_token: &str,
+) -> anyhow::Result<Session> {
+  let (auth, record) = storage
+    .lookup_user_session(session_token, session_ttl)
+    .await?
+    .ok_or_else(|| anyhow!("no such session"))?;
+  Ok(Session {
+    token: session_token.to_string(),
+    auth,
+    record,
+  })
+}
+
+/// A new token to replace `session`'s with, if it's older than SESSION_ROTATE_AFTER_SECS and hasn't been rotated
+/// already. Only one of two requests racing to rotate the same session gets a new one.
+pub async fn rotate_session_if_due(
+  config: &Config,
+  storage: &dyn Storage,
+  session: &Session,
+) -> anyhow::Result<Option<String>> {
+  if session.record.rotated_at.is_some()
+    || Utc::now() - session.record.created_at < config.session_rotate_after
+  {
+    return Ok(None);
+  }
+  storage
+    .rotate_user_session(
+      &session.token,
+      config.session_ttl,
+      Duration::seconds(ROTATION_GRACE_SECS),
+    )
+    .await
+}
+
+/// The session token that `req` carries as `Authorization: Bearer <token>` or else in our cookie, and whether it came
+/// from the cookie.
+fn session_token(req: &Request<Body>) -> anyhow::Result<(String, bool)> {
+  if let Some(header_value) = req.headers().get(header::AUTHORIZATION) {
     trace!("found authorization header");
     let value = header_value.to_str()?;
     ensure!(value.starts_with("Bearer "));
-    &value[7..]
+    Ok((value[7..].to_string(), false))
   } else {
     trace!("no authorization header, looking for cookies");
-    parse_cookies(&req)?
+    let token = parse_cookies(req)?
       .get(SESSION_TOKEN_COOKIE_NAME)
       .ok_or_else(|| anyhow!("couldn't get session token cookie"))?
-      .to_owned()
-  };
+      .to_string();
+    Ok((token, true))
+  }
+}
 
-  let auth = hasura::lookup_user_session(session_token)
-    .await?
-    .ok_or_else(|| anyhow!("couldn't find session token {:?} in hasura", session_token))?;
-  Ok(auth.github_node_id)
+async fn hasura_auth_webhook_inner(
+  config: &Config,
+  storage: &dyn Storage,
+  req: Request<Body>,
+) -> anyhow::Result<GitHubUserId> {
+  // Note: there's some redundancy here with `main::lookup_session_from_header`. Hasura is the one that sees our
+  // response, so there's no way to hand the client a rotated token from here.
+  let (session_token, _) = session_token(&req)?;
+  let session = lookup_session(storage, config.session_ttl, &session_token).await?;
+  Ok(session.auth.github_node_id)
 }
-pub async fn hasura_auth_webhook(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-  let response = match hasura_auth_webhook_inner(req).await {
+pub async fn hasura_auth_webhook(
+  config: &Config,
+  storage: &dyn Storage,
+  req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+  let response = match hasura_auth_webhook_inner(config, storage, req).await {
     Ok(GitHubUserId(GitHubNodeId(node_id))) => {
       log::trace!("auth accepted for user: {}", node_id);
       Response::builder()
@@ -423,8 +769,12 @@ pub async fn hasura_auth_webhook(req: Request<Body>) -> Result<Response<Body>, h
 
 #[cfg(test)]
 mod tests {
-  use chrono::prelude::Utc;
-  use chrono::Duration;
+  use super::*;
+  use crate::fakes;
+  use crate::fakes::FakeGitHub;
+  use crate::fakes::InMemoryStorage;
+  use crate::github::GitHubApi;
+  use std::sync::Arc;
 
   // See https://github.com/instructure/paseto/issues/37.
   #[test]
@@ -445,14 +795,83 @@ mod tests {
     assert!(validation.is_ok());
   }
 
-  // reqwest will panic at runtime if it's not happy with the version of tokio
-  // that it's provided. That's not cool.
+  fn set_cookies(resp: &Response<Body>) -> Vec<Cookie<'static>> {
+    resp
+      .headers()
+      .get_all(header::SET_COOKIE)
+      .iter()
+      .map(|v| Cookie::parse(v.to_str().unwrap().to_string()).unwrap())
+      .collect()
+  }
+
   #[tokio::test]
-  async fn basic_reqwest() {
-    let resp = reqwest::Client::new()
-      .get("https://google.com")
-      .send()
-      .await;
-    assert!(resp.is_ok())
+  async fn login_flow_against_fake_github_server() {
+    let fake = Arc::new(FakeGitHub::default());
+    fake.add_user("the-code", "the-token", "U_1", "someone");
+    let url = fakes::serve_github(fake).await;
+    let config = fakes::config(&url);
+    let github = GitHubApi::new(&config);
+    let storage = InMemoryStorage::default();
+
+    // We send the user off to GitHub...
+    let resp = login_route(&config, &github, Request::new(Body::empty()))
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+    let location = url::Url::parse(resp.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+    assert!(location
+      .as_str()
+      .starts_with(&format!("{}/login/oauth/authorize?", url)));
+    let state = location
+      .query_pairs()
+      .find(|(k, _)| k == "state")
+      .unwrap()
+      .1
+      .to_string();
+
+    // ... and they come back with a code, which only works along with our state.
+    let callback = |code: &str, state: &str| {
+      Request::get(format!(
+        "/oauth/callback/github?code={}&state={}",
+        code, state
+      ))
+      .body(Body::empty())
+      .unwrap()
+    };
+    for req in [
+      callback("bad-code", &state),
+      callback("the-code", "bad-state"),
+    ] {
+      let resp = github_callback_route(&config, &storage, &github, req)
+        .await
+        .unwrap();
+      assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+    let resp = github_callback_route(&config, &storage, &github, callback("the-code", &state))
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+    let cookies = set_cookies(&resp);
+    let session_token = cookies
+      .iter()
+      .find(|c| c.name() == SESSION_TOKEN_COOKIE_NAME)
+      .unwrap()
+      .value();
+    let (auth, _) = storage
+      .lookup_user_session(session_token, config.session_ttl)
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(auth.github_node_id.0 .0, "U_1");
+    assert_eq!(auth.access_token, "the-token");
+    let user_info = cookies
+      .iter()
+      .find(|c| c.name() == USER_INFO_COOKIE_NAME)
+      .unwrap()
+      .value();
+    assert_eq!(
+      serde_json::from_str::<serde_json::Value>(user_info).unwrap()["github_login"],
+      "someone"
+    );
   }
 }
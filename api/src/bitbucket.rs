@@ -0,0 +1,53 @@
+// Calling Bitbucket Cloud API endpoints.
+use anyhow::ensure;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+static USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Deserialize, Debug)]
+struct RepositoryResp {
+  is_private: bool,
+}
+
+/// Returns Some((is_private, commit_in_repo)) if we are able to find the repo, and None if we were not able to find the
+/// repo. We don't send any credentials, so private repos look exactly like missing ones.
+pub async fn lookup_commit(
+  repo_owner: &str,
+  repo_name: &str,
+  commit_oid: &str,
+) -> anyhow::Result<Option<(bool, bool)>> {
+  // This goes into a URL path, so make sure that it's nothing but hex.
+  let commit_oid = git2::Oid::from_str(commit_oid)?;
+  let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+
+  // See https://developer.atlassian.com/cloud/bitbucket/rest/api-group-repositories/#api-repositories-workspace-repo-slug-get.
+  let repo_response = client
+    .get(format!(
+      "https://api.bitbucket.org/2.0/repositories/{}/{}",
+      repo_owner, repo_name
+    ))
+    .send()
+    .await?;
+  if repo_response.status() == StatusCode::NOT_FOUND {
+    return Ok(None);
+  }
+  let repo: RepositoryResp = repo_response.error_for_status()?.json().await?;
+
+  // See https://developer.atlassian.com/cloud/bitbucket/rest/api-group-commits/#api-repositories-workspace-repo-slug-commit-commit-get.
+  let commit_response = client
+    .get(format!(
+      "https://api.bitbucket.org/2.0/repositories/{}/{}/commit/{}",
+      repo_owner, repo_name, commit_oid
+    ))
+    .send()
+    .await?;
+  let contains_commit = commit_response.status().is_success();
+  ensure!(
+    contains_commit || commit_response.status() == StatusCode::NOT_FOUND,
+    "bitbucket returned non-success status {}",
+    commit_response.status()
+  );
+
+  Ok(Some((repo.is_private, contains_commit)))
+}
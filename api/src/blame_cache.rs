@@ -0,0 +1,407 @@
+// A persistent, incremental cache of git blame results.
+//
+// `repo.blame_file` is slow on large repos (seconds per file) and blame info for a given (commit, file_path) never
+// changes, so we store the resulting hunks on disk inside of each mirror. That way the cache counts towards the mirror's
+// size, and goes away along with it when it's evicted. When a commit has a single parent whose
+// blame is already cached, we avoid a full blame entirely: lines that the commit didn't touch inherit their blame from
+// the parent, and lines that it did touch are, by definition, blamed to the commit itself.
+use anyhow::Context;
+use git2::BlameOptions;
+use git2::ObjectType;
+use git2::Oid;
+use git2::Patch;
+use git2::Repository;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+/// Where the cache lives inside of a mirror. Git leaves anything it doesn't know about alone.
+const MIRROR_CACHE_DIR: &str = "blame-cache";
+
+/// A contiguous run of lines in the final file that all originate from the same place. All line numbers are 1-indexed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CachedHunk {
+  pub orig_commit: String,
+  pub orig_file_path: String,
+  pub orig_start_line: usize,
+  pub final_start_line: usize,
+  pub lines: usize,
+}
+
+impl CachedHunk {
+  /// The original (commit, file_path, line_number) for each line covered by this hunk.
+  pub fn orig_lines(&self) -> impl Iterator<Item = (&str, &str, usize)> {
+    (0..self.lines).map(move |i| {
+      (
+        self.orig_commit.as_str(),
+        self.orig_file_path.as_str(),
+        self.orig_start_line + i,
+      )
+    })
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+  // Not strictly necessary since the entry file name is derived from these, but it makes poking around the cache by
+  // hand a lot less painful.
+  commit: String,
+  file_path: String,
+  hunks: Vec<CachedHunk>,
+}
+
+pub struct BlameCache {
+  root: PathBuf,
+  /// How much we've added to the disk, so that the mirror's size can be kept up to date.
+  written_bytes: AtomicU64,
+}
+
+impl BlameCache {
+  pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+    BlameCache {
+      root: root.into(),
+      written_bytes: AtomicU64::new(0),
+    }
+  }
+
+  /// The cache for everything in the bare `mirror`.
+  pub fn in_mirror(mirror: &Repository) -> Self {
+    BlameCache::new(mirror.path().join(MIRROR_CACHE_DIR))
+  }
+
+  /// Bytes written by `put` so far.
+  pub fn written_bytes(&self) -> u64 {
+    self.written_bytes.load(Ordering::Relaxed)
+  }
+
+  /// Commits are content addressed so we don't need to namespace by repo. File paths can be arbitrarily long and
+  /// contain all sorts of characters, so we hash them to get something that's always a valid file name.
+  fn entry_path(&self, commit: &str, file_path: &str) -> anyhow::Result<PathBuf> {
+    let path_hash = Oid::hash_object(ObjectType::Blob, file_path.as_bytes())?;
+    Ok(self.root.join(commit).join(format!("{}.json", path_hash)))
+  }
+
+  pub fn get(&self, commit: &str, file_path: &str) -> anyhow::Result<Option<Vec<CachedHunk>>> {
+    let path = self.entry_path(commit, file_path)?;
+    if !path.exists() {
+      return Ok(None);
+    }
+    let entry: CacheEntry = serde_json::from_slice(&std::fs::read(&path)?)
+      .with_context(|| format!("parsing blame cache entry {}", path.to_string_lossy()))?;
+    Ok(Some(entry.hunks))
+  }
+
+  pub fn put(&self, commit: &str, file_path: &str, hunks: &[CachedHunk]) -> anyhow::Result<()> {
+    let path = self.entry_path(commit, file_path)?;
+    let dir = path.parent().expect("entry path always has a parent");
+    std::fs::create_dir_all(dir)?;
+
+    // Write to a temporary file and rename so that readers (and crashes) never see a half-written entry.
+    let tmp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    let contents = serde_json::to_vec(&CacheEntry {
+      commit: commit.to_string(),
+      file_path: file_path.to_string(),
+      hunks: hunks.to_vec(),
+    })?;
+    std::fs::write(&tmp_path, &contents)?;
+    std::fs::rename(&tmp_path, &path)?;
+    self
+      .written_bytes
+      .fetch_add(contents.len() as u64, Ordering::Relaxed);
+    Ok(())
+  }
+
+  /// Get the blame for `file_path` as of `commit`, consulting and populating the cache.
+  pub fn blame(
+    &self,
+    repo: &Repository,
+    commit: Oid,
+    file_path: &str,
+  ) -> anyhow::Result<Vec<CachedHunk>> {
+    let commit_str = commit.to_string();
+    if let Some(hunks) = self.get(&commit_str, file_path)? {
+      log::trace!("blame cache hit for {} {}", commit_str, file_path);
+      return Ok(hunks);
+    }
+
+    let hunks = match self.incremental_blame(repo, commit, file_path)? {
+      Some(hunks) => {
+        log::trace!("incremental blame for {} {}", commit_str, file_path);
+        hunks
+      }
+      None => {
+        log::trace!("full blame for {} {}", commit_str, file_path);
+        full_blame(repo, commit, file_path)?
+      }
+    };
+    self.put(&commit_str, file_path, &hunks)?;
+    Ok(hunks)
+  }
+
+  /// Try to derive the blame for `commit` from its parent's cached blame. Returns `None` when that isn't possible, eg.
+  /// merge commits, root commits, files that don't exist in the parent, or a parent blame that isn't cached yet.
+  fn incremental_blame(
+    &self,
+    repo: &Repository,
+    commit: Oid,
+    file_path: &str,
+  ) -> anyhow::Result<Option<Vec<CachedHunk>>> {
+    let commit_obj = repo.find_commit(commit)?;
+    if commit_obj.parent_count() != 1 {
+      return Ok(None);
+    }
+    let parent = commit_obj.parent(0)?;
+    let parent_hunks = match self.get(&parent.id().to_string(), file_path)? {
+      Some(hunks) => hunks,
+      None => return Ok(None),
+    };
+
+    let path = Path::new(file_path);
+    let new_blob = repo.find_blob(commit_obj.tree()?.get_path(path)?.id())?;
+    let old_blob = match parent.tree()?.get_path(path) {
+      Ok(entry) => repo.find_blob(entry.id())?,
+      // The file was added (or renamed) in this commit. A full blame knows how to deal with that.
+      Err(_) => return Ok(None),
+    };
+
+    // Expand the parent's blame so that we can index it by line.
+    let parent_lines = parent_hunks
+      .iter()
+      .flat_map(|h| h.orig_lines())
+      .collect::<Vec<_>>();
+
+    // Walk the diff between the parent and this commit. Lines outside of any hunk are untouched and inherit the
+    // parent's blame. Lines added by a hunk belong to this commit.
+    let mut diff_opts = git2::DiffOptions::new();
+    diff_opts.context_lines(0);
+    let patch = Patch::from_blobs(
+      &old_blob,
+      Some(path),
+      &new_blob,
+      Some(path),
+      Some(&mut diff_opts),
+    )?;
+    let new_line_count = count_lines(new_blob.content());
+    let commit_str = commit.to_string();
+    let mut builder = HunkBuilder::default();
+    // Next line number to be emitted in the old and new files, 1-indexed.
+    let mut old_line = 1;
+    let mut new_line = 1;
+    let copy_unchanged =
+      |builder: &mut HunkBuilder, old_line: &mut usize, new_line: &mut usize, until: usize| {
+        while *new_line < until {
+          let (c, p, l) = *parent_lines.get(*old_line - 1)?;
+          builder.push(c, p, l);
+          *old_line += 1;
+          *new_line += 1;
+        }
+        Some(())
+      };
+    for hunk_idx in 0..patch.num_hunks() {
+      let (hunk, _) = patch.hunk(hunk_idx)?;
+      // With zero context lines, a pure deletion reports the line *before* the deletion as its new_start.
+      let hunk_new_start = if hunk.new_lines() == 0 {
+        hunk.new_start() as usize + 1
+      } else {
+        hunk.new_start() as usize
+      };
+      if copy_unchanged(&mut builder, &mut old_line, &mut new_line, hunk_new_start).is_none() {
+        // The parent's cached blame doesn't line up with the parent's blob. Don't trust it.
+        return Ok(None);
+      }
+      for _ in 0..hunk.new_lines() {
+        builder.push(&commit_str, file_path, new_line);
+        new_line += 1;
+      }
+      old_line += hunk.old_lines() as usize;
+    }
+    if copy_unchanged(
+      &mut builder,
+      &mut old_line,
+      &mut new_line,
+      new_line_count + 1,
+    )
+    .is_none()
+      || old_line != parent_lines.len() + 1
+    {
+      return Ok(None);
+    }
+
+    Ok(Some(builder.finish()))
+  }
+}
+
+/// Run a regular, non-incremental git blame.
+pub fn full_blame(
+  repo: &Repository,
+  commit: Oid,
+  file_path: &str,
+) -> anyhow::Result<Vec<CachedHunk>> {
+  log::trace!("Running git blame...");
+  let blame = repo.blame_file(
+    Path::new(file_path),
+    Some(BlameOptions::new().newest_commit(commit)),
+  )?;
+  log::trace!("... git blame done");
+
+  Ok(
+    blame
+      .iter()
+      .map(|blamehunk| CachedHunk {
+        orig_commit: blamehunk.orig_commit_id().to_string(),
+        // Re expect here: The .path() should only ever be None in unicode situations on Windows
+        // (https://docs.rs/git2/0.11.0/git2/struct.BlameHunk.html#method.path).
+        orig_file_path: blamehunk
+          .path()
+          .expect("Could not get BlameHunk.path()")
+          .to_string_lossy()
+          .to_string(),
+        orig_start_line: blamehunk.orig_start_line(),
+        final_start_line: blamehunk.final_start_line(),
+        lines: blamehunk.lines_in_hunk(),
+      })
+      .collect(),
+  )
+}
+
+/// Number of lines in a blob, the same way that git counts them: a trailing newline does not start a new line.
+fn count_lines(content: &[u8]) -> usize {
+  if content.is_empty() {
+    return 0;
+  }
+  let newlines = content.iter().filter(|&&b| b == b'\n').count();
+  if content.ends_with(b"\n") {
+    newlines
+  } else {
+    newlines + 1
+  }
+}
+
+/// Accumulates per-line blame info into hunks, merging runs of consecutive lines.
+#[derive(Default)]
+struct HunkBuilder {
+  hunks: Vec<CachedHunk>,
+}
+
+impl HunkBuilder {
+  fn push(&mut self, orig_commit: &str, orig_file_path: &str, orig_line: usize) {
+    let final_line = self
+      .hunks
+      .last()
+      .map_or(1, |h| h.final_start_line + h.lines);
+    if let Some(last) = self.hunks.last_mut() {
+      if last.orig_commit == orig_commit
+        && last.orig_file_path == orig_file_path
+        && last.orig_start_line + last.lines == orig_line
+      {
+        last.lines += 1;
+        return;
+      }
+    }
+    self.hunks.push(CachedHunk {
+      orig_commit: orig_commit.to_string(),
+      orig_file_path: orig_file_path.to_string(),
+      orig_start_line: orig_line,
+      final_start_line: final_line,
+      lines: 1,
+    });
+  }
+
+  fn finish(self) -> Vec<CachedHunk> {
+    self.hunks
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use git2::Signature;
+
+  fn commit_file(repo: &Repository, file_path: &str, content: &str) -> Oid {
+    let workdir = repo.workdir().unwrap();
+    std::fs::write(workdir.join(file_path), content).unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new(file_path)).unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = Signature::now("test", "test@example.com").unwrap();
+    let parents = match repo.head() {
+      Ok(head) => vec![head.peel_to_commit().unwrap()],
+      Err(_) => vec![],
+    };
+    repo
+      .commit(
+        Some("HEAD"),
+        &sig,
+        &sig,
+        "commit",
+        &tree,
+        &parents.iter().collect::<Vec<_>>(),
+      )
+      .unwrap()
+  }
+
+  fn flatten(hunks: &[CachedHunk]) -> Vec<(String, String, usize)> {
+    hunks
+      .iter()
+      .flat_map(|h| h.orig_lines())
+      .map(|(c, p, l)| (c.to_string(), p.to_string(), l))
+      .collect()
+  }
+
+  #[test]
+  fn incremental_matches_full_blame() {
+    let repo_dir = tempfile::tempdir().unwrap();
+    let cache_dir = tempfile::tempdir().unwrap();
+    let repo = Repository::init(repo_dir.path()).unwrap();
+    let cache = BlameCache::new(cache_dir.path());
+
+    let versions = [
+      "a\nb\nc\nd\ne\n",
+      "a\nB\nc\nd\ne\nf\n",
+      "x\na\nB\nd\ne\nf",
+      "x\na\nB\nd\ne\nf\n",
+      "",
+      "new\n",
+    ];
+    for content in versions {
+      let commit = commit_file(&repo, "file.txt", content);
+      let incremental = cache.blame(&repo, commit, "file.txt").unwrap();
+      let full = full_blame(&repo, commit, "file.txt").unwrap();
+      assert_eq!(
+        flatten(&incremental),
+        flatten(&full),
+        "content = {:?}",
+        content
+      );
+      assert_eq!(
+        cache.get(&commit.to_string(), "file.txt").unwrap(),
+        Some(incremental)
+      );
+    }
+  }
+
+  #[test]
+  fn weird_file_paths() {
+    let cache_dir = tempfile::tempdir().unwrap();
+    let cache = BlameCache::new(cache_dir.path());
+    let path = "dir/with \"quotes\"/and\\backslashes/ünïcödé.rs";
+    let hunks = vec![CachedHunk {
+      orig_commit: "abc".to_string(),
+      orig_file_path: path.to_string(),
+      orig_start_line: 1,
+      final_start_line: 1,
+      lines: 3,
+    }];
+    assert_eq!(cache.get("abc", path).unwrap(), None);
+    cache.put("abc", path, &hunks).unwrap();
+    assert_eq!(cache.get("abc", path).unwrap(), Some(hunks));
+    assert_eq!(
+      cache.written_bytes(),
+      crate::mirror_store::dir_size(cache_dir.path()).unwrap()
+    );
+  }
+}
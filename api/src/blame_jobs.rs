@@ -0,0 +1,208 @@
+// Background jobs that calculate blamelines.
+//
+// Cloning and blaming a big repo can take far longer than any client is willing to wait on a single request, so
+// `CalculateBlameLines` just records a job in the blame_jobs table and returns its id. Jobs are run here with bounded
+// concurrency. Clients can either poll the `blameJob` query or subscribe to the blame_jobs table through Hasura.
+use crate::parse_repo_id;
+use crate::storage::BlameJobRecord;
+use crate::storage::Storage;
+use anyhow::bail;
+use anyhow::Result;
+use juniper::GraphQLEnum;
+use juniper::GraphQLObject;
+use lazy_static::lazy_static;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use tokio::sync::Mutex;
+use tokio::sync::Semaphore;
+
+#[derive(Clone, Copy, Debug, PartialEq, GraphQLEnum)]
+pub enum BlameJobStatus {
+  Queued,
+  Running,
+  Done,
+  Failed,
+}
+
+impl BlameJobStatus {
+  /// How this status is stored in the blame_jobs.status column.
+  fn as_str(self) -> &'static str {
+    match self {
+      BlameJobStatus::Queued => "queued",
+      BlameJobStatus::Running => "running",
+      BlameJobStatus::Done => "done",
+      BlameJobStatus::Failed => "failed",
+    }
+  }
+
+  fn parse(s: &str) -> Result<Self> {
+    Ok(match s {
+      "queued" => BlameJobStatus::Queued,
+      "running" => BlameJobStatus::Running,
+      "done" => BlameJobStatus::Done,
+      "failed" => BlameJobStatus::Failed,
+      _ => bail!("unknown blame job status {:?}", s),
+    })
+  }
+}
+
+#[derive(Debug, GraphQLObject)]
+pub struct BlameJob {
+  id: String,
+  repo_id: String,
+  commit_hash: String,
+  file_path: String,
+  status: BlameJobStatus,
+  /// Why the job failed, when `status` is FAILED.
+  error: Option<String>,
+}
+
+impl TryFrom<BlameJobRecord> for BlameJob {
+  type Error = anyhow::Error;
+
+  fn try_from(record: BlameJobRecord) -> Result<Self> {
+    Ok(BlameJob {
+      status: BlameJobStatus::parse(&record.status)?,
+      id: record.id,
+      repo_id: record.repo_id,
+      commit_hash: record.commit_hash,
+      file_path: record.file_path,
+      error: record.error,
+    })
+  }
+}
+
+/// Bounds how many jobs run at once. Set up by `init`.
+static BLAME_WORKERS: OnceLock<Semaphore> = OnceLock::new();
+
+lazy_static! {
+  // Held while checking for an existing job and inserting a new one, so that we don't end up with two jobs for the same
+  // file when a bunch of clients show up at once.
+  static ref ENQUEUE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Run at most `workers` jobs at once. Call this before queueing or resuming any jobs. Only the first call counts.
+pub fn init(workers: usize) {
+  BLAME_WORKERS.get_or_init(|| Semaphore::new(workers));
+}
+
+/// Queue up a job to calculate the blamelines for `file_path` as of `commit_hash`. Returns the id of the job, or None
+/// if the blamelines are already in the database. If there's already a job in progress for this file in this repo, we
+/// return that one instead of starting another. Jobs from other repos don't count, since they can fail, eg. when someone
+/// asks about a repo that doesn't have the commit, where this one would succeed.
+pub async fn enqueue(
+  storage: &Arc<dyn Storage>,
+  repo_id: &str,
+  commit_hash: &str,
+  file_path: &str,
+) -> Result<Option<String>> {
+  // Make sure that this is a repo we know how to fetch before we bother the database.
+  parse_repo_id(repo_id)?;
+
+  if storage
+    .lookup_existing_blamelines(commit_hash, file_path)
+    .await?
+  {
+    log::trace!("blamelines already exist in the database!");
+    return Ok(None);
+  }
+
+  let _lock = ENQUEUE_LOCK.lock().await;
+  if let Some(job) = storage
+    .lookup_unfinished_blame_job(repo_id, commit_hash, file_path)
+    .await?
+  {
+    log::trace!("blame job {} is already in progress", job.id);
+    return Ok(Some(job.id));
+  }
+  let job = storage
+    .insert_blame_job(repo_id, commit_hash, file_path)
+    .await?;
+  let job_id = job.id.clone();
+  log::info!("queued blame job {}", job_id);
+  spawn(storage.clone(), job);
+  Ok(Some(job_id))
+}
+
+pub async fn lookup(storage: &dyn Storage, job_id: &str) -> Result<Option<BlameJob>> {
+  storage
+    .lookup_blame_job(job_id)
+    .await?
+    .map(BlameJob::try_from)
+    .transpose()
+}
+
+/// Pick up any jobs that were queued or running when we last shut down.
+pub async fn resume_unfinished_jobs(storage: &Arc<dyn Storage>) -> Result<()> {
+  let jobs = storage.unfinished_blame_jobs().await?;
+  log::info!("resuming {} unfinished blame jobs", jobs.len());
+  for job in jobs {
+    spawn(storage.clone(), job);
+  }
+  Ok(())
+}
+
+fn spawn(storage: Arc<dyn Storage>, job: BlameJobRecord) {
+  tokio::spawn(async move {
+    let _permit = BLAME_WORKERS
+      .get()
+      .expect("BLAME_WORKERS used before it was set up")
+      .acquire()
+      .await
+      .expect("BLAME_WORKERS is never closed");
+    let (status, error) = match run(&*storage, &job).await {
+      Ok(()) => {
+        log::info!("blame job {} done", job.id);
+        (BlameJobStatus::Done, None)
+      }
+      Err(e) => {
+        log::error!("blame job {} failed: {:?}", job.id, e);
+        (BlameJobStatus::Failed, Some(format!("{:#}", e)))
+      }
+    };
+    if let Err(e) = storage
+      .update_blame_job_status(&job.id, status.as_str(), error.as_deref())
+      .await
+    {
+      log::error!("failed to update status of blame job {}: {:?}", job.id, e);
+    }
+  });
+}
+
+async fn run(storage: &dyn Storage, job: &BlameJobRecord) -> Result<()> {
+  storage
+    .update_blame_job_status(&job.id, BlameJobStatus::Running.as_str(), None)
+    .await?;
+
+  // Another job may have gotten to this file in the meantime, eg. if we're resuming after a restart.
+  if storage
+    .lookup_existing_blamelines(&job.commit_hash, &job.file_path)
+    .await?
+  {
+    return Ok(());
+  }
+  let repo_id = parse_repo_id(&job.repo_id)?;
+  let blamelines = crate::git_blame(&repo_id, &job.commit_hash, &job.file_path).await?;
+  // Existing values ok.
+  storage
+    .insert_blamelines(&job.commit_hash, &job.file_path, blamelines)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn status_round_trip() {
+    for status in [
+      BlameJobStatus::Queued,
+      BlameJobStatus::Running,
+      BlameJobStatus::Done,
+      BlameJobStatus::Failed,
+    ] {
+      assert_eq!(BlameJobStatus::parse(status.as_str()).unwrap(), status);
+    }
+    assert!(BlameJobStatus::parse("QUEUED").is_err());
+  }
+}
@@ -0,0 +1,333 @@
+// Replying to, editing and deleting comments.
+//
+// Threads are started with `StartThread`, and every comment after the first one is added here. Only a comment's author
+// may edit or delete it. Edits keep the old body around in comment_edits (see the save_comment_edit trigger), and
+// deleting a comment only sets its deleted_at, so that the replies after it still make sense.
+//
+// New and edited comments are checked for @mentions, see `mentions`. Edits only ever add mentions, since the people
+// who were mentioned before may already have been notified.
+//
+// Reactions are a lighter way to respond to a comment than another reply, and don't notify anyone.
+use crate::comment_from_record;
+use crate::mentions;
+use crate::storage::CommentRecord;
+use crate::storage::ReactionRecord;
+use crate::subscriptions;
+use crate::subscriptions::ThreadEventKind;
+use crate::AuthContext;
+use crate::Comment;
+use crate::GitHubAuth;
+use crate::GitHubUserId;
+use crate::JuniperContext;
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Result;
+use juniper::GraphQLEnum;
+use juniper::GraphQLObject;
+
+/// Comments can be long, but not unboundedly so.
+pub const MAX_COMMENT_BODY_CHARS: usize = 65_536;
+
+/// A previous version of a comment.
+#[derive(Debug, GraphQLObject)]
+pub struct CommentEdit {
+  body: String,
+  /// When `body` was replaced by a newer version.
+  replaced_at: String,
+}
+
+/// The reactions that comments can get, same as GitHub's.
+#[derive(Clone, Copy, Debug, PartialEq, GraphQLEnum)]
+pub enum Reaction {
+  ThumbsUp,
+  ThumbsDown,
+  Laugh,
+  Hooray,
+  Confused,
+  Heart,
+  Rocket,
+  Eyes,
+}
+
+impl Reaction {
+  /// In the order that they're shown in.
+  const ALL: [Reaction; 8] = [
+    Reaction::ThumbsUp,
+    Reaction::ThumbsDown,
+    Reaction::Laugh,
+    Reaction::Hooray,
+    Reaction::Confused,
+    Reaction::Heart,
+    Reaction::Rocket,
+    Reaction::Eyes,
+  ];
+
+  /// How this reaction is stored in the comment_reactions.reaction column.
+  fn as_str(self) -> &'static str {
+    match self {
+      Reaction::ThumbsUp => "thumbs_up",
+      Reaction::ThumbsDown => "thumbs_down",
+      Reaction::Laugh => "laugh",
+      Reaction::Hooray => "hooray",
+      Reaction::Confused => "confused",
+      Reaction::Heart => "heart",
+      Reaction::Rocket => "rocket",
+      Reaction::Eyes => "eyes",
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, GraphQLObject)]
+pub struct ReactionCount {
+  reaction: Reaction,
+  count: i32,
+  /// Whether the logged in user is one of the `count`.
+  viewer_has_reacted: bool,
+}
+
+/// How many of each reaction a comment has, leaving out the ones it has none of.
+pub fn reaction_counts(
+  reactions: &[ReactionRecord],
+  viewer: Option<&GitHubUserId>,
+) -> Vec<ReactionCount> {
+  Reaction::ALL
+    .iter()
+    .filter_map(|&reaction| {
+      let matching = reactions
+        .iter()
+        .filter(|r| r.reaction == reaction.as_str())
+        .collect::<Vec<_>>();
+      (!matching.is_empty()).then(|| ReactionCount {
+        reaction,
+        count: matching.len() as i32,
+        viewer_has_reacted: viewer
+          .is_some_and(|viewer| matching.iter().any(|r| r.github_node_id == viewer.0 .0)),
+      })
+    })
+    .collect()
+}
+
+pub fn validate_body(body: &str) -> Result<()> {
+  ensure!(!body.trim().is_empty(), "comment body is empty");
+  ensure!(
+    body.chars().count() <= MAX_COMMENT_BODY_CHARS,
+    "comment body is longer than {} characters",
+    MAX_COMMENT_BODY_CHARS
+  );
+  Ok(())
+}
+
+fn require_login(context: &JuniperContext) -> Result<&GitHubAuth> {
+  match &context.auth {
+    AuthContext::GitHub(auth) => Ok(auth),
+    AuthContext::Anonymous => Err(anyhow!("unauthorized")),
+  }
+}
+
+/// Look up a comment that `auth` is allowed to change. Returns its thread id along with it.
+async fn own_comment(
+  context: &JuniperContext,
+  auth: &GitHubAuth,
+  comment_id: &str,
+) -> Result<(String, CommentRecord)> {
+  let (thread_id, comment) = context
+    .storage
+    .lookup_comment(comment_id)
+    .await?
+    .filter(|(_, c)| c.deleted_at.is_none())
+    .ok_or_else(|| anyhow!("no comment {}", comment_id))?;
+  // Comments that came in by email don't have a GitHub author, so nobody gets to change those.
+  ensure!(
+    comment.author_github_node_id.as_ref() == Some(&auth.github_node_id.0 .0),
+    "only a comment's author can change it"
+  );
+  Ok((thread_id, comment))
+}
+
+async fn publish(
+  context: &JuniperContext,
+  kind: ThreadEventKind,
+  thread_id: &str,
+  comment: &CommentRecord,
+) -> Result<()> {
+  let thread = context
+    .storage
+    .lookup_thread(thread_id)
+    .await?
+    .ok_or_else(|| {
+      anyhow!(
+        "comment {} belongs to a thread that doesn't exist",
+        comment.id
+      )
+    })?;
+  subscriptions::publish_comment_changed(kind, thread_id, &thread, comment);
+  Ok(())
+}
+
+/// Reply to a thread. Subscribers hear about the new comment from Hasura's insert_comments event, same as for comments
+/// inserted through Hasura.
+pub async fn add(context: &JuniperContext, thread_id: &str, body: &str) -> Result<Comment> {
+  let auth = require_login(context)?;
+  validate_body(body)?;
+  if context.storage.lookup_thread(thread_id).await?.is_none() {
+    bail!("no thread {}", thread_id);
+  }
+  let mut comment = context
+    .storage
+    .add_comment(&auth.github_node_id, thread_id, body)
+    .await?;
+  comment.mentions = mentions::record(context, &comment.id, body).await;
+  Ok(comment_from_record(comment, Some(&auth.github_node_id)))
+}
+
+pub async fn edit(context: &JuniperContext, comment_id: &str, body: &str) -> Result<Comment> {
+  let auth = require_login(context)?;
+  validate_body(body)?;
+  let (thread_id, _) = own_comment(context, auth, comment_id).await?;
+  // The comment could still have been deleted in the meantime.
+  let mut comment = context
+    .storage
+    .edit_comment(comment_id, body)
+    .await?
+    .ok_or_else(|| anyhow!("no comment {}", comment_id))?;
+  for mention in mentions::record(context, comment_id, body).await {
+    if !comment
+      .mentions
+      .iter()
+      .any(|m| m.github_node_id == mention.github_node_id)
+    {
+      comment.mentions.push(mention);
+    }
+  }
+  comment
+    .mentions
+    .sort_by(|a, b| a.github_username.cmp(&b.github_username));
+  publish(
+    context,
+    ThreadEventKind::CommentEdited,
+    &thread_id,
+    &comment,
+  )
+  .await?;
+  Ok(comment_from_record(comment, Some(&auth.github_node_id)))
+}
+
+/// Returns the id of the deleted comment.
+pub async fn delete(context: &JuniperContext, comment_id: &str) -> Result<String> {
+  let auth = require_login(context)?;
+  let (thread_id, comment) = own_comment(context, auth, comment_id).await?;
+  ensure!(
+    context.storage.delete_comment(comment_id).await?,
+    "no comment {}",
+    comment_id
+  );
+  publish(
+    context,
+    ThreadEventKind::CommentDeleted,
+    &thread_id,
+    &comment,
+  )
+  .await?;
+  Ok(comment.id)
+}
+
+/// Anyone who's logged in can react to any comment that hasn't been deleted. Returns the comment with its new reaction
+/// counts.
+pub async fn set_reaction(
+  context: &JuniperContext,
+  comment_id: &str,
+  reaction: Reaction,
+  reacted: bool,
+) -> Result<Comment> {
+  let auth = require_login(context)?;
+  let comment_exists = |c: &Option<(String, CommentRecord)>| matches!(c, Some((_, comment)) if comment.deleted_at.is_none());
+  ensure!(
+    comment_exists(&context.storage.lookup_comment(comment_id).await?),
+    "no comment {}",
+    comment_id
+  );
+  if reacted {
+    context
+      .storage
+      .add_reaction(comment_id, &auth.github_node_id, reaction.as_str())
+      .await?;
+  } else {
+    context
+      .storage
+      .remove_reaction(comment_id, &auth.github_node_id, reaction.as_str())
+      .await?;
+  }
+  let (_, comment) = context
+    .storage
+    .lookup_comment(comment_id)
+    .await?
+    .ok_or_else(|| anyhow!("no comment {}", comment_id))?;
+  Ok(comment_from_record(comment, Some(&auth.github_node_id)))
+}
+
+/// Every previous version of a comment, oldest first. Empty for deleted comments, since those shouldn't be seen at all.
+/// Like comment_edits in hasura, only logged in users get to see these.
+pub async fn edits(context: &JuniperContext, comment_id: &str) -> Result<Vec<CommentEdit>> {
+  require_login(context)?;
+  match context.storage.lookup_comment(comment_id).await? {
+    Some((_, comment)) if comment.deleted_at.is_none() => Ok(
+      context
+        .storage
+        .comment_edits(comment_id)
+        .await?
+        .into_iter()
+        .map(|e| CommentEdit {
+          body: e.body,
+          replaced_at: e.replaced_at,
+        })
+        .collect(),
+    ),
+    _ => Ok(vec![]),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn body_limits() {
+    assert!(validate_body("lgtm").is_ok());
+    assert!(validate_body(" \n\t").is_err());
+    assert!(validate_body(&"é".repeat(MAX_COMMENT_BODY_CHARS)).is_ok());
+    assert!(validate_body(&"x".repeat(MAX_COMMENT_BODY_CHARS + 1)).is_err());
+  }
+
+  #[test]
+  fn reaction_counts_are_in_a_fixed_order() {
+    let reaction = |reaction: &str, github_node_id: &str| ReactionRecord {
+      reaction: reaction.to_string(),
+      github_node_id: github_node_id.to_string(),
+    };
+    let reactions = [
+      reaction("eyes", "U_1"),
+      reaction("thumbs_up", "U_2"),
+      reaction("eyes", "U_2"),
+    ];
+    let viewer = GitHubUserId(crate::github::GitHubNodeId("U_1".to_string()));
+    assert_eq!(
+      reaction_counts(&reactions, Some(&viewer)),
+      vec![
+        ReactionCount {
+          reaction: Reaction::ThumbsUp,
+          count: 1,
+          viewer_has_reacted: false,
+        },
+        ReactionCount {
+          reaction: Reaction::Eyes,
+          count: 2,
+          viewer_has_reacted: true,
+        },
+      ]
+    );
+    assert!(reaction_counts(&reactions, None)
+      .iter()
+      .all(|r| !r.viewer_has_reacted));
+  }
+}
@@ -0,0 +1,731 @@
+// Settings for the api server. Each one can come from an env var or from the TOML file named by CONFIG_FILE, where it
+// goes by the same name in lowercase, eg. `mirrors_dir = "/var/lib/mirrors"`. Env vars win when both are set.
+//
+// `main` loads this once and passes it to whoever needs it. Everything is checked up front, and we report every problem
+// at once rather than making people fix them one restart at a time.
+use anyhow::anyhow;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// The frontend uses 3000 by default, and this one is easier to configure.
+// See https://community.render.com/t/502-bad-gateway-errors/616/4?u=samuela.
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:3001";
+const DEFAULT_GITHUB_URL: &str = "https://github.com";
+const DEFAULT_GITHUB_API_URL: &str = "https://api.github.com";
+/// How many blamelines go into each Hasura insert, unless overridden with BLAMELINES_CHUNK_SIZE.
+const DEFAULT_BLAMELINES_CHUNK_SIZE: usize = 1000;
+/// 30 days.
+const DEFAULT_SESSION_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+/// 1 day.
+const DEFAULT_SESSION_ROTATE_AFTER_SECS: i64 = 24 * 60 * 60;
+const DEFAULT_BLAME_WORKERS: usize = 4;
+/// 15 minutes.
+const DEFAULT_MIRROR_REFRESH_INTERVAL_SECS: u64 = 15 * 60;
+/// 1 day.
+const DEFAULT_MIRROR_MAX_REFRESH_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// Every setting that we know about. Anything else in the config file is an error, since it's probably a typo.
+const SETTINGS: &[&str] = &[
+  "LISTEN_ADDR",
+  "PUBLIC_API_URL",
+  "FRONTEND_URL",
+  "RETURN_TO_ORIGINS",
+  "COOKIE_DOMAIN",
+  "GITHUB_URL",
+  "GITHUB_API_URL",
+  "GITHUB_OAUTH_CLIENT_ID",
+  "GITHUB_OAUTH_CLIENT_SECRET",
+  "GITHUB_API_TOKEN",
+  "API_PASETO_SECRET_KEY",
+  "ACCESS_TOKEN_KEYS",
+  "HASURA_HOST",
+  "HASURA_PORT",
+  "HASURA_GRAPHQL_ADMIN_SECRET",
+  "STORAGE_BACKEND",
+  "DATABASE_URL",
+  "BLAMELINES_CHUNK_SIZE",
+  "MIRRORS_DIR",
+  "MIRRORS_QUOTA_BYTES",
+  "SESSION_TTL_SECS",
+  "SESSION_ROTATE_AFTER_SECS",
+  "BLAME_WORKERS",
+  "MIRROR_REFRESH_INTERVAL_SECS",
+  "MIRROR_MAX_REFRESH_INTERVAL_SECS",
+  "RENDER",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageBackend {
+  Hasura,
+  Postgres,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+  /// Where we accept connections.
+  pub listen_addr: SocketAddr,
+  /// Where the outside world reaches us, eg. "https://api.cuddlefish.app". GitHub sends people back here after they
+  /// log in.
+  pub public_api_url: String,
+  /// Where we send people after they log in or out, eg. "https://cuddlefish.app/".
+  pub frontend_url: String,
+  /// Other places that we're willing to send people back to after they log in or out, eg. PR preview deployments of
+  /// the frontend. FRONTEND_URL's origin is always allowed.
+  pub return_to_origins: Vec<OriginPattern>,
+  /// Set on our cookies so that they're sent to every subdomain, eg. "cuddlefish.app". Without it cookies only go back
+  /// to the host that set them.
+  pub cookie_domain: Option<String>,
+  pub github_url: String,
+  pub github_api_url: String,
+  pub github_oauth_client_id: String,
+  pub github_oauth_client_secret: String,
+  pub github_api_token: String,
+  /// Exactly 32 bytes.
+  pub api_paseto_secret_key: String,
+  /// What we encrypt GitHub access tokens with before storing them. The first key encrypts, and the rest are only
+  /// there to decrypt tokens stored before the first one took over. Defaults to API_PASETO_SECRET_KEY with id
+  /// "paseto".
+  pub access_token_keys: Vec<AccessTokenKey>,
+  pub hasura_host: String,
+  pub hasura_port: u16,
+  pub hasura_graphql_admin_secret: String,
+  pub storage_backend: StorageBackend,
+  /// Only used, and required, with the postgres storage backend.
+  pub database_url: Option<String>,
+  pub blamelines_chunk_size: usize,
+  pub mirrors_dir: PathBuf,
+  pub mirrors_quota_bytes: Option<u64>,
+  /// Sessions that go unused for this long expire. Every use pushes the expiry back.
+  pub session_ttl: chrono::Duration,
+  /// Sessions older than this get a new token the next time that they're used on one of our own routes.
+  pub session_rotate_after: chrono::Duration,
+  /// How many blame jobs run at once.
+  pub blame_workers: usize,
+  /// How often the mirrors of active repos get fetched in the background. Zero turns that off.
+  pub mirror_refresh_interval: Duration,
+  /// Repos that keep turning out to be unchanged get fetched less often, but never less often than this.
+  pub mirror_max_refresh_interval: Duration,
+}
+
+impl Config {
+  /// Load the config from the environment and CONFIG_FILE, if it's set.
+  pub fn load() -> anyhow::Result<Self> {
+    let file = match std::env::var("CONFIG_FILE") {
+      Ok(path) => Some(
+        std::fs::read_to_string(&path).with_context(|| format!("reading config file {}", path))?,
+      ),
+      Err(_) => None,
+    };
+    Self::from_sources(|name| std::env::var(name).ok(), file.as_deref())
+  }
+
+  /// `env` looks up env vars, and `file` is the contents of the config file, if there is one.
+  pub fn from_sources(
+    env: impl Fn(&str) -> Option<String>,
+    file: Option<&str>,
+  ) -> anyhow::Result<Self> {
+    let mut file_settings = HashMap::new();
+    if let Some(file) = file {
+      let table: toml::value::Table = toml::from_str(file).context("parsing config file")?;
+      for (key, value) in table {
+        let name = key.to_uppercase();
+        if !SETTINGS.contains(&name.as_str()) || key != name.to_lowercase() {
+          return Err(anyhow!("unknown setting {:?} in config file", key));
+        }
+        let value = match value {
+          toml::Value::String(s) => s,
+          toml::Value::Integer(i) => i.to_string(),
+          toml::Value::Boolean(b) => b.to_string(),
+          _ => {
+            return Err(anyhow!(
+              "{} in config file should be a string or a number",
+              key
+            ))
+          }
+        };
+        file_settings.insert(name, value);
+      }
+    }
+
+    let mut problems = vec![];
+    let mut settings = Settings {
+      get: |name: &str| env(name).or_else(|| file_settings.get(name).cloned()),
+      problems: &mut problems,
+    };
+
+    // Whether or not we're running on render at all, either in prod or as an ephemeral PR environment. See
+    // https://render.com/docs/environment-variables. This only picks the defaults for the URLs below.
+    let on_render = settings.optional("RENDER").as_deref() == Some("true");
+    let (
+      default_public_api_url,
+      default_frontend_url,
+      default_cookie_domain,
+      default_return_to_origins,
+    ) = if on_render {
+      // Can't use RENDER_EXTERNAL_URL because it's https://cf-api.onrender.com, and we can't do any kind of
+      // callback_url wildcard in the GitHub app. PR preview frontends log in through this api instead, and get sent
+      // back to themselves with RETURN_TO_ORIGINS.
+      (
+        "https://api.cuddlefish.app",
+        "https://cuddlefish.app/",
+        Some("cuddlefish.app"),
+        "",
+      )
+    } else {
+      (
+        "http://localhost:3001",
+        "http://localhost:3000/",
+        None,
+        "http://localhost:*,http://127.0.0.1:*",
+      )
+    };
+
+    let listen_addr = settings.parse_or("LISTEN_ADDR", DEFAULT_LISTEN_ADDR);
+    let public_api_url = settings.url_or("PUBLIC_API_URL", default_public_api_url);
+    let frontend_url = settings.url_or("FRONTEND_URL", default_frontend_url);
+    let return_to_origins = settings
+      .optional("RETURN_TO_ORIGINS")
+      .unwrap_or_else(|| default_return_to_origins.to_string())
+      .split(',')
+      .map(str::trim)
+      .filter(|origin| !origin.is_empty())
+      .filter_map(|origin| settings.parse_value("RETURN_TO_ORIGINS", origin))
+      .collect();
+    let cookie_domain = settings
+      .optional("COOKIE_DOMAIN")
+      .or_else(|| default_cookie_domain.map(String::from));
+    let github_url = settings.url_or("GITHUB_URL", DEFAULT_GITHUB_URL);
+    let github_api_url = settings.url_or("GITHUB_API_URL", DEFAULT_GITHUB_API_URL);
+    let github_oauth_client_id = settings.required("GITHUB_OAUTH_CLIENT_ID");
+    let github_oauth_client_secret = settings.required("GITHUB_OAUTH_CLIENT_SECRET");
+    let github_api_token = settings.required("GITHUB_API_TOKEN");
+    let api_paseto_secret_key = settings.required("API_PASETO_SECRET_KEY");
+    if api_paseto_secret_key
+      .as_ref()
+      .is_some_and(|key| key.len() != 32)
+    {
+      settings.problem("API_PASETO_SECRET_KEY should be exactly 32 bytes long".to_string());
+    }
+    let access_token_keys = match settings.optional("ACCESS_TOKEN_KEYS") {
+      Some(keys) => {
+        let keys = keys
+          .split(',')
+          .filter_map(|key| settings.parse_value::<AccessTokenKey>("ACCESS_TOKEN_KEYS", key.trim()))
+          .collect::<Vec<_>>();
+        if keys.is_empty() {
+          settings.problem("ACCESS_TOKEN_KEYS should have at least one key".to_string());
+        }
+        keys
+      }
+      None => api_paseto_secret_key
+        .iter()
+        .map(|key| AccessTokenKey {
+          id: "paseto".to_string(),
+          key: key.clone(),
+        })
+        .collect(),
+    };
+    let hasura_host = settings.required("HASURA_HOST");
+    let hasura_port = settings
+      .required("HASURA_PORT")
+      .and_then(|port| settings.parse_value("HASURA_PORT", &port));
+    let hasura_graphql_admin_secret = settings.required("HASURA_GRAPHQL_ADMIN_SECRET");
+    let storage_backend = match settings.optional("STORAGE_BACKEND").as_deref() {
+      None | Some("hasura") => Some(StorageBackend::Hasura),
+      Some("postgres") => Some(StorageBackend::Postgres),
+      Some(other) => {
+        settings.problem(format!(
+          "STORAGE_BACKEND should be \"hasura\" or \"postgres\", not {:?}",
+          other
+        ));
+        None
+      }
+    };
+    let database_url = settings.optional("DATABASE_URL");
+    if storage_backend == Some(StorageBackend::Postgres) && database_url.is_none() {
+      settings.problem("DATABASE_URL is required with STORAGE_BACKEND=postgres".to_string());
+    }
+    let blamelines_chunk_size = settings.parse_or(
+      "BLAMELINES_CHUNK_SIZE",
+      &DEFAULT_BLAMELINES_CHUNK_SIZE.to_string(),
+    );
+    let mirrors_dir = settings.required("MIRRORS_DIR");
+    let mirrors_quota_bytes = settings
+      .optional("MIRRORS_QUOTA_BYTES")
+      .map(|quota| settings.parse_value("MIRRORS_QUOTA_BYTES", &quota));
+    let session_ttl_secs: Option<i64> =
+      settings.parse_or("SESSION_TTL_SECS", &DEFAULT_SESSION_TTL_SECS.to_string());
+    let session_rotate_after_secs: Option<i64> = settings.parse_or(
+      "SESSION_ROTATE_AFTER_SECS",
+      &DEFAULT_SESSION_ROTATE_AFTER_SECS.to_string(),
+    );
+    if let (Some(ttl), Some(rotate_after)) = (session_ttl_secs, session_rotate_after_secs) {
+      if ttl <= 0 || rotate_after <= 0 {
+        settings
+          .problem("SESSION_TTL_SECS and SESSION_ROTATE_AFTER_SECS should be positive".to_string());
+      }
+    }
+    let blame_workers: Option<usize> =
+      settings.parse_or("BLAME_WORKERS", &DEFAULT_BLAME_WORKERS.to_string());
+    if blame_workers == Some(0) {
+      settings.problem("BLAME_WORKERS should be at least 1".to_string());
+    }
+    let mirror_refresh_interval_secs: Option<u64> = settings.parse_or(
+      "MIRROR_REFRESH_INTERVAL_SECS",
+      &DEFAULT_MIRROR_REFRESH_INTERVAL_SECS.to_string(),
+    );
+    let mirror_max_refresh_interval_secs: Option<u64> = settings.parse_or(
+      "MIRROR_MAX_REFRESH_INTERVAL_SECS",
+      &DEFAULT_MIRROR_MAX_REFRESH_INTERVAL_SECS.to_string(),
+    );
+    if let (Some(base), Some(max)) = (
+      mirror_refresh_interval_secs,
+      mirror_max_refresh_interval_secs,
+    ) {
+      if max < base {
+        settings.problem(
+          "MIRROR_MAX_REFRESH_INTERVAL_SECS should be at least MIRROR_REFRESH_INTERVAL_SECS"
+            .to_string(),
+        );
+      }
+    }
+
+    if !problems.is_empty() {
+      return Err(anyhow!("bad config:\n  {}", problems.join("\n  ")));
+    }
+    // Everything is Some at this point, or else there would have been a problem.
+    Ok(Config {
+      listen_addr: listen_addr.unwrap(),
+      public_api_url: public_api_url.unwrap(),
+      frontend_url: frontend_url.unwrap(),
+      return_to_origins,
+      cookie_domain,
+      github_url: github_url.unwrap(),
+      github_api_url: github_api_url.unwrap(),
+      github_oauth_client_id: github_oauth_client_id.unwrap(),
+      github_oauth_client_secret: github_oauth_client_secret.unwrap(),
+      github_api_token: github_api_token.unwrap(),
+      api_paseto_secret_key: api_paseto_secret_key.unwrap(),
+      access_token_keys,
+      hasura_host: hasura_host.unwrap(),
+      hasura_port: hasura_port.unwrap(),
+      hasura_graphql_admin_secret: hasura_graphql_admin_secret.unwrap(),
+      storage_backend: storage_backend.unwrap(),
+      database_url,
+      blamelines_chunk_size: blamelines_chunk_size.unwrap(),
+      mirrors_dir: PathBuf::from(mirrors_dir.unwrap()),
+      mirrors_quota_bytes: mirrors_quota_bytes.flatten(),
+      session_ttl: chrono::Duration::seconds(session_ttl_secs.unwrap()),
+      session_rotate_after: chrono::Duration::seconds(session_rotate_after_secs.unwrap()),
+      blame_workers: blame_workers.unwrap(),
+      mirror_refresh_interval: Duration::from_secs(mirror_refresh_interval_secs.unwrap()),
+      mirror_max_refresh_interval: Duration::from_secs(mirror_max_refresh_interval_secs.unwrap()),
+    })
+  }
+
+  /// Where GitHub sends people back to after they log in.
+  pub fn github_callback_url(&self) -> String {
+    format!(
+      "{}/oauth/callback/github",
+      self.public_api_url.trim_end_matches('/')
+    )
+  }
+
+  /// Whether we're willing to send people to `return_to` after they log in or out. It has to be an http(s) URL with
+  /// the same origin as FRONTEND_URL or one of RETURN_TO_ORIGINS.
+  pub fn return_to_allowed(&self, return_to: &str) -> bool {
+    let return_to = match url::Url::parse(return_to) {
+      Ok(url) => url,
+      Err(_) => return false,
+    };
+    // Userinfo in the URL is a classic way to make it look like it's headed somewhere else.
+    if !return_to.username().is_empty() || return_to.password().is_some() {
+      return false;
+    }
+    let same_origin_as_frontend = url::Url::parse(&self.frontend_url)
+      .is_ok_and(|frontend| frontend.origin() == return_to.origin());
+    same_origin_as_frontend
+      || self
+        .return_to_origins
+        .iter()
+        .any(|origin| origin.matches(&return_to))
+  }
+
+  /// Whether cookies should only be sent over HTTPS. That's whenever we're served over HTTPS.
+  pub fn secure_cookies(&self) -> bool {
+    self.public_api_url.starts_with("https://")
+  }
+
+  pub fn hasura_url(&self) -> String {
+    // TODO should use https here
+    format!(
+      "http://{}:{}/v1/graphql",
+      self.hasura_host, self.hasura_port
+    )
+  }
+
+  /// Everything worth logging at startup. Leaves out the secrets.
+  pub fn log(&self) {
+    log::info!("LISTEN_ADDR = {}", self.listen_addr);
+    log::info!("PUBLIC_API_URL = {}", self.public_api_url);
+    log::info!("FRONTEND_URL = {}", self.frontend_url);
+    log::info!("RETURN_TO_ORIGINS = {:?}", self.return_to_origins);
+    log::info!("COOKIE_DOMAIN = {:?}", self.cookie_domain);
+    log::info!("GITHUB_URL = {}", self.github_url);
+    log::info!("GITHUB_API_URL = {}", self.github_api_url);
+    log::info!("GITHUB_OAUTH_CLIENT_ID = {}", self.github_oauth_client_id);
+    log::info!("HASURA_HOST = {}", self.hasura_host);
+    log::info!("HASURA_PORT = {}", self.hasura_port);
+    log::info!("STORAGE_BACKEND = {:?}", self.storage_backend);
+    log::info!("MIRRORS_DIR = {}", self.mirrors_dir.display());
+    log::info!("MIRRORS_QUOTA_BYTES = {:?}", self.mirrors_quota_bytes);
+    log::info!("SESSION_TTL_SECS = {}", self.session_ttl.num_seconds());
+    log::info!(
+      "SESSION_ROTATE_AFTER_SECS = {}",
+      self.session_rotate_after.num_seconds()
+    );
+    log::info!("BLAME_WORKERS = {}", self.blame_workers);
+    log::info!(
+      "MIRROR_REFRESH_INTERVAL_SECS = {}",
+      self.mirror_refresh_interval.as_secs()
+    );
+    log::info!(
+      "MIRROR_MAX_REFRESH_INTERVAL_SECS = {}",
+      self.mirror_max_refresh_interval.as_secs()
+    );
+  }
+}
+
+/// One of ACCESS_TOKEN_KEYS, written "<id>=<key>". The id is stored alongside everything encrypted with the key, so it
+/// has to stay the same for as long as the key is around. The key is exactly 32 bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessTokenKey {
+  pub id: String,
+  pub key: String,
+}
+
+impl std::str::FromStr for AccessTokenKey {
+  type Err = anyhow::Error;
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    let (id, key) = s
+      .split_once('=')
+      .ok_or_else(|| anyhow!("expected <id>=<key>"))?;
+    anyhow::ensure!(
+      !id.is_empty()
+        && id
+          .chars()
+          .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+      "key ids should be letters, digits, '-' and '_'"
+    );
+    anyhow::ensure!(key.len() == 32, "keys should be exactly 32 bytes long");
+    Ok(AccessTokenKey {
+      id: id.to_string(),
+      key: key.to_string(),
+    })
+  }
+}
+
+/// An origin like "https://cuddlefish.app". The host can start with "*." to match any subdomain, and the port can be "*"
+/// to match any port. Without a port, only the scheme's default port matches.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OriginPattern {
+  scheme: String,
+  host: String,
+  port: Option<String>,
+}
+
+impl std::str::FromStr for OriginPattern {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    let (scheme, host_and_port) = s
+      .split_once("://")
+      .ok_or_else(|| anyhow!("no scheme in {:?}", s))?;
+    if scheme != "http" && scheme != "https" {
+      return Err(anyhow!("{:?} isn't http(s)", s));
+    }
+    let (host, port) = match host_and_port.rsplit_once(':') {
+      Some((host, port)) => (host, Some(port)),
+      None => (host_and_port, None),
+    };
+    let valid_host = {
+      let rest = host.strip_prefix("*.").unwrap_or(host);
+      !rest.is_empty()
+        && rest
+          .chars()
+          .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    };
+    let valid_port = port.is_none_or(|port| port == "*" || port.parse::<u16>().is_ok());
+    if !valid_host || !valid_port {
+      return Err(anyhow!("{:?} isn't an origin", s));
+    }
+    Ok(OriginPattern {
+      scheme: scheme.to_string(),
+      host: host.to_ascii_lowercase(),
+      port: port.map(String::from),
+    })
+  }
+}
+
+impl OriginPattern {
+  fn matches(&self, url: &url::Url) -> bool {
+    let host = match url.host_str() {
+      Some(host) => host,
+      None => return false,
+    };
+    let host_matches = match self.host.strip_prefix('*') {
+      // Just ".cuddlefish.app", so that "evilcuddlefish.app" doesn't match.
+      Some(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+      None => host == self.host,
+    };
+    let port_matches = match self.port.as_deref() {
+      Some("*") => true,
+      Some(port) => {
+        url
+          .port_or_known_default()
+          .map(|p| p.to_string())
+          .as_deref()
+          == Some(port)
+      }
+      None => url.port().is_none(),
+    };
+    url.scheme() == self.scheme && host_matches && port_matches
+  }
+}
+
+/// Looks settings up and keeps track of what's wrong with them.
+struct Settings<'a, F> {
+  get: F,
+  problems: &'a mut Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> Settings<'_, F> {
+  fn problem(&mut self, problem: String) {
+    self.problems.push(problem);
+  }
+
+  fn optional(&self, name: &str) -> Option<String> {
+    (self.get)(name).filter(|value| !value.is_empty())
+  }
+
+  fn required(&mut self, name: &str) -> Option<String> {
+    let value = self.optional(name);
+    if value.is_none() {
+      self.problem(format!("{} is required", name));
+    }
+    value
+  }
+
+  fn parse_value<T: std::str::FromStr>(&mut self, name: &str, value: &str) -> Option<T> {
+    let parsed = value.parse().ok();
+    if parsed.is_none() {
+      self.problem(format!("{} has a bad value: {:?}", name, value));
+    }
+    parsed
+  }
+
+  fn parse_or<T: std::str::FromStr>(&mut self, name: &str, default: &str) -> Option<T> {
+    let value = self.optional(name).unwrap_or_else(|| default.to_string());
+    self.parse_value(name, &value)
+  }
+
+  /// An http(s) URL, without a trailing slash unless `default` has one.
+  fn url_or(&mut self, name: &str, default: &str) -> Option<String> {
+    let value = self.optional(name).unwrap_or_else(|| default.to_string());
+    match url::Url::parse(&value) {
+      Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Some(value),
+      _ => {
+        self.problem(format!(
+          "{} should be an http(s) URL, not {:?}",
+          name, value
+        ));
+        None
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars = vars
+      .iter()
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect::<HashMap<_, _>>();
+    move |name| vars.get(name).cloned()
+  }
+
+  const REQUIRED: &[(&str, &str)] = &[
+    ("GITHUB_OAUTH_CLIENT_ID", "client-id"),
+    ("GITHUB_OAUTH_CLIENT_SECRET", "client-secret"),
+    ("GITHUB_API_TOKEN", "api-token"),
+    ("API_PASETO_SECRET_KEY", "0123456789abcdef0123456789abcdef"),
+    ("HASURA_HOST", "localhost"),
+    ("HASURA_PORT", "8080"),
+    ("HASURA_GRAPHQL_ADMIN_SECRET", "hasurasecret"),
+    ("MIRRORS_DIR", "/tmp/mirrors"),
+  ];
+
+  #[test]
+  fn defaults() {
+    let config = Config::from_sources(env(REQUIRED), None).unwrap();
+    assert_eq!(config.listen_addr, "0.0.0.0:3001".parse().unwrap());
+    assert_eq!(
+      config.github_callback_url(),
+      "http://localhost:3001/oauth/callback/github"
+    );
+    assert_eq!(config.frontend_url, "http://localhost:3000/");
+    assert_eq!(config.cookie_domain, None);
+    assert!(!config.secure_cookies());
+    assert_eq!(config.github_api_url, "https://api.github.com");
+    assert_eq!(config.storage_backend, StorageBackend::Hasura);
+    assert_eq!(config.hasura_url(), "http://localhost:8080/v1/graphql");
+    assert_eq!(config.session_ttl, chrono::Duration::days(30));
+    assert_eq!(config.session_rotate_after, chrono::Duration::days(1));
+    assert_eq!(config.blame_workers, 4);
+    assert_eq!(config.mirror_refresh_interval, Duration::from_secs(15 * 60));
+    assert_eq!(
+      config.access_token_keys,
+      vec![AccessTokenKey {
+        id: "paseto".to_string(),
+        key: "0123456789abcdef0123456789abcdef".to_string(),
+      }]
+    );
+
+    let on_render =
+      Config::from_sources(env(&[REQUIRED, &[("RENDER", "true")]].concat()), None).unwrap();
+    assert_eq!(
+      on_render.github_callback_url(),
+      "https://api.cuddlefish.app/oauth/callback/github"
+    );
+    assert_eq!(on_render.cookie_domain.as_deref(), Some("cuddlefish.app"));
+    assert!(on_render.secure_cookies());
+  }
+
+  #[test]
+  fn env_overrides_file() {
+    let file = r#"
+      listen_addr = "127.0.0.1:4000"
+      hasura_port = 9090
+      mirrors_dir = "/from/file"
+      frontend_url = "https://example.com/"
+    "#;
+    let config = Config::from_sources(
+      env(&[REQUIRED, &[("FRONTEND_URL", "https://example.org/")]].concat()),
+      Some(file),
+    )
+    .unwrap();
+    assert_eq!(config.listen_addr, "127.0.0.1:4000".parse().unwrap());
+    // The env has its own HASURA_PORT and MIRRORS_DIR.
+    assert_eq!(config.hasura_port, 8080);
+    assert_eq!(config.mirrors_dir, PathBuf::from("/tmp/mirrors"));
+    assert_eq!(config.frontend_url, "https://example.org/");
+
+    assert!(Config::from_sources(env(REQUIRED), Some("mirror_dir = \"/typo\"")).is_err());
+    assert!(Config::from_sources(env(REQUIRED), Some("MIRRORS_DIR = \"/shouting\"")).is_err());
+    assert!(Config::from_sources(env(REQUIRED), Some("not toml")).is_err());
+  }
+
+  #[test]
+  fn return_to() {
+    let config = Config::from_sources(
+      env(
+        &[
+          REQUIRED,
+          &[
+            ("FRONTEND_URL", "https://cuddlefish.app/"),
+            (
+              "RETURN_TO_ORIGINS",
+              "https://*.onrender.com, http://localhost:*",
+            ),
+          ],
+        ]
+        .concat(),
+      ),
+      None,
+    )
+    .unwrap();
+    for allowed in [
+      "https://cuddlefish.app/",
+      "https://cuddlefish.app/some/page?x=1#y",
+      "https://cuddlefish.app:443/",
+      "https://cf-web-pr-12.onrender.com/repo",
+      "http://localhost:1234/",
+    ] {
+      assert!(config.return_to_allowed(allowed), "{}", allowed);
+    }
+    for denied in [
+      "http://cuddlefish.app/",
+      "https://cuddlefish.app:8443/",
+      "https://evil.com/",
+      "https://cuddlefish.app.evil.com/",
+      "https://onrender.com/",
+      "https://evilonrender.com/",
+      "https://user@cuddlefish.app/",
+      "http://localhost.evil.com:1234/",
+      "javascript:alert(1)",
+      "/relative",
+    ] {
+      assert!(!config.return_to_allowed(denied), "{}", denied);
+    }
+
+    for bad in [
+      "cuddlefish.app",
+      "ftp://x.com",
+      "https://x.com/path",
+      "https://x.com:port",
+    ] {
+      assert!(
+        Config::from_sources(
+          env(&[REQUIRED, &[("RETURN_TO_ORIGINS", bad)]].concat()),
+          None
+        )
+        .is_err(),
+        "{}",
+        bad
+      );
+    }
+  }
+
+  #[test]
+  fn reports_every_problem() {
+    let err = Config::from_sources(
+      env(&[
+        ("API_PASETO_SECRET_KEY", "too short"),
+        ("HASURA_PORT", "eighty"),
+        ("PUBLIC_API_URL", "api.example.com"),
+        ("STORAGE_BACKEND", "postgres"),
+        ("SESSION_TTL_SECS", "0"),
+        ("BLAME_WORKERS", "0"),
+        ("MIRROR_REFRESH_INTERVAL_SECS", "3600"),
+        ("MIRROR_MAX_REFRESH_INTERVAL_SECS", "60"),
+        (
+          "ACCESS_TOKEN_KEYS",
+          "new=0123456789abcdef0123456789abcdef,old:too short",
+        ),
+      ]),
+      None,
+    )
+    .unwrap_err()
+    .to_string();
+    for expected in [
+      "GITHUB_OAUTH_CLIENT_ID is required",
+      "MIRRORS_DIR is required",
+      "API_PASETO_SECRET_KEY should be exactly 32 bytes long",
+      "HASURA_PORT has a bad value",
+      "PUBLIC_API_URL should be an http(s) URL",
+      "DATABASE_URL is required",
+      "SESSION_TTL_SECS and SESSION_ROTATE_AFTER_SECS should be positive",
+      "BLAME_WORKERS should be at least 1",
+      "MIRROR_MAX_REFRESH_INTERVAL_SECS should be at least MIRROR_REFRESH_INTERVAL_SECS",
+      "ACCESS_TOKEN_KEYS has a bad value: \"old:too short\"",
+    ] {
+      assert!(err.contains(expected), "{:?} not in {:?}", expected, err);
+    }
+  }
+}
@@ -0,0 +1,1216 @@
+// In-memory stand-ins for `Storage` and `GitHub`, so that tests can run the GraphQL layer without Hasura, Postgres, or
+// the network. They keep just enough state to behave like the real thing for the queries that we make.
+//
+// `serve_github` also puts a `FakeGitHub` behind a local HTTP server that speaks just enough of GitHub's GraphQL API,
+// OAuth, and REST API, so that `github::GitHubApi` itself can be tested.
+use crate::config::Config;
+use crate::config::StorageBackend;
+use crate::github::DeviceCode;
+use crate::github::DeviceFlowPoll;
+use crate::github::GitHub;
+use crate::github::GitHubNodeId;
+use crate::github::GitHubUser;
+use crate::github::GitHubUserInfo;
+use crate::github::DEVICE_CODE_GRANT_TYPE;
+use crate::storage::looks_like_uuid;
+use crate::storage::BlameJobRecord;
+use crate::storage::CommentEditRecord;
+use crate::storage::CommentRecord;
+use crate::storage::LineRange;
+use crate::storage::MentionRecord;
+use crate::storage::ReactionRecord;
+use crate::storage::SessionRecord;
+use crate::storage::Storage;
+use crate::storage::ThreadAnchor;
+use crate::storage::ThreadWithComments;
+use crate::AuthContext;
+use crate::BlameLine;
+use crate::GitHubAuth;
+use crate::GitHubUserId;
+use crate::JuniperContext;
+use crate::RepoWithCommit;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::Duration;
+use chrono::Utc;
+use hyper::header;
+use hyper::service::make_service_fn;
+use hyper::service::service_fn;
+use hyper::Body;
+use hyper::Method;
+use hyper::Request;
+use hyper::Response;
+use hyper::Server;
+use hyper::StatusCode;
+use serde_json::json;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+pub struct FakeSession {
+  pub github_node_id: String,
+  pub record: SessionRecord,
+}
+
+#[derive(Default)]
+pub struct StorageState {
+  /// GitHub node id -> access token.
+  pub users: HashMap<String, String>,
+  /// GitHub node id -> username, for everyone in github_users, including users who have only been mentioned.
+  pub usernames: HashMap<String, String>,
+  /// Session token -> session. Expired sessions stay here until they're ended, like in the database.
+  pub sessions: HashMap<String, FakeSession>,
+  /// (commit_hash, file_path) -> blamelines
+  pub blamelines: HashMap<(String, String), Vec<BlameLine>>,
+  pub threads: Vec<ThreadWithComments>,
+  /// Comment id -> previous bodies, oldest first.
+  pub comment_edits: HashMap<String, Vec<CommentEditRecord>>,
+  /// (commit_hash, repo_github_node_id)
+  pub commit_github_repos: HashSet<(String, String)>,
+  /// (commit_hash, repo_id)
+  pub commit_repos: HashSet<(String, String)>,
+  pub blame_jobs: Vec<BlameJobRecord>,
+  /// When set, looking up and ending sessions fails, as if the database were unreachable.
+  pub sessions_down: bool,
+  next_id: u64,
+}
+
+impl StorageState {
+  fn next_id(&mut self, kind: &str) -> String {
+    self.next_id += 1;
+    format!("{}-{}", kind, self.next_id)
+  }
+
+  /// For ids that are uuids in the database, and which the real backends check the shape of.
+  fn next_uuid(&mut self) -> String {
+    self.next_id += 1;
+    format!("00000000-0000-4000-8000-{:012x}", self.next_id)
+  }
+}
+
+#[derive(Default)]
+pub struct InMemoryStorage {
+  pub state: Mutex<StorageState>,
+}
+
+fn is_unfinished(job: &BlameJobRecord) -> bool {
+  job.status == "queued" || job.status == "running"
+}
+
+#[async_trait]
+impl Storage for InMemoryStorage {
+  async fn lookup_existing_blamelines(
+    &self,
+    commit_hash: &str,
+    file_path: &str,
+  ) -> anyhow::Result<bool> {
+    let state = self.state.lock().unwrap();
+    // Like the real thing, an empty file only counts once its job is done.
+    let has_lines = state
+      .blamelines
+      .get(&(commit_hash.to_string(), file_path.to_string()))
+      .is_some_and(|lines| !lines.is_empty());
+    Ok(
+      has_lines
+        || state
+          .blame_jobs
+          .iter()
+          .any(|j| j.commit_hash == commit_hash && j.file_path == file_path && j.status == "done"),
+    )
+  }
+
+  async fn insert_blamelines(
+    &self,
+    commit_hash: &str,
+    file_path: &str,
+    blamelines: Vec<BlameLine>,
+  ) -> anyhow::Result<()> {
+    self
+      .state
+      .lock()
+      .unwrap()
+      .blamelines
+      .entry((commit_hash.to_string(), file_path.to_string()))
+      .or_insert(blamelines);
+    Ok(())
+  }
+
+  async fn upsert_user(
+    &self,
+    github_node_id: &GitHubUserId,
+    _github_database_id: u32,
+    _github_name: &str,
+    github_username: &str,
+    _email: Option<String>,
+    github_access_token: &str,
+  ) -> anyhow::Result<()> {
+    let mut state = self.state.lock().unwrap();
+    state
+      .users
+      .insert(github_node_id.0 .0.clone(), github_access_token.to_string());
+    state
+      .usernames
+      .insert(github_node_id.0 .0.clone(), github_username.to_string());
+    Ok(())
+  }
+
+  async fn upsert_mentioned_user(&self, user: &GitHubUser) -> anyhow::Result<()> {
+    self
+      .state
+      .lock()
+      .unwrap()
+      .usernames
+      .insert(user.node_id.0.clone(), user.login.clone());
+    Ok(())
+  }
+
+  async fn lookup_users_by_username(
+    &self,
+    usernames: &[String],
+  ) -> anyhow::Result<Vec<MentionRecord>> {
+    Ok(
+      self
+        .state
+        .lock()
+        .unwrap()
+        .usernames
+        .iter()
+        .filter(|(_, username)| usernames.iter().any(|u| u.eq_ignore_ascii_case(username)))
+        .map(|(github_node_id, github_username)| MentionRecord {
+          github_node_id: github_node_id.clone(),
+          github_username: github_username.clone(),
+        })
+        .collect(),
+    )
+  }
+
+  async fn reencrypt_access_tokens(&self) -> anyhow::Result<usize> {
+    // Tokens here never leave memory, so they're kept in plaintext.
+    Ok(0)
+  }
+
+  async fn start_user_session(
+    &self,
+    github_user: &GitHubUserId,
+    ttl: Duration,
+  ) -> anyhow::Result<String> {
+    let mut state = self.state.lock().unwrap();
+    let token = state.next_uuid();
+    let public_id = state.next_uuid();
+    let now = Utc::now();
+    state.sessions.insert(
+      token.clone(),
+      FakeSession {
+        github_node_id: github_user.0 .0.clone(),
+        record: SessionRecord {
+          public_id,
+          created_at: now,
+          last_used_at: now,
+          expires_at: now + ttl,
+          rotated_at: None,
+        },
+      },
+    );
+    Ok(token)
+  }
+
+  async fn lookup_user_session(
+    &self,
+    session_token: &str,
+    ttl: Duration,
+  ) -> anyhow::Result<Option<(GitHubAuth, SessionRecord)>> {
+    // Like the real backends, which never get as far as the database with these.
+    if !looks_like_uuid(session_token) {
+      return Ok(None);
+    }
+    let mut state = self.state.lock().unwrap();
+    if state.sessions_down {
+      return Err(anyhow!("sessions are down"));
+    }
+    let now = Utc::now();
+    let StorageState {
+      sessions, users, ..
+    } = &mut *state;
+    Ok(
+      sessions
+        .get_mut(session_token)
+        .filter(|session| session.record.expires_at > now)
+        .and_then(|session| {
+          let access_token = users.get(&session.github_node_id)?;
+          session.record.last_used_at = now;
+          if session.record.rotated_at.is_none() {
+            session.record.expires_at = now + ttl;
+          }
+          Some((
+            GitHubAuth {
+              github_node_id: GitHubUserId(GitHubNodeId(session.github_node_id.clone())),
+              access_token: access_token.clone(),
+            },
+            session.record.clone(),
+          ))
+        }),
+    )
+  }
+
+  async fn end_user_session(&self, session_token: &str) -> anyhow::Result<()> {
+    if !looks_like_uuid(session_token) {
+      return Ok(());
+    }
+    let mut state = self.state.lock().unwrap();
+    if state.sessions_down {
+      return Err(anyhow!("sessions are down"));
+    }
+    state.sessions.remove(session_token);
+    Ok(())
+  }
+
+  async fn rotate_user_session(
+    &self,
+    session_token: &str,
+    ttl: Duration,
+    grace: Duration,
+  ) -> anyhow::Result<Option<String>> {
+    let now = Utc::now();
+    let github_node_id = {
+      let mut state = self.state.lock().unwrap();
+      match state.sessions.get_mut(session_token) {
+        Some(session) if session.record.expires_at > now && session.record.rotated_at.is_none() => {
+          session.record.expires_at = session.record.expires_at.min(now + grace);
+          session.record.rotated_at = Some(now);
+          session.github_node_id.clone()
+        }
+        _ => return Ok(None),
+      }
+    };
+    self
+      .start_user_session(&GitHubUserId(GitHubNodeId(github_node_id)), ttl)
+      .await
+      .map(Some)
+  }
+
+  async fn user_sessions(&self, github_user: &GitHubUserId) -> anyhow::Result<Vec<SessionRecord>> {
+    let now = Utc::now();
+    let mut sessions = self
+      .state
+      .lock()
+      .unwrap()
+      .sessions
+      .values()
+      .filter(|session| {
+        session.github_node_id == github_user.0 .0 && session.record.expires_at > now
+      })
+      .map(|session| session.record.clone())
+      .collect::<Vec<_>>();
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.last_used_at));
+    Ok(sessions)
+  }
+
+  async fn end_user_session_by_public_id(
+    &self,
+    github_user: &GitHubUserId,
+    public_id: &str,
+  ) -> anyhow::Result<bool> {
+    let mut state = self.state.lock().unwrap();
+    let before = state.sessions.len();
+    state.sessions.retain(|_, session| {
+      session.github_node_id != github_user.0 .0 || session.record.public_id != public_id
+    });
+    Ok(state.sessions.len() < before)
+  }
+
+  async fn start_thread(
+    &self,
+    author_github_node_id: &GitHubUserId,
+    repo: &RepoWithCommit,
+    commit_hash: &str,
+    file_path: &str,
+    range: &LineRange,
+    body: &str,
+  ) -> anyhow::Result<(String, String)> {
+    let mut state = self.state.lock().unwrap();
+    match repo {
+      RepoWithCommit::GitHub(node_id) => state
+        .commit_github_repos
+        .insert((commit_hash.to_string(), node_id.0.clone())),
+      RepoWithCommit::Other(repo_id) => state
+        .commit_repos
+        .insert((commit_hash.to_string(), repo_id.to_string())),
+    };
+    let thread_id = state.next_id("thread");
+    let comment_id = state.next_id("comment");
+    state.threads.push(ThreadWithComments {
+      id: thread_id.clone(),
+      original_commit_hash: commit_hash.to_string(),
+      original_file_path: file_path.to_string(),
+      original_line_number: range.start_line.into(),
+      original_end_line_number: Some(range.end_line.into()),
+      original_start_column: range.start_column.map(Into::into),
+      original_end_column: range.end_column.map(Into::into),
+      resolved_at: None,
+      resolved_by_github_node_id: None,
+      comments: vec![CommentRecord {
+        id: comment_id.clone(),
+        body: body.to_string(),
+        created_at: "2021-11-20T00:00:00+00:00".to_string(),
+        author_github_node_id: Some(author_github_node_id.0 .0.clone()),
+        author_email: None,
+        edited_at: None,
+        deleted_at: None,
+        reactions: vec![],
+        mentions: vec![],
+      }],
+    });
+    Ok((thread_id, comment_id))
+  }
+
+  async fn resolve_thread(&self, thread_id: &str, resolver: &GitHubUserId) -> anyhow::Result<bool> {
+    let mut state = self.state.lock().unwrap();
+    match state
+      .threads
+      .iter_mut()
+      .find(|t| t.id == thread_id && t.resolved_at.is_none())
+    {
+      Some(thread) => {
+        thread.resolved_at = Some(Utc::now().to_rfc3339());
+        thread.resolved_by_github_node_id = Some(resolver.0 .0.clone());
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+
+  async fn reopen_thread(&self, thread_id: &str) -> anyhow::Result<bool> {
+    let mut state = self.state.lock().unwrap();
+    match state
+      .threads
+      .iter_mut()
+      .find(|t| t.id == thread_id && t.resolved_at.is_some())
+    {
+      Some(thread) => {
+        thread.resolved_at = None;
+        thread.resolved_by_github_node_id = None;
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+
+  async fn add_comment(
+    &self,
+    author_github_node_id: &GitHubUserId,
+    thread_id: &str,
+    body: &str,
+  ) -> anyhow::Result<CommentRecord> {
+    let mut state = self.state.lock().unwrap();
+    let comment = CommentRecord {
+      id: state.next_id("comment"),
+      body: body.to_string(),
+      created_at: Utc::now().to_rfc3339(),
+      author_github_node_id: Some(author_github_node_id.0 .0.clone()),
+      author_email: None,
+      edited_at: None,
+      deleted_at: None,
+      reactions: vec![],
+      mentions: vec![],
+    };
+    let thread = state
+      .threads
+      .iter_mut()
+      .find(|t| t.id == thread_id)
+      .ok_or_else(|| anyhow!("no thread {}", thread_id))?;
+    thread.comments.push(comment.clone());
+    Ok(comment)
+  }
+
+  async fn lookup_comment(
+    &self,
+    comment_id: &str,
+  ) -> anyhow::Result<Option<(String, CommentRecord)>> {
+    let state = self.state.lock().unwrap();
+    Ok(state.threads.iter().find_map(|t| {
+      t.comments
+        .iter()
+        .find(|c| c.id == comment_id)
+        .map(|c| (t.id.clone(), c.clone()))
+    }))
+  }
+
+  async fn edit_comment(
+    &self,
+    comment_id: &str,
+    body: &str,
+  ) -> anyhow::Result<Option<CommentRecord>> {
+    let mut state = self.state.lock().unwrap();
+    let comment = match state
+      .threads
+      .iter_mut()
+      .flat_map(|t| t.comments.iter_mut())
+      .find(|c| c.id == comment_id && c.deleted_at.is_none())
+    {
+      Some(comment) => comment,
+      None => return Ok(None),
+    };
+    if comment.body == body {
+      return Ok(Some(comment.clone()));
+    }
+    let now = Utc::now().to_rfc3339();
+    let previous = CommentEditRecord {
+      body: std::mem::replace(&mut comment.body, body.to_string()),
+      replaced_at: now.clone(),
+    };
+    comment.edited_at = Some(now);
+    let comment = comment.clone();
+    state
+      .comment_edits
+      .entry(comment_id.to_string())
+      .or_default()
+      .push(previous);
+    Ok(Some(comment))
+  }
+
+  async fn delete_comment(&self, comment_id: &str) -> anyhow::Result<bool> {
+    let mut state = self.state.lock().unwrap();
+    match state
+      .threads
+      .iter_mut()
+      .flat_map(|t| t.comments.iter_mut())
+      .find(|c| c.id == comment_id && c.deleted_at.is_none())
+    {
+      Some(comment) => {
+        comment.deleted_at = Some(Utc::now().to_rfc3339());
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+
+  async fn comment_edits(&self, comment_id: &str) -> anyhow::Result<Vec<CommentEditRecord>> {
+    Ok(
+      self
+        .state
+        .lock()
+        .unwrap()
+        .comment_edits
+        .get(comment_id)
+        .cloned()
+        .unwrap_or_default(),
+    )
+  }
+
+  async fn lookup_thread(&self, thread_id: &str) -> anyhow::Result<Option<ThreadAnchor>> {
+    Ok(
+      self
+        .state
+        .lock()
+        .unwrap()
+        .threads
+        .iter()
+        .find(|t| t.id == thread_id)
+        .map(|t| t.anchor()),
+    )
+  }
+
+  async fn add_reaction(
+    &self,
+    comment_id: &str,
+    github_user: &GitHubUserId,
+    reaction: &str,
+  ) -> anyhow::Result<()> {
+    let mut state = self.state.lock().unwrap();
+    let comment = state
+      .threads
+      .iter_mut()
+      .flat_map(|t| t.comments.iter_mut())
+      .find(|c| c.id == comment_id)
+      .ok_or_else(|| anyhow!("no comment {}", comment_id))?;
+    let record = ReactionRecord {
+      reaction: reaction.to_string(),
+      github_node_id: github_user.0 .0.clone(),
+    };
+    if !comment.reactions.contains(&record) {
+      comment.reactions.push(record);
+    }
+    Ok(())
+  }
+
+  async fn remove_reaction(
+    &self,
+    comment_id: &str,
+    github_user: &GitHubUserId,
+    reaction: &str,
+  ) -> anyhow::Result<()> {
+    let mut state = self.state.lock().unwrap();
+    for comment in state
+      .threads
+      .iter_mut()
+      .flat_map(|t| t.comments.iter_mut())
+      .filter(|c| c.id == comment_id)
+    {
+      comment
+        .reactions
+        .retain(|r| r.reaction != reaction || r.github_node_id != github_user.0 .0);
+    }
+    Ok(())
+  }
+
+  async fn add_mentions(&self, comment_id: &str, mentions: &[MentionRecord]) -> anyhow::Result<()> {
+    let mut state = self.state.lock().unwrap();
+    for mention in mentions {
+      if !state.usernames.contains_key(&mention.github_node_id) {
+        return Err(anyhow!("no user {}", mention.github_node_id));
+      }
+    }
+    let comment = state
+      .threads
+      .iter_mut()
+      .flat_map(|t| t.comments.iter_mut())
+      .find(|c| c.id == comment_id)
+      .ok_or_else(|| anyhow!("no comment {}", comment_id))?;
+    for mention in mentions {
+      if !comment
+        .mentions
+        .iter()
+        .any(|m| m.github_node_id == mention.github_node_id)
+      {
+        comment.mentions.push(mention.clone());
+      }
+    }
+    comment
+      .mentions
+      .sort_by(|a, b| a.github_username.cmp(&b.github_username));
+    Ok(())
+  }
+
+  async fn threads_for_original_lines(
+    &self,
+    commit_hashes: Vec<String>,
+    file_paths: Vec<String>,
+  ) -> anyhow::Result<Vec<ThreadWithComments>> {
+    Ok(
+      self
+        .state
+        .lock()
+        .unwrap()
+        .threads
+        .iter()
+        .filter(|t| {
+          commit_hashes.contains(&t.original_commit_hash)
+            && file_paths.contains(&t.original_file_path)
+        })
+        .cloned()
+        .collect(),
+    )
+  }
+
+  async fn threads_for_file_paths(
+    &self,
+    repo: &RepoWithCommit,
+    file_paths: Vec<String>,
+  ) -> anyhow::Result<Vec<ThreadWithComments>> {
+    let state = self.state.lock().unwrap();
+    let in_repo = |commit_hash: &String| match repo {
+      RepoWithCommit::GitHub(node_id) => state
+        .commit_github_repos
+        .contains(&(commit_hash.clone(), node_id.0.clone())),
+      RepoWithCommit::Other(repo_id) => state
+        .commit_repos
+        .contains(&(commit_hash.clone(), repo_id.to_string())),
+    };
+    Ok(
+      state
+        .threads
+        .iter()
+        .filter(|t| file_paths.contains(&t.original_file_path) && in_repo(&t.original_commit_hash))
+        .cloned()
+        .collect(),
+    )
+  }
+
+  async fn insert_blame_job(
+    &self,
+    repo_id: &str,
+    commit_hash: &str,
+    file_path: &str,
+  ) -> anyhow::Result<BlameJobRecord> {
+    let mut state = self.state.lock().unwrap();
+    let job = BlameJobRecord {
+      id: state.next_id("blame-job"),
+      repo_id: repo_id.to_string(),
+      commit_hash: commit_hash.to_string(),
+      file_path: file_path.to_string(),
+      status: "queued".to_string(),
+      error: None,
+    };
+    state.blame_jobs.push(job.clone());
+    Ok(job)
+  }
+
+  async fn lookup_blame_job(&self, job_id: &str) -> anyhow::Result<Option<BlameJobRecord>> {
+    Ok(
+      self
+        .state
+        .lock()
+        .unwrap()
+        .blame_jobs
+        .iter()
+        .find(|j| j.id == job_id)
+        .cloned(),
+    )
+  }
+
+  async fn lookup_unfinished_blame_job(
+    &self,
+    repo_id: &str,
+    commit_hash: &str,
+    file_path: &str,
+  ) -> anyhow::Result<Option<BlameJobRecord>> {
+    Ok(
+      self
+        .state
+        .lock()
+        .unwrap()
+        .blame_jobs
+        .iter()
+        .find(|j| {
+          j.repo_id == repo_id
+            && j.commit_hash == commit_hash
+            && j.file_path == file_path
+            && is_unfinished(j)
+        })
+        .cloned(),
+    )
+  }
+
+  async fn unfinished_blame_jobs(&self) -> anyhow::Result<Vec<BlameJobRecord>> {
+    Ok(
+      self
+        .state
+        .lock()
+        .unwrap()
+        .blame_jobs
+        .iter()
+        .filter(|j| is_unfinished(j))
+        .cloned()
+        .collect(),
+    )
+  }
+
+  async fn update_blame_job_status(
+    &self,
+    job_id: &str,
+    status: &str,
+    error: Option<&str>,
+  ) -> anyhow::Result<()> {
+    let mut state = self.state.lock().unwrap();
+    let job = state
+      .blame_jobs
+      .iter_mut()
+      .find(|j| j.id == job_id)
+      .ok_or_else(|| anyhow!("no blame job {}", job_id))?;
+    job.status = status.to_string();
+    job.error = error.map(|e| e.to_string());
+    Ok(())
+  }
+
+  async fn active_github_repos(&self) -> anyhow::Result<Vec<GitHubNodeId>> {
+    let state = self.state.lock().unwrap();
+    let node_ids = state
+      .commit_github_repos
+      .iter()
+      .map(|(_, node_id)| node_id.clone())
+      .collect::<HashSet<_>>();
+    Ok(node_ids.into_iter().map(GitHubNodeId).collect())
+  }
+
+  async fn active_other_repos(&self) -> anyhow::Result<Vec<String>> {
+    let state = self.state.lock().unwrap();
+    let repo_ids = state
+      .commit_repos
+      .iter()
+      .map(|(_, repo_id)| repo_id.clone())
+      .collect::<HashSet<_>>();
+    Ok(repo_ids.into_iter().collect())
+  }
+}
+
+pub struct FakeRepo {
+  pub node_id: String,
+  pub owner: String,
+  pub name: String,
+  pub is_private: bool,
+  pub commits: Vec<String>,
+}
+
+pub struct FakeGitHubUser {
+  /// What GitHub hands the user after they log in, to be exchanged for `access_token`.
+  pub oauth_code: String,
+  pub access_token: String,
+  pub info: GitHubUserInfo,
+}
+
+pub struct FakeDeviceCode {
+  pub device_code: String,
+  pub user_code: String,
+  /// None until the user enters the code, then either the access token or GitHub's error code.
+  pub outcome: Option<Result<String, String>>,
+}
+
+/// GitHub with nothing on it but `repos` and `users`.
+#[derive(Default)]
+pub struct FakeGitHub {
+  pub repos: Mutex<Vec<FakeRepo>>,
+  pub users: Mutex<Vec<FakeGitHubUser>>,
+  pub device_codes: Mutex<Vec<FakeDeviceCode>>,
+  /// When set, looking up users fails, as if GitHub were unreachable.
+  pub users_down: Mutex<bool>,
+  /// How many times `lookup_users` has gotten through to us.
+  pub user_lookups: Mutex<usize>,
+}
+
+impl FakeGitHub {
+  pub fn add_repo(
+    &self,
+    node_id: &str,
+    owner: &str,
+    name: &str,
+    is_private: bool,
+    commits: &[&str],
+  ) {
+    self.repos.lock().unwrap().push(FakeRepo {
+      node_id: node_id.to_string(),
+      owner: owner.to_string(),
+      name: name.to_string(),
+      is_private,
+      commits: commits.iter().map(|c| c.to_string()).collect(),
+    });
+  }
+
+  pub fn add_user(&self, oauth_code: &str, access_token: &str, node_id: &str, login: &str) {
+    let mut users = self.users.lock().unwrap();
+    let id = users.len() as u32 + 1;
+    users.push(FakeGitHubUser {
+      oauth_code: oauth_code.to_string(),
+      access_token: access_token.to_string(),
+      info: GitHubUserInfo {
+        login: login.to_string(),
+        id,
+        node_id: node_id.to_string(),
+        name: login.to_string(),
+        email: Some(format!("{}@example.com", login)),
+        ..Default::default()
+      },
+    });
+  }
+
+  fn find_user(&self, login: &str) -> Option<GitHubUser> {
+    self
+      .users
+      .lock()
+      .unwrap()
+      .iter()
+      .find(|u| u.info.login.eq_ignore_ascii_case(login))
+      .map(|u| GitHubUser {
+        login: u.info.login.clone(),
+        node_id: GitHubNodeId(u.info.node_id.clone()),
+        database_id: u.info.id,
+        name: Some(u.info.name.clone()),
+        email: u.info.email.clone(),
+      })
+  }
+
+  /// The user enters `user_code`, and logs in as whoever `access_token` belongs to.
+  pub fn authorize_device(&self, user_code: &str, access_token: &str) {
+    self.finish_device_flow(user_code, Ok(access_token.to_string()));
+  }
+
+  /// The device flow for `user_code` ends with `error`, eg. "access_denied" or "expired_token".
+  pub fn fail_device_flow(&self, user_code: &str, error: &str) {
+    self.finish_device_flow(user_code, Err(error.to_string()));
+  }
+
+  fn finish_device_flow(&self, user_code: &str, outcome: Result<String, String>) {
+    let mut device_codes = self.device_codes.lock().unwrap();
+    let device_code = device_codes
+      .iter_mut()
+      .find(|d| d.user_code == user_code)
+      .expect("no such user code");
+    device_code.outcome = Some(outcome);
+  }
+
+  fn new_device_code(&self) -> DeviceCode {
+    let mut device_codes = self.device_codes.lock().unwrap();
+    let n = device_codes.len() + 1;
+    let device_code = DeviceCode {
+      device_code: format!("device-code-{}", n),
+      user_code: format!("USER-{:04}", n),
+      verification_uri: "https://github.invalid/login/device".to_string(),
+      expires_in: 900,
+      interval: 5,
+    };
+    device_codes.push(FakeDeviceCode {
+      device_code: device_code.device_code.clone(),
+      user_code: device_code.user_code.clone(),
+      outcome: None,
+    });
+    device_code
+  }
+
+  /// Like GitHub, device codes stop working once they've been traded in for an access token.
+  fn poll_device_code(&self, device_code: &str) -> DeviceFlowPoll {
+    let mut device_codes = self.device_codes.lock().unwrap();
+    let i = match device_codes
+      .iter()
+      .position(|d| d.device_code == device_code)
+    {
+      Some(i) => i,
+      None => {
+        return DeviceFlowPoll::Failed {
+          error: "incorrect_device_code".to_string(),
+        }
+      }
+    };
+    match device_codes[i].outcome.clone() {
+      None => DeviceFlowPoll::Pending,
+      Some(outcome) => {
+        device_codes.remove(i);
+        match outcome {
+          Ok(access_token) => DeviceFlowPoll::Authorized { access_token },
+          Err(error) => DeviceFlowPoll::Failed { error },
+        }
+      }
+    }
+  }
+}
+
+#[async_trait]
+impl GitHub for FakeGitHub {
+  async fn lookup_commit(
+    &self,
+    _auth: Option<&GitHubAuth>,
+    repo_owner: &str,
+    repo_name: &str,
+    commit_oid: &str,
+  ) -> anyhow::Result<Option<(GitHubNodeId, bool, bool)>> {
+    Ok(
+      self
+        .repos
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|r| r.owner == repo_owner && r.name == repo_name)
+        .map(|r| {
+          (
+            GitHubNodeId(r.node_id.clone()),
+            r.is_private,
+            r.commits.iter().any(|c| c == commit_oid),
+          )
+        }),
+    )
+  }
+
+  async fn lookup_repo_names(
+    &self,
+    node_ids: &[GitHubNodeId],
+  ) -> anyhow::Result<Vec<(String, String)>> {
+    let repos = self.repos.lock().unwrap();
+    Ok(
+      node_ids
+        .iter()
+        .filter_map(|id| repos.iter().find(|r| r.node_id == id.0))
+        .map(|r| (r.owner.clone(), r.name.clone()))
+        .collect(),
+    )
+  }
+
+  async fn lookup_users(&self, logins: &[String]) -> anyhow::Result<Vec<Option<GitHubUser>>> {
+    if *self.users_down.lock().unwrap() {
+      return Err(anyhow!("GitHub is down"));
+    }
+    *self.user_lookups.lock().unwrap() += 1;
+    Ok(logins.iter().map(|login| self.find_user(login)).collect())
+  }
+
+  fn oauth_authorize_url(&self) -> String {
+    "https://github.invalid/login/oauth/authorize".to_string()
+  }
+
+  async fn exchange_oauth_code(
+    &self,
+    _client_id: &str,
+    _client_secret: &str,
+    code: &str,
+    _state: &str,
+  ) -> anyhow::Result<String> {
+    self
+      .users
+      .lock()
+      .unwrap()
+      .iter()
+      .find(|u| u.oauth_code == code)
+      .map(|u| u.access_token.clone())
+      .ok_or_else(|| anyhow!("bad_verification_code"))
+  }
+
+  async fn user_info(&self, access_token: &str) -> anyhow::Result<GitHubUserInfo> {
+    self
+      .users
+      .lock()
+      .unwrap()
+      .iter()
+      .find(|u| u.access_token == access_token)
+      .map(|u| u.info.clone())
+      .ok_or_else(|| anyhow!("bad credentials"))
+  }
+
+  async fn start_device_flow(&self, _client_id: &str) -> anyhow::Result<DeviceCode> {
+    Ok(self.new_device_code())
+  }
+
+  async fn poll_device_flow(
+    &self,
+    _client_id: &str,
+    device_code: &str,
+  ) -> anyhow::Result<DeviceFlowPoll> {
+    Ok(self.poll_device_code(device_code))
+  }
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header(header::CONTENT_TYPE, "application/json")
+    .body(Body::from(body.to_string()))
+    .unwrap()
+}
+
+async fn fake_github_graphql(github: &FakeGitHub, req: Request<Body>) -> Response<Body> {
+  let authorized = req
+    .headers()
+    .get(header::AUTHORIZATION)
+    .and_then(|v| v.to_str().ok())
+    .is_some_and(|v| v.starts_with("Bearer "));
+  if !authorized {
+    return json_response(
+      StatusCode::UNAUTHORIZED,
+      json!({ "message": "Bad credentials" }),
+    );
+  }
+  let body: serde_json::Value =
+    serde_json::from_slice(&hyper::body::to_bytes(req.into_body()).await.unwrap()).unwrap();
+  let variables = &body["variables"];
+  let data = match body["operationName"].as_str() {
+    Some("LookupCommit") => {
+      let repos = github.repos.lock().unwrap();
+      let repo = repos
+        .iter()
+        .find(|r| r.owner == variables["repo_owner"] && r.name == variables["repo_name"]);
+      json!({
+        "repository": repo.map(|r| {
+          let oid = variables["commit_oid"].as_str().unwrap_or_default();
+          json!({
+            "id": r.node_id,
+            "databaseId": 1,
+            "isPrivate": r.is_private,
+            "object": r.commits.iter().any(|c| c == oid).then(|| json!({
+              "__typename": "Commit",
+              "id": format!("C_{}", oid),
+              "oid": oid,
+            })),
+          })
+        }),
+      })
+    }
+    Some("LookupRepoNames") => {
+      let repos = github.repos.lock().unwrap();
+      let ids = variables["ids"].as_array().cloned().unwrap_or_default();
+      json!({
+        "nodes": ids.iter().map(|id| {
+          repos.iter().find(|r| r.node_id == *id).map(|r| json!({
+            "__typename": "Repository",
+            "id": r.node_id,
+            "name": r.name,
+            "owner": { "__typename": "User", "login": r.owner },
+          }))
+        }).collect::<Vec<_>>(),
+      })
+    }
+    Some("LookupUsers") => {
+      // Each $loginN is looked up as uN.
+      let mut data = serde_json::Map::new();
+      let mut errors = vec![];
+      for (name, login) in variables.as_object().cloned().unwrap_or_default() {
+        let alias = name.replacen("login", "u", 1);
+        let login = login.as_str().unwrap_or_default();
+        let user = github.find_user(login).map(|u| {
+          json!({
+            "id": u.node_id.0,
+            "databaseId": u.database_id,
+            "login": u.login,
+            "name": u.name,
+            "email": u.email.unwrap_or_default(),
+          })
+        });
+        if user.is_none() {
+          errors.push(json!({
+            "type": "NOT_FOUND",
+            "path": [alias],
+            "message": format!("Could not resolve to a User with the login of '{}'.", login),
+          }));
+        }
+        data.insert(alias, user.unwrap_or(serde_json::Value::Null));
+      }
+      if !errors.is_empty() {
+        return json_response(StatusCode::OK, json!({ "data": data, "errors": errors }));
+      }
+      data.into()
+    }
+    op => {
+      return json_response(
+        StatusCode::OK,
+        json!({ "errors": [{ "message": format!("unknown operation {:?}", op) }] }),
+      )
+    }
+  };
+  json_response(StatusCode::OK, json!({ "data": data }))
+}
+
+fn fake_github_access_token(github: &FakeGitHub, req: Request<Body>) -> Response<Body> {
+  let params: HashMap<String, String> = req
+    .uri()
+    .query()
+    .map(|q| {
+      url::form_urlencoded::parse(q.as_bytes())
+        .into_owned()
+        .collect()
+    })
+    .unwrap_or_default();
+  if params.get("grant_type").map(String::as_str) == Some(DEVICE_CODE_GRANT_TYPE) {
+    let device_code = params.get("device_code").cloned().unwrap_or_default();
+    // GitHub really does send a 200 for all of these.
+    return json_response(
+      StatusCode::OK,
+      match github.poll_device_code(&device_code) {
+        DeviceFlowPoll::Pending => json!({ "error": "authorization_pending" }),
+        DeviceFlowPoll::SlowDown { interval } => {
+          json!({ "error": "slow_down", "interval": interval })
+        }
+        DeviceFlowPoll::Authorized { access_token } => {
+          json!({ "access_token": access_token, "token_type": "bearer", "scope": "" })
+        }
+        DeviceFlowPoll::Failed { error } => json!({ "error": error }),
+      },
+    );
+  }
+  let users = github.users.lock().unwrap();
+  match users
+    .iter()
+    .find(|u| params.get("code") == Some(&u.oauth_code))
+  {
+    Some(u) => json_response(
+      StatusCode::OK,
+      json!({ "access_token": u.access_token, "token_type": "bearer", "scope": "" }),
+    ),
+    // GitHub really does send a 200 for this.
+    None => json_response(StatusCode::OK, json!({ "error": "bad_verification_code" })),
+  }
+}
+
+fn fake_github_user(github: &FakeGitHub, req: Request<Body>) -> Response<Body> {
+  let token = req
+    .headers()
+    .get(header::AUTHORIZATION)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.strip_prefix("token "))
+    .unwrap_or_default();
+  let users = github.users.lock().unwrap();
+  match users.iter().find(|u| u.access_token == token) {
+    Some(u) => json_response(StatusCode::OK, serde_json::to_value(&u.info).unwrap()),
+    None => json_response(
+      StatusCode::UNAUTHORIZED,
+      json!({ "message": "Bad credentials" }),
+    ),
+  }
+}
+
+/// Serve `github` on an ephemeral local port until the runtime shuts down. Returns the base URL, which works as both
+/// `GitHubApi::web_url` and `GitHubApi::api_url`.
+pub async fn serve_github(github: Arc<FakeGitHub>) -> String {
+  let make_service = make_service_fn(move |_| {
+    let github = github.clone();
+    async move {
+      Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+        let github = github.clone();
+        async move {
+          Ok::<_, hyper::Error>(match (req.method(), req.uri().path()) {
+            (&Method::POST, "/graphql") => fake_github_graphql(&github, req).await,
+            (&Method::POST, "/login/oauth/access_token") => fake_github_access_token(&github, req),
+            (&Method::POST, "/login/device/code") => json_response(
+              StatusCode::OK,
+              serde_json::to_value(github.new_device_code()).unwrap(),
+            ),
+            (&Method::GET, "/user") => fake_github_user(&github, req),
+            _ => json_response(StatusCode::NOT_FOUND, json!({ "message": "Not Found" })),
+          })
+        }
+      }))
+    }
+  });
+  let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+  let url = format!("http://{}", server.local_addr());
+  tokio::spawn(server);
+  url
+}
+
+/// A config for tests, with GitHub at `github_url`. Nothing in it points anywhere real.
+pub fn config(github_url: &str) -> Config {
+  Config {
+    listen_addr: ([127, 0, 0, 1], 0).into(),
+    public_api_url: "http://localhost:3001".to_string(),
+    frontend_url: "http://localhost:3000/".to_string(),
+    return_to_origins: vec!["http://localhost:*".parse().unwrap()],
+    cookie_domain: None,
+    github_url: github_url.to_string(),
+    github_api_url: github_url.to_string(),
+    github_oauth_client_id: "client-id".to_string(),
+    github_oauth_client_secret: "client-secret".to_string(),
+    github_api_token: "api-token".to_string(),
+    api_paseto_secret_key: "0123456789abcdef0123456789abcdef".to_string(),
+    access_token_keys: vec!["paseto=0123456789abcdef0123456789abcdef".parse().unwrap()],
+    hasura_host: "hasura.invalid".to_string(),
+    hasura_port: 8080,
+    hasura_graphql_admin_secret: "hasurasecret".to_string(),
+    storage_backend: StorageBackend::Hasura,
+    database_url: None,
+    blamelines_chunk_size: 1000,
+    mirrors_dir: PathBuf::from("/nonexistent/mirrors"),
+    mirrors_quota_bytes: None,
+    session_ttl: chrono::Duration::days(30),
+    session_rotate_after: chrono::Duration::days(1),
+    blame_workers: 4,
+    mirror_refresh_interval: std::time::Duration::ZERO,
+    mirror_max_refresh_interval: std::time::Duration::from_secs(24 * 60 * 60),
+  }
+}
+
+/// A context backed by `storage` and `github`, which are usually fakes.
+pub fn context(
+  auth: AuthContext,
+  storage: Arc<dyn Storage>,
+  github: Arc<dyn GitHub>,
+) -> JuniperContext {
+  JuniperContext {
+    auth,
+    storage,
+    github,
+  }
+}
+
+/// A logged in user, as far as `context` is concerned.
+pub fn github_auth(node_id: &str) -> AuthContext {
+  AuthContext::GitHub(GitHubAuth {
+    github_node_id: GitHubUserId(GitHubNodeId(node_id.to_string())),
+    access_token: "token".to_string(),
+  })
+}
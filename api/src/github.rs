@@ -1,7 +1,14 @@
 // Calling GitHub API endpoints.
 use anyhow::anyhow;
+use async_trait::async_trait;
 use graphql_client::GraphQLQuery;
+use hyper::header;
+use log::trace;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
 
+use crate::config::Config;
 use crate::GitHubAuth;
 
 static USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
@@ -11,37 +18,169 @@ type GitObjectID = String;
 
 // The GitHub API has two notions of id: a node id, and databaseId. Node ids are the "new" solutions and are
 // base64-encoded id's that are globally unique. DatabaseIds are the "old" solutions and are numeric.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct GitHubNodeId(pub String);
 
-async fn github_request<B: serde::ser::Serialize + ?Sized, T: serde::de::DeserializeOwned>(
-  auth: Option<&GitHubAuth>,
-  json_body: &B,
-) -> anyhow::Result<T> {
-  // The GitHub API requires User-Agent to be set on every request
-  // (https://developer.github.com/v3/#user-agent-required).
-  let response = reqwest::Client::builder()
-    .user_agent(USER_AGENT)
-    .build()?
-    .post("https://api.github.com/graphql")
-    .bearer_auth(
-      auth
-        .map(|x| &x.access_token)
-        .unwrap_or(&*crate::GITHUB_API_TOKEN),
-    )
-    .json(&json_body)
-    .send()
-    .await?;
-
-  let response_parsed: graphql_client::Response<T> = response.json().await?;
-
-  // The order of these branches is significant.
-  match (response_parsed.data, response_parsed.errors) {
-    (_, Some(errs)) => Err(anyhow!("GraphQL response includes errors: {:?}", errs)),
-    (Some(x), _) => Ok(x),
-    _ => Err(anyhow!(
-      "expected either `data` or `response` fields to be present",
-    )),
+// Not all of these fields are used yet, but they're nice to have when debugging.
+#[allow(dead_code)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct GitHubUserInfo {
+  /// The user's GitHub username, eg. "samuela".
+  pub login: String,
+  /// The "databaseId" in GitHub API speak.
+  pub id: u32,
+  /// The "node id" in GitHub API speak.
+  pub node_id: String,
+  // TODO what happens with users that don't have a name set?
+  pub name: String,
+  // This comes in as null sometimes. Serde seems to handle correctly.
+  pub company: Option<String>,
+  pub blog: Option<String>,
+  pub location: Option<String>,
+  pub email: Option<String>,
+  pub hireable: bool,
+  pub bio: Option<String>,
+  pub twitter_username: Option<String>,
+}
+
+/// Someone on GitHub, who may or may not have logged in to Cuddlefish.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GitHubUser {
+  pub login: String,
+  pub node_id: GitHubNodeId,
+  pub database_id: u32,
+  pub name: Option<String>,
+  /// Only if they've made it public.
+  pub email: Option<String>,
+}
+
+/// What GitHub hands back when a device flow login starts. The user goes to `verification_uri` and types in
+/// `user_code`, while the device polls with `device_code` every `interval` seconds until it gets an access token or
+/// `expires_in` seconds have passed. See
+/// https://docs.github.com/en/developers/apps/building-oauth-apps/authorizing-oauth-apps#device-flow.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceCode {
+  pub device_code: String,
+  pub user_code: String,
+  pub verification_uri: String,
+  pub expires_in: u64,
+  pub interval: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceFlowPoll {
+  /// The user hasn't entered the code yet.
+  Pending,
+  /// We're polling too often, and should wait `interval` seconds from now on.
+  SlowDown {
+    interval: u64,
+  },
+  Authorized {
+    access_token: String,
+  },
+  /// GitHub's error code, eg. "expired_token" or "access_denied". Polling again won't help.
+  Failed {
+    error: String,
+  },
+}
+
+/// The parts of GitHub that we use, so that tests can swap in a fake. `GitHubApi` is the real thing.
+#[async_trait]
+pub trait GitHub: Send + Sync {
+  /// Returns Some((repo_node_id, isPrivate, commit_in_repo)) if we are able to find the repo, and None if we were not
+  /// able to find the repo.
+  async fn lookup_commit(
+    &self,
+    auth: Option<&GitHubAuth>,
+    repo_owner: &str,
+    repo_name: &str,
+    commit_oid: &str,
+  ) -> anyhow::Result<Option<(GitHubNodeId, bool, bool)>>;
+  /// Returns (owner, name) for each of the repos in `node_ids` that still exist. Uses our own API token.
+  async fn lookup_repo_names(
+    &self,
+    node_ids: &[GitHubNodeId],
+  ) -> anyhow::Result<Vec<(String, String)>>;
+  /// Look up users by their logins, eg. "samuela", all in one request. Logins are case insensitive. Returns one entry
+  /// per login, in the same order, which is None if there's no such user. Uses our own API token.
+  async fn lookup_users(&self, logins: &[String]) -> anyhow::Result<Vec<Option<GitHubUser>>>;
+
+  /// Where to send users to log in with GitHub.
+  fn oauth_authorize_url(&self) -> String;
+  /// Trade in the code that GitHub gave the user after logging in for an access token.
+  async fn exchange_oauth_code(
+    &self,
+    client_id: &str,
+    client_secret: &str,
+    code: &str,
+    state: &str,
+  ) -> anyhow::Result<String>;
+  /// Info about the user that `access_token` belongs to.
+  async fn user_info(&self, access_token: &str) -> anyhow::Result<GitHubUserInfo>;
+
+  /// Start logging someone in with the device flow, for clients that can't receive a redirect.
+  async fn start_device_flow(&self, client_id: &str) -> anyhow::Result<DeviceCode>;
+  /// Check whether the user has entered the code for `device_code` yet.
+  async fn poll_device_flow(
+    &self,
+    client_id: &str,
+    device_code: &str,
+  ) -> anyhow::Result<DeviceFlowPoll>;
+}
+
+pub struct GitHubApi {
+  /// Where OAuth lives, eg. "https://github.com".
+  pub web_url: String,
+  /// Where the REST and GraphQL APIs live, eg. "https://api.github.com".
+  pub api_url: String,
+  /// Our own token, for when we're not acting on behalf of a user.
+  pub api_token: String,
+}
+
+impl GitHubApi {
+  pub fn new(config: &Config) -> Self {
+    GitHubApi {
+      web_url: config.github_url.clone(),
+      api_url: config.github_api_url.clone(),
+      api_token: config.github_api_token.clone(),
+    }
+  }
+
+  async fn graphql_response<B: serde::ser::Serialize + ?Sized, T: serde::de::DeserializeOwned>(
+    &self,
+    auth: Option<&GitHubAuth>,
+    json_body: &B,
+  ) -> anyhow::Result<graphql_client::Response<T>> {
+    // The GitHub API requires User-Agent to be set on every request
+    // (https://developer.github.com/v3/#user-agent-required).
+    let response = reqwest::Client::builder()
+      .user_agent(USER_AGENT)
+      .build()?
+      .post(format!("{}/graphql", self.api_url))
+      .bearer_auth(auth.map(|x| &x.access_token).unwrap_or(&self.api_token))
+      .json(&json_body)
+      .send()
+      .await?;
+
+    Ok(response.json().await?)
+  }
+
+  async fn graphql_request<B: serde::ser::Serialize + ?Sized, T: serde::de::DeserializeOwned>(
+    &self,
+    auth: Option<&GitHubAuth>,
+    json_body: &B,
+  ) -> anyhow::Result<T> {
+    let response_parsed: graphql_client::Response<T> =
+      self.graphql_response(auth, json_body).await?;
+
+    // The order of these branches is significant.
+    match (response_parsed.data, response_parsed.errors) {
+      (_, Some(errs)) => Err(anyhow!("GraphQL response includes errors: {:?}", errs)),
+      (Some(x), _) => Ok(x),
+      _ => Err(anyhow!(
+        "expected either `data` or `response` fields to be present",
+      )),
+    }
   }
 }
 
@@ -53,30 +192,383 @@ async fn github_request<B: serde::ser::Serialize + ?Sized, T: serde::de::Deseria
 )]
 pub struct LookupCommit;
 
-/// Returns Some((repo_node_id, isPrivate, commit_in_repo)) if we are able to find the repo, and None if we were not
-/// able to find the repo.
-pub async fn lookup_commit(
-  auth: Option<&GitHubAuth>,
-  repo_owner: &str,
-  repo_name: &str,
-  commit_oid: &str,
-) -> anyhow::Result<Option<(GitHubNodeId, bool, bool)>> {
-  let res: lookup_commit::ResponseData = github_request(
-    auth,
-    &LookupCommit::build_query(lookup_commit::Variables {
-      repo_owner: repo_owner.to_string(),
-      repo_name: repo_name.to_string(),
-      commit_oid: commit_oid.to_string(),
-    }),
-  )
-  .await?;
-
-  // `res.repository == None` when the repo can't be found.
-  Ok(res.repository.map(|repo| {
-    (
-      GitHubNodeId(repo.id),
-      repo.is_private,
-      repo.object.is_some(),
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/github/schema.json",
+  query_path = "gql/github/queries.graphql",
+  response_derives = "Debug"
+)]
+pub struct LookupRepoNames;
+
+/// One user in the response to `lookup_users_query`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UserNode {
+  id: String,
+  database_id: Option<i64>,
+  login: String,
+  name: Option<String>,
+  email: String,
+}
+
+/// A query that looks up each of `logins` under its own alias, `u0`, `u1`, and so on. graphql_client needs every field
+/// of a query up front, so we write this one ourselves.
+fn lookup_users_query(logins: &[String]) -> serde_json::Value {
+  let params = (0..logins.len())
+    .map(|i| format!("$login{}: String!", i))
+    .collect::<Vec<_>>()
+    .join(", ");
+  let fields = (0..logins.len())
+    .map(|i| format!("u{0}: user(login: $login{0}) {{ ...UserFields }}", i))
+    .collect::<Vec<_>>()
+    .join("\n  ");
+  let variables = logins
+    .iter()
+    .enumerate()
+    .map(|(i, login)| {
+      (
+        format!("login{}", i),
+        serde_json::Value::from(login.as_str()),
+      )
+    })
+    .collect::<serde_json::Map<_, _>>();
+  serde_json::json!({
+    "operationName": "LookupUsers",
+    "query": format!(
+      "query LookupUsers({}) {{\n  {}\n}}\n\nfragment UserFields on User {{\n  id\n  databaseId\n  login\n  name\n  email\n}}",
+      params, fields
+    ),
+    "variables": variables,
+  })
+}
+
+pub const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// GitHub won't look up more than this many nodes in one go.
+const MAX_NODES_PER_REQUEST: usize = 100;
+
+#[async_trait]
+impl GitHub for GitHubApi {
+  async fn lookup_commit(
+    &self,
+    auth: Option<&GitHubAuth>,
+    repo_owner: &str,
+    repo_name: &str,
+    commit_oid: &str,
+  ) -> anyhow::Result<Option<(GitHubNodeId, bool, bool)>> {
+    let res: lookup_commit::ResponseData = self
+      .graphql_request(
+        auth,
+        &LookupCommit::build_query(lookup_commit::Variables {
+          repo_owner: repo_owner.to_string(),
+          repo_name: repo_name.to_string(),
+          commit_oid: commit_oid.to_string(),
+        }),
+      )
+      .await?;
+
+    // `res.repository == None` when the repo can't be found.
+    Ok(res.repository.map(|repo| {
+      (
+        GitHubNodeId(repo.id),
+        repo.is_private,
+        repo.object.is_some(),
+      )
+    }))
+  }
+
+  async fn lookup_repo_names(
+    &self,
+    node_ids: &[GitHubNodeId],
+  ) -> anyhow::Result<Vec<(String, String)>> {
+    let mut res = vec![];
+    for chunk in node_ids.chunks(MAX_NODES_PER_REQUEST) {
+      let data: lookup_repo_names::ResponseData = self
+        .graphql_request(
+          None,
+          &LookupRepoNames::build_query(lookup_repo_names::Variables {
+            ids: chunk.iter().map(|id| id.0.clone()).collect(),
+          }),
+        )
+        .await?;
+      // Deleted repos come back as nulls.
+      for node in data.nodes.into_iter().flatten() {
+        if let lookup_repo_names::LookupRepoNamesNodes::Repository(repo) = node {
+          res.push((repo.owner.login, repo.name));
+        }
+      }
+    }
+    Ok(res)
+  }
+
+  async fn lookup_users(&self, logins: &[String]) -> anyhow::Result<Vec<Option<GitHubUser>>> {
+    if logins.is_empty() {
+      return Ok(vec![]);
+    }
+    let res: graphql_client::Response<HashMap<String, Option<UserNode>>> = self
+      .graphql_response(None, &lookup_users_query(logins))
+      .await?;
+    // GitHub answers logins that don't exist with a null user and a NOT_FOUND error for it, rather than just the null.
+    let not_found = |errs: &[graphql_client::Error]| {
+      errs.iter().all(|e| {
+        matches!(e.path.as_deref(), Some([graphql_client::PathFragment::Key(key)]) if key.strip_prefix('u').is_some_and(|i| i.parse::<usize>().is_ok()))
+      })
+    };
+    let mut users = match (res.data, res.errors) {
+      (Some(data), Some(errs)) if not_found(&errs) => data,
+      (_, Some(errs)) => return Err(anyhow!("GraphQL response includes errors: {:?}", errs)),
+      (Some(data), None) => data,
+      (None, None) => return Err(anyhow!("expected either `data` or `errors` to be present")),
+    };
+    logins
+      .iter()
+      .enumerate()
+      .map(|(i, login)| {
+        users
+          .remove(&format!("u{}", i))
+          .flatten()
+          .map(|user| {
+            Ok(GitHubUser {
+              login: user.login,
+              node_id: GitHubNodeId(user.id),
+              database_id: u32::try_from(
+                user
+                  .database_id
+                  .ok_or_else(|| anyhow!("user {} has no databaseId", login))?,
+              )?,
+              name: user.name,
+              // GitHub hands out an empty string for private emails.
+              email: Some(user.email).filter(|email| !email.is_empty()),
+            })
+          })
+          .transpose()
+      })
+      .collect()
+  }
+
+  fn oauth_authorize_url(&self) -> String {
+    format!("{}/login/oauth/authorize", self.web_url)
+  }
+
+  async fn exchange_oauth_code(
+    &self,
+    client_id: &str,
+    client_secret: &str,
+    code: &str,
+    state: &str,
+  ) -> anyhow::Result<String> {
+    let access_token_response = reqwest::Client::new()
+      .post(format!("{}/login/oauth/access_token", self.web_url))
+      .header(header::ACCEPT, "application/json")
+      .query(&[
+        ("client_id", client_id),
+        ("client_secret", client_secret),
+        ("code", code),
+        ("state", state),
+      ])
+      .send()
+      .await
+      // Turn error status codes into rust errors.
+      .and_then(|resp| resp.error_for_status())?;
+    // The URL has the client secret and the code in it, so don't log the whole response.
+    trace!(
+      "access_token_response status = {}",
+      access_token_response.status()
+    );
+
+    #[derive(Deserialize)]
+    struct AccessTokenResp {
+      access_token: String,
+    }
+    // GitHub responds to bad codes with a 200 and an error body, which fails to deserialize here.
+    Ok(
+      access_token_response
+        .json::<AccessTokenResp>()
+        .await?
+        .access_token,
     )
-  }))
+  }
+
+  async fn user_info(&self, access_token: &str) -> anyhow::Result<GitHubUserInfo> {
+    // TODO: use the gql way
+    let user_info_response = reqwest::Client::new()
+      .get(format!("{}/user", self.api_url))
+      .header(header::AUTHORIZATION, format!("token {}", access_token))
+      // Setting a user agent is mandatory when calling api.github.com.
+      .header(header::USER_AGENT, USER_AGENT)
+      .send()
+      .await
+      // Turn error status codes into rust errors.
+      .and_then(|resp| resp.error_for_status())?;
+    trace!("user_info_response = {:?}", user_info_response);
+
+    // Deserialize the user info response.
+    let response_body = user_info_response.text().await?;
+    trace!("response body = {:?}", response_body);
+    let user_info: GitHubUserInfo = serde_json::from_str(&response_body)?;
+
+    // Slightly more efficient, harder to debug with:
+    // let user_info: GitHubUserInfo = user_info_response.json().await?;
+
+    trace!("user_info = {:?}", user_info);
+
+    Ok(user_info)
+  }
+
+  async fn start_device_flow(&self, client_id: &str) -> anyhow::Result<DeviceCode> {
+    Ok(
+      reqwest::Client::new()
+        .post(format!("{}/login/device/code", self.web_url))
+        .header(header::ACCEPT, "application/json")
+        .query(&[("client_id", client_id)])
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())?
+        .json()
+        .await?,
+    )
+  }
+
+  async fn poll_device_flow(
+    &self,
+    client_id: &str,
+    device_code: &str,
+  ) -> anyhow::Result<DeviceFlowPoll> {
+    #[derive(Deserialize)]
+    struct PollResp {
+      access_token: Option<String>,
+      error: Option<String>,
+      interval: Option<u64>,
+    }
+    let resp: PollResp = reqwest::Client::new()
+      .post(format!("{}/login/oauth/access_token", self.web_url))
+      .header(header::ACCEPT, "application/json")
+      .query(&[
+        ("client_id", client_id),
+        ("device_code", device_code),
+        ("grant_type", DEVICE_CODE_GRANT_TYPE),
+      ])
+      .send()
+      .await
+      .and_then(|resp| resp.error_for_status())?
+      .json()
+      .await?;
+    // Like with codes, GitHub responds with a 200 and an error body until the user is done.
+    Ok(match (resp.access_token, resp.error) {
+      (Some(access_token), _) => DeviceFlowPoll::Authorized { access_token },
+      (None, Some(error)) if error == "authorization_pending" => DeviceFlowPoll::Pending,
+      (None, Some(error)) if error == "slow_down" => DeviceFlowPoll::SlowDown {
+        interval: resp
+          .interval
+          .ok_or_else(|| anyhow!("slow_down without an interval"))?,
+      },
+      (None, Some(error)) => DeviceFlowPoll::Failed { error },
+      (None, None) => return Err(anyhow!("expected either `access_token` or `error`")),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fakes;
+  use crate::fakes::FakeGitHub;
+  use crate::GitHubUserId;
+  use std::sync::Arc;
+
+  #[tokio::test]
+  async fn against_fake_server() {
+    let fake = Arc::new(FakeGitHub::default());
+    fake.add_repo("R_public", "owner", "public", false, &["abc"]);
+    fake.add_repo("R_private", "owner", "private", true, &["abc"]);
+    fake.add_user("code", "token", "U_1", "someone");
+    let url = fakes::serve_github(fake.clone()).await;
+    let github = GitHubApi::new(&fakes::config(&url));
+    let auth = GitHubAuth {
+      github_node_id: GitHubUserId(GitHubNodeId("U_1".to_string())),
+      access_token: "token".to_string(),
+    };
+
+    assert_eq!(
+      github
+        .lookup_commit(Some(&auth), "owner", "public", "abc")
+        .await
+        .unwrap(),
+      Some((GitHubNodeId("R_public".to_string()), false, true))
+    );
+    assert_eq!(
+      github
+        .lookup_commit(Some(&auth), "owner", "public", "def")
+        .await
+        .unwrap(),
+      Some((GitHubNodeId("R_public".to_string()), false, false))
+    );
+    assert_eq!(
+      github
+        .lookup_commit(None, "owner", "private", "abc")
+        .await
+        .unwrap(),
+      Some((GitHubNodeId("R_private".to_string()), true, true))
+    );
+    assert_eq!(
+      github
+        .lookup_commit(None, "owner", "missing", "abc")
+        .await
+        .unwrap(),
+      None
+    );
+    assert_eq!(
+      github
+        .lookup_repo_names(&[
+          GitHubNodeId("R_private".to_string()),
+          GitHubNodeId("R_deleted".to_string())
+        ])
+        .await
+        .unwrap(),
+      vec![("owner".to_string(), "private".to_string())]
+    );
+    let users = github
+      .lookup_users(&["nobody".to_string(), "SomeOne".to_string()])
+      .await
+      .unwrap();
+    assert_eq!(users[0], None);
+    let user = users[1].as_ref().unwrap();
+    assert_eq!(user.login, "someone");
+    assert_eq!(user.node_id, GitHubNodeId("U_1".to_string()));
+    assert_eq!(user.email.as_deref(), Some("someone@example.com"));
+    assert_eq!(github.lookup_users(&[]).await.unwrap(), vec![]);
+
+    let access_token = github
+      .exchange_oauth_code("client-id", "client-secret", "code", "state")
+      .await
+      .unwrap();
+    assert_eq!(access_token, "token");
+    assert!(github
+      .exchange_oauth_code("client-id", "client-secret", "bad code", "state")
+      .await
+      .is_err());
+    let user_info = github.user_info(&access_token).await.unwrap();
+    assert_eq!(
+      (user_info.node_id.as_str(), user_info.login.as_str()),
+      ("U_1", "someone")
+    );
+    assert!(github.user_info("bad token").await.is_err());
+
+    let device = github.start_device_flow("client-id").await.unwrap();
+    let poll = || github.poll_device_flow("client-id", &device.device_code);
+    assert_eq!(poll().await.unwrap(), DeviceFlowPoll::Pending);
+    fake.authorize_device(&device.user_code, "token");
+    assert_eq!(
+      poll().await.unwrap(),
+      DeviceFlowPoll::Authorized {
+        access_token: "token".to_string()
+      }
+    );
+    assert_eq!(
+      poll().await.unwrap(),
+      DeviceFlowPoll::Failed {
+        error: "incorrect_device_code".to_string()
+      }
+    );
+  }
 }
@@ -0,0 +1,57 @@
+// Calling GitLab API endpoints. Works for both gitlab.com and self-managed instances.
+use anyhow::ensure;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+static USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Deserialize, Debug)]
+struct ProjectResp {
+  /// GitLab's numeric project id.
+  id: u64,
+  /// One of "public", "internal", or "private".
+  visibility: String,
+}
+
+/// Returns Some((is_private, commit_in_repo)) if we are able to find the project, and None if we were not able to find
+/// the project. We don't send any credentials, so private projects look exactly like missing ones.
+pub async fn lookup_commit(
+  host: &str,
+  project_path: &str,
+  commit_oid: &str,
+) -> anyhow::Result<Option<(bool, bool)>> {
+  // This goes into a URL path, so make sure that it's nothing but hex.
+  let commit_oid = git2::Oid::from_str(commit_oid)?;
+  let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+
+  // See https://docs.gitlab.com/ee/api/projects.html#get-single-project. The project path must be URL-encoded,
+  // including its `/`s.
+  let encoded_path =
+    url::form_urlencoded::byte_serialize(project_path.as_bytes()).collect::<String>();
+  let project_response = client
+    .get(format!("https://{}/api/v4/projects/{}", host, encoded_path))
+    .send()
+    .await?;
+  if project_response.status() == StatusCode::NOT_FOUND {
+    return Ok(None);
+  }
+  let project: ProjectResp = project_response.error_for_status()?.json().await?;
+
+  // See https://docs.gitlab.com/ee/api/commits.html#get-a-single-commit.
+  let commit_response = client
+    .get(format!(
+      "https://{}/api/v4/projects/{}/repository/commits/{}",
+      host, project.id, commit_oid
+    ))
+    .send()
+    .await?;
+  let contains_commit = commit_response.status().is_success();
+  ensure!(
+    contains_commit || commit_response.status() == StatusCode::NOT_FOUND,
+    "gitlab returned non-success status {}",
+    commit_response.status()
+  );
+
+  // "internal" projects are only visible to logged in users on that instance, which is not public enough for us.
+  Ok(Some((project.visibility != "public", contains_commit)))
+}
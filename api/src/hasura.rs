@@ -1,32 +1,46 @@
+use crate::config::Config;
 use crate::github::GitHubNodeId;
+use crate::github::GitHubUser;
+use crate::storage::looks_like_uuid;
+use crate::storage::BlameJobRecord;
+use crate::storage::CommentEditRecord;
+use crate::storage::CommentRecord;
+use crate::storage::LineRange;
+use crate::storage::MentionRecord;
+use crate::storage::SessionRecord;
+use crate::storage::Storage;
+use crate::storage::ThreadAnchor;
+use crate::storage::ThreadWithComments;
+use crate::token_cipher::TokenCipher;
 use crate::BlameLine;
 use crate::GitHubAuth;
 use crate::GitHubUserId;
-use crate::HASURA_HOST;
-use crate::HASURA_PORT;
+use crate::RepoWithCommit;
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::ensure;
 use anyhow::Context;
+use async_trait::async_trait;
+use chrono::Duration;
+use chrono::Utc;
 use graphql_client::GraphQLQuery;
+use serde::Deserialize;
 use serde_json::json;
 
-// This name comes from GraphQL/Hasura, so it's not camel case.
+// These names come from GraphQL/Hasura, so they're not camel case.
 #[allow(non_camel_case_types)]
 type uuid = String;
+#[allow(non_camel_case_types)]
+type timestamptz = String;
 
 #[allow(non_snake_case)]
 async fn ADMIN_hasura_request<B: serde::ser::Serialize + ?Sized, T: serde::de::DeserializeOwned>(
+  hasura: &HasuraStorage,
   json_body: &B,
 ) -> anyhow::Result<T> {
-  // TODO should use https here
-  let hasura_url = format!("http://{}:{}/v1/graphql", *HASURA_HOST, *HASURA_PORT);
   let response = reqwest::Client::new()
-    .post(&hasura_url)
-    .header(
-      "x-hasura-admin-secret",
-      &*crate::HASURA_GRAPHQL_ADMIN_SECRET,
-    )
+    .post(&hasura.url)
+    .header("x-hasura-admin-secret", &hasura.admin_secret)
     .json(&json_body)
     .send()
     .await
@@ -59,60 +73,74 @@ async fn ADMIN_hasura_request<B: serde::ser::Serialize + ?Sized, T: serde::de::D
 }
 
 // See https://github.com/rust-lang/rust/issues/75798 and https://github.com/graphql-rust/graphql-client/issues/302 as
-// to why we can't have nice things. We definitely want these to happen in a single transaction which means we need to
-// write the GraphQL ourselves.
-pub async fn insert_blamelines(
-  commit: &str,
+// to why we can't have nice things. blamelines_insert_input is recursive, so we write the GraphQL ourselves.
+//
+// Having a non-empty update_columns on the nested lines insert is necessary unfortunately.
+// See https://github.com/hasura/graphql-engine/issues/1911.
+const INSERT_BLAMELINES_MUTATION: &str = r#"mutation InsertBlamelines($objects: [blamelines_insert_input!]!) {
+  insert_blamelines(objects: $objects, on_conflict: { constraint: blamelines_pkey, update_columns: [] }) {
+    affected_rows
+  }
+}"#;
+
+/// Build the request bodies that `insert_blamelines` sends, at most `chunk_size` lines apiece. The chunk with line 1 in
+/// it always comes last. See `insert_blamelines`.
+fn insert_blamelines_requests(
+  commit_hash: &str,
   file_path: &str,
-  blamelines: Vec<BlameLine>,
-) -> anyhow::Result<()> {
-  // Having a non-empty update_columns is necessary unfortunately.
-  // See https://github.com/hasura/graphql-engine/issues/1911.
-  // Also be careful! Line numbers are always 1-indexed.
+  blamelines: &[BlameLine],
+  chunk_size: usize,
+) -> Vec<serde_json::Value> {
+  // Be careful! Line numbers are always 1-indexed.
   let objects = blamelines
     .iter()
     .enumerate()
     .map(|(i, bl)| {
-      format!(
-        r#"{{
-          original_line: {{
-            data: {{ commit: "{}", file_path: "{}", line_number: {} }}
-            on_conflict: {{ constraint: lines_pkey, update_columns: [commit] }}
-          }}
-          x_commit: "{}"
-          x_file_path: "{}"
-          x_line_number: {}
-        }}"#,
-        bl.original_commit,
-        bl.original_file_path,
-        bl.original_line_number,
-        commit,
-        file_path,
-        i + 1
-      )
+      json!({
+        "original_line": {
+          "data": {
+            "commit_hash": bl.original_commit,
+            "file_path": bl.original_file_path,
+            "line_number": bl.original_line_number,
+          },
+          "on_conflict": { "constraint": "lines_pkey", "update_columns": ["commit_hash"] },
+        },
+        "x_commit_hash": commit_hash,
+        "x_file_path": file_path,
+        "x_line_number": i + 1,
+      })
     })
-    .collect::<Vec<_>>()
-    .join("\n");
-
-  let gql = format!(
-    r#"mutation {{
-      insert_blamelines(
-        objects: [
-          {}
-        ]
-        on_conflict: {{ constraint: blamelines_pkey, update_columns: [] }}
-      ) {{
-        affected_rows
-      }}
-    }}"#,
-    objects
-  );
+    .collect::<Vec<_>>();
+  objects
+    .chunks(chunk_size.max(1))
+    .rev()
+    .map(|chunk| {
+      json!({
+        "query": INSERT_BLAMELINES_MUTATION,
+        "variables": { "objects": chunk },
+      })
+    })
+    .collect()
+}
 
-  let _: serde_json::Value = ADMIN_hasura_request(&json!({
-    "query": gql,
-    "variables":{}
-  }))
-  .await?;
+/// Insert the blamelines for `file_path` as of `commit_hash`, `chunk_size` lines per request. Existing rows are left
+/// alone.
+///
+/// Each request is its own transaction, so a failure part way through can leave some of the lines behind. That's ok
+/// since `lookup_existing_blamelines` only looks for line 1, which goes in last. Until it's there, the whole file counts
+/// as not done yet and will get inserted again, skipping over what's already there.
+pub async fn insert_blamelines(
+  hasura: &HasuraStorage,
+  commit_hash: &str,
+  file_path: &str,
+  blamelines: Vec<BlameLine>,
+  chunk_size: usize,
+) -> anyhow::Result<()> {
+  for request in insert_blamelines_requests(commit_hash, file_path, &blamelines, chunk_size) {
+    let _: serde_json::Value = ADMIN_hasura_request(hasura, &request)
+      .await
+      .context("inserting blamelines into hasura")?;
+  }
   Ok(())
 }
 
@@ -125,17 +153,19 @@ pub async fn insert_blamelines(
 struct LookupExistingBlamelines;
 
 pub async fn lookup_existing_blamelines(
+  hasura: &HasuraStorage,
   commit_hash: &str,
   file_path: &str,
 ) -> anyhow::Result<bool> {
   let res: lookup_existing_blamelines::ResponseData = ADMIN_hasura_request(
+    hasura,
     &LookupExistingBlamelines::build_query(lookup_existing_blamelines::Variables {
       commit_hash: commit_hash.into(),
       file_path: file_path.into(),
     }),
   )
   .await?;
-  Ok(res.blamelines_by_pk.is_some())
+  Ok(res.blamelines_by_pk.is_some() || !res.done_blame_jobs.is_empty())
 }
 
 #[derive(graphql_client::GraphQLQuery)]
@@ -147,6 +177,7 @@ pub async fn lookup_existing_blamelines(
 struct UpsertUser;
 
 pub async fn upsert_user(
+  hasura: &HasuraStorage,
   github_node_id: &GitHubUserId,
   github_database_id: u32,
   github_name: &str,
@@ -154,22 +185,88 @@ pub async fn upsert_user(
   email: Option<String>,
   github_access_token: &str,
 ) -> anyhow::Result<()> {
-  let res: upsert_user::ResponseData =
-    ADMIN_hasura_request(&UpsertUser::build_query(upsert_user::Variables {
+  let res: upsert_user::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &UpsertUser::build_query(upsert_user::Variables {
       github_node_id: github_node_id.0 .0.to_string(),
       github_database_id: github_database_id.into(),
       github_name: github_name.to_string(),
       github_username: github_username.to_string(),
-      email: email.into(),
-      access_token: github_access_token.to_string(),
-    }))
-    .await
-    .context("upserting user info into hasura")?;
+      email,
+      access_token: hasura
+        .token_cipher
+        .seal(&github_node_id.0 .0, github_access_token)?,
+    }),
+  )
+  .await
+  .context("upserting user info into hasura")?;
 
   ensure!(res.insert_github_users_one.is_some());
   Ok(())
 }
 
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct UpsertMentionedUser;
+
+pub async fn upsert_mentioned_user(
+  hasura: &HasuraStorage,
+  user: &GitHubUser,
+) -> anyhow::Result<()> {
+  let _: upsert_mentioned_user::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &UpsertMentionedUser::build_query(upsert_mentioned_user::Variables {
+      github_node_id: user.node_id.0.clone(),
+      github_database_id: user.database_id.into(),
+      github_name: user.name.clone(),
+      github_username: user.login.clone(),
+      email: user.email.clone(),
+    }),
+  )
+  .await
+  .context("upserting mentioned user into hasura")?;
+  Ok(())
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct LookupUsersByUsername;
+
+pub async fn lookup_users_by_username(
+  hasura: &HasuraStorage,
+  usernames: &[String],
+) -> anyhow::Result<Vec<MentionRecord>> {
+  if usernames.is_empty() {
+    return Ok(vec![]);
+  }
+  let res: lookup_users_by_username::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &LookupUsersByUsername::build_query(lookup_users_by_username::Variables {
+      pattern: format!("^({})$", usernames.join("|")),
+    }),
+  )
+  .await
+  .context("looking up users by username in hasura")?;
+  Ok(
+    res
+      .github_users
+      .into_iter()
+      .map(|u| MentionRecord {
+        github_node_id: u.github_node_id,
+        github_username: u.github_username,
+      })
+      .collect(),
+  )
+}
+
 #[derive(graphql_client::GraphQLQuery)]
 #[graphql(
   schema_path = "gql/hasura/schema.json",
@@ -178,12 +275,18 @@ pub async fn upsert_user(
 )]
 struct StartUserSession;
 
-pub async fn start_user_session(github_user: &GitHubUserId) -> anyhow::Result<String> {
-  let res: start_user_session::ResponseData = ADMIN_hasura_request(&StartUserSession::build_query(
-    start_user_session::Variables {
+pub async fn start_user_session(
+  hasura: &HasuraStorage,
+  github_user: &GitHubUserId,
+  ttl: Duration,
+) -> anyhow::Result<String> {
+  let res: start_user_session::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &StartUserSession::build_query(start_user_session::Variables {
       user_github_node_id: github_user.0 .0.to_string(),
-    },
-  ))
+      expires_at: (Utc::now() + ttl).to_rfc3339(),
+    }),
+  )
   .await
   .context("inserting new session into hasura")?;
 
@@ -203,20 +306,227 @@ pub async fn start_user_session(github_user: &GitHubUserId) -> anyhow::Result<St
 struct LookupSession;
 
 // For now, all user sessions are initiated through GitHub.
-pub async fn lookup_user_session(session_token: &str) -> anyhow::Result<Option<GitHubAuth>> {
-  let res: lookup_session::ResponseData =
-    ADMIN_hasura_request(&LookupSession::build_query(lookup_session::Variables {
+pub async fn lookup_user_session(
+  hasura: &HasuraStorage,
+  session_token: &str,
+  ttl: Duration,
+) -> anyhow::Result<Option<(GitHubAuth, SessionRecord)>> {
+  if !looks_like_uuid(session_token) {
+    return Ok(None);
+  }
+  #[derive(Deserialize)]
+  struct GitHubUser {
+    github_node_id: String,
+    access_token: Option<String>,
+  }
+  #[derive(Deserialize)]
+  struct Session {
+    github_user: GitHubUser,
+    #[serde(flatten)]
+    record: SessionRecord,
+  }
+  #[derive(Deserialize)]
+  struct Returning {
+    returning: Vec<Session>,
+  }
+  #[derive(Deserialize)]
+  struct Response {
+    current: Returning,
+    rotated: Returning,
+  }
+  let res: Response = ADMIN_hasura_request(
+    hasura,
+    &LookupSession::build_query(lookup_session::Variables {
       session_token: session_token.to_owned(),
-    }))
-    .await
-    .context("looking up session in hasura")?;
+      expires_at: (Utc::now() + ttl).to_rfc3339(),
+    }),
+  )
+  .await
+  .context("looking up session in hasura")?;
 
-  Ok(res.user_sessions_by_pk.and_then(|x| {
-    x.github_user.access_token.map(|token| GitHubAuth {
-      github_node_id: GitHubUserId(GitHubNodeId(x.github_user.github_node_id)),
-      access_token: token.to_string(),
+  res
+    .current
+    .returning
+    .into_iter()
+    .chain(res.rotated.returning)
+    .next()
+    .and_then(|x| {
+      let github_node_id = x.github_user.github_node_id;
+      let record = x.record;
+      x.github_user.access_token.map(|token| {
+        let access_token = hasura.token_cipher.open(&github_node_id, &token)?;
+        Ok((
+          GitHubAuth {
+            github_node_id: GitHubUserId(GitHubNodeId(github_node_id)),
+            access_token,
+          },
+          record,
+        ))
+      })
     })
-  }))
+    .transpose()
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct AccessTokens;
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct ReencryptAccessToken;
+
+/// Encrypt every stored access token that isn't encrypted with the current key yet.
+pub async fn reencrypt_access_tokens(hasura: &HasuraStorage) -> anyhow::Result<usize> {
+  let res: access_tokens::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &AccessTokens::build_query(access_tokens::Variables {}),
+  )
+  .await
+  .context("looking up access tokens in hasura")?;
+
+  let mut updated = 0;
+  for user in res.github_users {
+    let access_token = match user.access_token {
+      Some(access_token) => access_token,
+      None => continue,
+    };
+    let resealed = match hasura
+      .token_cipher
+      .reseal(&user.github_node_id, &access_token)
+    {
+      Ok(Some(resealed)) => resealed,
+      Ok(None) => continue,
+      Err(e) => {
+        log::warn!("can't re-encrypt access token: {:?}", e);
+        continue;
+      }
+    };
+    let res: reencrypt_access_token::ResponseData = ADMIN_hasura_request(
+      hasura,
+      &ReencryptAccessToken::build_query(reencrypt_access_token::Variables {
+        github_node_id: user.github_node_id,
+        old: access_token,
+        new: resealed,
+      }),
+    )
+    .await
+    .context("updating access token in hasura")?;
+    updated += res
+      .update_github_users
+      .map_or(0, |r| r.affected_rows as usize);
+  }
+  Ok(updated)
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct CutOffSession;
+
+/// Hasura can't do this in one transaction, so there's a moment where the old session is cut off and the new one
+/// doesn't exist yet. That's fine since the old one lives on for `grace`.
+pub async fn rotate_user_session(
+  hasura: &HasuraStorage,
+  session_token: &str,
+  ttl: Duration,
+  grace: Duration,
+) -> anyhow::Result<Option<String>> {
+  if !looks_like_uuid(session_token) {
+    return Ok(None);
+  }
+  // Unlike the postgres backend this can push the expiry of a session that has less than `grace` left back a little,
+  // which is harmless.
+  let res: cut_off_session::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &CutOffSession::build_query(cut_off_session::Variables {
+      session_token: session_token.to_owned(),
+      expires_at: (Utc::now() + grace).to_rfc3339(),
+    }),
+  )
+  .await
+  .context("cutting off old session in hasura")?;
+  match res
+    .update_user_sessions
+    .and_then(|r| r.returning.into_iter().next())
+  {
+    Some(old) => {
+      let github_user = GitHubUserId(GitHubNodeId(old.user_github_node_id));
+      Ok(Some(start_user_session(hasura, &github_user, ttl).await?))
+    }
+    None => Ok(None),
+  }
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct UserSessions;
+
+/// Every unexpired session that `github_user` has, most recently used first.
+pub async fn user_sessions(
+  hasura: &HasuraStorage,
+  github_user: &GitHubUserId,
+) -> anyhow::Result<Vec<SessionRecord>> {
+  #[derive(Deserialize)]
+  struct Response {
+    user_sessions: Vec<SessionRecord>,
+  }
+  let res: Response = ADMIN_hasura_request(
+    hasura,
+    &UserSessions::build_query(user_sessions::Variables {
+      user_github_node_id: github_user.0 .0.to_string(),
+    }),
+  )
+  .await
+  .context("looking up user sessions in hasura")?;
+  Ok(res.user_sessions)
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct EndUserSessionByPublicId;
+
+pub async fn end_user_session_by_public_id(
+  hasura: &HasuraStorage,
+  github_user: &GitHubUserId,
+  public_id: &str,
+) -> anyhow::Result<bool> {
+  // Hasura would reject anything that isn't a uuid, but it can't belong to anyone either.
+  if !looks_like_uuid(public_id) {
+    return Ok(false);
+  }
+  let res: end_user_session_by_public_id::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &EndUserSessionByPublicId::build_query(end_user_session_by_public_id::Variables {
+      user_github_node_id: github_user.0 .0.to_string(),
+      public_id: public_id.to_owned(),
+    }),
+  )
+  .await
+  .context("deleting session from hasura")?;
+  Ok(
+    res
+      .delete_user_sessions
+      .is_some_and(|r| r.affected_rows > 0),
+  )
 }
 
 #[derive(graphql_client::GraphQLQuery)]
@@ -227,15 +537,69 @@ pub async fn lookup_user_session(session_token: &str) -> anyhow::Result<Option<G
 )]
 struct EndUserSession;
 
-pub async fn end_user_session(session_token: &str) -> anyhow::Result<()> {
+pub async fn end_user_session(hasura: &HasuraStorage, session_token: &str) -> anyhow::Result<()> {
+  if !looks_like_uuid(session_token) {
+    return Ok(());
+  }
   // The success of response.json() depends on the correct type being inferred
   // for the output, so we must be explicit in requesting `end_user_session::ResponseData`.
-  let _: end_user_session::ResponseData =
-    ADMIN_hasura_request(&EndUserSession::build_query(end_user_session::Variables {
+  let _: end_user_session::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &EndUserSession::build_query(end_user_session::Variables {
       session_token: session_token.to_owned(),
-    }))
-    .await
-    .context("deleting session from hasura")?;
+    }),
+  )
+  .await
+  .context("deleting session from hasura")?;
+  Ok(())
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct UpsertCommitGitHubRepo;
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct UpsertCommitRepo;
+
+/// Record that `commit_hash` lives in `repo`.
+async fn upsert_commit_repo(
+  hasura: &HasuraStorage,
+  repo: &RepoWithCommit,
+  commit_hash: &str,
+) -> anyhow::Result<()> {
+  match repo {
+    RepoWithCommit::GitHub(repo_github_node_id) => {
+      let _: upsert_commit_git_hub_repo::ResponseData = ADMIN_hasura_request(
+        hasura,
+        &UpsertCommitGitHubRepo::build_query(upsert_commit_git_hub_repo::Variables {
+          repo_github_node_id: repo_github_node_id.0.to_string(),
+          commit_hash: commit_hash.to_string(),
+        }),
+      )
+      .await
+      .context("upserting commit_github_repo into hasura")?;
+    }
+    RepoWithCommit::Other(repo_id) => {
+      let _: upsert_commit_repo::ResponseData = ADMIN_hasura_request(
+        hasura,
+        &UpsertCommitRepo::build_query(upsert_commit_repo::Variables {
+          commit_hash: commit_hash.to_string(),
+          repo_id: repo_id.to_string(),
+        }),
+      )
+      .await
+      .context("upserting commit_repo into hasura")?;
+    }
+  }
   Ok(())
 }
 
@@ -247,27 +611,37 @@ pub async fn end_user_session(session_token: &str) -> anyhow::Result<()> {
 )]
 struct UpsertLine;
 
-pub async fn upsert_line(
-  repo_github_node_id: &GitHubNodeId,
+/// Upsert every line in `range`, one at a time. graphql_client would send a null for every column that we leave out of
+/// a lines_insert_input, so inserting them all at once means writing the GraphQL ourselves like `insert_blamelines`.
+/// Threads don't span enough lines to make that worth it.
+pub async fn upsert_lines(
+  hasura: &HasuraStorage,
+  repo: &RepoWithCommit,
   commit_hash: &str,
   file_path: &str,
-  line_number: u32,
+  range: &LineRange,
 ) -> anyhow::Result<()> {
-  // TODO also upsert into the commit_github_repo table
-
-  let _: upsert_line::ResponseData =
-    ADMIN_hasura_request(&UpsertLine::build_query(upsert_line::Variables {
-      repo_github_node_id: repo_github_node_id.0.to_string(),
-      commit_hash: commit_hash.to_string(),
-      file_path: file_path.to_string(),
-      line_number: line_number.into(),
-    }))
+  for line_number in range.start_line..=range.end_line {
+    let _: upsert_line::ResponseData = ADMIN_hasura_request(
+      hasura,
+      &UpsertLine::build_query(upsert_line::Variables {
+        commit_hash: commit_hash.to_string(),
+        file_path: file_path.to_string(),
+        line_number: line_number.into(),
+      }),
+    )
     .await
     .context("upserting line into hasura")?;
+  }
+  upsert_commit_repo(hasura, repo, commit_hash).await?;
 
   Ok(())
 }
 
+// The queries below that return whole threads, comments, sessions or blame jobs select them with the fragments in
+// queries.graphql. Those match our storage records field for field, so we deserialize straight into the records instead
+// of converting from graphql_client's copies of them.
+
 #[derive(graphql_client::GraphQLQuery)]
 #[graphql(
   schema_path = "gql/hasura/schema.json",
@@ -278,27 +652,33 @@ struct StartThread;
 
 /// Returns the created thread's ID.
 pub async fn start_thread(
+  hasura: &HasuraStorage,
   author_github_node_id: &GitHubUserId,
-  repo_github_node_id: &GitHubNodeId,
+  repo: &RepoWithCommit,
   commit_hash: &str,
   file_path: &str,
-  line_number: u32,
+  range: &LineRange,
   body: &str,
-) -> anyhow::Result<String> {
-  upsert_line(repo_github_node_id, &commit_hash, &file_path, line_number).await?;
+) -> anyhow::Result<(String, String)> {
+  upsert_lines(hasura, repo, commit_hash, file_path, range).await?;
 
-  let res: start_thread::ResponseData =
-    ADMIN_hasura_request(&StartThread::build_query(start_thread::Variables {
+  let res: start_thread::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &StartThread::build_query(start_thread::Variables {
       author_github_node_id: author_github_node_id.0 .0.to_string(),
       commit_hash: commit_hash.to_string(),
       file_path: file_path.to_string(),
-      line_number: line_number.into(),
+      line_number: range.start_line.into(),
+      end_line_number: range.end_line.into(),
+      start_column: range.start_column.map(Into::into),
+      end_column: range.end_column.map(Into::into),
       body: body.to_string(),
-    }))
-    .await
-    .context("inserting thread into hasura")?;
+    }),
+  )
+  .await
+  .context("inserting thread into hasura")?;
 
-  let thread = res
+  let mut thread = res
     .insert_threads_one
     .ok_or_else(|| anyhow!("insert_threads_one didn't return a thread"))?;
   ensure!(
@@ -306,5 +686,956 @@ pub async fn start_thread(
     "new thread should have exactly one comment"
   );
 
-  Ok(thread.id)
+  Ok((thread.id, thread.comments.remove(0).id))
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct LookupThread;
+
+pub async fn lookup_thread(
+  hasura: &HasuraStorage,
+  thread_id: &str,
+) -> anyhow::Result<Option<ThreadAnchor>> {
+  let res: lookup_thread::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &LookupThread::build_query(lookup_thread::Variables {
+      thread_id: thread_id.to_owned(),
+    }),
+  )
+  .await
+  .context("looking up thread in hasura")?;
+  Ok(res.threads_by_pk.map(|t| ThreadAnchor {
+    original_commit_hash: t.original_commit_hash,
+    original_file_path: t.original_file_path,
+    original_line_number: t.original_line_number,
+    original_end_line_number: t.original_end_line_number,
+    original_start_column: t.original_start_column,
+    original_end_column: t.original_end_column,
+  }))
+}
+
+#[derive(Deserialize)]
+struct ThreadsResponseData {
+  threads: Vec<ThreadWithComments>,
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct ThreadsForOriginalLines;
+
+/// Every thread anchored to one of `commit_hashes` and one of `file_paths`. This is a superset of what you probably
+/// want, so filter the results.
+pub async fn threads_for_original_lines(
+  hasura: &HasuraStorage,
+  commit_hashes: Vec<String>,
+  file_paths: Vec<String>,
+) -> anyhow::Result<Vec<ThreadWithComments>> {
+  let res: ThreadsResponseData = ADMIN_hasura_request(
+    hasura,
+    &ThreadsForOriginalLines::build_query(threads_for_original_lines::Variables {
+      commit_hashes,
+      file_paths,
+    }),
+  )
+  .await
+  .context("looking up threads in hasura")?;
+  Ok(res.threads)
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct ThreadsForFilePathsInGitHubRepo;
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct ThreadsForFilePathsInRepo;
+
+/// Every thread ever started on one of `file_paths` in `repo`, at any commit.
+pub async fn threads_for_file_paths(
+  hasura: &HasuraStorage,
+  repo: &RepoWithCommit,
+  file_paths: Vec<String>,
+) -> anyhow::Result<Vec<ThreadWithComments>> {
+  let res: ThreadsResponseData = match repo {
+    RepoWithCommit::GitHub(repo_github_node_id) => {
+      ADMIN_hasura_request(
+        hasura,
+        &ThreadsForFilePathsInGitHubRepo::build_query(
+          threads_for_file_paths_in_git_hub_repo::Variables {
+            file_paths,
+            repo_github_node_id: repo_github_node_id.0.to_string(),
+          },
+        ),
+      )
+      .await
+    }
+    RepoWithCommit::Other(repo_id) => {
+      ADMIN_hasura_request(
+        hasura,
+        &ThreadsForFilePathsInRepo::build_query(threads_for_file_paths_in_repo::Variables {
+          file_paths,
+          repo_id: repo_id.to_string(),
+        }),
+      )
+      .await
+    }
+  }
+  .context("looking up threads in hasura")?;
+  Ok(res.threads)
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct ResolveThread;
+
+pub async fn resolve_thread(
+  hasura: &HasuraStorage,
+  thread_id: &str,
+  resolver: &GitHubUserId,
+) -> anyhow::Result<bool> {
+  let res: resolve_thread::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &ResolveThread::build_query(resolve_thread::Variables {
+      id: thread_id.to_owned(),
+      resolver: resolver.0 .0.to_string(),
+    }),
+  )
+  .await
+  .context("resolving thread in hasura")?;
+  Ok(res.update_threads.is_some_and(|r| r.affected_rows > 0))
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct ReopenThread;
+
+pub async fn reopen_thread(hasura: &HasuraStorage, thread_id: &str) -> anyhow::Result<bool> {
+  let res: reopen_thread::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &ReopenThread::build_query(reopen_thread::Variables {
+      id: thread_id.to_owned(),
+    }),
+  )
+  .await
+  .context("reopening thread in hasura")?;
+  Ok(res.update_threads.is_some_and(|r| r.affected_rows > 0))
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct AddComment;
+
+pub async fn add_comment(
+  hasura: &HasuraStorage,
+  author_github_node_id: &GitHubUserId,
+  thread_id: &str,
+  body: &str,
+) -> anyhow::Result<CommentRecord> {
+  #[derive(Deserialize)]
+  struct Response {
+    insert_comments_one: CommentRecord,
+  }
+  let res: Response = ADMIN_hasura_request(
+    hasura,
+    &AddComment::build_query(add_comment::Variables {
+      thread_id: thread_id.to_owned(),
+      author_github_node_id: author_github_node_id.0 .0.to_string(),
+      body: body.to_owned(),
+    }),
+  )
+  .await
+  .context("inserting comment into hasura")?;
+  Ok(res.insert_comments_one)
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct LookupComment;
+
+pub async fn lookup_comment(
+  hasura: &HasuraStorage,
+  comment_id: &str,
+) -> anyhow::Result<Option<(String, CommentRecord)>> {
+  #[derive(Deserialize)]
+  struct Comment {
+    thread_id: String,
+    #[serde(flatten)]
+    record: CommentRecord,
+  }
+  #[derive(Deserialize)]
+  struct Response {
+    comments_by_pk: Option<Comment>,
+  }
+  let res: Response = ADMIN_hasura_request(
+    hasura,
+    &LookupComment::build_query(lookup_comment::Variables {
+      id: comment_id.to_owned(),
+    }),
+  )
+  .await
+  .context("looking up comment in hasura")?;
+  Ok(res.comments_by_pk.map(|c| (c.thread_id, c.record)))
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct EditComment;
+
+pub async fn edit_comment(
+  hasura: &HasuraStorage,
+  comment_id: &str,
+  body: &str,
+) -> anyhow::Result<Option<CommentRecord>> {
+  #[derive(Deserialize)]
+  struct Returning {
+    returning: Vec<CommentRecord>,
+  }
+  #[derive(Deserialize)]
+  struct Response {
+    update_comments: Returning,
+  }
+  let res: Response = ADMIN_hasura_request(
+    hasura,
+    &EditComment::build_query(edit_comment::Variables {
+      id: comment_id.to_owned(),
+      body: body.to_owned(),
+    }),
+  )
+  .await
+  .context("editing comment in hasura")?;
+  Ok(res.update_comments.returning.into_iter().next())
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct DeleteComment;
+
+pub async fn delete_comment(hasura: &HasuraStorage, comment_id: &str) -> anyhow::Result<bool> {
+  let res: delete_comment::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &DeleteComment::build_query(delete_comment::Variables {
+      id: comment_id.to_owned(),
+    }),
+  )
+  .await
+  .context("deleting comment in hasura")?;
+  Ok(res.update_comments.is_some_and(|r| r.affected_rows > 0))
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct AddReaction;
+
+pub async fn add_reaction(
+  hasura: &HasuraStorage,
+  comment_id: &str,
+  github_user: &GitHubUserId,
+  reaction: &str,
+) -> anyhow::Result<()> {
+  let _: add_reaction::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &AddReaction::build_query(add_reaction::Variables {
+      comment_id: comment_id.to_owned(),
+      github_node_id: github_user.0 .0.to_string(),
+      reaction: reaction.to_owned(),
+    }),
+  )
+  .await
+  .context("inserting reaction into hasura")?;
+  Ok(())
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct RemoveReaction;
+
+pub async fn remove_reaction(
+  hasura: &HasuraStorage,
+  comment_id: &str,
+  github_user: &GitHubUserId,
+  reaction: &str,
+) -> anyhow::Result<()> {
+  let _: remove_reaction::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &RemoveReaction::build_query(remove_reaction::Variables {
+      comment_id: comment_id.to_owned(),
+      github_node_id: github_user.0 .0.to_string(),
+      reaction: reaction.to_owned(),
+    }),
+  )
+  .await
+  .context("deleting reaction from hasura")?;
+  Ok(())
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct AddMention;
+
+/// One request per mention, for the same reason as `upsert_lines`. There are at most
+/// `mentions::MAX_MENTIONS_PER_COMMENT` of them.
+pub async fn add_mentions(
+  hasura: &HasuraStorage,
+  comment_id: &str,
+  mentions: &[MentionRecord],
+) -> anyhow::Result<()> {
+  for mention in mentions {
+    let _: add_mention::ResponseData = ADMIN_hasura_request(
+      hasura,
+      &AddMention::build_query(add_mention::Variables {
+        comment_id: comment_id.to_owned(),
+        github_node_id: mention.github_node_id.clone(),
+        github_username: mention.github_username.clone(),
+      }),
+    )
+    .await
+    .context("inserting mention into hasura")?;
+  }
+  Ok(())
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct CommentEdits;
+
+/// Every previous version of a comment, oldest first.
+pub async fn comment_edits(
+  hasura: &HasuraStorage,
+  comment_id: &str,
+) -> anyhow::Result<Vec<CommentEditRecord>> {
+  let res: comment_edits::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &CommentEdits::build_query(comment_edits::Variables {
+      comment_id: comment_id.to_owned(),
+    }),
+  )
+  .await
+  .context("looking up comment edits in hasura")?;
+  Ok(
+    res
+      .comment_edits
+      .into_iter()
+      .map(|e| CommentEditRecord {
+        body: e.body,
+        replaced_at: e.replaced_at,
+      })
+      .collect(),
+  )
+}
+
+#[derive(Deserialize)]
+struct BlameJobsResponse {
+  blame_jobs: Vec<BlameJobRecord>,
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct InsertBlameJob;
+
+/// Insert a new queued blame job and return it.
+pub async fn insert_blame_job(
+  hasura: &HasuraStorage,
+  repo_id: &str,
+  commit_hash: &str,
+  file_path: &str,
+) -> anyhow::Result<BlameJobRecord> {
+  #[derive(Deserialize)]
+  struct Response {
+    insert_blame_jobs_one: BlameJobRecord,
+  }
+  let res: Response = ADMIN_hasura_request(
+    hasura,
+    &InsertBlameJob::build_query(insert_blame_job::Variables {
+      repo_id: repo_id.to_owned(),
+      commit_hash: commit_hash.to_owned(),
+      file_path: file_path.to_owned(),
+    }),
+  )
+  .await
+  .context("inserting blame job into hasura")?;
+  Ok(res.insert_blame_jobs_one)
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct LookupBlameJob;
+
+pub async fn lookup_blame_job(
+  hasura: &HasuraStorage,
+  job_id: &str,
+) -> anyhow::Result<Option<BlameJobRecord>> {
+  #[derive(Deserialize)]
+  struct Response {
+    blame_jobs_by_pk: Option<BlameJobRecord>,
+  }
+  let res: Response = ADMIN_hasura_request(
+    hasura,
+    &LookupBlameJob::build_query(lookup_blame_job::Variables {
+      id: job_id.to_owned(),
+    }),
+  )
+  .await
+  .context("looking up blame job in hasura")?;
+  Ok(res.blame_jobs_by_pk)
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct LookupUnfinishedBlameJob;
+
+/// A queued or running job for (repo_id, commit_hash, file_path), if there is one.
+pub async fn lookup_unfinished_blame_job(
+  hasura: &HasuraStorage,
+  repo_id: &str,
+  commit_hash: &str,
+  file_path: &str,
+) -> anyhow::Result<Option<BlameJobRecord>> {
+  let res: BlameJobsResponse = ADMIN_hasura_request(
+    hasura,
+    &LookupUnfinishedBlameJob::build_query(lookup_unfinished_blame_job::Variables {
+      repo_id: repo_id.to_owned(),
+      commit_hash: commit_hash.to_owned(),
+      file_path: file_path.to_owned(),
+    }),
+  )
+  .await
+  .context("looking up unfinished blame job in hasura")?;
+  Ok(res.blame_jobs.into_iter().next())
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct UnfinishedBlameJobs;
+
+/// Every job that hasn't finished yet, oldest first.
+pub async fn unfinished_blame_jobs(hasura: &HasuraStorage) -> anyhow::Result<Vec<BlameJobRecord>> {
+  let res: BlameJobsResponse = ADMIN_hasura_request(
+    hasura,
+    &UnfinishedBlameJobs::build_query(unfinished_blame_jobs::Variables {}),
+  )
+  .await
+  .context("looking up unfinished blame jobs in hasura")?;
+  Ok(res.blame_jobs)
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct UpdateBlameJobStatus;
+
+pub async fn update_blame_job_status(
+  hasura: &HasuraStorage,
+  job_id: &str,
+  status: &str,
+  error: Option<&str>,
+) -> anyhow::Result<()> {
+  let _: update_blame_job_status::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &UpdateBlameJobStatus::build_query(update_blame_job_status::Variables {
+      id: job_id.to_owned(),
+      status: status.to_owned(),
+      error: error.map(str::to_owned),
+    }),
+  )
+  .await
+  .context("updating blame job status in hasura")?;
+  Ok(())
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct ActiveGitHubRepos;
+
+/// Every GitHub repo that has had a thread started on one of its commits.
+pub async fn active_github_repos(hasura: &HasuraStorage) -> anyhow::Result<Vec<GitHubNodeId>> {
+  let res: active_git_hub_repos::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &ActiveGitHubRepos::build_query(active_git_hub_repos::Variables {}),
+  )
+  .await
+  .context("looking up active github repos in hasura")?;
+  Ok(
+    res
+      .commit_github_repo
+      .into_iter()
+      .map(|r| GitHubNodeId(r.repo_github_node_id))
+      .collect(),
+  )
+}
+
+#[derive(graphql_client::GraphQLQuery)]
+#[graphql(
+  schema_path = "gql/hasura/schema.json",
+  query_path = "gql/hasura/queries.graphql",
+  response_derives = "Debug"
+)]
+struct ActiveOtherRepos;
+
+/// The same as `active_github_repos`, but for every other kind of repo. These are `RepoId` strings.
+pub async fn active_other_repos(hasura: &HasuraStorage) -> anyhow::Result<Vec<String>> {
+  let res: active_other_repos::ResponseData = ADMIN_hasura_request(
+    hasura,
+    &ActiveOtherRepos::build_query(active_other_repos::Variables {}),
+  )
+  .await
+  .context("looking up active repos in hasura")?;
+  Ok(res.commit_repo.into_iter().map(|r| r.repo_id).collect())
+}
+
+/// `Storage` on top of the functions above.
+pub struct HasuraStorage {
+  /// Hasura's GraphQL endpoint.
+  url: String,
+  admin_secret: String,
+  /// How many blamelines go into each insert. See `insert_blamelines`.
+  blamelines_chunk_size: usize,
+  token_cipher: TokenCipher,
+}
+
+impl HasuraStorage {
+  pub fn new(config: &Config) -> Self {
+    HasuraStorage {
+      url: config.hasura_url(),
+      admin_secret: config.hasura_graphql_admin_secret.clone(),
+      blamelines_chunk_size: config.blamelines_chunk_size,
+      token_cipher: TokenCipher::new(config.access_token_keys.clone()),
+    }
+  }
+}
+
+#[async_trait]
+impl Storage for HasuraStorage {
+  async fn lookup_existing_blamelines(
+    &self,
+    commit_hash: &str,
+    file_path: &str,
+  ) -> anyhow::Result<bool> {
+    lookup_existing_blamelines(self, commit_hash, file_path).await
+  }
+
+  async fn insert_blamelines(
+    &self,
+    commit_hash: &str,
+    file_path: &str,
+    blamelines: Vec<BlameLine>,
+  ) -> anyhow::Result<()> {
+    insert_blamelines(
+      self,
+      commit_hash,
+      file_path,
+      blamelines,
+      self.blamelines_chunk_size,
+    )
+    .await
+  }
+
+  async fn upsert_user(
+    &self,
+    github_node_id: &GitHubUserId,
+    github_database_id: u32,
+    github_name: &str,
+    github_username: &str,
+    email: Option<String>,
+    github_access_token: &str,
+  ) -> anyhow::Result<()> {
+    upsert_user(
+      self,
+      github_node_id,
+      github_database_id,
+      github_name,
+      github_username,
+      email,
+      github_access_token,
+    )
+    .await
+  }
+
+  async fn upsert_mentioned_user(&self, user: &GitHubUser) -> anyhow::Result<()> {
+    upsert_mentioned_user(self, user).await
+  }
+
+  async fn lookup_users_by_username(
+    &self,
+    usernames: &[String],
+  ) -> anyhow::Result<Vec<MentionRecord>> {
+    lookup_users_by_username(self, usernames).await
+  }
+
+  async fn reencrypt_access_tokens(&self) -> anyhow::Result<usize> {
+    reencrypt_access_tokens(self).await
+  }
+
+  async fn start_user_session(
+    &self,
+    github_user: &GitHubUserId,
+    ttl: Duration,
+  ) -> anyhow::Result<String> {
+    start_user_session(self, github_user, ttl).await
+  }
+
+  async fn lookup_user_session(
+    &self,
+    session_token: &str,
+    ttl: Duration,
+  ) -> anyhow::Result<Option<(GitHubAuth, SessionRecord)>> {
+    lookup_user_session(self, session_token, ttl).await
+  }
+
+  async fn end_user_session(&self, session_token: &str) -> anyhow::Result<()> {
+    end_user_session(self, session_token).await
+  }
+
+  async fn rotate_user_session(
+    &self,
+    session_token: &str,
+    ttl: Duration,
+    grace: Duration,
+  ) -> anyhow::Result<Option<String>> {
+    rotate_user_session(self, session_token, ttl, grace).await
+  }
+
+  async fn user_sessions(&self, github_user: &GitHubUserId) -> anyhow::Result<Vec<SessionRecord>> {
+    user_sessions(self, github_user).await
+  }
+
+  async fn end_user_session_by_public_id(
+    &self,
+    github_user: &GitHubUserId,
+    public_id: &str,
+  ) -> anyhow::Result<bool> {
+    end_user_session_by_public_id(self, github_user, public_id).await
+  }
+
+  async fn start_thread(
+    &self,
+    author_github_node_id: &GitHubUserId,
+    repo: &RepoWithCommit,
+    commit_hash: &str,
+    file_path: &str,
+    range: &LineRange,
+    body: &str,
+  ) -> anyhow::Result<(String, String)> {
+    start_thread(
+      self,
+      author_github_node_id,
+      repo,
+      commit_hash,
+      file_path,
+      range,
+      body,
+    )
+    .await
+  }
+
+  async fn lookup_thread(&self, thread_id: &str) -> anyhow::Result<Option<ThreadAnchor>> {
+    lookup_thread(self, thread_id).await
+  }
+
+  async fn resolve_thread(&self, thread_id: &str, resolver: &GitHubUserId) -> anyhow::Result<bool> {
+    resolve_thread(self, thread_id, resolver).await
+  }
+
+  async fn reopen_thread(&self, thread_id: &str) -> anyhow::Result<bool> {
+    reopen_thread(self, thread_id).await
+  }
+
+  async fn add_comment(
+    &self,
+    author_github_node_id: &GitHubUserId,
+    thread_id: &str,
+    body: &str,
+  ) -> anyhow::Result<CommentRecord> {
+    add_comment(self, author_github_node_id, thread_id, body).await
+  }
+
+  async fn lookup_comment(
+    &self,
+    comment_id: &str,
+  ) -> anyhow::Result<Option<(String, CommentRecord)>> {
+    lookup_comment(self, comment_id).await
+  }
+
+  async fn edit_comment(
+    &self,
+    comment_id: &str,
+    body: &str,
+  ) -> anyhow::Result<Option<CommentRecord>> {
+    edit_comment(self, comment_id, body).await
+  }
+
+  async fn delete_comment(&self, comment_id: &str) -> anyhow::Result<bool> {
+    delete_comment(self, comment_id).await
+  }
+
+  async fn comment_edits(&self, comment_id: &str) -> anyhow::Result<Vec<CommentEditRecord>> {
+    comment_edits(self, comment_id).await
+  }
+
+  async fn add_reaction(
+    &self,
+    comment_id: &str,
+    github_user: &GitHubUserId,
+    reaction: &str,
+  ) -> anyhow::Result<()> {
+    add_reaction(self, comment_id, github_user, reaction).await
+  }
+
+  async fn remove_reaction(
+    &self,
+    comment_id: &str,
+    github_user: &GitHubUserId,
+    reaction: &str,
+  ) -> anyhow::Result<()> {
+    remove_reaction(self, comment_id, github_user, reaction).await
+  }
+
+  async fn add_mentions(&self, comment_id: &str, mentions: &[MentionRecord]) -> anyhow::Result<()> {
+    add_mentions(self, comment_id, mentions).await
+  }
+
+  async fn threads_for_original_lines(
+    &self,
+    commit_hashes: Vec<String>,
+    file_paths: Vec<String>,
+  ) -> anyhow::Result<Vec<ThreadWithComments>> {
+    threads_for_original_lines(self, commit_hashes, file_paths).await
+  }
+
+  async fn threads_for_file_paths(
+    &self,
+    repo: &RepoWithCommit,
+    file_paths: Vec<String>,
+  ) -> anyhow::Result<Vec<ThreadWithComments>> {
+    threads_for_file_paths(self, repo, file_paths).await
+  }
+
+  async fn insert_blame_job(
+    &self,
+    repo_id: &str,
+    commit_hash: &str,
+    file_path: &str,
+  ) -> anyhow::Result<BlameJobRecord> {
+    insert_blame_job(self, repo_id, commit_hash, file_path).await
+  }
+
+  async fn lookup_blame_job(&self, job_id: &str) -> anyhow::Result<Option<BlameJobRecord>> {
+    lookup_blame_job(self, job_id).await
+  }
+
+  async fn lookup_unfinished_blame_job(
+    &self,
+    repo_id: &str,
+    commit_hash: &str,
+    file_path: &str,
+  ) -> anyhow::Result<Option<BlameJobRecord>> {
+    lookup_unfinished_blame_job(self, repo_id, commit_hash, file_path).await
+  }
+
+  async fn unfinished_blame_jobs(&self) -> anyhow::Result<Vec<BlameJobRecord>> {
+    unfinished_blame_jobs(self).await
+  }
+
+  async fn update_blame_job_status(
+    &self,
+    job_id: &str,
+    status: &str,
+    error: Option<&str>,
+  ) -> anyhow::Result<()> {
+    update_blame_job_status(self, job_id, status, error).await
+  }
+
+  async fn active_github_repos(&self) -> anyhow::Result<Vec<GitHubNodeId>> {
+    active_github_repos(self).await
+  }
+
+  async fn active_other_repos(&self) -> anyhow::Result<Vec<String>> {
+    active_other_repos(self).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::service::make_service_fn;
+  use hyper::service::service_fn;
+  use hyper::Body;
+  use hyper::Request;
+  use hyper::Response;
+  use std::sync::Arc;
+  use std::sync::Mutex;
+
+  /// A stand-in for Hasura that records the body of every request, and says that every insert went fine.
+  async fn serve_fake_hasura(requests: Arc<Mutex<Vec<(String, serde_json::Value)>>>) -> String {
+    let make_service = make_service_fn(move |_| {
+      let requests = requests.clone();
+      async move {
+        Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+          let requests = requests.clone();
+          async move {
+            let secret = req.headers()["x-hasura-admin-secret"]
+              .to_str()
+              .unwrap()
+              .to_string();
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            requests
+              .lock()
+              .unwrap()
+              .push((secret, serde_json::from_slice(&body).unwrap()));
+            Ok::<_, hyper::Error>(Response::new(Body::from(
+              json!({ "data": { "insert_blamelines": { "affected_rows": 0 } } }).to_string(),
+            )))
+          }
+        }))
+      }
+    });
+    let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let url = format!("http://{}/v1/graphql", server.local_addr());
+    tokio::spawn(server);
+    url
+  }
+
+  #[tokio::test]
+  async fn insert_blamelines_sends_lines_as_variables() {
+    let requests = Arc::new(Mutex::new(vec![]));
+    let hasura = HasuraStorage {
+      url: serve_fake_hasura(requests.clone()).await,
+      blamelines_chunk_size: 4,
+      ..HasuraStorage::new(&crate::fakes::config("http://github.invalid"))
+    };
+    let weird_paths = [
+      r#"src/"quoted".rs"#,
+      r"src\back\slashes.rs",
+      "src/$dollars/${objects}.rs",
+      "src/ünïcødé/日本語.rs",
+      "src/new\nline\t.rs",
+    ];
+    let commit_hash = "0123456789abcdef0123456789abcdef01234567";
+    let file_path = r#"a "weird" \ $path/😀.rs"#;
+    let blamelines = (0..10)
+      .map(|i| BlameLine {
+        original_commit: format!("{:040}", i),
+        original_file_path: weird_paths[i % weird_paths.len()].to_string(),
+        original_line_number: i as i32 + 1,
+      })
+      .collect::<Vec<_>>();
+    let objects = blamelines
+      .iter()
+      .enumerate()
+      .map(|(i, bl)| {
+        json!({
+          "original_line": {
+            "data": {
+              "commit_hash": bl.original_commit,
+              "file_path": bl.original_file_path,
+              "line_number": bl.original_line_number,
+            },
+            "on_conflict": { "constraint": "lines_pkey", "update_columns": ["commit_hash"] },
+          },
+          "x_commit_hash": commit_hash,
+          "x_file_path": file_path,
+          "x_line_number": i + 1,
+        })
+      })
+      .collect::<Vec<_>>();
+
+    Storage::insert_blamelines(&hasura, commit_hash, file_path, blamelines)
+      .await
+      .unwrap();
+
+    // Chunks of `blamelines_chunk_size`, with line 1 last since it's the marker for "done". The query itself is always
+    // the same, and everything from the file only ever shows up in the variables.
+    let requests = requests.lock().unwrap();
+    let chunks = [9..=10, 5..=8, 1..=4];
+    assert_eq!(requests.len(), chunks.len());
+    for ((secret, body), lines) in requests.iter().zip(chunks) {
+      assert_eq!(secret, &hasura.admin_secret);
+      assert_eq!(
+        body,
+        &json!({
+          "query": INSERT_BLAMELINES_MUTATION,
+          "variables": { "objects": objects[lines.start() - 1..*lines.end()] },
+        })
+      );
+    }
+  }
 }
@@ -0,0 +1,663 @@
+// End-to-end tests for the HTTP routes. Each test serves the api on an ephemeral port, backed by an `InMemoryStorage`
+// and a `GitHubApi` that talks to `fakes::serve_github`, and pokes at it with reqwest. Nothing leaves the machine.
+use crate::fakes;
+use crate::fakes::FakeGitHub;
+use crate::fakes::InMemoryStorage;
+use crate::github::GitHubApi;
+use crate::github::GitHubNodeId;
+use crate::storage::Storage;
+use crate::GitHubUserId;
+use chrono::Duration;
+use chrono::Utc;
+use cookie::Cookie;
+use hyper::header;
+use hyper::StatusCode;
+use serde_json::json;
+use std::sync::Arc;
+
+const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+struct TestServer {
+  url: String,
+  storage: Arc<InMemoryStorage>,
+  github: Arc<FakeGitHub>,
+  /// Doesn't follow redirects, so that we can check where they go.
+  client: reqwest::Client,
+}
+
+impl TestServer {
+  async fn start() -> Self {
+    let fake = Arc::new(FakeGitHub::default());
+    fake.add_repo("R_public", "owner", "public", false, &[COMMIT]);
+    fake.add_user("the-code", "the-token", "U_1", "someone");
+    let config = Arc::new(fakes::config(&fakes::serve_github(fake.clone()).await));
+    let storage = Arc::new(InMemoryStorage::default());
+    let github = Arc::new(GitHubApi::new(&config));
+    let (addr, server) = crate::serve(config, storage.clone(), github);
+    tokio::spawn(server);
+    TestServer {
+      url: format!("http://{}", addr),
+      storage,
+      github: fake,
+      client: reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap(),
+    }
+  }
+
+  fn get(&self, path: &str) -> reqwest::RequestBuilder {
+    self.client.get(format!("{}{}", self.url, path))
+  }
+
+  fn graphql(&self, query: &str) -> reqwest::RequestBuilder {
+    self
+      .client
+      .post(format!("{}/graphql", self.url))
+      .json(&json!({ "query": query }))
+  }
+
+  /// Log in U_1 without going through GitHub, and return the session token.
+  async fn start_session(&self) -> String {
+    let user = GitHubUserId(GitHubNodeId("U_1".to_string()));
+    self
+      .storage
+      .upsert_user(&user, 1, "Someone", "someone", None, "the-token")
+      .await
+      .unwrap();
+    self
+      .storage
+      .start_user_session(&user, Duration::days(30))
+      .await
+      .unwrap()
+  }
+
+  /// Make the session for `token` look like it was started `age` ago and expires `expires_in` from now.
+  fn backdate_session(&self, token: &str, age: Duration, expires_in: Duration) {
+    let mut state = self.storage.state.lock().unwrap();
+    let record = &mut state.sessions.get_mut(token).unwrap().record;
+    record.created_at = Utc::now() - age;
+    record.expires_at = Utc::now() + expires_in;
+  }
+
+  fn set_sessions_down(&self) {
+    self.storage.state.lock().unwrap().sessions_down = true;
+  }
+}
+
+fn location(resp: &reqwest::Response) -> &str {
+  resp.headers()[header::LOCATION].to_str().unwrap()
+}
+
+/// name -> value for each Set-Cookie header.
+fn set_cookies(resp: &reqwest::Response) -> Vec<(String, String)> {
+  resp
+    .headers()
+    .get_all(header::SET_COOKIE)
+    .iter()
+    .map(|v| {
+      let cookie = Cookie::parse(v.to_str().unwrap()).unwrap();
+      (cookie.name().to_string(), cookie.value().to_string())
+    })
+    .collect()
+}
+
+#[tokio::test]
+async fn healthz_and_unknown_routes() {
+  let server = TestServer::start().await;
+  let resp = server.get("/healthz").send().await.unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  let resp = server.get("/nope").send().await.unwrap();
+  assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+  let resp = server
+    .client
+    .post(format!("{}/healthz", server.url))
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn graphql() {
+  let server = TestServer::start().await;
+  let start_thread = format!(
+    r#"mutation {{
+      StartThread(repoIds: ["github-owner!public"], commitHash: "{}", filePath: "src/lib.rs", lineNumber: 3, body: "hello")
+    }}"#,
+    COMMIT
+  );
+
+  // Anonymous users can query, but can't start threads.
+  let resp = server.graphql("{ noop }").send().await.unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  assert_eq!(
+    resp.json::<serde_json::Value>().await.unwrap(),
+    json!({ "data": { "noop": true } })
+  );
+  let resp = server.graphql(&start_thread).send().await.unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  assert!(resp.json::<serde_json::Value>().await.unwrap()["errors"].is_array());
+  assert!(server.storage.state.lock().unwrap().threads.is_empty());
+
+  // Bad auth headers are rejected outright rather than treated as anonymous.
+  for value in ["Bearer no-such-session", "Basic dXNlcjpwYXNz", "Bearer"] {
+    let resp = server
+      .graphql("{ noop }")
+      .header(header::AUTHORIZATION, value)
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{}", value);
+  }
+
+  let session_token = server.start_session().await;
+  let resp = server
+    .graphql(&start_thread)
+    .bearer_auth(&session_token)
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  let body = resp.json::<serde_json::Value>().await.unwrap();
+  assert!(body["errors"].is_null(), "{}", body);
+  {
+    let state = server.storage.state.lock().unwrap();
+    assert_eq!(state.threads.len(), 1);
+    assert_eq!(body["data"]["StartThread"], json!(state.threads[0].id));
+  }
+
+  // Not being able to check the session is no excuse for letting the request through.
+  server.set_sessions_down();
+  let resp = server
+    .graphql("{ noop }")
+    .bearer_auth(&session_token)
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn login() {
+  let server = TestServer::start().await;
+
+  let resp = server.get("/login").send().await.unwrap();
+  assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+  let authorize_url = url::Url::parse(location(&resp)).unwrap();
+  assert_eq!(authorize_url.path(), "/login/oauth/authorize");
+  let query = |name: &str| {
+    authorize_url
+      .query_pairs()
+      .find(|(k, _)| k == name)
+      .map(|(_, v)| v.to_string())
+  };
+  assert_eq!(query("client_id").as_deref(), Some("client-id"));
+  let state = query("state").unwrap();
+
+  // Missing params, a state that we didn't sign, and a code that GitHub doesn't know about all fail.
+  for path in [
+    "/oauth/callback/github".to_string(),
+    format!("/oauth/callback/github?state={}", state),
+    "/oauth/callback/github?code=the-code".to_string(),
+    "/oauth/callback/github?code=the-code&state=forged".to_string(),
+    format!("/oauth/callback/github?code=bad-code&state={}", state),
+  ] {
+    let resp = server.get(&path).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", path);
+    assert!(set_cookies(&resp).is_empty());
+  }
+  assert!(server.storage.state.lock().unwrap().sessions.is_empty());
+
+  let resp = server
+    .get(&format!(
+      "/oauth/callback/github?code=the-code&state={}",
+      state
+    ))
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+  assert_eq!(location(&resp), "http://localhost:3000/");
+  let cookies = set_cookies(&resp);
+  let session_token = &cookies
+    .iter()
+    .find(|(name, _)| name == "cf_session_token")
+    .unwrap()
+    .1;
+  let state = server.storage.state.lock().unwrap();
+  assert_eq!(state.sessions[session_token].github_node_id, "U_1");
+  assert_eq!(state.users["U_1"], "the-token");
+}
+
+#[tokio::test]
+async fn login_return_to() {
+  let server = TestServer::start().await;
+  let login = |return_to: &str| {
+    server
+      .get("/login")
+      .query(&[("return_to", return_to)])
+      .send()
+  };
+
+  // Only to places on the allowlist.
+  for return_to in ["https://evil.com/", "http://localhost.evil.com/", "nope"] {
+    let resp = login(return_to).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", return_to);
+  }
+
+  // The state carries return_to through GitHub and back.
+  let resp = login("http://localhost:5173/some/page?x=1").await.unwrap();
+  assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+  let authorize_url = url::Url::parse(location(&resp)).unwrap();
+  let state = authorize_url
+    .query_pairs()
+    .find(|(k, _)| k == "state")
+    .unwrap()
+    .1
+    .to_string();
+  assert!(!state.contains("5173"));
+  let resp = server
+    .get("/oauth/callback/github")
+    .query(&[("code", "the-code"), ("state", &state)])
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+  assert_eq!(location(&resp), "http://localhost:5173/some/page?x=1");
+
+  // Logging out works the same way.
+  let resp = server
+    .get("/logout")
+    .query(&[("return_to", "http://localhost:5173/")])
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+  assert_eq!(location(&resp), "http://localhost:5173/");
+  let resp = server
+    .get("/logout")
+    .query(&[("return_to", "https://evil.com/")])
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn logout() {
+  let server = TestServer::start().await;
+  let session_token = server.start_session().await;
+
+  let resp = server
+    .get("/logout")
+    .header(
+      header::COOKIE,
+      format!("cf_session_token={}", session_token),
+    )
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+  assert_eq!(location(&resp), "http://localhost:3000/");
+  assert_eq!(
+    set_cookies(&resp),
+    vec![
+      ("cf_session_token".to_string(), "".to_string()),
+      ("cf_user_info".to_string(), "".to_string())
+    ]
+  );
+  assert!(server.storage.state.lock().unwrap().sessions.is_empty());
+
+  // Logging out without a session still clears the cookies.
+  let resp = server.get("/logout").send().await.unwrap();
+  assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+  assert_eq!(set_cookies(&resp).len(), 2);
+
+  server.set_sessions_down();
+  let resp = server
+    .get("/logout")
+    .header(
+      header::COOKIE,
+      "cf_session_token=00000000-0000-4000-8000-000000000000",
+    )
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn hasura_auth_webhook() {
+  let server = TestServer::start().await;
+  let session_token = server.start_session().await;
+  let webhook = |name: header::HeaderName, value: String| {
+    let req = server.get("/hasura_auth_webhook").header(name, value);
+    async {
+      let resp = req.send().await.unwrap();
+      // Hasura wants a 200 either way.
+      assert_eq!(resp.status(), StatusCode::OK);
+      resp.json::<serde_json::Value>().await.unwrap()
+    }
+  };
+  let user = json!({ "X-Hasura-User-Id": "U_1", "X-Hasura-Role": "user" });
+  let anonymous = json!({ "X-Hasura-Role": "anonymous" });
+
+  assert_eq!(
+    webhook(header::AUTHORIZATION, format!("Bearer {}", session_token)).await,
+    user
+  );
+  assert_eq!(
+    webhook(
+      header::COOKIE,
+      format!("cf_user_info=x; cf_session_token={}", session_token)
+    )
+    .await,
+    user
+  );
+  assert_eq!(
+    webhook(header::AUTHORIZATION, "Bearer no-such-session".to_string()).await,
+    anonymous
+  );
+  assert_eq!(
+    webhook(header::AUTHORIZATION, format!("Token {}", session_token)).await,
+    anonymous
+  );
+  assert_eq!(
+    webhook(header::COOKIE, "cf_user_info=x".to_string()).await,
+    anonymous
+  );
+  let resp = server.get("/hasura_auth_webhook").send().await.unwrap();
+  assert_eq!(resp.json::<serde_json::Value>().await.unwrap(), anonymous);
+
+  server.set_sessions_down();
+  assert_eq!(
+    webhook(header::AUTHORIZATION, format!("Bearer {}", session_token)).await,
+    anonymous
+  );
+}
+
+#[tokio::test]
+async fn device_login() {
+  let server = TestServer::start().await;
+  let poll = |device_code: &str| {
+    server
+      .client
+      .post(format!("{}/login/device/token", server.url))
+      .json(&json!({ "device_code": device_code }))
+      .send()
+  };
+
+  let resp = server
+    .client
+    .post(format!("{}/login/device", server.url))
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  let device: serde_json::Value = resp.json().await.unwrap();
+  let device_code = device["device_code"].as_str().unwrap();
+  let user_code = device["user_code"].as_str().unwrap();
+  assert!(device["verification_uri"].is_string());
+  assert!(device["interval"].is_u64());
+
+  let resp = poll(device_code).await.unwrap();
+  assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+  assert_eq!(
+    resp.json::<serde_json::Value>().await.unwrap(),
+    json!({ "error": "authorization_pending" })
+  );
+
+  server.github.authorize_device(user_code, "the-token");
+  let resp = poll(device_code).await.unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  let body: serde_json::Value = resp.json().await.unwrap();
+  assert_eq!(body["github_login"], "someone");
+  let session_token = body["session_token"].as_str().unwrap();
+  assert_eq!(
+    server.storage.state.lock().unwrap().sessions[session_token].github_node_id,
+    "U_1"
+  );
+  // The session token works like any other.
+  let resp = server
+    .graphql("{ noop }")
+    .bearer_auth(session_token)
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+
+  // Device codes only work once.
+  let resp = poll(device_code).await.unwrap();
+  assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+  assert_eq!(
+    resp.json::<serde_json::Value>().await.unwrap(),
+    json!({ "error": "incorrect_device_code" })
+  );
+
+  // GitHub's errors get passed along.
+  let device: serde_json::Value = server
+    .client
+    .post(format!("{}/login/device", server.url))
+    .send()
+    .await
+    .unwrap()
+    .json()
+    .await
+    .unwrap();
+  server
+    .github
+    .fail_device_flow(device["user_code"].as_str().unwrap(), "access_denied");
+  let resp = poll(device["device_code"].as_str().unwrap()).await.unwrap();
+  assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+  assert_eq!(
+    resp.json::<serde_json::Value>().await.unwrap(),
+    json!({ "error": "access_denied" })
+  );
+
+  let resp = server
+    .client
+    .post(format!("{}/login/device/token", server.url))
+    .body("device_code=nope")
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+  assert_eq!(
+    resp.json::<serde_json::Value>().await.unwrap(),
+    json!({ "error": "invalid_request" })
+  );
+}
+
+#[tokio::test]
+async fn session_expiry() {
+  let server = TestServer::start().await;
+  let session_token = server.start_session().await;
+  let webhook = |token: &str| {
+    server
+      .get("/hasura_auth_webhook")
+      .header(header::COOKIE, format!("cf_session_token={}", token))
+      .send()
+  };
+
+  // Using a session pushes its expiry back.
+  server.backdate_session(&session_token, Duration::zero(), Duration::minutes(1));
+  let resp = webhook(&session_token).await.unwrap();
+  assert_eq!(
+    resp.json::<serde_json::Value>().await.unwrap()["X-Hasura-Role"],
+    "user"
+  );
+  assert!(
+    server.storage.state.lock().unwrap().sessions[&session_token]
+      .record
+      .expires_at
+      > Utc::now() + Duration::days(29)
+  );
+
+  // Until it goes unused for too long.
+  server.backdate_session(&session_token, Duration::zero(), -Duration::minutes(1));
+  let resp = webhook(&session_token).await.unwrap();
+  assert_eq!(
+    resp.json::<serde_json::Value>().await.unwrap()["X-Hasura-Role"],
+    "anonymous"
+  );
+  let resp = server
+    .graphql("{ noop }")
+    .bearer_auth(&session_token)
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn session_rotation() {
+  let server = TestServer::start().await;
+  let session_token = server.start_session().await;
+  let noop = |token: &str| server.graphql("{ noop }").bearer_auth(token).send();
+
+  // Young sessions are left alone.
+  let resp = noop(&session_token).await.unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  assert!(resp.headers().get("x-cuddlefish-session-token").is_none());
+
+  server.backdate_session(&session_token, Duration::days(2), Duration::days(30));
+  let resp = noop(&session_token).await.unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  let rotated_token = resp.headers()["x-cuddlefish-session-token"]
+    .to_str()
+    .unwrap()
+    .to_string();
+  assert_ne!(rotated_token, session_token);
+  let resp = noop(&rotated_token).await.unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  assert!(resp.headers().get("x-cuddlefish-session-token").is_none());
+  // The old token only works for a little while longer. Using it again doesn't push that back, or rotate it again.
+  let old_expires_at = || {
+    server.storage.state.lock().unwrap().sessions[&session_token]
+      .record
+      .expires_at
+  };
+  let cut_off_at = old_expires_at();
+  assert!(cut_off_at < Utc::now() + Duration::minutes(2));
+  let resp = noop(&session_token).await.unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  assert!(resp.headers().get("x-cuddlefish-session-token").is_none());
+  assert_eq!(old_expires_at(), cut_off_at);
+  assert_eq!(server.storage.state.lock().unwrap().sessions.len(), 2);
+
+  // Requests that authenticated with the cookie get a new cookie.
+  server.backdate_session(&rotated_token, Duration::days(2), Duration::days(30));
+  let resp = server
+    .get("/sessions")
+    .header(
+      header::COOKIE,
+      format!("cf_session_token={}", rotated_token),
+    )
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  let cookies = set_cookies(&resp);
+  assert_eq!(cookies.len(), 1);
+  assert_eq!(cookies[0].0, "cf_session_token");
+  assert_ne!(cookies[0].1, rotated_token);
+}
+
+#[tokio::test]
+async fn list_and_revoke_sessions() {
+  let server = TestServer::start().await;
+  let session_token = server.start_session().await;
+  let other_tokens = [server.start_session().await, server.start_session().await];
+  let list = || async {
+    let resp = server
+      .get("/sessions")
+      .bearer_auth(&session_token)
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    resp.json::<serde_json::Value>().await.unwrap()["sessions"]
+      .as_array()
+      .unwrap()
+      .clone()
+  };
+  let revoke = |public_id: &str| {
+    server
+      .client
+      .post(format!("{}/sessions/revoke", server.url))
+      .bearer_auth(&session_token)
+      .json(&json!({ "public_id": public_id }))
+      .send()
+  };
+
+  let sessions = list().await;
+  assert_eq!(sessions.len(), 3);
+  // Most recently used first, which is the one that we're using.
+  assert_eq!(sessions[0]["current"], true);
+  assert!(sessions[1..].iter().all(|s| s["current"] == false));
+  assert!(sessions.iter().all(|s| s["expires_at"].is_string()));
+
+  let public_id = sessions[1]["public_id"].as_str().unwrap();
+  let resp = revoke(public_id).await.unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  let resp = revoke(public_id).await.unwrap();
+  assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+  // The current session ends with /logout instead.
+  let resp = revoke(sessions[0]["public_id"].as_str().unwrap())
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+  assert_eq!(list().await.len(), 2);
+
+  let resp = server
+    .client
+    .post(format!("{}/sessions/revoke_others", server.url))
+    .bearer_auth(&session_token)
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::OK);
+  assert_eq!(
+    resp.json::<serde_json::Value>().await.unwrap(),
+    json!({ "revoked": 1 })
+  );
+  assert_eq!(list().await.len(), 1);
+  for token in &other_tokens {
+    let resp = server
+      .get("/sessions")
+      .bearer_auth(token)
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+  }
+
+  let resp = server.get("/sessions").send().await.unwrap();
+  assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+  server.set_sessions_down();
+  let resp = server
+    .get("/sessions")
+    .bearer_auth(&session_token)
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+  // Tokens that aren't even uuids are turned away without asking the database, so they're a 401 rather than a 500.
+  for (method, path) in [
+    (reqwest::Method::GET, "/sessions"),
+    (reqwest::Method::POST, "/sessions/revoke"),
+    (reqwest::Method::POST, "/sessions/revoke_others"),
+  ] {
+    let resp = server
+      .client
+      .request(method, format!("{}{}", server.url, path))
+      .bearer_auth("not-a-session-token")
+      .json(&json!({ "public_id": "whatever" }))
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{}", path);
+  }
+}
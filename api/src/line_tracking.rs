@@ -0,0 +1,595 @@
+// Track lines of code forward through history.
+//
+// Threads are anchored to a (commit, file_path, line_number). git blame only gets us from a newer commit back to the
+// exact commit that last touched a line, so a thread disappears as soon as its line is edited, or when it was started
+// on a commit that isn't the one that last touched the line. Here we go the other way: diff the anchor commit against
+// a newer commit, with rename and copy detection, and figure out where the anchored line most likely ended up.
+//
+// Threads on a range of lines are tracked one line at a time, and stay around for as long as any of their lines do.
+use anyhow::anyhow;
+use git2::Delta;
+use git2::Diff;
+use git2::DiffFindOptions;
+use git2::DiffOptions;
+use git2::Oid;
+use git2::Patch;
+use git2::Repository;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Minimum similarity for us to consider two lines to be the same line, edited.
+const MIN_SIMILARITY: f64 = 0.6;
+/// Lines that were moved to a different file are a little more suspect than edits in place.
+const MOVED_PENALTY: f64 = 0.9;
+/// Lines that are too short (think `}` or blank lines) match way too many things to fuzzy match meaningfully.
+const MIN_FUZZY_LINE_LENGTH: usize = 3;
+/// Don't go looking for moved code in ridiculously large diffs.
+const MAX_DELTAS_FOR_MOVE_DETECTION: usize = 1000;
+
+/// Where a line ended up in a newer commit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackedLine {
+  pub file_path: String,
+  /// 1-indexed.
+  pub line_number: usize,
+  /// 1.0 means that the line is unchanged, just possibly shifted around or in a renamed file. Anything less means that
+  /// the line was edited or moved and we're guessing based on how similar it looks.
+  pub confidence: f64,
+}
+
+/// Where a range of lines ended up in a newer commit, as a whole.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackedRange {
+  pub file_path: String,
+  /// The first and last of the original lines that made it into `file_path`, 1-indexed. Lines in between may have
+  /// been deleted, and new ones may have been added.
+  pub start_line_number: usize,
+  pub end_line_number: usize,
+  /// Averaged over every line of the original range, with the lines that didn't make it into `file_path` counting as
+  /// 0.0.
+  pub confidence: f64,
+}
+
+/// Put together where each line of a range ended up. The range follows `file_path` if given, or else whichever file
+/// got the most of its lines. None when none of the lines made it there.
+pub fn combine_range(
+  lines: &[Option<TrackedLine>],
+  file_path: Option<&str>,
+) -> Option<TrackedRange> {
+  let file_path = match file_path {
+    Some(file_path) => file_path.to_string(),
+    None => {
+      let mut counts: Vec<(&str, usize)> = vec![];
+      for line in lines.iter().flatten() {
+        match counts.iter_mut().find(|(path, _)| *path == line.file_path) {
+          Some((_, count)) => *count += 1,
+          None => counts.push((&line.file_path, 1)),
+        }
+      }
+      // max_by_key picks the last of equals, and we want the first file to show up to win ties.
+      counts
+        .iter()
+        .rev()
+        .max_by_key(|(_, count)| *count)?
+        .0
+        .to_string()
+    }
+  };
+  let surviving = lines
+    .iter()
+    .flatten()
+    .filter(|line| line.file_path == file_path)
+    .collect::<Vec<_>>();
+  Some(TrackedRange {
+    start_line_number: surviving.iter().map(|line| line.line_number).min()?,
+    end_line_number: surviving.iter().map(|line| line.line_number).max()?,
+    confidence: surviving.iter().map(|line| line.confidence).sum::<f64>() / lines.len() as f64,
+    file_path,
+  })
+}
+
+/// Maps lines from `from` to `to`. The diff between the two is computed once up front, so reuse a tracker when
+/// tracking many lines between the same pair of commits.
+pub struct LineTracker<'r> {
+  repo: &'r Repository,
+  from: Oid,
+  diff: Diff<'r>,
+}
+
+impl<'r> LineTracker<'r> {
+  pub fn new(repo: &'r Repository, from: Oid, to: Oid) -> anyhow::Result<Self> {
+    let from_tree = repo.find_commit(from)?.tree()?;
+    let to_tree = repo.find_commit(to)?.tree()?;
+    let mut diff_opts = DiffOptions::new();
+    diff_opts.context_lines(0);
+    let mut diff =
+      repo.diff_tree_to_tree(Some(&from_tree), Some(&to_tree), Some(&mut diff_opts))?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true).copies(true)))?;
+    Ok(LineTracker { repo, from, diff })
+  }
+
+  /// Find where `start_line_number..=end_line_number` of `file_path` in the `from` commit ended up in the `to` commit,
+  /// see `combine_range`.
+  pub fn track_range(
+    &self,
+    file_path: &str,
+    start_line_number: usize,
+    end_line_number: usize,
+  ) -> anyhow::Result<Option<TrackedRange>> {
+    let lines = (start_line_number..=end_line_number)
+      .map(|line_number| self.track(file_path, line_number))
+      .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(combine_range(&lines, None))
+  }
+
+  /// Find where `line_number` (1-indexed) of `file_path` in the `from` commit ended up in the `to` commit. Returns
+  /// `None` when the line was deleted outright, or changed beyond recognition.
+  pub fn track(&self, file_path: &str, line_number: usize) -> anyhow::Result<Option<TrackedLine>> {
+    let original = self.original_line(file_path, line_number)?;
+
+    // Renames and edits beat copies, since with a copy the original is still sitting right where we left it.
+    let mut deltas = self
+      .diff
+      .deltas()
+      .enumerate()
+      .filter(|(_, d)| d.old_file().path() == Some(Path::new(file_path)))
+      .collect::<Vec<_>>();
+    deltas.sort_by_key(|(_, d)| d.status() == Delta::Copied);
+
+    let delta_idx = match deltas.first() {
+      // Not in the diff at all, so the file is exactly the same.
+      None => {
+        return Ok(Some(TrackedLine {
+          file_path: file_path.to_string(),
+          line_number,
+          confidence: 1.0,
+        }))
+      }
+      Some((_, d)) if d.status() == Delta::Deleted => None,
+      Some((idx, _)) => Some(*idx),
+    };
+
+    if let Some(delta_idx) = delta_idx {
+      if let Some(tracked) = self.track_in_delta(delta_idx, &original, line_number)? {
+        return Ok(Some(tracked));
+      }
+    }
+
+    // Last resort: maybe the line was moved somewhere else entirely.
+    Ok(self.find_moved(&original, delta_idx)?.map(|mut tracked| {
+      tracked.confidence *= MOVED_PENALTY;
+      tracked
+    }))
+  }
+
+  fn original_line(&self, file_path: &str, line_number: usize) -> anyhow::Result<String> {
+    let tree = self.repo.find_commit(self.from)?.tree()?;
+    let blob = self
+      .repo
+      .find_blob(tree.get_path(Path::new(file_path))?.id())?;
+    let content = String::from_utf8_lossy(blob.content());
+    content
+      .lines()
+      .nth(line_number.wrapping_sub(1))
+      .map(|l| l.to_string())
+      .ok_or_else(|| anyhow!("{} has no line {}", file_path, line_number))
+  }
+
+  /// Track a line within a single modified/renamed file.
+  fn track_in_delta(
+    &self,
+    delta_idx: usize,
+    original: &str,
+    line_number: usize,
+  ) -> anyhow::Result<Option<TrackedLine>> {
+    let patch = match Patch::from_diff(&self.diff, delta_idx)? {
+      Some(patch) => patch,
+      // Binary files.
+      None => return Ok(None),
+    };
+    let new_path = path_string(patch.delta().new_file().path())?;
+
+    // How far lines have shifted due to the hunks that come before our line.
+    let mut offset: i64 = 0;
+    for hunk_idx in 0..patch.num_hunks() {
+      let (hunk, _) = patch.hunk(hunk_idx)?;
+      let old_start = hunk.old_start() as usize;
+      let old_lines = hunk.old_lines() as usize;
+      // A pure insertion reports the line *after which* lines were inserted as its old_start.
+      let before_hunk = if old_lines == 0 {
+        line_number <= old_start
+      } else {
+        line_number < old_start
+      };
+      if before_hunk {
+        break;
+      }
+      if old_lines > 0 && line_number < old_start + old_lines {
+        // Our line was removed or edited in this hunk. Find the most similar added line, preferring ones that are in a
+        // similar position within the hunk.
+        let relative_pos = (line_number - old_start) as f64 / old_lines as f64;
+        let mut best: Option<(f64, f64, usize)> = None;
+        let num_lines = patch.num_lines_in_hunk(hunk_idx)?;
+        let added = (0..num_lines)
+          .map(|i| patch.line_in_hunk(hunk_idx, i))
+          .collect::<Result<Vec<_>, _>>()?
+          .into_iter()
+          .filter(|l| l.origin() == '+')
+          .collect::<Vec<_>>();
+        for (i, line) in added.iter().enumerate() {
+          let sim = similarity(original, &String::from_utf8_lossy(line.content()));
+          let distance = (relative_pos - i as f64 / added.len() as f64).abs();
+          let better = match best {
+            None => true,
+            Some((best_sim, best_distance, _)) => {
+              sim > best_sim || (sim == best_sim && distance < best_distance)
+            }
+          };
+          if better {
+            best = Some((
+              sim,
+              distance,
+              line.new_lineno().unwrap_or_default() as usize,
+            ));
+          }
+        }
+        return Ok(match best {
+          Some((sim, _, new_line)) if sim >= MIN_SIMILARITY => Some(TrackedLine {
+            file_path: new_path,
+            line_number: new_line,
+            confidence: sim,
+          }),
+          _ => self.find_in_patch(&patch, original)?,
+        });
+      }
+      offset += hunk.new_lines() as i64 - old_lines as i64;
+    }
+
+    // Our line comes after (or between) all of the hunks, so it's unchanged.
+    Ok(Some(TrackedLine {
+      file_path: new_path,
+      line_number: (line_number as i64 + offset) as usize,
+      confidence: 1.0,
+    }))
+  }
+
+  /// Best fuzzy match among all of the lines added anywhere in `patch`.
+  fn find_in_patch(&self, patch: &Patch, original: &str) -> anyhow::Result<Option<TrackedLine>> {
+    if original.trim().len() < MIN_FUZZY_LINE_LENGTH {
+      return Ok(None);
+    }
+    let new_path = path_string(patch.delta().new_file().path())?;
+    let mut best: Option<TrackedLine> = None;
+    for hunk_idx in 0..patch.num_hunks() {
+      for i in 0..patch.num_lines_in_hunk(hunk_idx)? {
+        let line = patch.line_in_hunk(hunk_idx, i)?;
+        if line.origin() != '+' {
+          continue;
+        }
+        let sim = similarity(original, &String::from_utf8_lossy(line.content()));
+        if sim >= MIN_SIMILARITY && best.as_ref().is_none_or(|b| sim > b.confidence) {
+          best = Some(TrackedLine {
+            file_path: new_path.clone(),
+            line_number: line.new_lineno().unwrap_or_default() as usize,
+            confidence: sim,
+          });
+        }
+      }
+    }
+    Ok(best)
+  }
+
+  /// Look for the line in every other file touched by the diff.
+  fn find_moved(
+    &self,
+    original: &str,
+    skip_delta: Option<usize>,
+  ) -> anyhow::Result<Option<TrackedLine>> {
+    if self.diff.deltas().len() > MAX_DELTAS_FOR_MOVE_DETECTION {
+      return Ok(None);
+    }
+    let mut best: Option<TrackedLine> = None;
+    for (idx, delta) in self.diff.deltas().enumerate() {
+      if Some(idx) == skip_delta || delta.status() == Delta::Deleted {
+        continue;
+      }
+      if let Some(patch) = Patch::from_diff(&self.diff, idx)? {
+        if let Some(found) = self.find_in_patch(&patch, original)? {
+          if best
+            .as_ref()
+            .is_none_or(|b| found.confidence > b.confidence)
+          {
+            best = Some(found);
+          }
+        }
+      }
+    }
+    Ok(best)
+  }
+}
+
+fn path_string(path: Option<&Path>) -> anyhow::Result<String> {
+  Ok(
+    path
+      .ok_or_else(|| anyhow!("diff delta has no path"))?
+      .to_string_lossy()
+      .to_string(),
+  )
+}
+
+/// Sørensen–Dice coefficient over character bigrams, ignoring leading/trailing whitespace. 1.0 means identical.
+pub fn similarity(a: &str, b: &str) -> f64 {
+  let (a, b) = (a.trim(), b.trim());
+  if a == b {
+    return 1.0;
+  }
+  let bigrams = |s: &str| {
+    let chars = s.chars().collect::<Vec<_>>();
+    chars.windows(2).map(|w| (w[0], w[1])).collect::<Vec<_>>()
+  };
+  let (a_bigrams, mut b_bigrams) = (bigrams(a), bigrams(b));
+  if a_bigrams.is_empty() || b_bigrams.is_empty() {
+    return 0.0;
+  }
+  let total = (a_bigrams.len() + b_bigrams.len()) as f64;
+  let mut matches = 0;
+  for bigram in a_bigrams {
+    if let Some(pos) = b_bigrams.iter().position(|x| *x == bigram) {
+      b_bigrams.swap_remove(pos);
+      matches += 1;
+    }
+  }
+  2.0 * matches as f64 / total
+}
+
+/// All of the paths that `file_path` has had in the first-parent history of `commit`, following renames. Looks at no
+/// more than `max_commits` commits.
+pub fn file_history_paths(
+  repo: &Repository,
+  commit: Oid,
+  file_path: &str,
+  max_commits: usize,
+) -> anyhow::Result<Vec<String>> {
+  let mut paths = vec![file_path.to_string()];
+  let mut seen = paths.iter().cloned().collect::<HashSet<_>>();
+  let mut current_path = file_path.to_string();
+  let mut current = repo.find_commit(commit)?;
+  for _ in 0..max_commits {
+    let parent = match current.parents().next() {
+      Some(parent) => parent,
+      None => break,
+    };
+    if parent.tree()?.get_path(Path::new(&current_path)).is_err() {
+      // The file appeared in this commit. See if it was renamed from something else.
+      let mut diff = repo.diff_tree_to_tree(Some(&parent.tree()?), Some(&current.tree()?), None)?;
+      diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+      let renamed_from = diff
+        .deltas()
+        .find(|d| {
+          d.status() == Delta::Renamed && d.new_file().path() == Some(Path::new(&current_path))
+        })
+        .map(|d| path_string(d.old_file().path()))
+        .transpose()?;
+      match renamed_from {
+        Some(old_path) => {
+          if seen.insert(old_path.clone()) {
+            paths.push(old_path.clone());
+          }
+          current_path = old_path;
+        }
+        None => break,
+      }
+    }
+    current = parent;
+  }
+  Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use git2::Signature;
+
+  fn commit_files(repo: &Repository, files: &[(&str, Option<&str>)]) -> Oid {
+    let workdir = repo.workdir().unwrap();
+    let mut index = repo.index().unwrap();
+    for (path, content) in files {
+      match content {
+        Some(content) => {
+          let full_path = workdir.join(path);
+          std::fs::create_dir_all(full_path.parent().unwrap()).unwrap();
+          std::fs::write(full_path, content).unwrap();
+          index.add_path(Path::new(path)).unwrap();
+        }
+        None => {
+          std::fs::remove_file(workdir.join(path)).unwrap();
+          index.remove_path(Path::new(path)).unwrap();
+        }
+      }
+    }
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = Signature::now("test", "test@example.com").unwrap();
+    let parents = match repo.head() {
+      Ok(head) => vec![head.peel_to_commit().unwrap()],
+      Err(_) => vec![],
+    };
+    repo
+      .commit(
+        Some("HEAD"),
+        &sig,
+        &sig,
+        "commit",
+        &tree,
+        &parents.iter().collect::<Vec<_>>(),
+      )
+      .unwrap()
+  }
+
+  const ORIGINAL: &str =
+    "fn main() {\n  let x = compute_the_thing(1, 2);\n  println!(\"{}\", x);\n}\n";
+
+  #[test]
+  fn shifted_and_edited_lines() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = Repository::init(dir.path()).unwrap();
+    let from = commit_files(&repo, &[("main.rs", Some(ORIGINAL))]);
+    let to = commit_files(
+      &repo,
+      &[(
+        "main.rs",
+        Some("use std::fmt;\n\nfn main() {\n  let x = compute_the_thing(1, 3);\n  println!(\"{}\", x);\n}\n"),
+      )],
+    );
+    let tracker = LineTracker::new(&repo, from, to).unwrap();
+
+    // Unchanged, but shifted down by two lines.
+    assert_eq!(
+      tracker.track("main.rs", 3).unwrap(),
+      Some(TrackedLine {
+        file_path: "main.rs".to_string(),
+        line_number: 5,
+        confidence: 1.0
+      })
+    );
+
+    // Edited slightly.
+    let edited = tracker.track("main.rs", 2).unwrap().unwrap();
+    assert_eq!(edited.line_number, 4);
+    assert!(edited.confidence > MIN_SIMILARITY && edited.confidence < 1.0);
+  }
+
+  #[test]
+  fn renamed_and_moved_lines() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = Repository::init(dir.path()).unwrap();
+    let from = commit_files(
+      &repo,
+      &[
+        ("main.rs", Some(ORIGINAL)),
+        (
+          "util.rs",
+          Some("pub fn helper_function_with_a_long_name() -> usize {\n  42\n}\n"),
+        ),
+      ],
+    );
+    let to = commit_files(
+      &repo,
+      &[
+        ("main.rs", None),
+        ("src/main.rs", Some(ORIGINAL)),
+        ("util.rs", None),
+        (
+          "lib.rs",
+          // Different enough from util.rs that git doesn't consider it a rename.
+          Some(concat!(
+            "pub struct SomethingElseEntirely {\n  a: String,\n  b: Vec<u8>,\n}\n",
+            "pub fn helper_function_with_a_long_name(x: usize) -> usize {\n  43\n}\n",
+          )),
+        ),
+      ],
+    );
+    let tracker = LineTracker::new(&repo, from, to).unwrap();
+
+    assert_eq!(
+      tracker.track("main.rs", 2).unwrap(),
+      Some(TrackedLine {
+        file_path: "src/main.rs".to_string(),
+        line_number: 2,
+        confidence: 1.0
+      })
+    );
+
+    let moved = tracker.track("util.rs", 1).unwrap().unwrap();
+    assert_eq!(moved.file_path, "lib.rs");
+    assert_eq!(moved.line_number, 5);
+    assert!(moved.confidence < 1.0);
+
+    // `}` is too generic to track once its file is gone.
+    assert_eq!(tracker.track("util.rs", 3).unwrap(), None);
+
+    assert_eq!(
+      file_history_paths(&repo, to, "src/main.rs", 100).unwrap(),
+      vec!["src/main.rs".to_string(), "main.rs".to_string()]
+    );
+  }
+
+  #[test]
+  fn deleted_lines() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = Repository::init(dir.path()).unwrap();
+    let from = commit_files(&repo, &[("main.rs", Some(ORIGINAL))]);
+    let to = commit_files(&repo, &[("main.rs", Some("fn main() {\n}\n"))]);
+    let tracker = LineTracker::new(&repo, from, to).unwrap();
+    assert_eq!(tracker.track("main.rs", 2).unwrap(), None);
+    assert_eq!(tracker.track("main.rs", 4).unwrap().unwrap().line_number, 2);
+  }
+
+  #[test]
+  fn ranges() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = Repository::init(dir.path()).unwrap();
+    let from = commit_files(&repo, &[("main.rs", Some(ORIGINAL))]);
+    let to = commit_files(
+      &repo,
+      &[(
+        "main.rs",
+        Some("// hi\nfn main() {\n  println!(\"{}\", 3);\n}\n"),
+      )],
+    );
+    let tracker = LineTracker::new(&repo, from, to).unwrap();
+    // The body of main lost a line, but the range lives on.
+    let range = tracker.track_range("main.rs", 1, 4).unwrap().unwrap();
+    assert_eq!(
+      (
+        range.file_path.as_str(),
+        range.start_line_number,
+        range.end_line_number
+      ),
+      ("main.rs", 2, 4)
+    );
+    assert!(range.confidence < 1.0 && range.confidence > 0.5);
+    assert_eq!(
+      tracker
+        .track_range("main.rs", 1, 1)
+        .unwrap()
+        .unwrap()
+        .confidence,
+      1.0
+    );
+  }
+
+  #[test]
+  fn combine_range_picks_a_file() {
+    let line = |file_path: &str, line_number| {
+      Some(TrackedLine {
+        file_path: file_path.to_string(),
+        line_number,
+        confidence: 1.0,
+      })
+    };
+    let lines = [line("a.rs", 7), None, line("b.rs", 1), line("b.rs", 3)];
+    let range = combine_range(&lines, None).unwrap();
+    assert_eq!(
+      (
+        range.file_path.as_str(),
+        range.start_line_number,
+        range.end_line_number
+      ),
+      ("b.rs", 1, 3)
+    );
+    assert_eq!(range.confidence, 0.5);
+    let range = combine_range(&lines, Some("a.rs")).unwrap();
+    assert_eq!((range.start_line_number, range.end_line_number), (7, 7));
+    assert_eq!(range.confidence, 0.25);
+    assert_eq!(combine_range(&lines, Some("c.rs")), None);
+    assert_eq!(combine_range(&[None, None], None), None);
+    // Ties go to whichever file comes first.
+    assert_eq!(
+      combine_range(&[line("b.rs", 2), line("a.rs", 2)], None)
+        .unwrap()
+        .file_path,
+      "b.rs"
+    );
+  }
+}
@@ -1,66 +1,68 @@
 mod auth;
+mod bitbucket;
+mod blame_cache;
+mod blame_jobs;
+mod comments;
+mod config;
+#[cfg(test)]
+mod fakes;
 mod github;
+mod gitlab;
 mod hasura;
+#[cfg(test)]
+mod http_tests;
+mod line_tracking;
+mod mentions;
+mod mirror;
+mod mirror_manager;
+mod mirror_store;
+mod postgres;
+mod refresh_scheduler;
+mod repo_id;
+mod storage;
+mod subscriptions;
+mod token_cipher;
+use crate::config::Config;
 use crate::github::GitHubNodeId;
+use crate::mirror_manager::MirrorRepo;
+use crate::repo_id::parse_repo_id;
+use crate::repo_id::RepoId;
+use crate::storage::LineRange;
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::ensure;
-use anyhow::format_err;
 use anyhow::Result;
-use git2::BlameOptions;
 use git2::Oid;
-use git2::Repository;
-use hyper;
 use hyper::header;
 use hyper::server::Server;
 use hyper::service::make_service_fn;
 use hyper::service::service_fn;
 use hyper::Body;
 use hyper::Method;
+use hyper::Request;
 use hyper::Response;
 use hyper::StatusCode;
-use juniper::EmptySubscription;
 use juniper::FieldResult;
 use juniper::GraphQLObject;
 use juniper::RootNode;
-use lazy_static::lazy_static;
 // use log::info;
 // use log::trace;
-use std::path::Path;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::OnceLock;
+use std::time::Duration;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct GitHubUserId(GitHubNodeId);
 
-/// RepoId identifies a repository. See the README for more info. This is not used when hitting the database, etc. It is
-/// only used when calculating blamelines internally. For database related stuff use GitHub's node ids.
-// TODO simplify this
-pub enum RepoId {
-  GitHubRepo { owner: String, name: String },
-}
-
-impl ToString for RepoId {
-  fn to_string(&self) -> String {
-    match self {
-      RepoId::GitHubRepo { owner, name } => format!("github-{}!{}", owner, name),
-    }
-  }
-}
-
-/// Parse a string like "github-cuddlefish-app!cuddlefish" into a `RepoId`.
-fn parse_repo_id(repo_id: &str) -> Result<RepoId> {
-  let rest = repo_id
-    .strip_prefix("github-")
-    .ok_or(format_err!("bad repo_id"))?;
-  let split = rest.split('!').collect::<Vec<_>>();
-  match split[..] {
-    [owner, name] => Ok(RepoId::GitHubRepo {
-      owner: owner.into(),
-      name: name.into(),
-    }),
-    _ => bail!("bad repo_id"),
-  }
+/// A public repo that we have verified contains a particular commit.
+pub enum RepoWithCommit {
+  GitHub(GitHubNodeId),
+  /// Everything that's not on GitHub. These don't have a nice global id like GitHub's node ids, so we use the RepoId.
+  Other(RepoId),
 }
 
 #[derive(Debug, GraphQLObject)]
@@ -71,56 +73,73 @@ pub struct BlameLine {
   original_line_number: i32,
 }
 
-fn mirror_dir(repo_id: &RepoId) -> PathBuf {
-  // repo_id has its `/`s escaped, so it's safe as a file path.
-  Path::new(&*MIRRORS_DIR).join(repo_id.to_string())
+#[derive(Debug, GraphQLObject)]
+pub struct Comment {
+  id: String,
+  body: String,
+  created_at: String,
+  /// Exactly one of author_github_node_id and author_email is present.
+  author_github_node_id: Option<String>,
+  author_email: Option<String>,
+  /// When the body was last edited, if ever. See `commentEdits` for what it used to say.
+  edited_at: Option<String>,
+  /// Deleted comments stay in their thread so that the replies to them still make sense, but with an empty body.
+  deleted: bool,
+  reactions: Vec<comments::ReactionCount>,
+  /// Everyone @mentioned in the body, ordered by username.
+  mentions: Vec<mentions::Mention>,
 }
 
-/// Get a Repository object for a given RepoId. If we already have the repo cloned, great. If not, clone it first.
-async fn git_repo(repo_id: &RepoId) -> Result<Repository> {
-  let expected_path = mirror_dir(&repo_id);
-
-  // If the repo already exists, then we open and return it.
-  if expected_path.exists() && expected_path.is_dir() {
-    log::trace!(
-      "Returning existing repo at expected_path {}",
-      expected_path.to_string_lossy()
-    );
-    let repo = Repository::open(&expected_path)?;
-    return Ok(repo);
-  }
-
-  // We could also fetch this from the GitHub API, but it's really pretty easy to just do it ourselves.
-  let repo_url = match repo_id {
-    RepoId::GitHubRepo { owner, name } => format!("https://github.com/{}/{}", owner, name),
-  };
-  log::info!("repo_url = {}", repo_url);
-
-  log::info!("Cloning repo {}...", repo_url);
-  // TODO how to actually make this a --mirror clone with git2-rs?
-  let git_clone_successful = std::process::Command::new("git")
-    .arg("clone")
-    .arg("--mirror")
-    .arg(repo_url)
-    .arg(&expected_path)
-    .status()?
-    .success();
-  ensure!(git_clone_successful, "git clone was not successful");
-  log::info!("Clone complete.");
-
-  let repo = Repository::open(&expected_path)?;
-
-  // TODO: Check disk space and send alert once it passes 50%.
+/// Where a thread's line ended up in a newer commit.
+#[derive(Debug, GraphQLObject)]
+pub struct ThreadLocation {
+  file_path: String,
+  line_number: i32,
+  /// The last line of the thread's range, see `Thread.endLineNumber`.
+  end_line_number: i32,
+  /// 1.0 when the line is unchanged, lower the more it has been edited or moved around.
+  confidence: f64,
+  outdated: bool,
+}
 
-  Ok(repo)
+/// A thread as seen on a particular version of a file.
+#[derive(Debug, GraphQLObject)]
+pub struct Thread {
+  id: String,
+  /// The 1-indexed line in the requested version of the file that this thread shows up on. Null when the line that the
+  /// thread was started on has since been deleted. For threads on a range of lines, this is the first line of the range
+  /// that's still around.
+  line_number: Option<i32>,
+  /// The last line of the range that's still around, inclusive. Same as `line_number` for single line threads.
+  end_line_number: Option<i32>,
+  /// How sure we are that `line_number` is the same line that the thread was started on, from 0.0 to 1.0. Averaged over
+  /// every line of the range, where lines that have been deleted count as 0.0.
+  confidence: f64,
+  /// Whether any of the lines have been edited or deleted since the thread was started.
+  outdated: bool,
+  original_commit: String,
+  original_file_path: String,
+  original_line_number: i32,
+  original_end_line_number: i32,
+  /// 0-indexed character offsets into the first and last original line, for threads on part of a line. Only
+  /// meaningful while the thread isn't outdated.
+  original_start_column: Option<i32>,
+  original_end_column: Option<i32>,
+  /// Resolved threads are still returned, so that clients can show them collapsed.
+  resolved: bool,
+  resolved_at: Option<String>,
+  resolved_by_github_node_id: Option<String>,
+  comments: Vec<Comment>,
 }
 
-/// Does the given commit exist in the local repo?
-fn commit_exists(repo: &Repository, commit: &str) -> bool {
-  match repo.revparse_single(commit) {
-    Err(_) => false,
-    Ok(obj) => obj.as_commit().is_some(),
-  }
+/// How often we look for mirrors that could use a repack, and how recently a mirror needs to have been used for us to
+/// bother.
+const MIRROR_REPACK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MIRROR_HOT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Get a Repository object for a given RepoId that is guaranteed to contain `commit`, cloning and fetching as necessary.
+async fn git_repo_with_commit(repo_id: &RepoId, commit: &str) -> Result<MirrorRepo> {
+  mirrors().repo_with_commit(repo_id, commit).await
 }
 
 async fn git_blame(repo_id: &RepoId, commit: &str, file_path: &str) -> Result<Vec<BlameLine>> {
@@ -131,74 +150,296 @@ async fn git_blame(repo_id: &RepoId, commit: &str, file_path: &str) -> Result<Ve
     commit
   );
 
-  // Check if repo_id is cloned in the filesystem. If not then do a clone.
-  let repo = git_repo(repo_id).await?;
-
-  // Do a `git remote update` or `git pull` if need be. We may have already
-  // pulled this commit to get the blame on a different file, or it may have
-  // gotten pulled down incidentally previously.
-  if !commit_exists(&repo, commit) {
-    log::info!(
-      "Commit {} not found in repo {}. Pulling all changes.",
-      commit,
-      repo_id.to_string()
+  let repo = git_repo_with_commit(repo_id, commit).await?;
+
+  // Run git blame, or reuse a cached/incremental one if we can.
+  let cache = blame_cache::BlameCache::in_mirror(&repo);
+  let hunks = cache.blame(&repo, Oid::from_str(commit)?, file_path)?;
+  if cache.written_bytes() > 0 {
+    mirrors().grew(repo_id, cache.written_bytes()).await;
+  }
+
+  // Calculate blameline info.
+  Ok(
+    hunks
+      .iter()
+      .flat_map(|hunk| hunk.orig_lines())
+      .map(|(orig_commit, orig_file_path, orig_line)| BlameLine {
+        original_commit: orig_commit.to_string(),
+        original_file_path: orig_file_path.to_string(),
+        original_line_number: orig_line as i32,
+      })
+      .collect(),
+  )
+}
+
+/// How far back in a file's history we look for threads that may have been anchored to older versions of it.
+const MAX_THREAD_HISTORY_COMMITS: usize = 1000;
+
+/// `viewer` is whoever is logged in, if anyone.
+fn comment_from_record(c: storage::CommentRecord, viewer: Option<&GitHubUserId>) -> Comment {
+  let deleted = c.deleted_at.is_some();
+  Comment {
+    reactions: if deleted {
+      vec![]
+    } else {
+      comments::reaction_counts(&c.reactions, viewer)
+    },
+    mentions: if deleted {
+      vec![]
+    } else {
+      c.mentions.into_iter().map(Into::into).collect()
+    },
+    id: c.id,
+    body: if deleted { String::new() } else { c.body },
+    created_at: c.created_at,
+    author_github_node_id: c.author_github_node_id,
+    author_email: c.author_email,
+    edited_at: c.edited_at,
+    deleted,
+  }
+}
+
+/// `lines` is the first and last line of the thread's range in the requested version of the file.
+fn thread_from_record(
+  t: storage::ThreadWithComments,
+  lines: Option<(i32, i32)>,
+  confidence: f64,
+  viewer: Option<&GitHubUserId>,
+) -> Thread {
+  let original_end_line_number = t.anchor().original_end_line_number() as i32;
+  Thread {
+    id: t.id,
+    line_number: lines.map(|(start, _)| start),
+    end_line_number: lines.map(|(_, end)| end),
+    confidence,
+    // Anything less than perfect confidence means that the line has changed since the thread was started.
+    outdated: confidence < 1.0,
+    original_commit: t.original_commit_hash,
+    original_file_path: t.original_file_path,
+    original_line_number: t.original_line_number as i32,
+    original_end_line_number,
+    original_start_column: t.original_start_column.map(|c| c as i32),
+    original_end_column: t.original_end_column.map(|c| c as i32),
+    resolved: t.resolved_at.is_some(),
+    resolved_at: t.resolved_at,
+    resolved_by_github_node_id: t.resolved_by_github_node_id,
+    comments: t
+      .comments
+      .into_iter()
+      .map(|c| comment_from_record(c, viewer))
+      .collect(),
+  }
+}
+
+async fn gql_threads_for_file_inner(
+  context: &JuniperContext,
+  repo_id: String,
+  commit: String,
+  file_path: String,
+  resolved: Option<bool>,
+) -> anyhow::Result<Vec<Thread>> {
+  // Threads are only visible to logged in users in hasura, so we do the same here.
+  let auth = match &context.auth {
+    AuthContext::GitHub(auth) => auth,
+    AuthContext::Anonymous => bail!("unauthorized"),
+  };
+  let viewer = Some(&auth.github_node_id);
+
+  let repo_id_parsed = parse_repo_id(&repo_id)?;
+  let blamelines = git_blame(&repo_id_parsed, &commit, &file_path).await?;
+  let repo = git_repo_with_commit(&repo_id_parsed, &commit).await?;
+  let commit_oid = Oid::from_str(&commit)?;
+
+  // Map every original line that's visible in this version of the file to where it shows up. Threads can also be
+  // anchored directly to this commit, eg. when they were started while looking at this exact version of the file.
+  let mut visible_lines = HashMap::new();
+  for (i, bl) in blamelines.iter().enumerate() {
+    let line_number = i as i32 + 1;
+    visible_lines.insert(
+      (commit.clone(), file_path.clone(), line_number),
+      line_number,
+    );
+    visible_lines.insert(
+      (
+        bl.original_commit.clone(),
+        bl.original_file_path.clone(),
+        bl.original_line_number,
+      ),
+      line_number,
     );
+  }
+
+  let commit_hashes = visible_lines
+    .keys()
+    .map(|(c, _, _)| c.clone())
+    .collect::<HashSet<_>>();
+  let file_paths = visible_lines
+    .keys()
+    .map(|(_, p, _)| p.clone())
+    .collect::<HashSet<_>>();
+  let mut threads = context
+    .storage
+    .threads_for_original_lines(
+      commit_hashes.into_iter().collect(),
+      file_paths.into_iter().collect(),
+    )
+    .await?;
 
-    // TODO: figure out how to get git2-rs to do the same thing.
-    let git_remote_update_successful = std::process::Command::new("git")
-      .arg("remote")
-      .arg("update")
-      .current_dir(mirror_dir(&repo_id))
-      .status()?
-      .success();
-    ensure!(git_remote_update_successful);
+  // Threads anchored to older versions of this file, possibly under a different name, that blame alone can't place.
+  // Only this repo's threads though, other repos are bound to have files with the same names.
+  let history_paths =
+    line_tracking::file_history_paths(&repo, commit_oid, &file_path, MAX_THREAD_HISTORY_COMMITS)?;
+  let mut seen_thread_ids = threads.iter().map(|t| t.id.clone()).collect::<HashSet<_>>();
+  if let Some(repo_with_commit) =
+    lookup_public_repo_with_commit(&*context.github, auth, repo_id_parsed.clone(), &commit).await?
+  {
+    for t in context
+      .storage
+      .threads_for_file_paths(&repo_with_commit, history_paths)
+      .await?
+    {
+      if seen_thread_ids.insert(t.id.clone()) {
+        threads.push(t);
+      }
+    }
+  }
+  if let Some(resolved) = resolved {
+    threads.retain(|t| t.resolved_at.is_some() == resolved);
   }
-  ensure!(
-    commit_exists(&repo, commit),
-    "commit still doesn't exist after pulling"
-  );
 
-  // Run git blame
-  log::trace!("Running git blame...");
-  let blame = repo.blame_file(
-    Path::new(file_path),
-    Some(BlameOptions::new().newest_commit(Oid::from_str(commit)?)),
-  )?;
-  log::trace!("... git blame done");
+  let mut res = vec![];
+  let mut trackers = HashMap::new();
+  for t in threads {
+    let anchor = t.anchor();
+    let original_lines = anchor.original_line_number..=anchor.original_end_line_number();
+    // Where each line of the thread's range shows up, as far as blame can tell.
+    let mut lines = original_lines
+      .clone()
+      .map(|line_number| {
+        visible_lines
+          .get(&(
+            t.original_commit_hash.clone(),
+            t.original_file_path.clone(),
+            line_number as i32,
+          ))
+          .map(|&line_number| line_tracking::TrackedLine {
+            file_path: file_path.clone(),
+            line_number: line_number as usize,
+            confidence: 1.0,
+          })
+      })
+      .collect::<Vec<_>>();
 
-  // Calculate blameline info.
-  let mut blamelines = vec![];
-  for blamehunk in blame.iter() {
-    // Re expect here: The .path() should only ever be None in unicode situations on Windows
-    // (https://docs.rs/git2/0.11.0/git2/struct.BlameHunk.html#method.path).
-    let hunk_file_path = blamehunk
-      .path()
-      .expect("Could not get BlameHunk.path()")
-      .to_string_lossy()
-      .to_string();
-    let hunk_commit = blamehunk.orig_commit_id().to_string();
-    let hunk_start_line = blamehunk.orig_start_line();
-
-    for hunkline in 0..blamehunk.lines_in_hunk() {
-      blamelines.push(BlameLine {
-        original_commit: hunk_commit.clone(),
-        original_file_path: hunk_file_path.clone(),
-        original_line_number: (hunk_start_line + hunkline) as i32,
-      });
+    if lines.iter().any(Option::is_none) {
+      // Only threads from this commit's past can be tracked through edits and moves.
+      match Oid::from_str(&t.original_commit_hash) {
+        Ok(anchor_oid)
+          if repo
+            .graph_descendant_of(commit_oid, anchor_oid)
+            .unwrap_or(false) =>
+        {
+          if let std::collections::hash_map::Entry::Vacant(e) = trackers.entry(anchor_oid) {
+            e.insert(line_tracking::LineTracker::new(
+              &repo, anchor_oid, commit_oid,
+            )?);
+          }
+          let mut untrackable = None;
+          for (line, line_number) in lines.iter_mut().zip(original_lines) {
+            if line.is_none() {
+              match trackers[&anchor_oid].track(&t.original_file_path, line_number as usize) {
+                Ok(tracked) => *line = tracked,
+                Err(e) => {
+                  untrackable = Some(e);
+                  break;
+                }
+              }
+            }
+          }
+          // Say, because the thread's anchor doesn't match its commit. That's no reason to hide every other thread.
+          if let Some(e) = untrackable {
+            log::warn!("can't track thread {} to {}: {:?}", t.id, commit, e);
+            continue;
+          }
+        }
+        _ if lines.iter().all(Option::is_none) => continue,
+        _ => {}
+      }
+    }
+
+    match line_tracking::combine_range(&lines, Some(&file_path)) {
+      Some(range) => {
+        let lines = (range.start_line_number as i32, range.end_line_number as i32);
+        res.push(thread_from_record(t, Some(lines), range.confidence, viewer))
+      }
+      // The lines live on in some other file now.
+      None if lines.iter().any(Option::is_some) => {}
+      // The lines are gone.
+      None => res.push(thread_from_record(t, None, 0.0, viewer)),
     }
   }
 
-  Ok(blamelines)
+  // Outdated threads that no longer have a line go last.
+  res.sort_by_key(|t| t.line_number.unwrap_or(i32::MAX));
+  Ok(res)
+}
+
+async fn gql_track_thread_inner(
+  context: &JuniperContext,
+  repo_id: String,
+  thread_id: String,
+  commit: String,
+) -> anyhow::Result<Option<ThreadLocation>> {
+  ensure!(
+    matches!(context.auth, AuthContext::GitHub(_)),
+    "unauthorized"
+  );
+
+  let thread = context
+    .storage
+    .lookup_thread(&thread_id)
+    .await?
+    .ok_or_else(|| anyhow!("thread not found"))?;
+  let repo_id_parsed = parse_repo_id(&repo_id)?;
+  let repo = git_repo_with_commit(&repo_id_parsed, &commit).await?;
+  // The thread's commit has to be in the same repo, but it may not have been fetched yet.
+  let repo = if mirror_manager::commit_exists(&repo, &thread.original_commit_hash) {
+    repo
+  } else {
+    git_repo_with_commit(&repo_id_parsed, &thread.original_commit_hash).await?
+  };
+
+  let tracker = line_tracking::LineTracker::new(
+    &repo,
+    Oid::from_str(&thread.original_commit_hash)?,
+    Oid::from_str(&commit)?,
+  )?;
+  Ok(
+    tracker
+      .track_range(
+        &thread.original_file_path,
+        thread.original_line_number as usize,
+        thread.original_end_line_number() as usize,
+      )?
+      .map(|tracked| ThreadLocation {
+        file_path: tracked.file_path,
+        line_number: tracked.start_line_number as i32,
+        end_line_number: tracked.end_line_number as i32,
+        confidence: tracked.confidence,
+        outdated: tracked.confidence < 1.0,
+      }),
+  )
 }
 
 async fn gql_calculate_blamelines_inner(
+  context: &JuniperContext,
   repo_id: String,
   last_commit: String,
   file_path: String,
-) -> anyhow::Result<bool> {
+) -> anyhow::Result<Option<String>> {
   // There are situations in which it makes sense to allow anonymous users to call this endpoint. Eg, there are comments
   // on a file but its latest commit version has not been git blamed yet, so the line association info is not yet
-  // present in the blamelines table. Return value is whether or not we had cached values already.
+  // present in the blamelines table.
 
   log::trace!(
     "CalculateBlameLines repo_id = \"{}\", last_commit = \"{}\", file_path = \"{}\"",
@@ -207,19 +448,99 @@ async fn gql_calculate_blamelines_inner(
     file_path,
   );
 
-  // Check if it's already in the database to save a few electrons here.
-  if hasura::lookup_existing_blamelines(&last_commit, &file_path).await? {
-    log::trace!("blamelines already exist in hasura!");
-    Ok(true)
-  } else {
-    log::trace!("blamelines do not yet exist in hasura.");
-    // Not in the database, have to calculate the git blame.
-    let repo_id_parsed = parse_repo_id(&repo_id)?;
-    let blamelines = git_blame(&repo_id_parsed, &last_commit, &file_path).await?;
-    // Insert into the blamelines table. Existing values ok.
-    hasura::insert_blamelines(&last_commit, &file_path, blamelines).await?;
-    Ok(false)
-  }
+  blame_jobs::enqueue(&context.storage, &repo_id, &last_commit, &file_path).await
+}
+
+/// Returns Some if `repo_id` is a public repo that contains `commit_hash`. Don't let people add threads on commits that
+/// don't exist/are private.
+async fn lookup_public_repo_with_commit(
+  github: &dyn github::GitHub,
+  gh_auth: &GitHubAuth,
+  repo_id: RepoId,
+  commit_hash: &str,
+) -> Result<Option<RepoWithCommit>> {
+  // Each host gives us Some((is_private, contains_commit)) when it can find the repo at all.
+  let res = match &repo_id {
+    RepoId::GitHubRepo { owner, name } => {
+      // Note: we are using the user's GitHub token here to save on our own API call rate limiting.
+      return Ok(
+        match github
+          .lookup_commit(Some(gh_auth), owner, name, commit_hash)
+          .await?
+        {
+          Some((node_id, false, true)) => Some(RepoWithCommit::GitHub(node_id)),
+          _ => None,
+        },
+      );
+    }
+    RepoId::GitLabRepo { host, path } => gitlab::lookup_commit(host, path, commit_hash).await?,
+    RepoId::BitbucketRepo { owner, name } => {
+      bitbucket::lookup_commit(owner, name, commit_hash).await?
+    }
+    RepoId::GitUrl { .. } => {
+      // There's no API to ask here. But we only ever clone anonymously, so if we're able to get the commit from the
+      // repo then it's public by definition.
+      match git_repo_with_commit(&repo_id, commit_hash).await {
+        Ok(_) => Some((false, true)),
+        Err(e) => {
+          log::info!(
+            "couldn't find commit {} in {}: {:?}",
+            commit_hash,
+            repo_id,
+            e
+          );
+          None
+        }
+      }
+    }
+  };
+  Ok(match res {
+    Some((false, true)) => Some(RepoWithCommit::Other(repo_id)),
+    _ => None,
+  })
+}
+
+/// The most lines that a single thread can cover.
+const MAX_THREAD_LINES: i32 = 1000;
+
+/// Check the range that a new thread is being started on. Without `end_line_number` and columns, that's just
+/// `line_number`.
+fn thread_line_range(
+  line_number: i32,
+  end_line_number: Option<i32>,
+  start_column: Option<i32>,
+  end_column: Option<i32>,
+) -> anyhow::Result<LineRange> {
+  // Line numbers are 1-indexed! juniper does not support unsigned integers.
+  ensure!(line_number > 0);
+  let end_line_number = end_line_number.unwrap_or(line_number);
+  ensure!(
+    end_line_number >= line_number,
+    "endLineNumber is before lineNumber"
+  );
+  ensure!(
+    end_line_number - line_number < MAX_THREAD_LINES,
+    "threads can cover at most {} lines",
+    MAX_THREAD_LINES
+  );
+  let (start_column, end_column) = match (start_column, end_column) {
+    (None, None) => (None, None),
+    (Some(start), Some(end)) => {
+      ensure!(start >= 0 && end >= 0, "columns can't be negative");
+      ensure!(
+        end_line_number > line_number || end >= start,
+        "endColumn is before startColumn"
+      );
+      (Some(start as u32), Some(end as u32))
+    }
+    _ => bail!("startColumn and endColumn go together"),
+  };
+  Ok(LineRange {
+    start_line: line_number as u32,
+    end_line: end_line_number as u32,
+    start_column,
+    end_column,
+  })
 }
 
 async fn gql_start_thread_inner(
@@ -227,7 +548,7 @@ async fn gql_start_thread_inner(
   repo_ids: Vec<String>,
   commit_hash: String,
   file_path: String,
-  line_number: i32,
+  range: LineRange,
   body: String,
 ) -> anyhow::Result<String> {
   let gh_auth = match &context.auth {
@@ -236,59 +557,97 @@ async fn gql_start_thread_inner(
   }?;
 
   log::trace!(
-    "StartThread repo_ids = {:?}, commit_hash = \"{}\", file_path = \"{}\", line_number = {}",
+    "StartThread repo_ids = {:?}, commit_hash = \"{}\", file_path = \"{}\", range = {:?}",
     repo_ids,
     commit_hash,
     file_path,
-    line_number
+    range
   );
 
-  // Line numbers are 1-indexed! juniper does not support unsigned integers.
-  ensure!(line_number > 0);
-  ensure!(repo_ids.len() > 0);
-  ensure!(body.trim().len() > 0);
+  ensure!(!repo_ids.is_empty());
+  comments::validate_body(&body)?;
 
-  // Find a public GitHub repo that contains the commit we're looking for. Don't let people add threads on commits
-  // that don't exist/are private.
+  // Find a public repo that contains the commit we're looking for.
   // Note: This is shockingly slow, eg. 700ms on a single repo. Another potentially faster way to do this is to first
   // check if we have this commit somewhere in hasura already which would imply that it exists on a public repo.
   log::trace!("starting commit lookup");
   let mut repo_with_commit_option = None;
   for repo_id in repo_ids {
     let repo_id_parsed = parse_repo_id(&repo_id)?;
-    let (owner, name) = match repo_id_parsed {
-      RepoId::GitHubRepo { owner, name } => (owner, name),
-    };
-    // Note: we are using the user's GitHub token here to save on our own API call rate limiting.
-    let res = github::lookup_commit(Some(&gh_auth), &owner, &name, &commit_hash).await?;
-    if let Some((repo_id, is_private, contains_commit)) = res {
-      if !is_private && contains_commit {
-        repo_with_commit_option = Some(repo_id);
-        break;
-      }
+    if let Some(repo) =
+      lookup_public_repo_with_commit(&*context.github, gh_auth, repo_id_parsed, &commit_hash)
+        .await?
+    {
+      repo_with_commit_option = Some(repo);
+      break;
     }
   }
   log::trace!("finished looking up commits");
 
-  // TODO we should also maintain a mapping of commits -> containing repos in hasura so that we can use it when sending
-  // email notifications.
-  // ensure!(repo_with_commit.is_some());
+  // start_thread also records the repo in commit_github_repo/commit_repo, which is how we find it again later on.
   let repo_id = repo_with_commit_option.ok_or_else(|| anyhow!("no repo with commit"))?;
 
-  // line_number is i32 but also asserted to be > 0 above, so it should fit into u32, no problem.
-  let new_thread_id = hasura::start_thread(
-    &gh_auth.github_node_id,
-    &repo_id,
+  let (new_thread_id, comment_id) = context
+    .storage
+    .start_thread(
+      &gh_auth.github_node_id,
+      &repo_id,
+      &commit_hash,
+      &file_path,
+      &range,
+      &body,
+    )
+    .await?;
+  mentions::record(context, &comment_id, &body).await;
+
+  subscriptions::publish_thread_started(
+    &new_thread_id,
+    &gh_auth.github_node_id.0 .0,
+    &body,
     &commit_hash,
     &file_path,
-    line_number.try_into().unwrap(),
-    &body,
-  )
-  .await?;
+    range.start_line as i32,
+    range.end_line as i32,
+  );
 
   Ok(new_thread_id)
 }
 
+/// Resolve or reopen a thread. Any logged in user can do either, same as anyone can reply. Doing it twice is fine, and
+/// the first resolver stays on record. Returns the thread's id.
+async fn gql_set_thread_resolved_inner(
+  context: &JuniperContext,
+  thread_id: String,
+  resolved: bool,
+) -> anyhow::Result<String> {
+  let gh_auth = match &context.auth {
+    AuthContext::GitHub(auth) => Ok(auth),
+    AuthContext::Anonymous => Err(anyhow!("unauthorized")),
+  }?;
+  let thread = context
+    .storage
+    .lookup_thread(&thread_id)
+    .await?
+    .ok_or_else(|| anyhow!("no thread {}", thread_id))?;
+  let changed = if resolved {
+    context
+      .storage
+      .resolve_thread(&thread_id, &gh_auth.github_node_id)
+      .await?
+  } else {
+    context.storage.reopen_thread(&thread_id).await?
+  };
+  if changed {
+    subscriptions::publish_thread_resolution(
+      resolved,
+      &thread_id,
+      &thread,
+      &gh_auth.github_node_id.0 .0,
+    );
+  }
+  Ok(thread_id)
+}
+
 /// Convert anyhow::Result types into `juniper::FieldResult`s with logging for when things go wrong.
 fn juniperify<T>(res: anyhow::Result<T>) -> juniper::FieldResult<T> {
   match res {
@@ -311,20 +670,83 @@ impl Query {
   async fn noop() -> FieldResult<bool> {
     Ok(true)
   }
+
+  /// Blame info for every line of `file_path` as of `commit`, computed on the fly instead of going through the
+  /// blamelines table.
+  async fn blamelines(
+    repo_id: String,
+    commit: String,
+    file_path: String,
+  ) -> FieldResult<Vec<BlameLine>> {
+    juniperify(
+      async {
+        let repo_id_parsed = parse_repo_id(&repo_id)?;
+        git_blame(&repo_id_parsed, &commit, &file_path).await
+      }
+      .await,
+    )
+  }
+
+  /// All of the threads that show up on `file_path` as of `commit`, sorted by line number. This includes threads that
+  /// were started on older versions of the file, even if their line has since been edited or moved. Pass `resolved` to
+  /// only get resolved (true) or open (false) threads.
+  async fn threads_for_file(
+    context: &JuniperContext,
+    repo_id: String,
+    commit: String,
+    file_path: String,
+    resolved: Option<bool>,
+  ) -> FieldResult<Vec<Thread>> {
+    juniperify(gql_threads_for_file_inner(context, repo_id, commit, file_path, resolved).await)
+  }
+
+  /// The blame job with `id`, as returned by `CalculateBlameLines`. Null if there's no such job.
+  async fn blame_job(
+    context: &JuniperContext,
+    id: String,
+  ) -> FieldResult<Option<blame_jobs::BlameJob>> {
+    juniperify(blame_jobs::lookup(&*context.storage, &id).await)
+  }
+
+  /// Where a thread's line ended up as of `commit`, following edits, renames, and code that was moved between files.
+  /// Null if the line has since been deleted.
+  async fn track_thread(
+    context: &JuniperContext,
+    repo_id: String,
+    thread_id: String,
+    commit: String,
+  ) -> FieldResult<Option<ThreadLocation>> {
+    juniperify(gql_track_thread_inner(context, repo_id, thread_id, commit).await)
+  }
+
+  /// Every previous version of a comment, oldest first. Requires a logged in user.
+  async fn comment_edits(
+    context: &JuniperContext,
+    comment_id: String,
+  ) -> FieldResult<Vec<comments::CommentEdit>> {
+    juniperify(comments::edits(context, &comment_id).await)
+  }
 }
 
 struct Mutation;
 
+type Schema = RootNode<'static, Query, Mutation, subscriptions::Subscription>;
+
 #[juniper::graphql_object(context = JuniperContext)]
 impl Mutation {
+  /// Start calculating the blamelines for `file_path` as of `last_commit` in the background. Returns the id of the
+  /// blame job, or null if the blamelines have already been calculated.
   async fn CalculateBlameLines(
+    context: &JuniperContext,
     repo_id: String,
     last_commit: String,
     file_path: String,
-  ) -> FieldResult<bool> {
-    juniperify(gql_calculate_blamelines_inner(repo_id, last_commit, file_path).await)
+  ) -> FieldResult<Option<String>> {
+    juniperify(gql_calculate_blamelines_inner(context, repo_id, last_commit, file_path).await)
   }
 
+  /// Start a thread on `line_number`, or on `line_number..=end_line_number` when `end_line_number` is given.
+  /// `start_column` and `end_column` narrow it down to part of the first and last line.
   async fn StartThread(
     context: &JuniperContext,
     repo_ids: Vec<String>,
@@ -332,71 +754,145 @@ impl Mutation {
     file_path: String,
     line_number: i32,
     body: String,
+    end_line_number: Option<i32>,
+    start_column: Option<i32>,
+    end_column: Option<i32>,
   ) -> FieldResult<String> {
     juniperify(
-      gql_start_thread_inner(
-        &context,
-        repo_ids,
-        commit_hash,
-        file_path,
-        line_number,
-        body,
-      )
+      async {
+        let range = thread_line_range(line_number, end_line_number, start_column, end_column)?;
+        gql_start_thread_inner(context, repo_ids, commit_hash, file_path, range, body).await
+      }
       .await,
     )
   }
+
+  /// Mark a thread as resolved. Returns its id.
+  async fn ResolveThread(context: &JuniperContext, thread_id: String) -> FieldResult<String> {
+    juniperify(gql_set_thread_resolved_inner(context, thread_id, true).await)
+  }
+
+  /// Undo `ResolveThread`. Returns the thread's id.
+  async fn ReopenThread(context: &JuniperContext, thread_id: String) -> FieldResult<String> {
+    juniperify(gql_set_thread_resolved_inner(context, thread_id, false).await)
+  }
+
+  /// Reply to a thread.
+  async fn AddComment(
+    context: &JuniperContext,
+    thread_id: String,
+    body: String,
+  ) -> FieldResult<Comment> {
+    juniperify(comments::add(context, &thread_id, &body).await)
+  }
+
+  /// Change the body of one of your own comments. The old body is kept in `commentEdits`.
+  async fn EditComment(
+    context: &JuniperContext,
+    comment_id: String,
+    body: String,
+  ) -> FieldResult<Comment> {
+    juniperify(comments::edit(context, &comment_id, &body).await)
+  }
+
+  /// React to a comment. Each user can react to a comment once with each kind of reaction, so adding one that you've
+  /// already added does nothing.
+  async fn AddReaction(
+    context: &JuniperContext,
+    comment_id: String,
+    reaction: comments::Reaction,
+  ) -> FieldResult<Comment> {
+    juniperify(comments::set_reaction(context, &comment_id, reaction, true).await)
+  }
+
+  async fn RemoveReaction(
+    context: &JuniperContext,
+    comment_id: String,
+    reaction: comments::Reaction,
+  ) -> FieldResult<Comment> {
+    juniperify(comments::set_reaction(context, &comment_id, reaction, false).await)
+  }
+
+  /// Delete one of your own comments. Returns its id.
+  async fn DeleteComment(context: &JuniperContext, comment_id: String) -> FieldResult<String> {
+    juniperify(comments::delete(context, &comment_id).await)
+  }
+}
+
+// The mirrors own what's on disk under MIRRORS_DIR, so there's only ever one of them. `main` sets it up from the config
+// before anything else runs.
+static MIRRORS: OnceLock<mirror_manager::MirrorManager> = OnceLock::new();
+
+fn mirrors() -> &'static mirror_manager::MirrorManager {
+  MIRRORS.get().expect("MIRRORS used before it was set up")
 }
 
-lazy_static! {
-  static ref HASURA_GRAPHQL_ADMIN_SECRET: String = std::env::var("HASURA_GRAPHQL_ADMIN_SECRET")
-    .expect("HASURA_GRAPHQL_ADMIN_SECRET env var not set");
-  static ref GITHUB_OAUTH_CLIENT_ID: String =
-    std::env::var("GITHUB_OAUTH_CLIENT_ID").expect("GITHUB_OAUTH_CLIENT_ID env var not set");
-  static ref GITHUB_OAUTH_CLIENT_SECRET: String = std::env::var("GITHUB_OAUTH_CLIENT_SECRET")
-    .expect("GITHUB_OAUTH_CLIENT_SECRET env var not set");
-  static ref GITHUB_API_TOKEN: String = std::env::var("GITHUB_API_TOKEN")
-    .expect("GITHUB_API_TOKEN env var not set");
-  static ref API_PASETO_SECRET_KEY: String =
-    std::env::var("API_PASETO_SECRET_KEY").expect("API_PASETO_SECRET_KEY env var not set");
-  static ref HASURA_HOST: String =
-    std::env::var("HASURA_HOST").expect("HASURA_HOST env var not set");
-  static ref HASURA_PORT: String =
-    std::env::var("HASURA_PORT").expect("HASURA_PORT env var not set");
-  static ref MIRRORS_DIR: String =
-    std::env::var("MIRRORS_DIR").expect("MIRRORS_DIR env var not set");
-
-  // Whether or not we're running on render at all, either in prod or as an
-  // ephemeral PR environment. See https://render.com/docs/environment-variables.
-  static ref RUNNING_ON_RENDER: bool = std::env::var("RENDER") == Ok("true".to_string());
+/// For requests that come from Hasura, or from us poking around.
+fn check_admin_secret(config: &Config, req: &Request<Body>) -> anyhow::Result<()> {
+  let secret = req
+    .headers()
+    .get("x-hasura-admin-secret")
+    .ok_or_else(|| anyhow!("missing x-hasura-admin-secret header"))?;
+  ensure!(
+    secret.as_bytes() == config.hasura_graphql_admin_secret.as_bytes(),
+    "bad x-hasura-admin-secret header"
+  );
+  Ok(())
+}
+
+/// Disk usage of each mirror, along with the quota.
+async fn admin_mirrors_route(
+  config: &Config,
+  req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+  if let Err(e) = check_admin_secret(config, &req) {
+    log::warn!("rejecting /admin/mirrors request: {:?}", e);
+    return Ok(
+      Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(Body::empty())
+        .expect("failed to construct response"),
+    );
+  }
+  Ok(
+    Response::builder()
+      .status(StatusCode::OK)
+      .header(header::CONTENT_TYPE, "application/json")
+      .body(Body::from(
+        serde_json::to_string(&mirrors().usage_report()).expect("failed to serialize usage report"),
+      ))
+      .expect("failed to construct response"),
+  )
 }
 
+#[derive(Clone)]
 pub struct GitHubAuth {
   github_node_id: GitHubUserId,
   access_token: String,
 }
 
-enum AuthContext {
+pub enum AuthContext {
   Anonymous,
   GitHub(GitHubAuth),
 }
-struct JuniperContext {
+pub struct JuniperContext {
   auth: AuthContext,
+  storage: Arc<dyn storage::Storage>,
+  github: Arc<dyn github::GitHub>,
 }
 impl juniper::Context for JuniperContext {}
 
 // Build a JuniperContext provided a session token via auth header. We throw an
 // error if the session token is invalid, as opposed to silenty proceeding as
 // anonymous.
-async fn lookup_github_auth_from_header(
+async fn lookup_session_from_header(
+  storage: &dyn storage::Storage,
+  session_ttl: chrono::Duration,
   auth_header_value: &header::HeaderValue,
-) -> anyhow::Result<GitHubAuth> {
+) -> anyhow::Result<auth::Session> {
   let parts = auth_header_value.to_str()?.split(" ").collect::<Vec<_>>();
   match parts.as_slice() {
-    ["Bearer", token] => Ok(
-      hasura::lookup_user_session(&token)
-        .await?
-        .ok_or_else(|| anyhow!("could not find session for token"))?,
-    ),
+    ["Bearer", token] => auth::lookup_session(storage, session_ttl, token).await,
     _ => Err(anyhow!("malformed auth header")),
   }
 }
@@ -407,139 +903,827 @@ async fn main() {
     .format_timestamp_millis()
     .init();
 
-  // Fail fast if we're missing environment variables we need.
-  lazy_static::initialize(&HASURA_GRAPHQL_ADMIN_SECRET);
-  lazy_static::initialize(&GITHUB_OAUTH_CLIENT_ID);
-  lazy_static::initialize(&GITHUB_OAUTH_CLIENT_SECRET);
-  lazy_static::initialize(&GITHUB_API_TOKEN);
-  lazy_static::initialize(&API_PASETO_SECRET_KEY);
-  lazy_static::initialize(&HASURA_HOST);
-  lazy_static::initialize(&HASURA_PORT);
-  lazy_static::initialize(&RUNNING_ON_RENDER);
-
+  let config = match Config::load() {
+    Ok(config) => Arc::new(config),
+    Err(e) => {
+      eprintln!("{:#}", e);
+      std::process::exit(1);
+    }
+  };
   log::info!("Starting with settings:");
-  log::info!("GITHUB_OAUTH_CLIENT_ID = {}", *GITHUB_OAUTH_CLIENT_ID);
-  log::info!("MIRRORS_DIR = {}", *MIRRORS_DIR);
-  log::info!("HASURA_HOST = {}", *HASURA_HOST);
-  log::info!("HASURA_PORT = {}", *HASURA_PORT);
-  log::info!("RUNNING_ON_RENDER = {}", *RUNNING_ON_RENDER);
-  // At the moment, we only use RENDER_EXTERNAL_URL to determine RUNNING_ON_RENDER.
-  log::info!(
-    "RENDER_EXTERNAL_URL = {:?}",
-    std::env::var("RENDER_EXTERNAL_URL")
-  );
+  config.log();
+
+  let storage = storage::from_config(&config).expect("failed to set up storage");
+  let github: Arc<dyn github::GitHub> = Arc::new(github::GitHubApi::new(&config));
+  let mirrors = MIRRORS.get_or_init(|| {
+    mirror_manager::MirrorManager::new(
+      config.mirrors_dir.clone(),
+      config.mirrors_quota_bytes,
+      config.github_url.clone(),
+    )
+  });
+
+  // Has to happen before anyone starts cloning.
+  mirrors
+    .remove_partial_clones()
+    .expect("failed to clean up partial clones");
+  mirrors
+    .remove_shared_blame_cache()
+    .expect("failed to clean up the old blame cache");
+  mirrors
+    .scan_usage()
+    .expect("failed to figure out mirror disk usage");
+
+  tokio::spawn(refresh_scheduler::run(
+    storage.clone(),
+    github.clone(),
+    config.mirror_refresh_interval,
+    config.mirror_max_refresh_interval,
+  ));
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(MIRROR_REPACK_INTERVAL);
+    loop {
+      interval.tick().await;
+      mirrors.repack_hot_mirrors(MIRROR_HOT_WINDOW).await;
+    }
+  });
+
+  blame_jobs::init(config.blame_workers);
+  {
+    let storage = storage.clone();
+    tokio::spawn(async move {
+      if let Err(e) = blame_jobs::resume_unfinished_jobs(&storage).await {
+        log::error!("failed to resume unfinished blame jobs: {:?}", e);
+      }
+    });
+  }
+
+  // Picks up rows from before we encrypted access tokens, and ones encrypted with keys that have since been rotated out
+  // of first place in ACCESS_TOKEN_KEYS.
+  {
+    let storage = storage.clone();
+    tokio::spawn(async move {
+      match storage.reencrypt_access_tokens().await {
+        Ok(n) => log::info!("re-encrypted {} access tokens", n),
+        Err(e) => log::error!("failed to re-encrypt access tokens: {:?}", e),
+      }
+    });
+  }
 
-  let root_node = Arc::new(RootNode::new(Query, Mutation, EmptySubscription::new()));
+  let (addr, server) = serve(config, storage, github);
+  println!("Listening on http://{}", addr);
+
+  if let Err(e) = server.await {
+    eprintln!("server error: {}", e)
+  }
+}
 
-  let make_service = make_service_fn(|_| {
+/// Serve the api on `config.listen_addr`, talking to `storage` and `github`. Returns the address that we ended up
+/// listening on, which only differs from `listen_addr` when it asks for port 0, along with the server itself, which
+/// does nothing until awaited.
+fn serve(
+  config: Arc<Config>,
+  storage: Arc<dyn storage::Storage>,
+  github: Arc<dyn github::GitHub>,
+) -> (SocketAddr, impl Future<Output = hyper::Result<()>>) {
+  let root_node: Arc<Schema> =
+    Arc::new(RootNode::new(Query, Mutation, subscriptions::Subscription));
+
+  let addr = config.listen_addr;
+  let make_service = make_service_fn(move |_| {
     let root_node = root_node.clone();
+    let config = config.clone();
+    let storage = storage.clone();
+    let github = github.clone();
     async {
       Ok::<_, hyper::Error>(service_fn(move |req| {
-        let root_node = root_node.clone();
-        async {
-          let start_time = std::time::Instant::now();
-          let method = req.method().clone();
-          // .path() drops ?k=v and #asdf stuff.
-          let uri = req.uri().path().to_owned();
-
-          // Too many nuisance logs for /healthz...
-          if (&method, uri.as_ref()) != (&Method::GET, "/healthz") {
-            log::info!("--> {} {}", method, uri);
-          }
+        handle_request(
+          root_node.clone(),
+          config.clone(),
+          storage.clone(),
+          github.clone(),
+          req,
+        )
+      }))
+    }
+  });
+  let server = Server::bind(&addr).serve(make_service);
+  (server.local_addr(), server)
+}
 
-          (match (&method, uri.as_ref()) {
-            // TODO: turn off graphiql in prod.
-            (&Method::GET, "/") => Ok(juniper_hyper::graphiql("/graphql", None).await),
-            (&Method::GET, "/graphql") | (&Method::POST, "/graphql") => {
-              match req.headers().get(header::AUTHORIZATION) {
-                Some(header_val) => {
-                  // Request has a auth header provided, try looking up session token.
-                  match lookup_github_auth_from_header(header_val).await {
-                    Ok(gh_auth) => {
-                      log::trace!("auth header valid for user {:?}", gh_auth.github_node_id);
-                      Ok(
-                        juniper_hyper::graphql(
-                          root_node,
-                          Arc::new(JuniperContext {
-                            auth: AuthContext::GitHub(gh_auth),
-                          }),
-                          req,
-                        )
-                        .await,
-                      )
-                    }
-                    Err(_) => {
-                      // User provided an auth header but it was invalid.
-                      log::trace!("auth header invalid");
-                      Ok(
-                        Response::builder()
-                          .status(StatusCode::UNAUTHORIZED)
-                          .body(Body::empty())
-                          .expect("failed to construct response"),
-                      )
-                    }
-                  }
-                }
-                None => {
-                  log::trace!("no auth header found");
-                  // Not auth header, so we're anonymous.
-                  Ok(
-                    juniper_hyper::graphql(
-                      root_node,
-                      Arc::new(JuniperContext {
-                        auth: AuthContext::Anonymous,
-                      }),
-                      req,
-                    )
-                    .await,
-                  )
-                }
-              }
-            }
+async fn handle_request(
+  root_node: Arc<Schema>,
+  config: Arc<Config>,
+  storage: Arc<dyn storage::Storage>,
+  github: Arc<dyn github::GitHub>,
+  req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+  let start_time = std::time::Instant::now();
+  let method = req.method().clone();
+  // .path() drops ?k=v and #asdf stuff.
+  let uri = req.uri().path().to_owned();
 
-            (&Method::GET, "/healthz") => Ok(
-              Response::builder()
-                .status(StatusCode::OK)
-                .body(Body::empty())
-                .expect("failed to construct response"),
-            ),
-
-            (&Method::GET, "/login") => auth::login_route(req).await,
-            (&Method::GET, "/oauth/callback/github") => auth::github_callback_route(req).await,
-            (&Method::GET, "/logout") => auth::logout_route(req).await,
-            (&Method::GET, "/hasura_auth_webhook") => auth::hasura_auth_webhook(req).await,
-
-            _ => Ok(
-              Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(Body::empty())
-                .expect("failed to construct response"),
-            ),
-          })
-          .and_then(|resp| {
-            if (&method, uri.as_ref()) != (&Method::GET, "/healthz") {
-              log::info!(
-                "<-- {} {} {} {}ms",
-                method,
-                uri,
-                resp.status().as_u16(),
-                start_time.elapsed().as_millis()
+  // Too many nuisance logs for /healthz...
+  if (&method, uri.as_ref()) != (&Method::GET, "/healthz") {
+    log::info!("--> {} {}", method, uri);
+  }
+
+  (match (&method, uri.as_ref()) {
+    // TODO: turn off graphiql in prod.
+    (&Method::GET, "/") => Ok(juniper_hyper::graphiql("/graphql", None).await),
+    (&Method::GET, "/graphql") | (&Method::POST, "/graphql") => {
+      match req.headers().get(header::AUTHORIZATION) {
+        Some(header_val) => {
+          // Request has a auth header provided, try looking up session token.
+          match lookup_session_from_header(&*storage, config.session_ttl, header_val).await {
+            Ok(session) => {
+              log::trace!(
+                "auth header valid for user {:?}",
+                session.auth.github_node_id
               );
+              let resp = juniper_hyper::graphql(
+                root_node,
+                Arc::new(JuniperContext {
+                  auth: AuthContext::GitHub(session.auth.clone()),
+                  storage: storage.clone(),
+                  github: github.clone(),
+                }),
+                req,
+              )
+              .await;
+              Ok(auth::with_rotated_token(&config, &*storage, &session, false, resp).await)
             }
-            Ok(resp)
-          })
+            Err(_) => {
+              // User provided an auth header but it was invalid.
+              log::trace!("auth header invalid");
+              Ok(
+                Response::builder()
+                  .status(StatusCode::UNAUTHORIZED)
+                  .body(Body::empty())
+                  .expect("failed to construct response"),
+              )
+            }
+          }
         }
-      }))
+        None => {
+          log::trace!("no auth header found");
+          // Not auth header, so we're anonymous.
+          Ok(
+            juniper_hyper::graphql(
+              root_node,
+              Arc::new(JuniperContext {
+                auth: AuthContext::Anonymous,
+                storage: storage.clone(),
+                github: github.clone(),
+              }),
+              req,
+            )
+            .await,
+          )
+        }
+      }
     }
-  });
 
-  // The frontend uses 3000 by default, and this one is easier to configure.
-  // See https://community.render.com/t/502-bad-gateway-errors/616/4?u=samuela.
-  let addr = ([0, 0, 0, 0], 3001).into();
-  let server = Server::bind(&addr).serve(make_service);
-  println!("Listening on http://{}", addr);
+    (&Method::GET, "/subscriptions") => {
+      subscriptions::subscriptions_route(root_node, &config, storage, github, req).await
+    }
+    (&Method::POST, "/hasura_events/insert_comments") => {
+      subscriptions::insert_comments_event_route(&config, &*storage, req).await
+    }
 
-  if let Err(e) = server.await {
-    eprintln!("server error: {}", e)
+    (&Method::GET, "/admin/mirrors") => admin_mirrors_route(&config, req).await,
+
+    (&Method::GET, "/healthz") => Ok(
+      Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::empty())
+        .expect("failed to construct response"),
+    ),
+
+    (&Method::GET, "/login") => auth::login_route(&config, &*github, req).await,
+    (&Method::GET, "/oauth/callback/github") => {
+      auth::github_callback_route(&config, &*storage, &*github, req).await
+    }
+    (&Method::POST, "/login/device") => auth::device_login_route(&config, &*github, req).await,
+    (&Method::POST, "/login/device/token") => {
+      auth::device_token_route(&config, &*storage, &*github, req).await
+    }
+    (&Method::GET, "/logout") => auth::logout_route(&config, &*storage, req).await,
+    (&Method::GET, "/hasura_auth_webhook") => {
+      auth::hasura_auth_webhook(&config, &*storage, req).await
+    }
+    (&Method::GET, "/sessions") => auth::sessions_route(&config, &*storage, req).await,
+    (&Method::POST, "/sessions/revoke") => {
+      auth::revoke_session_route(&config, &*storage, req).await
+    }
+    (&Method::POST, "/sessions/revoke_others") => {
+      auth::revoke_other_sessions_route(&config, &*storage, req).await
+    }
+
+    _ => Ok(
+      Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+        .expect("failed to construct response"),
+    ),
+  })
+  .inspect(|resp| {
+    if (&method, uri.as_ref()) != (&Method::GET, "/healthz") {
+      log::info!(
+        "<-- {} {} {} {}ms",
+        method,
+        uri,
+        resp.status().as_u16(),
+        start_time.elapsed().as_millis()
+      );
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fakes::FakeGitHub;
+  use crate::fakes::InMemoryStorage;
+  use crate::storage::Storage;
+
+  const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+  async fn execute(context: &JuniperContext, query: &str) -> Result<juniper::Value, String> {
+    let schema = RootNode::new(Query, Mutation, subscriptions::Subscription);
+    match juniper::execute(query, None, &schema, &juniper::Variables::new(), context).await {
+      Ok((value, errors)) if errors.is_empty() => Ok(value),
+      Ok((_, errors)) => Err(format!("{:?}", errors)),
+      Err(e) => Err(format!("{:?}", e)),
+    }
+  }
+
+  fn start_thread_mutation(repo_id: &str) -> String {
+    format!(
+      r#"mutation {{
+        StartThread(repoIds: ["{}"], commitHash: "{}", filePath: "src/lib.rs", lineNumber: 3, body: "hello")
+      }}"#,
+      repo_id, COMMIT
+    )
+  }
+
+  #[tokio::test]
+  async fn start_thread() {
+    let storage = Arc::new(InMemoryStorage::default());
+    let github = Arc::new(FakeGitHub::default());
+    github.add_repo("R_public", "owner", "public", false, &[COMMIT]);
+    github.add_repo("R_private", "owner", "private", true, &[COMMIT]);
+    github.add_repo("R_other", "owner", "other", false, &[]);
+    let context = fakes::context(fakes::github_auth("U_1"), storage.clone(), github.clone());
+
+    // Private repos, repos without the commit, and repos that don't exist are all off limits.
+    for repo_id in [
+      "github-owner!private",
+      "github-owner!other",
+      "github-owner!missing",
+    ] {
+      assert!(execute(&context, &start_thread_mutation(repo_id))
+        .await
+        .is_err());
+    }
+    // So are anonymous users.
+    let anonymous = fakes::context(AuthContext::Anonymous, storage.clone(), github.clone());
+    assert!(
+      execute(&anonymous, &start_thread_mutation("github-owner!public"))
+        .await
+        .is_err()
+    );
+    assert!(storage.state.lock().unwrap().threads.is_empty());
+
+    let res = execute(&context, &start_thread_mutation("github-owner!public"))
+      .await
+      .unwrap();
+    let thread_id = res
+      .as_object_value()
+      .and_then(|o| o.get_field_value("StartThread"))
+      .and_then(|v| v.as_string_value())
+      .unwrap()
+      .to_string();
+
+    let state = storage.state.lock().unwrap();
+    assert_eq!(state.threads.len(), 1);
+    let thread = &state.threads[0];
+    assert_eq!(thread.id, thread_id);
+    assert_eq!(
+      (
+        thread.original_commit_hash.as_str(),
+        thread.original_file_path.as_str(),
+        thread.original_line_number
+      ),
+      (COMMIT, "src/lib.rs", 3)
+    );
+    assert_eq!(thread.comments.len(), 1);
+    assert_eq!(thread.comments[0].body, "hello");
+    assert_eq!(
+      thread.comments[0].author_github_node_id.as_deref(),
+      Some("U_1")
+    );
+    assert!(state
+      .commit_github_repos
+      .contains(&(COMMIT.to_string(), "R_public".to_string())));
+  }
+
+  #[tokio::test]
+  async fn start_thread_on_a_range() {
+    let storage = Arc::new(InMemoryStorage::default());
+    let github = Arc::new(FakeGitHub::default());
+    github.add_repo("R_public", "owner", "public", false, &[COMMIT]);
+    let context = fakes::context(fakes::github_auth("U_1"), storage.clone(), github.clone());
+    let start_thread = |range: &str| {
+      format!(
+        r#"mutation {{
+          StartThread(repoIds: ["github-owner!public"], commitHash: "{}", filePath: "src/lib.rs", lineNumber: 3, body: "hello", {})
+        }}"#,
+        COMMIT, range
+      )
+    };
+
+    for bad_range in [
+      "endLineNumber: 2",
+      "endLineNumber: 1003",
+      "startColumn: 4",
+      "startColumn: -1, endColumn: 2",
+      "startColumn: 4, endColumn: 2",
+    ] {
+      assert!(
+        execute(&context, &start_thread(bad_range)).await.is_err(),
+        "{}",
+        bad_range
+      );
+    }
+    execute(
+      &context,
+      &start_thread("endLineNumber: 7, startColumn: 4, endColumn: 2"),
+    )
+    .await
+    .unwrap();
+    let thread = storage.state.lock().unwrap().threads[0].clone();
+    assert_eq!(
+      (
+        thread.original_line_number,
+        thread.original_end_line_number,
+        thread.original_start_column,
+        thread.original_end_column
+      ),
+      (3, Some(7), Some(4), Some(2))
+    );
+    let thread = thread_from_record(thread, Some((3, 6)), 0.8, None);
+    assert_eq!(
+      (thread.line_number, thread.end_line_number),
+      (Some(3), Some(6))
+    );
+    assert_eq!(thread.original_end_line_number, 7);
+    assert!(thread.outdated);
+  }
+
+  // The same, but checking the commit with GitHub over HTTP.
+  #[tokio::test]
+  async fn start_thread_against_fake_github_server() {
+    let fake = Arc::new(FakeGitHub::default());
+    fake.add_repo("R_public", "owner", "public", false, &[COMMIT]);
+    fake.add_repo("R_private", "owner", "private", true, &[COMMIT]);
+    let url = fakes::serve_github(fake).await;
+    let storage = Arc::new(InMemoryStorage::default());
+    let context = fakes::context(
+      fakes::github_auth("U_1"),
+      storage.clone(),
+      Arc::new(github::GitHubApi::new(&fakes::config(&url))),
+    );
+
+    assert!(
+      execute(&context, &start_thread_mutation("github-owner!private"))
+        .await
+        .is_err()
+    );
+    execute(&context, &start_thread_mutation("github-owner!public"))
+      .await
+      .unwrap();
+    let state = storage.state.lock().unwrap();
+    assert_eq!(state.threads.len(), 1);
+    assert!(state
+      .commit_github_repos
+      .contains(&(COMMIT.to_string(), "R_public".to_string())));
+  }
+
+  #[tokio::test]
+  async fn add_edit_and_delete_comments() {
+    let storage = Arc::new(InMemoryStorage::default());
+    let github = Arc::new(FakeGitHub::default());
+    github.add_repo("R_public", "owner", "public", false, &[COMMIT]);
+    let author = fakes::context(fakes::github_auth("U_1"), storage.clone(), github.clone());
+    let other = fakes::context(fakes::github_auth("U_2"), storage.clone(), github.clone());
+    let anonymous = fakes::context(AuthContext::Anonymous, storage.clone(), github.clone());
+    let run = |context, query: String| async move {
+      execute(context, &query)
+        .await
+        .map(|value| serde_json::to_value(value).unwrap())
+    };
+
+    let res = run(&author, start_thread_mutation("github-owner!public"))
+      .await
+      .unwrap();
+    let thread_id = res["StartThread"].as_str().unwrap().to_string();
+    let add = |body: &str| {
+      format!(
+        r#"mutation {{ AddComment(threadId: "{}", body: {:?}) {{ id body authorGithubNodeId }} }}"#,
+        thread_id, body
+      )
+    };
+    assert!(run(&anonymous, add("hi")).await.is_err());
+    assert!(run(&other, add("  ")).await.is_err());
+    assert!(run(
+      &other,
+      add(&"x".repeat(comments::MAX_COMMENT_BODY_CHARS + 1))
+    )
+    .await
+    .is_err());
+    assert!(run(
+      &other,
+      r#"mutation { AddComment(threadId: "thread-404", body: "hi") { id } }"#.to_string()
+    )
+    .await
+    .is_err());
+    let res = run(&other, add("first")).await.unwrap();
+    assert_eq!(res["AddComment"]["body"], "first");
+    assert_eq!(res["AddComment"]["authorGithubNodeId"], "U_2");
+    let comment_id = res["AddComment"]["id"].as_str().unwrap().to_string();
+
+    // Only the author gets to edit or delete.
+    let edit = |body: &str| {
+      format!(
+        r#"mutation {{ EditComment(commentId: "{}", body: {:?}) {{ body editedAt deleted }} }}"#,
+        comment_id, body
+      )
+    };
+    let delete = format!(
+      r#"mutation {{ DeleteComment(commentId: "{}") }}"#,
+      comment_id
+    );
+    assert!(run(&author, edit("mine now")).await.is_err());
+    assert!(run(&anonymous, edit("mine now")).await.is_err());
+    assert!(run(&other, edit("")).await.is_err());
+    assert!(run(&author, delete.clone()).await.is_err());
+
+    let res = run(&other, edit("second")).await.unwrap();
+    assert_eq!(res["EditComment"]["body"], "second");
+    assert!(res["EditComment"]["editedAt"].is_string());
+    run(&other, edit("third")).await.unwrap();
+    let edits = format!(
+      r#"{{ commentEdits(commentId: "{}") {{ body }} }}"#,
+      comment_id
+    );
+    assert!(run(&anonymous, edits.clone()).await.is_err());
+    assert_eq!(
+      run(&author, edits.clone()).await.unwrap()["commentEdits"],
+      serde_json::json!([{ "body": "first" }, { "body": "second" }])
+    );
+
+    assert_eq!(
+      run(&other, delete.clone()).await.unwrap()["DeleteComment"],
+      comment_id.as_str()
+    );
+    // Deleted comments can't be changed any more, and what they used to say is gone.
+    assert!(run(&other, delete).await.is_err());
+    assert!(run(&other, edit("fourth")).await.is_err());
+    assert_eq!(
+      run(&author, edits).await.unwrap()["commentEdits"],
+      serde_json::json!([])
+    );
+    let threads = storage
+      .threads_for_file_paths(
+        &RepoWithCommit::GitHub(GitHubNodeId("R_public".to_string())),
+        vec!["src/lib.rs".to_string()],
+      )
+      .await
+      .unwrap();
+    let comment = comment_from_record(threads[0].comments[1].clone(), None);
+    assert!(comment.deleted);
+    assert_eq!(comment.body, "");
+  }
+
+  #[tokio::test]
+  async fn mentions() {
+    let storage = Arc::new(InMemoryStorage::default());
+    let github = Arc::new(FakeGitHub::default());
+    github.add_repo("R_public", "owner", "public", false, &[COMMIT]);
+    // Neither of them has ever logged in.
+    github.add_user("code-alice", "token-alice", "U_alice", "alice");
+    github.add_user("code-bob", "token-bob", "U_bob", "bob");
+    let context = fakes::context(fakes::github_auth("U_1"), storage.clone(), github.clone());
+    let run = |query: String| {
+      let context = &context;
+      async move {
+        execute(context, &query)
+          .await
+          .map(|value| serde_json::to_value(value).unwrap())
+      }
+    };
+    let usernames = |mentions: &serde_json::Value| {
+      mentions
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["githubUsername"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>()
+    };
+
+    let res = run(format!(
+      r#"mutation {{
+        StartThread(repoIds: ["github-owner!public"], commitHash: "{}", filePath: "src/lib.rs", lineNumber: 3, body: "@Alice, @nobody?")
+      }}"#,
+      COMMIT
+    ))
+    .await
+    .unwrap();
+    let thread_id = res["StartThread"].as_str().unwrap().to_string();
+    {
+      let state = storage.state.lock().unwrap();
+      assert_eq!(
+        state.threads[0].comments[0].mentions,
+        vec![storage::MentionRecord {
+          github_node_id: "U_alice".to_string(),
+          github_username: "alice".to_string(),
+        }]
+      );
+      assert_eq!(state.usernames["U_alice"], "alice");
+      assert!(!state.users.contains_key("U_alice"));
+    }
+    // Both mentions were looked up together.
+    assert_eq!(*github.user_lookups.lock().unwrap(), 1);
+
+    let add = |body: &str| {
+      format!(
+        r#"mutation {{ AddComment(threadId: "{}", body: {:?}) {{ id mentions {{ githubNodeId githubUsername }} }} }}"#,
+        thread_id, body
+      )
+    };
+    let res = run(add("@bob, not `@alice`")).await.unwrap();
+    assert_eq!(
+      res["AddComment"]["mentions"],
+      serde_json::json!([{ "githubNodeId": "U_bob", "githubUsername": "bob" }])
+    );
+    // Edits add mentions, but don't take any away.
+    let res = run(format!(
+      r#"mutation {{ EditComment(commentId: "{}", body: "@alice") {{ mentions {{ githubUsername }} }} }}"#,
+      res["AddComment"]["id"].as_str().unwrap()
+    ))
+    .await
+    .unwrap();
+    assert_eq!(usernames(&res["EditComment"]["mentions"]), ["alice", "bob"]);
+
+    // Without GitHub, only the users we already have can be mentioned.
+    *github.users_down.lock().unwrap() = true;
+    let res = run(add("@BOB @alice @carol")).await.unwrap();
+    assert_eq!(usernames(&res["AddComment"]["mentions"]), ["alice", "bob"]);
+  }
+
+  #[tokio::test]
+  async fn comment_reactions() {
+    let storage = Arc::new(InMemoryStorage::default());
+    let github = Arc::new(FakeGitHub::default());
+    github.add_repo("R_public", "owner", "public", false, &[COMMIT]);
+    let author = fakes::context(fakes::github_auth("U_1"), storage.clone(), github.clone());
+    let other = fakes::context(fakes::github_auth("U_2"), storage.clone(), github.clone());
+    let anonymous = fakes::context(AuthContext::Anonymous, storage.clone(), github.clone());
+    execute(&author, &start_thread_mutation("github-owner!public"))
+      .await
+      .unwrap();
+    let comment_id = storage.state.lock().unwrap().threads[0].comments[0]
+      .id
+      .clone();
+    async fn react(
+      context: &JuniperContext,
+      comment_id: &str,
+      mutation: &str,
+      reaction: &str,
+    ) -> Result<serde_json::Value, String> {
+      let query = format!(
+        r#"mutation {{ {}(commentId: "{}", reaction: {}) {{ reactions {{ reaction count viewerHasReacted }} }} }}"#,
+        mutation, comment_id, reaction
+      );
+      let value = execute(context, &query).await?;
+      Ok(serde_json::to_value(value).unwrap()[mutation]["reactions"].clone())
+    }
+
+    assert!(react(&anonymous, &comment_id, "AddReaction", "HEART")
+      .await
+      .is_err());
+    assert!(react(&author, &comment_id, "AddReaction", "PARTY_PARROT")
+      .await
+      .is_err());
+    react(&author, &comment_id, "AddReaction", "HEART")
+      .await
+      .unwrap();
+    // Adding the same reaction twice only counts once.
+    react(&author, &comment_id, "AddReaction", "HEART")
+      .await
+      .unwrap();
+    react(&author, &comment_id, "AddReaction", "THUMBS_UP")
+      .await
+      .unwrap();
+    assert_eq!(
+      react(&other, &comment_id, "AddReaction", "HEART")
+        .await
+        .unwrap(),
+      serde_json::json!([
+        { "reaction": "THUMBS_UP", "count": 1, "viewerHasReacted": false },
+        { "reaction": "HEART", "count": 2, "viewerHasReacted": true },
+      ])
+    );
+    react(&author, &comment_id, "RemoveReaction", "THUMBS_UP")
+      .await
+      .unwrap();
+    assert_eq!(
+      react(&author, &comment_id, "RemoveReaction", "THUMBS_UP")
+        .await
+        .unwrap(),
+      serde_json::json!([{ "reaction": "HEART", "count": 2, "viewerHasReacted": true }])
+    );
+
+    // Deleted comments don't take reactions, or show the ones they had.
+    execute(
+      &author,
+      &format!(
+        r#"mutation {{ DeleteComment(commentId: "{}") }}"#,
+        comment_id
+      ),
+    )
+    .await
+    .unwrap();
+    assert!(react(&other, &comment_id, "AddReaction", "EYES")
+      .await
+      .is_err());
+    let thread = storage.state.lock().unwrap().threads[0].clone();
+    assert!(thread_from_record(thread, None, 0.0, None).comments[0]
+      .reactions
+      .is_empty());
+  }
+
+  #[tokio::test]
+  async fn resolve_and_reopen_thread() {
+    let storage = Arc::new(InMemoryStorage::default());
+    let github = Arc::new(FakeGitHub::default());
+    github.add_repo("R_public", "owner", "public", false, &[COMMIT]);
+    let author = fakes::context(fakes::github_auth("U_1"), storage.clone(), github.clone());
+    let other = fakes::context(fakes::github_auth("U_2"), storage.clone(), github.clone());
+    let anonymous = fakes::context(AuthContext::Anonymous, storage.clone(), github.clone());
+    let res = execute(&author, &start_thread_mutation("github-owner!public"))
+      .await
+      .unwrap();
+    let thread_id = res
+      .as_object_value()
+      .and_then(|o| o.get_field_value("StartThread"))
+      .and_then(|v| v.as_string_value())
+      .unwrap()
+      .to_string();
+    let resolve = format!(r#"mutation {{ ResolveThread(threadId: "{}") }}"#, thread_id);
+    let reopen = format!(r#"mutation {{ ReopenThread(threadId: "{}") }}"#, thread_id);
+    let thread = || {
+      thread_from_record(
+        storage.state.lock().unwrap().threads[0].clone(),
+        Some((3, 3)),
+        1.0,
+        None,
+      )
+    };
+
+    assert!(execute(&anonymous, &resolve).await.is_err());
+    assert!(execute(
+      &other,
+      r#"mutation { ResolveThread(threadId: "thread-404") }"#
+    )
+    .await
+    .is_err());
+    assert!(!thread().resolved);
+
+    execute(&other, &resolve).await.unwrap();
+    let resolved = thread();
+    assert!(resolved.resolved);
+    assert!(resolved.resolved_at.is_some());
+    assert_eq!(resolved.resolved_by_github_node_id.as_deref(), Some("U_2"));
+    // Resolving again doesn't change who resolved it.
+    execute(&author, &resolve).await.unwrap();
+    assert_eq!(thread().resolved_by_github_node_id.as_deref(), Some("U_2"));
+
+    assert!(execute(&anonymous, &reopen).await.is_err());
+    execute(&author, &reopen).await.unwrap();
+    let reopened = thread();
+    assert!(!reopened.resolved);
+    assert_eq!(reopened.resolved_at, None);
+    assert_eq!(reopened.resolved_by_github_node_id, None);
+    execute(&author, &reopen).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn calculate_blamelines() {
+    blame_jobs::init(1);
+    let storage = Arc::new(InMemoryStorage::default());
+    let context = fakes::context(
+      AuthContext::Anonymous,
+      storage.clone(),
+      Arc::new(FakeGitHub::default()),
+    );
+    let mutation_in = |repo_id: &str, file_path: &str| {
+      format!(
+        r#"mutation {{ CalculateBlameLines(repoId: "{}", lastCommit: "{}", filePath: "{}") }}"#,
+        repo_id, COMMIT, file_path
+      )
+    };
+    let mutation = |file_path: &str| mutation_in("github-owner!repo", file_path);
+    let job_id = |value: juniper::Value| {
+      value
+        .as_object_value()
+        .and_then(|o| o.get_field_value("CalculateBlameLines"))
+        .and_then(|v| v.as_string_value())
+        .map(|s| s.to_string())
+    };
+
+    // Nothing to do when we already have the blamelines.
+    storage
+      .insert_blamelines(
+        COMMIT,
+        "done.rs",
+        vec![BlameLine {
+          original_commit: COMMIT.to_string(),
+          original_file_path: "done.rs".to_string(),
+          original_line_number: 1,
+        }],
+      )
+      .await
+      .unwrap();
+    assert_eq!(
+      job_id(execute(&context, &mutation("done.rs")).await.unwrap()),
+      None
+    );
+
+    // Otherwise we get a job, and asking again before it's done gets the same job. Nothing else gets to run until we
+    // yield, so the job can't have finished yet.
+    let first = job_id(execute(&context, &mutation("todo.rs")).await.unwrap()).unwrap();
+    let second = job_id(execute(&context, &mutation("todo.rs")).await.unwrap()).unwrap();
+    assert_eq!(first, second);
+    assert_eq!(storage.state.lock().unwrap().blame_jobs.len(), 1);
+    // Someone asking about the same commit in another repo gets their own job, and doesn't get to hand theirs to anyone
+    // else either.
+    let elsewhere = mutation_in("github-someone!else", "todo.rs");
+    let other = job_id(execute(&context, &elsewhere).await.unwrap()).unwrap();
+    assert_ne!(other, first);
+    assert_eq!(
+      job_id(execute(&context, &elsewhere).await.unwrap()).unwrap(),
+      other
+    );
+    assert_eq!(
+      job_id(execute(&context, &mutation("todo.rs")).await.unwrap()).unwrap(),
+      first
+    );
+    assert_eq!(storage.state.lock().unwrap().blame_jobs.len(), 2);
+
+    // Pretend that someone else got to the file first, so that the job doesn't have to go clone anything.
+    storage
+      .insert_blamelines(
+        COMMIT,
+        "todo.rs",
+        vec![BlameLine {
+          original_commit: COMMIT.to_string(),
+          original_file_path: "todo.rs".to_string(),
+          original_line_number: 1,
+        }],
+      )
+      .await
+      .unwrap();
+    let status_query = format!(r#"{{ blameJob(id: "{}") {{ status }} }}"#, first);
+    let mut status = String::new();
+    for _ in 0..100 {
+      tokio::task::yield_now().await;
+      let res = execute(&context, &status_query).await.unwrap();
+      status = format!("{}", res);
+      if status.contains("DONE") {
+        break;
+      }
+    }
+    assert!(status.contains("DONE"), "{}", status);
+
+    // Empty files don't have any blamelines to find, so once their job is done that's what counts.
+    storage
+      .insert_blamelines(COMMIT, "empty.rs", vec![])
+      .await
+      .unwrap();
+    let empty = storage
+      .insert_blame_job("github-owner!repo", COMMIT, "empty.rs")
+      .await
+      .unwrap();
+    assert!(job_id(execute(&context, &mutation("empty.rs")).await.unwrap()).is_some());
+    storage
+      .update_blame_job_status(&empty.id, "done", None)
+      .await
+      .unwrap();
+    assert_eq!(
+      job_id(execute(&context, &mutation("empty.rs")).await.unwrap()),
+      None
+    );
   }
 }
@@ -0,0 +1,157 @@
+// Finding @mentions in comment bodies, and figuring out who they're for.
+//
+// Mentions are looked up on GitHub, so that people who have never logged in to Cuddlefish can be mentioned too. They
+// get a github_users row without an access token, same as people who reply by email, which is what makes them
+// eligible for notifications. If GitHub can't be reached, we fall back to the users that we already have. Mentions of
+// logins that GitHub doesn't know are ignored.
+//
+// Recording mentions is best effort. By the time we get to it the comment has been saved, and failing the request
+// would only get the client to post it again.
+use crate::storage::MentionRecord;
+use crate::JuniperContext;
+use juniper::GraphQLObject;
+
+/// Like GitHub, we only look at this many distinct mentions in a comment. The rest are left as plain text.
+pub const MAX_MENTIONS_PER_COMMENT: usize = 20;
+
+/// GitHub logins are at most this long.
+const MAX_LOGIN_CHARS: usize = 39;
+
+/// Someone who was @mentioned in a comment.
+#[derive(Debug, PartialEq, GraphQLObject)]
+pub struct Mention {
+  github_node_id: String,
+  /// As it was when they were mentioned.
+  github_username: String,
+}
+
+impl From<MentionRecord> for Mention {
+  fn from(m: MentionRecord) -> Self {
+    Mention {
+      github_node_id: m.github_node_id,
+      github_username: m.github_username,
+    }
+  }
+}
+
+/// The logins @mentioned in `body`, in the order that they first appear and without duplicates, ignoring case. Like
+/// GitHub, we skip email addresses, team mentions (@org/team), and anything in `code`.
+pub fn parse_mentions(body: &str) -> Vec<String> {
+  let chars = body.chars().collect::<Vec<_>>();
+  let mut logins: Vec<String> = vec![];
+  // How many backticks opened the code span that we're in, if any. Fenced code blocks are just longer runs.
+  let mut code_fence = None;
+  let mut i = 0;
+  while i < chars.len() && logins.len() < MAX_MENTIONS_PER_COMMENT {
+    if chars[i] == '`' {
+      let run = chars[i..].iter().take_while(|&&c| c == '`').count();
+      code_fence = match code_fence {
+        None => Some(run),
+        Some(fence) if fence == run => None,
+        fence => fence,
+      };
+      i += run;
+      continue;
+    }
+    let starts_mention = chars[i] == '@'
+      && code_fence.is_none()
+      && (i == 0 || !(chars[i - 1].is_alphanumeric() || "_-./@".contains(chars[i - 1])));
+    if !starts_mention {
+      i += 1;
+      continue;
+    }
+    let len = chars[i + 1..]
+      .iter()
+      .take_while(|c| c.is_ascii_alphanumeric() || **c == '-')
+      .count();
+    let after = chars.get(i + 1 + len).copied();
+    let login = chars[i + 1..i + 1 + len]
+      .iter()
+      .collect::<String>()
+      .trim_end_matches('-')
+      .to_string();
+    i += 1 + len;
+    let is_login = !login.is_empty() && !login.starts_with('-') && login.len() <= MAX_LOGIN_CHARS;
+    if is_login && after != Some('/') && !logins.iter().any(|l| l.eq_ignore_ascii_case(&login)) {
+      logins.push(login);
+    }
+  }
+  logins
+}
+
+/// Who each of `logins` is, asking GitHub about all of them at once, or github_users if GitHub can't be reached. Users
+/// found on GitHub are upserted into github_users along the way.
+async fn resolve(context: &JuniperContext, logins: &[String]) -> Vec<MentionRecord> {
+  let mut mentions = vec![];
+  match context.github.lookup_users(logins).await {
+    Ok(users) => {
+      for user in users.into_iter().flatten() {
+        match context.storage.upsert_mentioned_user(&user).await {
+          Ok(()) => mentions.push(MentionRecord {
+            github_node_id: user.node_id.0,
+            github_username: user.login,
+          }),
+          Err(e) => log::warn!("can't save mentioned user @{}: {:?}", user.login, e),
+        }
+      }
+    }
+    Err(e) => {
+      log::warn!("can't look up {:?} on GitHub: {:?}", logins, e);
+      match context.storage.lookup_users_by_username(logins).await {
+        Ok(known) => mentions.extend(known),
+        Err(e) => log::warn!("can't look up users {:?}: {:?}", logins, e),
+      }
+    }
+  }
+  mentions.sort_by(|a, b| a.github_username.cmp(&b.github_username));
+  mentions
+}
+
+/// Record everyone @mentioned in `body` as being mentioned by the comment with `comment_id`. Returns who they were,
+/// ordered by username.
+pub async fn record(context: &JuniperContext, comment_id: &str, body: &str) -> Vec<MentionRecord> {
+  let logins = parse_mentions(body);
+  if logins.is_empty() {
+    return vec![];
+  }
+  let mentions = resolve(context, &logins).await;
+  if mentions.is_empty() {
+    return vec![];
+  }
+  match context.storage.add_mentions(comment_id, &mentions).await {
+    Ok(()) => mentions,
+    Err(e) => {
+      log::warn!("can't save mentions for comment {}: {:?}", comment_id, e);
+      vec![]
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parsing() {
+    assert_eq!(
+      parse_mentions("@alice, can you and @Bob-2 look at this? cc @ALICE"),
+      vec!["alice", "Bob-2"]
+    );
+    assert_eq!(
+      parse_mentions("(@carol) @dave- @dave."),
+      vec!["carol", "dave"]
+    );
+    // Not mentions: emails, teams, bare @s, logins that start with a dash or are too long, and code.
+    assert!(parse_mentions(&format!(
+      "me@example.com @org/team @ @-x @{} `@eve` ```\n@frank\n```",
+      "a".repeat(MAX_LOGIN_CHARS + 1)
+    ))
+    .is_empty());
+    assert_eq!(parse_mentions("``@grace`` @heidi"), vec!["heidi"]);
+    let many = (0..MAX_MENTIONS_PER_COMMENT + 5)
+      .map(|i| format!("@user{}", i))
+      .collect::<Vec<_>>()
+      .join(" ");
+    assert_eq!(parse_mentions(&many).len(), MAX_MENTIONS_PER_COMMENT);
+  }
+}
@@ -0,0 +1,658 @@
+// Cloning and fetching mirrors with git2, so that we don't need a `git` binary around.
+//
+// A mirror is a bare repo that has every ref from the remote under the same name, just like `git clone --mirror`.
+// Everything in here is blocking, so call it from `tokio::task::spawn_blocking`.
+use git2::AutotagOption;
+use git2::FetchOptions;
+use git2::FetchPrune;
+use git2::ObjectType;
+use git2::Oid;
+use git2::RemoteCallbacks;
+use git2::Repository;
+use std::cell::Cell;
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+const MIRROR_REFSPEC: &str = "+refs/*:refs/*";
+const REMOTE_NAME: &str = "origin";
+
+#[derive(Debug)]
+pub enum MirrorError {
+  /// The transfer took longer than `TransferOptions::timeout`.
+  TimedOut,
+  /// Someone called `CancelToken::cancel`.
+  Cancelled,
+  Git(git2::Error),
+  Io(std::io::Error),
+}
+
+impl std::fmt::Display for MirrorError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      MirrorError::TimedOut => write!(f, "git transfer timed out"),
+      MirrorError::Cancelled => write!(f, "git transfer was cancelled"),
+      MirrorError::Git(e) => write!(f, "git error: {}", e),
+      MirrorError::Io(e) => write!(f, "io error: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for MirrorError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      MirrorError::Git(e) => Some(e),
+      MirrorError::Io(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<git2::Error> for MirrorError {
+  fn from(e: git2::Error) -> Self {
+    MirrorError::Git(e)
+  }
+}
+
+impl From<std::io::Error> for MirrorError {
+  fn from(e: std::io::Error) -> Self {
+    MirrorError::Io(e)
+  }
+}
+
+/// Lets another thread stop a clone or fetch that's in progress.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+  pub fn cancel(&self) {
+    self.0.store(true, Ordering::Relaxed);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+/// Cancels the token when dropped. Useful for stopping a blocking transfer once nobody is waiting on it anymore.
+pub struct CancelOnDrop(pub CancelToken);
+
+impl Drop for CancelOnDrop {
+  fn drop(&mut self) {
+    self.0.cancel();
+  }
+}
+
+/// A snapshot of how far along a clone or fetch is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+  pub received_objects: usize,
+  pub indexed_objects: usize,
+  pub total_objects: usize,
+  pub received_bytes: usize,
+}
+
+type ProgressCallback = Arc<dyn Fn(Progress) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct TransferOptions {
+  /// Give up once the transfer has been going for this long. Note that this is only checked while the remote is sending
+  /// us something, so it won't fire while a connection attempt is hanging.
+  pub timeout: Option<Duration>,
+  pub cancel: CancelToken,
+  pub on_progress: Option<ProgressCallback>,
+}
+
+/// Why the last callback asked libgit2 to stop, if it did.
+#[derive(Clone, Copy)]
+enum Abort {
+  TimedOut,
+  Cancelled,
+}
+
+/// Run `f` with fetch options wired up to `opts`, and translate any aborts into the appropriate `MirrorError`.
+fn with_fetch_options<T>(
+  opts: &TransferOptions,
+  f: impl FnOnce(&mut FetchOptions<'_>) -> Result<T, git2::Error>,
+) -> Result<T, MirrorError> {
+  let start = Instant::now();
+  let abort = Cell::new(None);
+  let should_continue = || {
+    if opts.cancel.is_cancelled() {
+      abort.set(Some(Abort::Cancelled));
+    } else if opts.timeout.is_some_and(|t| start.elapsed() >= t) {
+      abort.set(Some(Abort::TimedOut));
+    }
+    abort.get().is_none()
+  };
+
+  // Don't bother connecting if we're already done for.
+  if !should_continue() {
+    return Err(abort_error(abort.get()));
+  }
+
+  let res = {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.transfer_progress(|stats| {
+      if let Some(on_progress) = &opts.on_progress {
+        on_progress(Progress {
+          received_objects: stats.received_objects(),
+          indexed_objects: stats.indexed_objects(),
+          total_objects: stats.total_objects(),
+          received_bytes: stats.received_bytes(),
+        });
+      }
+      should_continue()
+    });
+    // The "Counting objects..." chatter can go on for a while before any objects show up.
+    callbacks.sideband_progress(|_| should_continue());
+    let mut fetch_options = FetchOptions::new();
+    fetch_options
+      .remote_callbacks(callbacks)
+      .prune(FetchPrune::On)
+      // Tags are already covered by the mirror refspec.
+      .download_tags(AutotagOption::None);
+    f(&mut fetch_options)
+  };
+
+  // Depending on where it's interrupted, libgit2 doesn't always report an abort as ErrorCode::User, so any error after
+  // we've asked it to stop is on us.
+  res.map_err(|e| match abort.get() {
+    Some(_) => abort_error(abort.get()),
+    None => MirrorError::Git(e),
+  })
+}
+
+fn abort_error(abort: Option<Abort>) -> MirrorError {
+  match abort {
+    Some(Abort::TimedOut) => MirrorError::TimedOut,
+    _ => MirrorError::Cancelled,
+  }
+}
+
+/// Fetch every ref from the remote, overwriting and pruning our own as necessary. Also points HEAD at the remote's
+/// default branch.
+fn fetch_all(repo: &Repository, opts: &TransferOptions) -> Result<(), MirrorError> {
+  let mut remote = repo.find_remote(REMOTE_NAME)?;
+  with_fetch_options(opts, |fetch_options| {
+    remote.fetch(&[MIRROR_REFSPEC], Some(fetch_options), None)
+  })?;
+
+  // The default branch is still available after the fetch disconnects. Empty repos don't have one.
+  if let Ok(default_branch) = remote.default_branch() {
+    if let Some(default_branch) = default_branch.as_str() {
+      repo.set_head(default_branch)?;
+    }
+  }
+  Ok(())
+}
+
+/// The equivalent of `git clone --mirror url path`. `path` must not exist yet, or be an empty directory.
+pub fn clone_mirror(
+  url: &str,
+  path: &Path,
+  opts: &TransferOptions,
+) -> Result<Repository, MirrorError> {
+  let repo = Repository::init_bare(path)?;
+  repo.remote_with_fetch(REMOTE_NAME, url, MIRROR_REFSPEC)?;
+  repo.config()?.set_bool("remote.origin.mirror", true)?;
+  fetch_all(&repo, opts)?;
+  Ok(repo)
+}
+
+/// The equivalent of `git remote update` on a mirror created by `clone_mirror`.
+pub fn fetch_mirror(repo: &Repository, opts: &TransferOptions) -> Result<(), MirrorError> {
+  fetch_all(repo, opts)
+}
+
+/// Like `gc.autoPackLimit` and `gc.auto`, but a bit more eager since every fetch leaves another pack behind.
+const AUTO_PACK_LIMIT: usize = 20;
+const AUTO_LOOSE_LIMIT: usize = 1000;
+
+fn pack_files(repo: &Repository) -> std::io::Result<Vec<PathBuf>> {
+  let mut res = vec![];
+  for entry in std::fs::read_dir(repo.path().join("objects").join("pack"))? {
+    let path = entry?.path();
+    if path.extension() == Some(OsStr::new("pack")) {
+      res.push(path);
+    }
+  }
+  Ok(res)
+}
+
+/// The objects/xx directories that hold loose objects.
+fn loose_object_dirs(repo: &Repository) -> std::io::Result<Vec<PathBuf>> {
+  let mut res = vec![];
+  for entry in std::fs::read_dir(repo.path().join("objects"))? {
+    let entry = entry?;
+    let name = entry.file_name();
+    let name = name.to_string_lossy();
+    if name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+      res.push(entry.path());
+    }
+  }
+  Ok(res)
+}
+
+/// Whether enough packs or loose objects have piled up that `repack` is worth it. Packs of unreachable objects don't
+/// count, since repacking leaves them alone.
+pub fn needs_repack(repo: &Repository) -> Result<bool, MirrorError> {
+  let packs = pack_files(repo)?;
+  if packs
+    .iter()
+    .filter(|pack| !is_unreachable_pack(pack))
+    .count()
+    > AUTO_PACK_LIMIT
+  {
+    return Ok(true);
+  }
+  let mut loose = 0;
+  for dir in loose_object_dirs(repo)? {
+    loose += std::fs::read_dir(dir)?.count();
+  }
+  Ok(loose > AUTO_LOOSE_LIMIT)
+}
+
+/// How long unreachable objects stick around after they're repacked out of the way, just like `gc.pruneExpire`. Fetches
+/// prune, so a force push can leave the commits that threads were started on unreachable, and we'd rather not lose
+/// those right away.
+const PRUNE_EXPIRE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Packs of unreachable objects are marked with a `.keep` file holding this, so that we know not to repack them.
+const UNREACHABLE_KEEP_MESSAGE: &str = "unreachable objects\n";
+
+/// Packs that `repack` wrote for unreachable objects, and which are left alone until they expire.
+fn is_unreachable_pack(pack: &Path) -> bool {
+  std::fs::read_to_string(pack.with_extension("keep")).is_ok_and(|s| s == UNREACHABLE_KEEP_MESSAGE)
+}
+
+/// The ids of every object in `pack`, sorted. These come straight out of its version 2 `.idx` file.
+fn pack_object_ids(pack: &Path) -> Result<Vec<Oid>, MirrorError> {
+  const HEADER: [u8; 8] = [0xff, b't', b'O', b'c', 0, 0, 0, 2];
+  const FANOUT_END: usize = HEADER.len() + 256 * 4;
+  let idx = std::fs::read(pack.with_extension("idx"))?;
+  let invalid = || {
+    std::io::Error::new(
+      std::io::ErrorKind::InvalidData,
+      format!("{} isn't a version 2 pack index", pack.display()),
+    )
+  };
+  if idx.len() < FANOUT_END || idx[..HEADER.len()] != HEADER {
+    return Err(invalid().into());
+  }
+  // The last fanout entry is the total number of objects, and their ids come right after it.
+  let count = u32::from_be_bytes(idx[FANOUT_END - 4..FANOUT_END].try_into().unwrap()) as usize;
+  let ids = idx
+    .get(FANOUT_END..FANOUT_END + count * 20)
+    .ok_or_else(invalid)?;
+  Ok(
+    ids
+      .chunks(20)
+      .map(Oid::from_bytes)
+      .collect::<Result<_, _>>()?,
+  )
+}
+
+/// Write everything in `builder` out as a new pack in `repo`, and return the path to it.
+fn write_pack(repo: &Repository, builder: &mut git2::PackBuilder) -> Result<PathBuf, MirrorError> {
+  // Stream the pack straight into the object database so that we never have to hold the whole thing in memory. Packs
+  // are named after their trailing checksum, which we hang on to so that we know which pack is the new one.
+  let odb = repo.odb()?;
+  let mut writer = odb.packwriter()?;
+  let mut trailer = Vec::new();
+  let mut write_err = None;
+  builder.foreach(|chunk| {
+    trailer.extend_from_slice(chunk);
+    if trailer.len() > 20 {
+      trailer.drain(..trailer.len() - 20);
+    }
+    match writer.write_all(chunk) {
+      Ok(()) => true,
+      Err(e) => {
+        write_err = Some(e);
+        false
+      }
+    }
+  })?;
+  if let Some(e) = write_err {
+    return Err(e.into());
+  }
+  writer.commit()?;
+  Ok(
+    repo
+      .path()
+      .join("objects")
+      .join("pack")
+      .join(format!("pack-{}.pack", Oid::from_bytes(&trailer)?)),
+  )
+}
+
+/// Roughly `git gc`: write everything reachable from any ref into a single new pack, and everything else into a pack of
+/// unreachable objects that's kept around for `PRUNE_EXPIRE`. Then all of the other packs and loose objects can go,
+/// since everything in them is in one of the new packs (`git prune-packed`). Nothing else may be fetching into or
+/// reading from `repo` while this runs.
+pub fn repack(repo: &Repository) -> Result<(), MirrorError> {
+  let mut builder = repo.packbuilder()?;
+  let mut walk = repo.revwalk()?;
+  for reference in repo.references()? {
+    let target = match reference?.target() {
+      Some(target) => target,
+      // Symbolic refs point at other refs that we'll get to anyhow.
+      None => continue,
+    };
+    let object = repo.find_object(target, None)?;
+    match object.kind() {
+      Some(ObjectType::Commit) => walk.push(target)?,
+      // Annotated tags, and the odd ref that points straight at a tree or blob.
+      _ => {
+        builder.insert_recursive(target, None)?;
+        if let Ok(commit) = object.peel_to_commit() {
+          walk.push(commit.id())?;
+        }
+      }
+    }
+  }
+  builder.insert_walk(&mut walk)?;
+  if builder.object_count() == 0 {
+    return Ok(());
+  }
+
+  let old_packs = pack_files(repo)?;
+  let old_loose_dirs = loose_object_dirs(repo)?;
+  let (unreachable_packs, old_packs): (Vec<_>, Vec<_>) = old_packs
+    .into_iter()
+    .partition(|pack| is_unreachable_pack(pack));
+
+  let new_pack = write_pack(repo, &mut builder)?;
+  let reachable = pack_object_ids(&new_pack)?;
+
+  // Objects that were already unreachable last time around keep their place, and their age, in the pack they're in.
+  let mut already_kept = std::collections::HashSet::new();
+  for pack in &unreachable_packs {
+    already_kept.extend(pack_object_ids(pack)?);
+  }
+  let mut unreachable = repo.packbuilder()?;
+  let mut insert_err = None;
+  repo.odb()?.foreach(|oid| {
+    if reachable.binary_search(oid).is_err() && !already_kept.contains(oid) {
+      if let Err(e) = unreachable.insert_object(*oid, None) {
+        insert_err = Some(e);
+        return false;
+      }
+    }
+    true
+  })?;
+  if let Some(e) = insert_err {
+    return Err(e.into());
+  }
+  let unreachable_pack = if unreachable.object_count() > 0 {
+    let pack = write_pack(repo, &mut unreachable)?;
+    std::fs::write(pack.with_extension("keep"), UNREACHABLE_KEEP_MESSAGE)?;
+    Some(pack)
+  } else {
+    None
+  };
+
+  let now = std::time::SystemTime::now();
+  let mut doomed = old_packs;
+  for pack in unreachable_packs {
+    let kept_at = std::fs::metadata(pack.with_extension("keep"))?.modified()?;
+    if now.duration_since(kept_at).unwrap_or_default() > PRUNE_EXPIRE {
+      doomed.push(pack);
+    }
+  }
+  for pack in doomed {
+    if pack == new_pack || Some(&pack) == unreachable_pack.as_ref() {
+      continue;
+    }
+    // Every pack comes with an .idx, and maybe a few other files that are named the same.
+    for ext in ["pack", "idx", "rev", "keep"] {
+      match std::fs::remove_file(pack.with_extension(ext)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+      }
+    }
+  }
+  for dir in old_loose_dirs {
+    std::fs::remove_dir_all(dir)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use git2::Signature;
+  use std::sync::Mutex;
+
+  /// Add a commit with a single file to `branch` in a bare repo.
+  fn commit(repo: &Repository, branch: &str, contents: &str) -> Oid {
+    let blob = repo.blob(contents.as_bytes()).unwrap();
+    let mut tree = repo.treebuilder(None).unwrap();
+    tree.insert("file.txt", blob, 0o100644).unwrap();
+    let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+    let refname = format!("refs/heads/{}", branch);
+    let parent = repo
+      .find_reference(&refname)
+      .ok()
+      .map(|r| r.peel_to_commit().unwrap());
+    let sig = Signature::now("test", "test@example.com").unwrap();
+    repo
+      .commit(
+        Some(&refname),
+        &sig,
+        &sig,
+        contents,
+        &tree,
+        parent.iter().collect::<Vec<_>>().as_slice(),
+      )
+      .unwrap()
+  }
+
+  fn origin() -> (tempfile::TempDir, Repository, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = Repository::init_bare(dir.path()).unwrap();
+    repo.set_head("refs/heads/main").unwrap();
+    let url = url::Url::from_directory_path(dir.path())
+      .unwrap()
+      .to_string();
+    (dir, repo, url)
+  }
+
+  #[test]
+  fn clone_and_fetch() {
+    let (_origin_dir, origin, url) = origin();
+    let first = commit(&origin, "main", "first");
+    let other = commit(&origin, "other", "other");
+
+    let progress = Arc::new(Mutex::new(vec![]));
+    let opts = TransferOptions {
+      on_progress: Some({
+        let progress = progress.clone();
+        Arc::new(move |p| progress.lock().unwrap().push(p))
+      }),
+      ..Default::default()
+    };
+
+    let mirror_dir = tempfile::tempdir().unwrap();
+    let mirror_path = mirror_dir.path().join("mirror");
+    let mirror = clone_mirror(&url, &mirror_path, &opts).unwrap();
+    assert!(mirror.is_bare());
+    assert_eq!(mirror.head().unwrap().name(), Some("refs/heads/main"));
+    assert_eq!(mirror.head().unwrap().target(), Some(first));
+    assert_eq!(mirror.refname_to_id("refs/heads/other").unwrap(), other);
+    assert!(!progress.lock().unwrap().is_empty());
+
+    // New commits show up, and deleted branches go away.
+    let second = commit(&origin, "main", "second");
+    origin
+      .find_reference("refs/heads/other")
+      .unwrap()
+      .delete()
+      .unwrap();
+    fetch_mirror(&mirror, &opts).unwrap();
+    assert_eq!(mirror.refname_to_id("refs/heads/main").unwrap(), second);
+    assert!(mirror.find_reference("refs/heads/other").is_err());
+  }
+
+  #[test]
+  fn cancelled_and_timed_out() {
+    let (_origin_dir, origin, url) = origin();
+    commit(&origin, "main", "first");
+
+    let mirror_dir = tempfile::tempdir().unwrap();
+    let cancelled = TransferOptions::default();
+    cancelled.cancel.cancel();
+    assert!(matches!(
+      clone_mirror(&url, &mirror_dir.path().join("a"), &cancelled),
+      Err(MirrorError::Cancelled)
+    ));
+
+    let timed_out = TransferOptions {
+      timeout: Some(Duration::ZERO),
+      ..Default::default()
+    };
+    assert!(matches!(
+      clone_mirror(&url, &mirror_dir.path().join("b"), &timed_out),
+      Err(MirrorError::TimedOut)
+    ));
+
+    // Cancelling from inside of the transfer works too.
+    let opts = TransferOptions::default();
+    let cancel = opts.cancel.clone();
+    let opts = TransferOptions {
+      on_progress: Some(Arc::new(move |_| cancel.cancel())),
+      ..opts
+    };
+    assert!(matches!(
+      clone_mirror(&url, &mirror_dir.path().join("c"), &opts),
+      Err(MirrorError::Cancelled)
+    ));
+  }
+
+  #[test]
+  fn bad_remote() {
+    let mirror_dir = tempfile::tempdir().unwrap();
+    let missing = url::Url::from_directory_path(mirror_dir.path().join("nope"))
+      .unwrap()
+      .to_string();
+    assert!(matches!(
+      clone_mirror(
+        &missing,
+        &mirror_dir.path().join("mirror"),
+        &TransferOptions::default()
+      ),
+      Err(MirrorError::Git(_))
+    ));
+  }
+
+  #[test]
+  fn repack_into_one_pack() {
+    let (_origin_dir, origin, url) = origin();
+    let mirror_dir = tempfile::tempdir().unwrap();
+    let mirror = clone_mirror(
+      &url,
+      &mirror_dir.path().join("mirror"),
+      &TransferOptions::default(),
+    )
+    .unwrap();
+
+    // Every fetch leaves a pack behind.
+    let mut commits = vec![];
+    for i in 0..5 {
+      commits.push(commit(&origin, "main", &format!("commit {}", i)));
+      fetch_mirror(&mirror, &TransferOptions::default()).unwrap();
+    }
+    let tagged = commits[2];
+    let sig = Signature::now("test", "test@example.com").unwrap();
+    origin
+      .tag(
+        "v1",
+        &origin.find_object(tagged, None).unwrap(),
+        &sig,
+        "v1",
+        false,
+      )
+      .unwrap();
+    fetch_mirror(&mirror, &TransferOptions::default()).unwrap();
+    assert!(pack_files(&mirror).unwrap().len() > 1);
+
+    repack(&mirror).unwrap();
+    assert_eq!(pack_files(&mirror).unwrap().len(), 1);
+    assert!(loose_object_dirs(&mirror).unwrap().is_empty());
+    assert!(!needs_repack(&mirror).unwrap());
+
+    // Everything is still there, as seen from a fresh handle.
+    let mirror = Repository::open(mirror.path()).unwrap();
+    for c in commits {
+      let c = mirror.find_commit(c).unwrap();
+      assert!(c.tree().unwrap().get_name("file.txt").is_some());
+    }
+    let tag = mirror.revparse_single("refs/tags/v1").unwrap();
+    assert_eq!(tag.peel_to_commit().unwrap().id(), tagged);
+
+    // Repacking an already packed repo is a no-op.
+    repack(&mirror).unwrap();
+    assert_eq!(pack_files(&mirror).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn repack_keeps_unreachable_objects_for_a_while() {
+    let (_origin_dir, origin, url) = origin();
+    commit(&origin, "main", "first");
+    let mirror_dir = tempfile::tempdir().unwrap();
+    let mirror = clone_mirror(
+      &url,
+      &mirror_dir.path().join("mirror"),
+      &TransferOptions::default(),
+    )
+    .unwrap();
+
+    // Someone pushes a branch and then deletes it again, which the mirror prunes.
+    let gone = commit(&origin, "feature", "gone");
+    fetch_mirror(&mirror, &TransferOptions::default()).unwrap();
+    origin
+      .find_reference("refs/heads/feature")
+      .unwrap()
+      .delete()
+      .unwrap();
+    fetch_mirror(&mirror, &TransferOptions::default()).unwrap();
+    assert!(mirror.find_reference("refs/heads/feature").is_err());
+
+    repack(&mirror).unwrap();
+    let packs = pack_files(&mirror).unwrap();
+    assert_eq!(packs.len(), 2);
+    assert_eq!(packs.iter().filter(|p| is_unreachable_pack(p)).count(), 1);
+    assert!(loose_object_dirs(&mirror).unwrap().is_empty());
+    assert!(!needs_repack(&mirror).unwrap());
+    let mirror = Repository::open(mirror.path()).unwrap();
+    assert_eq!(mirror.find_commit(gone).unwrap().message(), Some("gone"));
+
+    // Repacking again doesn't make another copy of them, or make them any younger.
+    repack(&mirror).unwrap();
+    assert_eq!(pack_files(&mirror).unwrap().len(), 2);
+
+    // Once they've been unreachable for long enough, they go away for good.
+    for pack in pack_files(&mirror).unwrap() {
+      if is_unreachable_pack(&pack) {
+        std::fs::File::options()
+          .write(true)
+          .open(pack.with_extension("keep"))
+          .unwrap()
+          .set_modified(std::time::SystemTime::now() - PRUNE_EXPIRE - Duration::from_secs(60))
+          .unwrap();
+      }
+    }
+    repack(&mirror).unwrap();
+    assert_eq!(pack_files(&mirror).unwrap().len(), 1);
+    let mirror = Repository::open(mirror.path()).unwrap();
+    assert!(mirror.find_commit(gone).is_err());
+    assert!(mirror.head().unwrap().peel_to_commit().is_ok());
+  }
+}
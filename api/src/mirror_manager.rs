@@ -0,0 +1,575 @@
+// Owns the mirrors in MIRRORS_DIR and makes sure that concurrent requests don't step on each other's toes.
+//
+// Every clone or fetch of a repo happens while holding that repo's lock, and anyone who asks for a fetch while one is
+// already in progress just waits on that one instead of starting another. Clones and fetches run in their own task, so
+// they finish (and let go of the lock) even if everyone waiting on them gives up. Clones are written to a staging directory
+// and only moved into place once they're complete, so a crash mid-clone never leaves a broken mirror behind.
+//
+// We also keep MIRRORS_DIR under its quota by evicting the least recently used mirrors after every clone/fetch, and
+// periodically repack the mirrors that are in use so that fetches don't pile up packs forever. Neither happens to a
+// mirror while anyone has it open.
+use crate::mirror;
+use crate::mirror_store::dir_size;
+use crate::mirror_store::MirrorStore;
+use crate::mirror_store::UsageReport;
+use crate::repo_id::RepoId;
+use anyhow::anyhow;
+use anyhow::ensure;
+use anyhow::Result;
+use futures::future::BoxFuture;
+use futures::future::Shared;
+use futures::FutureExt;
+use git2::Repository;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+/// Clones of big repos can legitimately take a while, but nothing should take this long.
+const CLONE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where in-progress clones live. The leading dot keeps it from colliding with any `RepoId`.
+const PARTIAL_DIR: &str = ".partial";
+/// Where the blame cache used to live, before each mirror got its own.
+const SHARED_BLAME_CACHE_DIR: &str = ".blame-cache";
+
+// Resolves to the size of the mirror on disk afterwards. The error has to be Clone so that every waiter can get a copy
+// of it.
+type SyncFuture = Shared<BoxFuture<'static, Result<u64, Arc<anyhow::Error>>>>;
+
+#[derive(Default)]
+struct RepoState {
+  /// Held for the duration of any clone or fetch, or anything else that needs the mirror on disk to stay put.
+  lock: tokio::sync::Mutex<()>,
+  /// The clone/fetch that's currently in progress, if any.
+  in_flight: Mutex<Option<SyncFuture>>,
+  /// Read locked by everyone who has the mirror open. Eviction and repacking only go ahead if they can get the write
+  /// lock without waiting.
+  readers: Arc<tokio::sync::RwLock<()>>,
+}
+
+/// A mirror that's open for reading. It stays on disk, and doesn't get repacked out from under us, until this is
+/// dropped.
+pub struct MirrorRepo {
+  repo: Repository,
+  _reader: tokio::sync::OwnedRwLockReadGuard<()>,
+}
+
+impl Deref for MirrorRepo {
+  type Target = Repository;
+
+  fn deref(&self) -> &Repository {
+    &self.repo
+  }
+}
+
+pub struct MirrorManager {
+  root: PathBuf,
+  /// Where GitHub repos get cloned from. See `RepoId::clone_url`.
+  github_url: String,
+  repos: Mutex<HashMap<RepoId, Arc<RepoState>>>,
+  store: MirrorStore,
+}
+
+impl MirrorManager {
+  /// With a `quota_bytes` of None, mirrors are never evicted.
+  pub fn new(root: PathBuf, quota_bytes: Option<u64>, github_url: String) -> Self {
+    MirrorManager {
+      root,
+      github_url,
+      repos: Mutex::new(HashMap::new()),
+      store: MirrorStore::new(quota_bytes),
+    }
+  }
+
+  pub fn mirror_dir(&self, repo_id: &RepoId) -> PathBuf {
+    // repo_id has its `/`s escaped, so it's safe as a file path.
+    self.root.join(repo_id.to_string())
+  }
+
+  fn partial_dir(&self, repo_id: &RepoId) -> PathBuf {
+    self.root.join(PARTIAL_DIR).join(repo_id.to_string())
+  }
+
+  fn state(&self, repo_id: &RepoId) -> Arc<RepoState> {
+    self
+      .repos
+      .lock()
+      .unwrap()
+      .entry(repo_id.clone())
+      .or_default()
+      .clone()
+  }
+
+  /// Throw away any clones that were still in progress when we last shut down. Call this once at startup, before
+  /// anything else touches the mirrors.
+  pub fn remove_partial_clones(&self) -> Result<()> {
+    let partial_root = self.root.join(PARTIAL_DIR);
+    if partial_root.exists() {
+      log::info!(
+        "removing partial clones in {}",
+        partial_root.to_string_lossy()
+      );
+      std::fs::remove_dir_all(&partial_root)?;
+    }
+    Ok(())
+  }
+
+  /// The blame cache used to be shared by every mirror, where nothing kept it under quota. Each mirror has its own now,
+  /// so the old one is just taking up space. Call this once at startup.
+  pub fn remove_shared_blame_cache(&self) -> Result<()> {
+    let shared = self.root.join(SHARED_BLAME_CACHE_DIR);
+    if shared.exists() {
+      log::info!("removing old blame cache in {}", shared.to_string_lossy());
+      std::fs::remove_dir_all(&shared)?;
+    }
+    Ok(())
+  }
+
+  /// Take stock of the mirrors that are already on disk. Call this once at startup.
+  pub fn scan_usage(&self) -> Result<()> {
+    self.store.scan(&self.root)?;
+    log::info!(
+      "mirrors are using {} bytes of disk",
+      self.store.used_bytes()
+    );
+    Ok(())
+  }
+
+  pub fn usage_report(&self) -> UsageReport {
+    self.store.report()
+  }
+
+  /// Get a Repository object for a given RepoId. If we already have the repo cloned, great. If not, clone it first.
+  pub async fn repo(&self, repo_id: &RepoId) -> Result<MirrorRepo> {
+    self.store.touch(repo_id);
+    let reader = self.state(repo_id).readers.clone().read_owned().await;
+    let path = self.mirror_dir(repo_id);
+    if path.is_dir() {
+      match Repository::open(&path) {
+        Ok(repo) => {
+          return Ok(MirrorRepo {
+            repo,
+            _reader: reader,
+          })
+        }
+        // Most likely left over from a crash before we started staging clones.
+        Err(e) => log::warn!(
+          "couldn't open mirror {}, recloning: {}",
+          path.to_string_lossy(),
+          e
+        ),
+      }
+    }
+    self.sync(repo_id).await?;
+    Ok(MirrorRepo {
+      repo: Repository::open(&path)?,
+      _reader: reader,
+    })
+  }
+
+  /// Get a Repository object for a given RepoId that is guaranteed to contain `commit`, cloning and fetching as
+  /// necessary.
+  pub async fn repo_with_commit(&self, repo_id: &RepoId, commit: &str) -> Result<MirrorRepo> {
+    let mut repo = self.repo(repo_id).await?;
+
+    // We may have already pulled this commit to get the blame on a different file, or it may have gotten pulled down
+    // incidentally previously.
+    if commit_exists(&repo, commit) {
+      return Ok(repo);
+    }
+    log::info!(
+      "Commit {} not found in repo {}. Pulling all changes.",
+      commit,
+      repo_id
+    );
+    self.sync(repo_id).await?;
+    // Sync may have replaced the mirror entirely, so open it up fresh.
+    repo.repo = Repository::open(self.mirror_dir(repo_id))?;
+    ensure!(
+      commit_exists(&repo, commit),
+      "commit still doesn't exist after pulling"
+    );
+    Ok(repo)
+  }
+
+  /// Bring `repo_id` up to date with its remote, if we have a mirror of it. Mirrors that we don't have, say because
+  /// they were evicted, are left for whoever needs them next to clone. Returns whether anything changed.
+  pub async fn refresh(&self, repo_id: &RepoId) -> Result<bool> {
+    // Keeps the mirror from being evicted between checking for it and fetching into it.
+    let _reader = self.state(repo_id).readers.clone().read_owned().await;
+    let path = self.mirror_dir(repo_id);
+    let before = ref_targets(&path);
+    if before.is_none() {
+      return Ok(false);
+    }
+    self.sync(repo_id).await?;
+    Ok(ref_targets(&path) != before)
+  }
+
+  /// Clone or fetch `repo_id`, or wait on the clone/fetch that's already in progress.
+  async fn sync(&self, repo_id: &RepoId) -> Result<()> {
+    let state = self.state(repo_id);
+    let fut = {
+      let mut in_flight = state.in_flight.lock().unwrap();
+      match &*in_flight {
+        Some(fut) => {
+          log::trace!("joining in-flight sync of {}", repo_id);
+          fut.clone()
+        }
+        None => {
+          let task = tokio::spawn(sync_inner(
+            state.clone(),
+            repo_id.clone(),
+            repo_id.clone_url(&self.github_url),
+            self.mirror_dir(repo_id),
+            self.partial_dir(repo_id),
+          ));
+          let fut = async move {
+            match task.await {
+              Ok(res) => res,
+              Err(e) => Err(Arc::new(anyhow!(e))),
+            }
+          }
+          .boxed()
+          .shared();
+          *in_flight = Some(fut.clone());
+          fut
+        }
+      }
+    };
+    let size_bytes = fut.await.map_err(|e| anyhow!("{:#}", e))?;
+    self.store.set_size(repo_id, size_bytes);
+    self.enforce_quota(repo_id).await;
+    Ok(())
+  }
+
+  /// Record that something other than a clone/fetch, like the blame cache, wrote `bytes` into the mirror of `repo_id`,
+  /// and evict other mirrors if that puts us over quota.
+  pub async fn grew(&self, repo_id: &RepoId, bytes: u64) {
+    self.store.add_size(repo_id, bytes);
+    self.enforce_quota(repo_id).await;
+  }
+
+  /// Evict least recently used mirrors until we're back under quota. Mirrors that are open or busy being
+  /// cloned/fetched/repacked are skipped for now, they'll get their turn next time around.
+  async fn enforce_quota(&self, keep: &RepoId) {
+    for repo_id in self.store.eviction_candidates(keep) {
+      let state = self.state(&repo_id);
+      let _lock = match state.lock.try_lock() {
+        Ok(lock) => lock,
+        Err(_) => continue,
+      };
+      let _writer = match state.readers.try_write() {
+        Ok(writer) => writer,
+        Err(_) => continue,
+      };
+      let path = self.mirror_dir(&repo_id);
+      log::info!("evicting mirror {} to stay under quota", repo_id);
+      match tokio::task::spawn_blocking(move || remove_dir_if_exists(&path)).await {
+        Ok(Ok(())) => self.store.remove(&repo_id),
+        Ok(Err(e)) => log::error!("failed to evict mirror {}: {:?}", repo_id, e),
+        Err(e) => log::error!("failed to evict mirror {}: {:?}", repo_id, e),
+      }
+    }
+  }
+
+  /// Repack every mirror that's been used within `window` and has accumulated enough packs/loose objects to make it
+  /// worthwhile. Mirrors that are open are skipped, since repacking deletes the packs that they might be reading from.
+  pub async fn repack_hot_mirrors(&self, window: Duration) {
+    let since = SystemTime::now() - window;
+    for repo_id in self.store.used_since(since) {
+      let state = self.state(&repo_id);
+      let _lock = state.lock.lock().await;
+      let _writer = match state.readers.try_write() {
+        Ok(writer) => writer,
+        Err(_) => {
+          log::debug!("not repacking mirror {}, it's in use", repo_id);
+          continue;
+        }
+      };
+      let path = self.mirror_dir(&repo_id);
+      let res = tokio::task::spawn_blocking(move || -> Result<Option<u64>> {
+        let repo = Repository::open(&path)?;
+        if !mirror::needs_repack(&repo)? {
+          return Ok(None);
+        }
+        mirror::repack(&repo)?;
+        Ok(Some(dir_size(&path)?))
+      })
+      .await;
+      match res {
+        Ok(Ok(Some(size_bytes))) => {
+          log::info!("repacked mirror {}, now {} bytes", repo_id, size_bytes);
+          self.store.set_size(&repo_id, size_bytes);
+        }
+        Ok(Ok(None)) => {}
+        Ok(Err(e)) => log::error!("failed to repack mirror {}: {:?}", repo_id, e),
+        Err(e) => log::error!("failed to repack mirror {}: {:?}", repo_id, e),
+      }
+    }
+  }
+}
+
+async fn sync_inner(
+  state: Arc<RepoState>,
+  repo_id: RepoId,
+  url: String,
+  path: PathBuf,
+  partial_path: PathBuf,
+) -> Result<u64, Arc<anyhow::Error>> {
+  let res = async {
+    let _lock = state.lock.lock().await;
+    if Repository::open(&path).is_ok() {
+      fetch(&repo_id, path.clone()).await?;
+    } else {
+      clone(&repo_id, url, path.clone(), partial_path).await?;
+    }
+    Ok(tokio::task::spawn_blocking(move || dir_size(&path)).await??)
+  }
+  .await;
+  // Anyone who shows up from now on gets a fresh sync.
+  *state.in_flight.lock().unwrap() = None;
+  res.map_err(Arc::new)
+}
+
+async fn clone(repo_id: &RepoId, url: String, path: PathBuf, partial_path: PathBuf) -> Result<()> {
+  log::info!("Cloning repo {}...", url);
+  let opts = transfer_options(repo_id, CLONE_TIMEOUT);
+  // Stop the transfer if the task gets cancelled, e.g. at shutdown.
+  let _cancel_on_drop = mirror::CancelOnDrop(opts.cancel.clone());
+  tokio::task::spawn_blocking(move || -> Result<()> {
+    // We hold the repo's lock, so anything in here is from a clone that didn't finish.
+    remove_dir_if_exists(&partial_path)?;
+    std::fs::create_dir_all(&partial_path)?;
+    mirror::clone_mirror(&url, &partial_path, &opts)?;
+    // And this would be a mirror that we couldn't open.
+    remove_dir_if_exists(&path)?;
+    std::fs::rename(&partial_path, &path)?;
+    Ok(())
+  })
+  .await??;
+  log::info!("Clone complete.");
+  Ok(())
+}
+
+async fn fetch(repo_id: &RepoId, path: PathBuf) -> Result<()> {
+  let opts = transfer_options(repo_id, FETCH_TIMEOUT);
+  let _cancel_on_drop = mirror::CancelOnDrop(opts.cancel.clone());
+  tokio::task::spawn_blocking(move || mirror::fetch_mirror(&Repository::open(path)?, &opts))
+    .await??;
+  Ok(())
+}
+
+fn remove_dir_if_exists(path: &Path) -> std::io::Result<()> {
+  match std::fs::remove_dir_all(path) {
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+    res => res,
+  }
+}
+
+/// Log clone/fetch progress every so often so that we can tell what's going on with big repos.
+fn transfer_options(repo_id: &RepoId, timeout: Duration) -> mirror::TransferOptions {
+  let repo_id = repo_id.to_string();
+  let last_logged = Mutex::new(Instant::now());
+  mirror::TransferOptions {
+    timeout: Some(timeout),
+    on_progress: Some(Arc::new(move |progress| {
+      let mut last_logged = last_logged.lock().unwrap();
+      if last_logged.elapsed() >= PROGRESS_LOG_INTERVAL {
+        *last_logged = Instant::now();
+        log::info!(
+          "{}: received {}/{} objects ({} bytes), indexed {}",
+          repo_id,
+          progress.received_objects,
+          progress.total_objects,
+          progress.received_bytes,
+          progress.indexed_objects
+        );
+      }
+    })),
+    ..Default::default()
+  }
+}
+
+/// Every ref in the mirror at `path` and what it points at, or None if there's no mirror there.
+fn ref_targets(path: &Path) -> Option<Vec<(String, Option<git2::Oid>)>> {
+  let repo = Repository::open(path).ok()?;
+  let mut res = repo
+    .references()
+    .ok()?
+    .filter_map(|r| r.ok())
+    .filter_map(|r| Some((r.name()?.to_string(), r.target())))
+    .collect::<Vec<_>>();
+  res.sort();
+  Some(res)
+}
+
+/// Does the given commit exist in the local repo?
+pub fn commit_exists(repo: &Repository, commit: &str) -> bool {
+  match repo.revparse_single(commit) {
+    Err(_) => false,
+    Ok(obj) => obj.as_commit().is_some(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use git2::Oid;
+  use git2::Signature;
+
+  fn commit(repo: &Repository, contents: &str) -> Oid {
+    let blob = repo.blob(contents.as_bytes()).unwrap();
+    let mut tree = repo.treebuilder(None).unwrap();
+    tree.insert("file.txt", blob, 0o100644).unwrap();
+    let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+    let parent = repo.head().ok().map(|r| r.peel_to_commit().unwrap());
+    let sig = Signature::now("test", "test@example.com").unwrap();
+    repo
+      .commit(
+        Some("HEAD"),
+        &sig,
+        &sig,
+        contents,
+        &tree,
+        parent.iter().collect::<Vec<_>>().as_slice(),
+      )
+      .unwrap()
+  }
+
+  // We never go through parse_repo_id here, so file:// urls are fair game.
+  fn file_repo_id(path: &Path) -> RepoId {
+    RepoId::GitUrl {
+      url: url::Url::from_directory_path(path).unwrap().to_string(),
+    }
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn concurrent_clones_and_fetches() {
+    let origin_dir = tempfile::tempdir().unwrap();
+    let origin = Repository::init_bare(origin_dir.path()).unwrap();
+    let first = commit(&origin, "first");
+    let repo_id = file_repo_id(origin_dir.path());
+
+    let mirrors_dir = tempfile::tempdir().unwrap();
+    let manager = Arc::new(MirrorManager::new(
+      mirrors_dir.path().to_path_buf(),
+      None,
+      "https://github.com".to_string(),
+    ));
+    // Pretend that we crashed in the middle of a clone last time around.
+    std::fs::create_dir_all(manager.partial_dir(&repo_id).join("objects")).unwrap();
+    manager.remove_partial_clones().unwrap();
+    assert!(!mirrors_dir.path().join(PARTIAL_DIR).exists());
+
+    let all_ok = |commit: Oid| {
+      let handles = (0..8)
+        .map(|_| {
+          let manager = manager.clone();
+          let repo_id = repo_id.clone();
+          tokio::spawn(async move {
+            manager
+              .repo_with_commit(&repo_id, &commit.to_string())
+              .await
+              .map(|_| ())
+          })
+        })
+        .collect::<Vec<_>>();
+      async move {
+        for handle in handles {
+          handle.await.unwrap().unwrap();
+        }
+      }
+    };
+
+    all_ok(first).await;
+    let second = commit(&origin, "second");
+    all_ok(second).await;
+
+    // Everything should have ended up in the one mirror, with nothing left behind in staging.
+    let entries = |path: &Path| {
+      std::fs::read_dir(path)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>()
+    };
+    let mut mirrors = entries(mirrors_dir.path());
+    mirrors.sort();
+    assert_eq!(mirrors, vec![PARTIAL_DIR.to_string(), repo_id.to_string()]);
+    assert!(entries(&mirrors_dir.path().join(PARTIAL_DIR)).is_empty());
+
+    // A commit that doesn't exist anywhere is an error for everyone waiting on it.
+    let missing = manager
+      .repo_with_commit(&repo_id, "0000000000000000000000000000000000000001")
+      .await;
+    assert!(missing.is_err());
+  }
+  #[tokio::test]
+  async fn open_mirrors_are_not_evicted() {
+    let origin_dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    for dir in &origin_dirs {
+      commit(&Repository::init_bare(dir.path()).unwrap(), "first");
+    }
+    let [a, b] = [0, 1].map(|i| file_repo_id(origin_dirs[i].path()));
+
+    let mirrors_dir = tempfile::tempdir().unwrap();
+    // Room for no more than one mirror.
+    let manager = MirrorManager::new(
+      mirrors_dir.path().to_path_buf(),
+      Some(1),
+      "https://github.com".to_string(),
+    );
+    let open = manager.repo(&a).await.unwrap();
+    manager.repo(&b).await.unwrap();
+    assert!(manager.mirror_dir(&a).is_dir());
+    assert!(open.head().is_ok());
+
+    drop(open);
+    manager.refresh(&b).await.unwrap();
+    assert!(!manager.mirror_dir(&a).exists());
+    assert!(manager.mirror_dir(&b).is_dir());
+
+    // Refreshing doesn't bring evicted mirrors back.
+    assert!(!manager.refresh(&a).await.unwrap());
+    assert!(!manager.mirror_dir(&a).exists());
+  }
+  #[tokio::test]
+  async fn syncs_finish_without_waiters() {
+    let origin_dir = tempfile::tempdir().unwrap();
+    commit(&Repository::init_bare(origin_dir.path()).unwrap(), "first");
+    let repo_id = file_repo_id(origin_dir.path());
+
+    let mirrors_dir = tempfile::tempdir().unwrap();
+    let manager = MirrorManager::new(
+      mirrors_dir.path().to_path_buf(),
+      None,
+      "https://github.com".to_string(),
+    );
+    // Start a clone and then lose interest in it right away.
+    let mut repo = Box::pin(manager.repo(&repo_id));
+    assert!(futures::poll!(&mut repo).is_pending());
+    drop(repo);
+
+    // The clone should carry on anyway, and let go of the repo's lock when it's done. Otherwise repacks and everything
+    // else would be stuck waiting on it forever.
+    tokio::time::timeout(Duration::from_secs(30), async {
+      while Repository::open(manager.mirror_dir(&repo_id)).is_err() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    })
+    .await
+    .unwrap();
+    let state = manager.state(&repo_id);
+    let _lock = tokio::time::timeout(Duration::from_secs(30), state.lock.lock())
+      .await
+      .unwrap();
+  }
+}
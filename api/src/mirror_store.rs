@@ -0,0 +1,250 @@
+// Keeps track of how much disk each mirror is using and when it was last used, so that we know which ones to evict
+// once MIRRORS_DIR gets too big. This is purely bookkeeping; MirrorManager does the actual evicting.
+use crate::repo_id::parse_repo_id;
+use crate::repo_id::RepoId;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[derive(Clone, Debug)]
+pub struct MirrorUsage {
+  pub size_bytes: u64,
+  pub last_access: SystemTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MirrorUsageReport {
+  pub repo_id: String,
+  pub size_bytes: u64,
+  /// Seconds since the epoch.
+  pub last_access: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+  pub quota_bytes: Option<u64>,
+  pub used_bytes: u64,
+  /// Most recently used first.
+  pub mirrors: Vec<MirrorUsageReport>,
+}
+
+pub struct MirrorStore {
+  quota_bytes: Option<u64>,
+  usage: Mutex<HashMap<RepoId, MirrorUsage>>,
+}
+
+impl MirrorStore {
+  pub fn new(quota_bytes: Option<u64>) -> Self {
+    MirrorStore {
+      quota_bytes,
+      usage: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Figure out what's already in `root`. We don't keep track of access times across restarts, so the last time that a
+  /// mirror was modified will have to do.
+  pub fn scan(&self, root: &Path) -> std::io::Result<()> {
+    if !root.exists() {
+      return Ok(());
+    }
+    let mut usage = HashMap::new();
+    for entry in std::fs::read_dir(root)? {
+      let entry = entry?;
+      let name = entry.file_name().to_string_lossy().to_string();
+      // Things like .partial.
+      if name.starts_with('.') {
+        continue;
+      }
+      let repo_id = match parse_repo_id(&name) {
+        Ok(repo_id) => repo_id,
+        Err(_) => {
+          log::warn!("ignoring unexpected entry {} in mirrors dir", name);
+          continue;
+        }
+      };
+      usage.insert(
+        repo_id,
+        MirrorUsage {
+          size_bytes: dir_size(&entry.path())?,
+          last_access: entry.metadata()?.modified()?,
+        },
+      );
+    }
+    *self.usage.lock().unwrap() = usage;
+    Ok(())
+  }
+
+  /// Mark a mirror as just used. Mirrors that we don't know the size of yet get picked up by `set_size` instead.
+  pub fn touch(&self, repo_id: &RepoId) {
+    if let Some(u) = self.usage.lock().unwrap().get_mut(repo_id) {
+      u.last_access = SystemTime::now();
+    }
+  }
+
+  pub fn set_size(&self, repo_id: &RepoId, size_bytes: u64) {
+    let mut usage = self.usage.lock().unwrap();
+    let entry = usage.entry(repo_id.clone()).or_insert(MirrorUsage {
+      size_bytes,
+      last_access: SystemTime::now(),
+    });
+    entry.size_bytes = size_bytes;
+  }
+
+  /// Like `set_size`, but for mirrors that have grown by `bytes` since then. Does nothing for mirrors that we don't know
+  /// about.
+  pub fn add_size(&self, repo_id: &RepoId, bytes: u64) {
+    if let Some(u) = self.usage.lock().unwrap().get_mut(repo_id) {
+      u.size_bytes += bytes;
+    }
+  }
+
+  pub fn remove(&self, repo_id: &RepoId) {
+    self.usage.lock().unwrap().remove(repo_id);
+  }
+
+  pub fn used_bytes(&self) -> u64 {
+    self
+      .usage
+      .lock()
+      .unwrap()
+      .values()
+      .map(|u| u.size_bytes)
+      .sum()
+  }
+
+  /// Mirrors that were used at or after `since`.
+  pub fn used_since(&self, since: SystemTime) -> Vec<RepoId> {
+    self
+      .usage
+      .lock()
+      .unwrap()
+      .iter()
+      .filter(|(_, u)| u.last_access >= since)
+      .map(|(repo_id, _)| repo_id.clone())
+      .collect()
+  }
+
+  /// The least recently used mirrors that would have to go in order to get back under quota, oldest first. `keep` is
+  /// never a candidate, even if it alone is over quota.
+  pub fn eviction_candidates(&self, keep: &RepoId) -> Vec<RepoId> {
+    let quota_bytes = match self.quota_bytes {
+      Some(quota_bytes) => quota_bytes,
+      None => return vec![],
+    };
+    let usage = self.usage.lock().unwrap();
+    let mut used_bytes: u64 = usage.values().map(|u| u.size_bytes).sum();
+    let mut lru = usage
+      .iter()
+      .filter(|(repo_id, _)| *repo_id != keep)
+      .collect::<Vec<_>>();
+    lru.sort_by_key(|(_, u)| u.last_access);
+
+    let mut res = vec![];
+    for (repo_id, u) in lru {
+      if used_bytes <= quota_bytes {
+        break;
+      }
+      used_bytes -= u.size_bytes;
+      res.push(repo_id.clone());
+    }
+    res
+  }
+
+  pub fn report(&self) -> UsageReport {
+    let usage = self.usage.lock().unwrap();
+    let mut mirrors = usage
+      .iter()
+      .map(|(repo_id, u)| MirrorUsageReport {
+        repo_id: repo_id.to_string(),
+        size_bytes: u.size_bytes,
+        last_access: u
+          .last_access
+          .duration_since(SystemTime::UNIX_EPOCH)
+          .unwrap_or_default()
+          .as_secs(),
+      })
+      .collect::<Vec<_>>();
+    mirrors.sort_by_key(|m| std::cmp::Reverse(m.last_access));
+    UsageReport {
+      quota_bytes: self.quota_bytes,
+      used_bytes: mirrors.iter().map(|m| m.size_bytes).sum(),
+      mirrors,
+    }
+  }
+}
+
+/// Total size of all of the files under `path`.
+pub fn dir_size(path: &Path) -> std::io::Result<u64> {
+  let mut size = 0;
+  for entry in std::fs::read_dir(path)? {
+    let entry = entry?;
+    let file_type = entry.file_type()?;
+    if file_type.is_dir() {
+      size += dir_size(&entry.path())?;
+    } else if file_type.is_file() {
+      size += entry.metadata()?.len();
+    }
+  }
+  Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  fn repo(name: &str) -> RepoId {
+    RepoId::GitHubRepo {
+      owner: "owner".into(),
+      name: name.into(),
+    }
+  }
+
+  #[test]
+  fn evicts_least_recently_used() {
+    let store = MirrorStore::new(Some(100));
+    let start = SystemTime::now() - Duration::from_secs(60);
+    for (i, name) in ["a", "b", "c", "d"].iter().enumerate() {
+      store.set_size(&repo(name), 40);
+      store
+        .usage
+        .lock()
+        .unwrap()
+        .get_mut(&repo(name))
+        .unwrap()
+        .last_access = start + Duration::from_secs(i as u64);
+    }
+    assert_eq!(store.used_bytes(), 160);
+
+    // 160 bytes, so a and b have to go to get under 100.
+    assert_eq!(
+      store.eviction_candidates(&repo("d")),
+      vec![repo("a"), repo("b")]
+    );
+    // Unless we're in the middle of using a.
+    assert_eq!(
+      store.eviction_candidates(&repo("a")),
+      vec![repo("b"), repo("c")]
+    );
+
+    store.remove(&repo("a"));
+    store.touch(&repo("b"));
+    assert_eq!(store.eviction_candidates(&repo("d")), vec![repo("c")]);
+    // Blame cache entries written into d count too.
+    store.add_size(&repo("d"), 30);
+    store.add_size(&repo("a"), 1000);
+    assert_eq!(store.used_bytes(), 150);
+    assert_eq!(
+      store.eviction_candidates(&repo("d")),
+      vec![repo("c"), repo("b")]
+    );
+    assert_eq!(store.report().mirrors[0].repo_id, repo("b").to_string());
+
+    // No quota, no evictions.
+    let unlimited = MirrorStore::new(None);
+    unlimited.set_size(&repo("a"), u64::MAX / 2);
+    assert!(unlimited.eviction_candidates(&repo("b")).is_empty());
+  }
+}
@@ -0,0 +1,1463 @@
+// `Storage` that talks to Postgres directly instead of going through Hasura's admin API. This gets us things that
+// Hasura can't do for us, namely real multi-statement transactions and COPY for bulk inserts.
+//
+// The tables are the ones in hasura/migrations; nothing here creates or alters them. uuid columns go over the wire as
+// text so that we don't need to pull in a uuid type.
+use crate::github::GitHubNodeId;
+use crate::github::GitHubUser;
+use crate::storage::looks_like_uuid;
+use crate::storage::BlameJobRecord;
+use crate::storage::CommentEditRecord;
+use crate::storage::CommentRecord;
+use crate::storage::LineRange;
+use crate::storage::MentionRecord;
+use crate::storage::ReactionRecord;
+use crate::storage::SessionRecord;
+use crate::storage::Storage;
+use crate::storage::ThreadAnchor;
+use crate::storage::ThreadWithComments;
+use crate::token_cipher::TokenCipher;
+use crate::BlameLine;
+use crate::GitHubAuth;
+use crate::GitHubUserId;
+use crate::RepoWithCommit;
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::Duration;
+use deadpool_postgres::Pool;
+use std::collections::HashMap;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::Type;
+use tokio_postgres::GenericClient;
+use tokio_postgres::NoTls;
+use tokio_postgres::Row;
+
+const SESSION_COLUMNS: &str =
+  "user_sessions.public_id::text, user_sessions.created_at, user_sessions.last_used_at, \
+   user_sessions.expires_at, user_sessions.rotated_at";
+const ANCHOR_COLUMNS: &str =
+  "original_commit_hash, original_file_path, original_line_number, original_end_line_number, \
+   original_start_column, original_end_column";
+// to_json gives us the same ISO 8601 timestamps that Hasura does.
+const THREAD_COLUMNS: &str =
+  "id::text, original_commit_hash, original_file_path, original_line_number, original_end_line_number, \
+   original_start_column, original_end_column, to_json(resolved_at) #>> '{}', resolved_by_github_node_id";
+// The last four are the comment's reactions and mentions, as two pairs of parallel arrays.
+const COMMENT_COLUMNS: &str =
+  "comments.id::text, body, to_json(created_at) #>> '{}', author_github_node_id, author_email, \
+   to_json(edited_at) #>> '{}', to_json(deleted_at) #>> '{}', \
+   ARRAY(SELECT reaction FROM comment_reactions r WHERE r.comment_id = comments.id ORDER BY r.created_at, r.github_node_id, r.reaction), \
+   ARRAY(SELECT github_node_id FROM comment_reactions r WHERE r.comment_id = comments.id ORDER BY r.created_at, r.github_node_id, r.reaction), \
+   ARRAY(SELECT github_node_id FROM comment_mentions m WHERE m.comment_id = comments.id ORDER BY m.github_username), \
+   ARRAY(SELECT github_username FROM comment_mentions m WHERE m.comment_id = comments.id ORDER BY m.github_username)";
+const BLAME_JOB_COLUMNS: &str = "id::text, repo_id, commit_hash, file_path, status, error";
+
+pub struct PostgresStorage {
+  pool: Pool,
+  token_cipher: TokenCipher,
+}
+
+impl PostgresStorage {
+  /// Connections are made lazily, so this doesn't fail when the database is down.
+  pub fn new(database_url: &str, token_cipher: TokenCipher) -> anyhow::Result<Self> {
+    let pg_config = database_url
+      .parse::<tokio_postgres::Config>()
+      .context("parsing DATABASE_URL")?;
+    let pool = Pool::builder(deadpool_postgres::Manager::new(pg_config, NoTls))
+      .runtime(deadpool_postgres::Runtime::Tokio1)
+      .build()
+      .context("creating postgres connection pool")?;
+    Ok(PostgresStorage { pool, token_cipher })
+  }
+
+  async fn client(&self) -> anyhow::Result<deadpool_postgres::Client> {
+    self
+      .pool
+      .get()
+      .await
+      .context("getting a postgres connection")
+  }
+}
+
+fn blame_job_from_row(row: &Row) -> BlameJobRecord {
+  BlameJobRecord {
+    id: row.get(0),
+    repo_id: row.get(1),
+    commit_hash: row.get(2),
+    file_path: row.get(3),
+    status: row.get(4),
+    error: row.get(5),
+  }
+}
+
+/// `row` should have COMMENT_COLUMNS starting at `start`.
+fn comment_from_row(row: &Row, start: usize) -> CommentRecord {
+  CommentRecord {
+    id: row.get(start),
+    body: row.get(start + 1),
+    created_at: row.get(start + 2),
+    author_github_node_id: row.get(start + 3),
+    author_email: row.get(start + 4),
+    edited_at: row.get(start + 5),
+    deleted_at: row.get(start + 6),
+    reactions: row
+      .get::<_, Vec<String>>(start + 7)
+      .into_iter()
+      .zip(row.get::<_, Vec<String>>(start + 8))
+      .map(|(reaction, github_node_id)| ReactionRecord {
+        reaction,
+        github_node_id,
+      })
+      .collect(),
+    mentions: row
+      .get::<_, Vec<String>>(start + 9)
+      .into_iter()
+      .zip(row.get::<_, Vec<String>>(start + 10))
+      .map(|(github_node_id, github_username)| MentionRecord {
+        github_node_id,
+        github_username,
+      })
+      .collect(),
+  }
+}
+
+/// `row` should have SESSION_COLUMNS starting at `start`.
+fn session_from_row(row: &Row, start: usize) -> SessionRecord {
+  SessionRecord {
+    public_id: row.get(start),
+    created_at: row.get(start + 1),
+    last_used_at: row.get(start + 2),
+    expires_at: row.get(start + 3),
+    rotated_at: row.get(start + 4),
+  }
+}
+
+/// `row` should have ANCHOR_COLUMNS starting at `start`.
+fn anchor_from_row(row: &Row, start: usize) -> ThreadAnchor {
+  let int = |i: usize| row.get::<_, Option<i32>>(start + i).map(i64::from);
+  ThreadAnchor {
+    original_commit_hash: row.get(start),
+    original_file_path: row.get(start + 1),
+    original_line_number: row.get::<_, i32>(start + 2).into(),
+    original_end_line_number: int(3),
+    original_start_column: int(4),
+    original_end_column: int(5),
+  }
+}
+
+/// Fill in the comments for each of the threads in `rows`, which should select THREAD_COLUMNS.
+async fn threads_with_comments(
+  client: &impl GenericClient,
+  rows: Vec<Row>,
+) -> anyhow::Result<Vec<ThreadWithComments>> {
+  let mut threads = rows
+    .iter()
+    .map(|row| {
+      let anchor = anchor_from_row(row, 1);
+      ThreadWithComments {
+        id: row.get(0),
+        original_commit_hash: anchor.original_commit_hash,
+        original_file_path: anchor.original_file_path,
+        original_line_number: anchor.original_line_number,
+        original_end_line_number: anchor.original_end_line_number,
+        original_start_column: anchor.original_start_column,
+        original_end_column: anchor.original_end_column,
+        resolved_at: row.get(7),
+        resolved_by_github_node_id: row.get(8),
+        comments: vec![],
+      }
+    })
+    .collect::<Vec<_>>();
+  let thread_ids = threads.iter().map(|t| t.id.clone()).collect::<Vec<_>>();
+  let mut comments: HashMap<String, Vec<CommentRecord>> = HashMap::new();
+  for row in client
+    .query(
+      &format!(
+        "SELECT thread_id::text, {}
+         FROM comments
+         WHERE thread_id = ANY($1::text[]::uuid[])
+         ORDER BY created_at ASC",
+        COMMENT_COLUMNS
+      ),
+      &[&thread_ids],
+    )
+    .await
+    .context("looking up comments in postgres")?
+  {
+    comments
+      .entry(row.get(0))
+      .or_default()
+      .push(comment_from_row(&row, 1));
+  }
+  for t in &mut threads {
+    t.comments = comments.remove(&t.id).unwrap_or_default();
+  }
+  Ok(threads)
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+  async fn lookup_existing_blamelines(
+    &self,
+    commit_hash: &str,
+    file_path: &str,
+  ) -> anyhow::Result<bool> {
+    // Just like the Hasura version, line 1 stands in for the whole file, and a done job stands in for an empty one.
+    let row = self
+      .client()
+      .await?
+      .query_one(
+        "SELECT EXISTS (
+           SELECT 1 FROM blamelines WHERE x_commit_hash = $1 AND x_file_path = $2 AND x_line_number = 1
+         ) OR EXISTS (
+           SELECT 1 FROM blame_jobs WHERE commit_hash = $1 AND file_path = $2 AND status = 'done'
+         )",
+        &[&commit_hash, &file_path],
+      )
+      .await
+      .context("looking up blamelines in postgres")?;
+    Ok(row.get(0))
+  }
+
+  /// Everything goes in as one transaction: COPY into a temporary table, and then from there into lines and
+  /// blamelines. There's no need to worry about the order of the lines like there is with Hasura.
+  async fn insert_blamelines(
+    &self,
+    commit_hash: &str,
+    file_path: &str,
+    blamelines: Vec<BlameLine>,
+  ) -> anyhow::Result<()> {
+    let mut client = self.client().await?;
+    let tx = client.transaction().await?;
+    tx.batch_execute(
+      "CREATE TEMPORARY TABLE blamelines_import (
+         original_commit_hash text NOT NULL,
+         original_file_path text NOT NULL,
+         original_line_number integer NOT NULL,
+         x_line_number integer NOT NULL
+       ) ON COMMIT DROP",
+    )
+    .await?;
+
+    let sink = tx
+      .copy_in(
+        "COPY blamelines_import (original_commit_hash, original_file_path, original_line_number, x_line_number) \
+         FROM STDIN BINARY",
+      )
+      .await?;
+    let mut writer = std::pin::pin!(BinaryCopyInWriter::new(
+      sink,
+      &[Type::TEXT, Type::TEXT, Type::INT4, Type::INT4],
+    ));
+    // Be careful! Line numbers are always 1-indexed.
+    for (i, bl) in blamelines.iter().enumerate() {
+      let x_line_number = i as i32 + 1;
+      writer
+        .as_mut()
+        .write(&[
+          &bl.original_commit,
+          &bl.original_file_path,
+          &bl.original_line_number,
+          &x_line_number,
+        ])
+        .await
+        .context("copying blamelines into postgres")?;
+    }
+    writer.finish().await?;
+
+    tx.execute(
+      "INSERT INTO lines (commit_hash, file_path, line_number)
+       SELECT DISTINCT original_commit_hash, original_file_path, original_line_number FROM blamelines_import
+       ON CONFLICT DO NOTHING",
+      &[],
+    )
+    .await
+    .context("inserting lines into postgres")?;
+    tx.execute(
+      "INSERT INTO blamelines
+         (original_commit_hash, original_file_path, original_line_number, x_commit_hash, x_file_path, x_line_number)
+       SELECT original_commit_hash, original_file_path, original_line_number, $1, $2, x_line_number
+       FROM blamelines_import
+       ON CONFLICT DO NOTHING",
+      &[&commit_hash, &file_path],
+    )
+    .await
+    .context("inserting blamelines into postgres")?;
+    tx.commit().await?;
+    Ok(())
+  }
+
+  async fn upsert_user(
+    &self,
+    github_node_id: &GitHubUserId,
+    github_database_id: u32,
+    github_name: &str,
+    github_username: &str,
+    email: Option<String>,
+    github_access_token: &str,
+  ) -> anyhow::Result<()> {
+    let github_database_id = i32::try_from(github_database_id)?;
+    self
+      .client()
+      .await?
+      .execute(
+        "INSERT INTO github_users
+           (github_node_id, github_database_id, github_name, github_username, email, access_token)
+         VALUES ($1, $2, $3, $4, $5, $6)
+         ON CONFLICT ON CONSTRAINT users_github_id_key DO UPDATE SET
+           github_node_id = EXCLUDED.github_node_id,
+           github_name = EXCLUDED.github_name,
+           github_username = EXCLUDED.github_username,
+           email = EXCLUDED.email,
+           access_token = EXCLUDED.access_token,
+           updated_at = now()",
+        &[
+          &github_node_id.0 .0,
+          &github_database_id,
+          &github_name,
+          &github_username,
+          &email,
+          &self
+            .token_cipher
+            .seal(&github_node_id.0 .0, github_access_token)?,
+        ],
+      )
+      .await
+      .context("upserting user info into postgres")?;
+    Ok(())
+  }
+
+  async fn upsert_mentioned_user(&self, user: &GitHubUser) -> anyhow::Result<()> {
+    let github_database_id = i32::try_from(user.database_id)?;
+    self
+      .client()
+      .await?
+      .execute(
+        "INSERT INTO github_users (github_node_id, github_database_id, github_name, github_username, email)
+         VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (github_node_id) DO UPDATE SET
+           github_name = EXCLUDED.github_name,
+           github_username = EXCLUDED.github_username,
+           updated_at = now()",
+        &[
+          &user.node_id.0,
+          &github_database_id,
+          &user.name,
+          &user.login,
+          &user.email,
+        ],
+      )
+      .await
+      .context("upserting mentioned user into postgres")?;
+    Ok(())
+  }
+
+  async fn lookup_users_by_username(
+    &self,
+    usernames: &[String],
+  ) -> anyhow::Result<Vec<MentionRecord>> {
+    let usernames = usernames
+      .iter()
+      .map(|u| u.to_lowercase())
+      .collect::<Vec<_>>();
+    let rows = self
+      .client()
+      .await?
+      .query(
+        "SELECT github_node_id, github_username FROM github_users WHERE lower(github_username) = ANY($1)",
+        &[&usernames],
+      )
+      .await
+      .context("looking up users by username in postgres")?;
+    Ok(
+      rows
+        .iter()
+        .map(|row| MentionRecord {
+          github_node_id: row.get(0),
+          github_username: row.get(1),
+        })
+        .collect(),
+    )
+  }
+
+  async fn reencrypt_access_tokens(&self) -> anyhow::Result<usize> {
+    let client = self.client().await?;
+    let rows = client
+      .query(
+        "SELECT github_node_id, access_token FROM github_users WHERE access_token IS NOT NULL",
+        &[],
+      )
+      .await
+      .context("looking up access tokens in postgres")?;
+    let mut updated = 0;
+    for row in rows {
+      let github_node_id: String = row.get(0);
+      let stored: String = row.get(1);
+      let resealed = match self.token_cipher.reseal(&github_node_id, &stored) {
+        Ok(Some(resealed)) => resealed,
+        Ok(None) => continue,
+        Err(e) => {
+          log::warn!("can't re-encrypt access token: {:?}", e);
+          continue;
+        }
+      };
+      // Only if the token hasn't changed in the meantime, so that we don't clobber a fresh login.
+      updated += client
+        .execute(
+          "UPDATE github_users SET access_token = $3 WHERE github_node_id = $1 AND access_token = $2",
+          &[&github_node_id, &stored, &resealed],
+        )
+        .await
+        .context("updating access token in postgres")? as usize;
+    }
+    Ok(updated)
+  }
+
+  async fn start_user_session(
+    &self,
+    github_user: &GitHubUserId,
+    ttl: Duration,
+  ) -> anyhow::Result<String> {
+    let row = self
+      .client()
+      .await?
+      .query_one(
+        "INSERT INTO user_sessions (user_github_node_id, expires_at)
+         VALUES ($1, now() + $2 * interval '1 second')
+         RETURNING id::text",
+        &[&github_user.0 .0, &(ttl.num_seconds() as f64)],
+      )
+      .await
+      .context("inserting new session into postgres")?;
+    Ok(row.get(0))
+  }
+
+  async fn lookup_user_session(
+    &self,
+    session_token: &str,
+    ttl: Duration,
+  ) -> anyhow::Result<Option<(GitHubAuth, SessionRecord)>> {
+    // Otherwise casting it to a uuid fails the whole query.
+    if !looks_like_uuid(session_token) {
+      return Ok(None);
+    }
+    let row = self
+      .client()
+      .await?
+      .query_opt(
+        &format!(
+          "UPDATE user_sessions
+           SET last_used_at = now(),
+               expires_at = CASE
+                 WHEN rotated_at IS NULL THEN now() + $2 * interval '1 second'
+                 ELSE expires_at
+               END
+           FROM github_users
+           WHERE github_users.github_node_id = user_sessions.user_github_node_id
+             AND user_sessions.id = $1::text::uuid
+             AND user_sessions.expires_at > now()
+           RETURNING github_users.github_node_id, github_users.access_token, {}",
+          SESSION_COLUMNS
+        ),
+        &[&session_token, &(ttl.num_seconds() as f64)],
+      )
+      .await
+      .context("looking up session in postgres")?;
+    row
+      .and_then(|row| {
+        row.get::<_, Option<String>>(1).map(|stored| {
+          let github_node_id: String = row.get(0);
+          let access_token = self.token_cipher.open(&github_node_id, &stored)?;
+          Ok((
+            GitHubAuth {
+              github_node_id: GitHubUserId(GitHubNodeId(github_node_id)),
+              access_token,
+            },
+            session_from_row(&row, 2),
+          ))
+        })
+      })
+      .transpose()
+  }
+
+  async fn end_user_session(&self, session_token: &str) -> anyhow::Result<()> {
+    if !looks_like_uuid(session_token) {
+      return Ok(());
+    }
+    self
+      .client()
+      .await?
+      .execute(
+        "DELETE FROM user_sessions WHERE id = $1::text::uuid",
+        &[&session_token],
+      )
+      .await
+      .context("deleting session from postgres")?;
+    Ok(())
+  }
+
+  async fn rotate_user_session(
+    &self,
+    session_token: &str,
+    ttl: Duration,
+    grace: Duration,
+  ) -> anyhow::Result<Option<String>> {
+    if !looks_like_uuid(session_token) {
+      return Ok(None);
+    }
+    let mut client = self.client().await?;
+    let tx = client.transaction().await?;
+    let old = tx
+      .query_opt(
+        "UPDATE user_sessions
+         SET expires_at = least(expires_at, now() + $2 * interval '1 second'), rotated_at = now()
+         WHERE id = $1::text::uuid AND expires_at > now() AND rotated_at IS NULL
+         RETURNING user_github_node_id",
+        &[&session_token, &(grace.num_seconds() as f64)],
+      )
+      .await
+      .context("cutting off old session in postgres")?;
+    let github_node_id: String = match old {
+      Some(row) => row.get(0),
+      None => return Ok(None),
+    };
+    let new = tx
+      .query_one(
+        "INSERT INTO user_sessions (user_github_node_id, expires_at)
+         VALUES ($1, now() + $2 * interval '1 second')
+         RETURNING id::text",
+        &[&github_node_id, &(ttl.num_seconds() as f64)],
+      )
+      .await
+      .context("inserting rotated session into postgres")?;
+    tx.commit().await?;
+    Ok(Some(new.get(0)))
+  }
+
+  async fn user_sessions(&self, github_user: &GitHubUserId) -> anyhow::Result<Vec<SessionRecord>> {
+    let rows = self
+      .client()
+      .await?
+      .query(
+        &format!(
+          "SELECT {} FROM user_sessions
+           WHERE user_github_node_id = $1 AND expires_at > now()
+           ORDER BY last_used_at DESC",
+          SESSION_COLUMNS
+        ),
+        &[&github_user.0 .0],
+      )
+      .await
+      .context("looking up user sessions in postgres")?;
+    Ok(rows.iter().map(|row| session_from_row(row, 0)).collect())
+  }
+
+  async fn end_user_session_by_public_id(
+    &self,
+    github_user: &GitHubUserId,
+    public_id: &str,
+  ) -> anyhow::Result<bool> {
+    // A malformed public_id can't belong to anyone, and shouldn't be a database error.
+    let n = self
+      .client()
+      .await?
+      .execute(
+        "DELETE FROM user_sessions WHERE user_github_node_id = $1 AND public_id::text = $2",
+        &[&github_user.0 .0, &public_id],
+      )
+      .await
+      .context("deleting session from postgres")?;
+    Ok(n > 0)
+  }
+
+  async fn start_thread(
+    &self,
+    author_github_node_id: &GitHubUserId,
+    repo: &RepoWithCommit,
+    commit_hash: &str,
+    file_path: &str,
+    range: &LineRange,
+    body: &str,
+  ) -> anyhow::Result<(String, String)> {
+    let start_line = i32::try_from(range.start_line)?;
+    let end_line = i32::try_from(range.end_line)?;
+    let start_column = range.start_column.map(i32::try_from).transpose()?;
+    let end_column = range.end_column.map(i32::try_from).transpose()?;
+    let mut client = self.client().await?;
+    let tx = client.transaction().await?;
+    tx.execute(
+      "INSERT INTO lines (commit_hash, file_path, line_number)
+       SELECT $1, $2, generate_series($3::int, $4::int)
+       ON CONFLICT DO NOTHING",
+      &[&commit_hash, &file_path, &start_line, &end_line],
+    )
+    .await
+    .context("upserting lines into postgres")?;
+    let (query, repo_param): (&str, String) = match repo {
+      RepoWithCommit::GitHub(repo_github_node_id) => (
+        "INSERT INTO commit_github_repo (commit_hash, repo_github_node_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        repo_github_node_id.0.clone(),
+      ),
+      RepoWithCommit::Other(repo_id) => (
+        "INSERT INTO commit_repo (commit_hash, repo_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        repo_id.to_string(),
+      ),
+    };
+    tx.execute(query, &[&commit_hash, &repo_param])
+      .await
+      .context("upserting commit repo into postgres")?;
+    let thread_id: String = tx
+      .query_one(
+        "INSERT INTO threads (
+           original_commit_hash, original_file_path, original_line_number, original_end_line_number,
+           original_start_column, original_end_column
+         )
+         VALUES ($1, $2, $3, $4, $5, $6)
+         RETURNING id::text",
+        &[
+          &commit_hash,
+          &file_path,
+          &start_line,
+          &end_line,
+          &start_column,
+          &end_column,
+        ],
+      )
+      .await
+      .context("inserting thread into postgres")?
+      .get(0);
+    let comment_id: String = tx
+      .query_one(
+        "INSERT INTO comments (thread_id, author_github_node_id, body) VALUES ($1::text::uuid, $2, $3)
+         RETURNING id::text",
+        &[&thread_id, &author_github_node_id.0 .0, &body],
+      )
+      .await
+      .context("inserting comment into postgres")?
+      .get(0);
+    tx.commit().await?;
+    Ok((thread_id, comment_id))
+  }
+
+  async fn lookup_thread(&self, thread_id: &str) -> anyhow::Result<Option<ThreadAnchor>> {
+    let row = self
+      .client()
+      .await?
+      .query_opt(
+        &format!(
+          "SELECT {} FROM threads WHERE id = $1::text::uuid",
+          ANCHOR_COLUMNS
+        ),
+        &[&thread_id],
+      )
+      .await
+      .context("looking up thread in postgres")?;
+    Ok(row.map(|row| anchor_from_row(&row, 0)))
+  }
+
+  async fn resolve_thread(&self, thread_id: &str, resolver: &GitHubUserId) -> anyhow::Result<bool> {
+    let updated = self
+      .client()
+      .await?
+      .execute(
+        "UPDATE threads SET resolved_at = now(), resolved_by_github_node_id = $2
+         WHERE id = $1::text::uuid AND resolved_at IS NULL",
+        &[&thread_id, &resolver.0 .0],
+      )
+      .await
+      .context("resolving thread in postgres")?;
+    Ok(updated > 0)
+  }
+
+  async fn reopen_thread(&self, thread_id: &str) -> anyhow::Result<bool> {
+    let updated = self
+      .client()
+      .await?
+      .execute(
+        "UPDATE threads SET resolved_at = NULL, resolved_by_github_node_id = NULL
+         WHERE id = $1::text::uuid AND resolved_at IS NOT NULL",
+        &[&thread_id],
+      )
+      .await
+      .context("reopening thread in postgres")?;
+    Ok(updated > 0)
+  }
+
+  async fn add_comment(
+    &self,
+    author_github_node_id: &GitHubUserId,
+    thread_id: &str,
+    body: &str,
+  ) -> anyhow::Result<CommentRecord> {
+    let row = self
+      .client()
+      .await?
+      .query_one(
+        &format!(
+          "INSERT INTO comments (thread_id, author_github_node_id, body) VALUES ($1::text::uuid, $2, $3)
+           RETURNING {}",
+          COMMENT_COLUMNS
+        ),
+        &[&thread_id, &author_github_node_id.0 .0, &body],
+      )
+      .await
+      .context("inserting comment into postgres")?;
+    Ok(comment_from_row(&row, 0))
+  }
+
+  async fn lookup_comment(
+    &self,
+    comment_id: &str,
+  ) -> anyhow::Result<Option<(String, CommentRecord)>> {
+    let row = self
+      .client()
+      .await?
+      .query_opt(
+        &format!(
+          "SELECT thread_id::text, {} FROM comments WHERE id = $1::text::uuid",
+          COMMENT_COLUMNS
+        ),
+        &[&comment_id],
+      )
+      .await
+      .context("looking up comment in postgres")?;
+    Ok(row.map(|row| (row.get(0), comment_from_row(&row, 1))))
+  }
+
+  async fn edit_comment(
+    &self,
+    comment_id: &str,
+    body: &str,
+  ) -> anyhow::Result<Option<CommentRecord>> {
+    // The save_comment_edit trigger takes care of edited_at and comment_edits.
+    let row = self
+      .client()
+      .await?
+      .query_opt(
+        &format!(
+          "UPDATE comments SET body = $2 WHERE id = $1::text::uuid AND deleted_at IS NULL RETURNING {}",
+          COMMENT_COLUMNS
+        ),
+        &[&comment_id, &body],
+      )
+      .await
+      .context("editing comment in postgres")?;
+    Ok(row.map(|row| comment_from_row(&row, 0)))
+  }
+
+  async fn delete_comment(&self, comment_id: &str) -> anyhow::Result<bool> {
+    let n = self
+      .client()
+      .await?
+      .execute(
+        "UPDATE comments SET deleted_at = now() WHERE id = $1::text::uuid AND deleted_at IS NULL",
+        &[&comment_id],
+      )
+      .await
+      .context("deleting comment in postgres")?;
+    Ok(n > 0)
+  }
+
+  async fn comment_edits(&self, comment_id: &str) -> anyhow::Result<Vec<CommentEditRecord>> {
+    let rows = self
+      .client()
+      .await?
+      .query(
+        "SELECT body, to_json(replaced_at) #>> '{}' FROM comment_edits
+         WHERE comment_id = $1::text::uuid
+         ORDER BY replaced_at ASC",
+        &[&comment_id],
+      )
+      .await
+      .context("looking up comment edits in postgres")?;
+    Ok(
+      rows
+        .iter()
+        .map(|row| CommentEditRecord {
+          body: row.get(0),
+          replaced_at: row.get(1),
+        })
+        .collect(),
+    )
+  }
+
+  async fn add_reaction(
+    &self,
+    comment_id: &str,
+    github_user: &GitHubUserId,
+    reaction: &str,
+  ) -> anyhow::Result<()> {
+    self
+      .client()
+      .await?
+      .execute(
+        "INSERT INTO comment_reactions (comment_id, github_node_id, reaction) VALUES ($1::text::uuid, $2, $3)
+         ON CONFLICT DO NOTHING",
+        &[&comment_id, &github_user.0 .0, &reaction],
+      )
+      .await
+      .context("inserting reaction into postgres")?;
+    Ok(())
+  }
+
+  async fn remove_reaction(
+    &self,
+    comment_id: &str,
+    github_user: &GitHubUserId,
+    reaction: &str,
+  ) -> anyhow::Result<()> {
+    self
+      .client()
+      .await?
+      .execute(
+        "DELETE FROM comment_reactions WHERE comment_id = $1::text::uuid AND github_node_id = $2 AND reaction = $3",
+        &[&comment_id, &github_user.0 .0, &reaction],
+      )
+      .await
+      .context("deleting reaction from postgres")?;
+    Ok(())
+  }
+
+  async fn add_mentions(&self, comment_id: &str, mentions: &[MentionRecord]) -> anyhow::Result<()> {
+    let (github_node_ids, github_usernames): (Vec<&str>, Vec<&str>) = mentions
+      .iter()
+      .map(|m| (m.github_node_id.as_str(), m.github_username.as_str()))
+      .unzip();
+    self
+      .client()
+      .await?
+      .execute(
+        "INSERT INTO comment_mentions (comment_id, github_node_id, github_username)
+         SELECT $1::text::uuid, * FROM unnest($2::text[], $3::text[])
+         ON CONFLICT DO NOTHING",
+        &[&comment_id, &github_node_ids, &github_usernames],
+      )
+      .await
+      .context("inserting mentions into postgres")?;
+    Ok(())
+  }
+
+  async fn threads_for_original_lines(
+    &self,
+    commit_hashes: Vec<String>,
+    file_paths: Vec<String>,
+  ) -> anyhow::Result<Vec<ThreadWithComments>> {
+    let client = self.client().await?;
+    let rows = client
+      .query(
+        &format!(
+          "SELECT {}
+         FROM threads
+         WHERE original_commit_hash = ANY($1) AND original_file_path = ANY($2)",
+          THREAD_COLUMNS
+        ),
+        &[&commit_hashes, &file_paths],
+      )
+      .await
+      .context("looking up threads in postgres")?;
+    threads_with_comments(&**client, rows).await
+  }
+
+  async fn threads_for_file_paths(
+    &self,
+    repo: &RepoWithCommit,
+    file_paths: Vec<String>,
+  ) -> anyhow::Result<Vec<ThreadWithComments>> {
+    let (repo_filter, repo_param): (&str, String) = match repo {
+      RepoWithCommit::GitHub(repo_github_node_id) => (
+        "SELECT commit_hash FROM commit_github_repo WHERE repo_github_node_id = $2",
+        repo_github_node_id.0.clone(),
+      ),
+      RepoWithCommit::Other(repo_id) => (
+        "SELECT commit_hash FROM commit_repo WHERE repo_id = $2",
+        repo_id.to_string(),
+      ),
+    };
+    let client = self.client().await?;
+    let rows = client
+      .query(
+        &format!(
+          "SELECT {}
+         FROM threads
+         WHERE original_file_path = ANY($1) AND original_commit_hash IN ({})",
+          THREAD_COLUMNS, repo_filter
+        ),
+        &[&file_paths, &repo_param],
+      )
+      .await
+      .context("looking up threads in postgres")?;
+    threads_with_comments(&**client, rows).await
+  }
+
+  async fn insert_blame_job(
+    &self,
+    repo_id: &str,
+    commit_hash: &str,
+    file_path: &str,
+  ) -> anyhow::Result<BlameJobRecord> {
+    let row = self
+      .client()
+      .await?
+      .query_one(
+        &format!(
+          "INSERT INTO blame_jobs (repo_id, commit_hash, file_path) VALUES ($1, $2, $3) RETURNING {}",
+          BLAME_JOB_COLUMNS
+        ),
+        &[&repo_id, &commit_hash, &file_path],
+      )
+      .await
+      .context("inserting blame job into postgres")?;
+    Ok(blame_job_from_row(&row))
+  }
+
+  async fn lookup_blame_job(&self, job_id: &str) -> anyhow::Result<Option<BlameJobRecord>> {
+    let row = self
+      .client()
+      .await?
+      .query_opt(
+        &format!(
+          "SELECT {} FROM blame_jobs WHERE id = $1::text::uuid",
+          BLAME_JOB_COLUMNS
+        ),
+        &[&job_id],
+      )
+      .await
+      .context("looking up blame job in postgres")?;
+    Ok(row.as_ref().map(blame_job_from_row))
+  }
+
+  async fn lookup_unfinished_blame_job(
+    &self,
+    repo_id: &str,
+    commit_hash: &str,
+    file_path: &str,
+  ) -> anyhow::Result<Option<BlameJobRecord>> {
+    let row = self
+      .client()
+      .await?
+      .query_opt(
+        &format!(
+          "SELECT {} FROM blame_jobs
+           WHERE repo_id = $1 AND commit_hash = $2 AND file_path = $3 AND status IN ('queued', 'running')
+           ORDER BY created_at ASC
+           LIMIT 1",
+          BLAME_JOB_COLUMNS
+        ),
+        &[&repo_id, &commit_hash, &file_path],
+      )
+      .await
+      .context("looking up unfinished blame job in postgres")?;
+    Ok(row.as_ref().map(blame_job_from_row))
+  }
+
+  async fn unfinished_blame_jobs(&self) -> anyhow::Result<Vec<BlameJobRecord>> {
+    let rows = self
+      .client()
+      .await?
+      .query(
+        &format!(
+          "SELECT {} FROM blame_jobs WHERE status IN ('queued', 'running') ORDER BY created_at ASC",
+          BLAME_JOB_COLUMNS
+        ),
+        &[],
+      )
+      .await
+      .context("looking up unfinished blame jobs in postgres")?;
+    Ok(rows.iter().map(blame_job_from_row).collect())
+  }
+
+  async fn update_blame_job_status(
+    &self,
+    job_id: &str,
+    status: &str,
+    error: Option<&str>,
+  ) -> anyhow::Result<()> {
+    self
+      .client()
+      .await?
+      .execute(
+        "UPDATE blame_jobs SET status = $2, error = $3, updated_at = now() WHERE id = $1::text::uuid",
+        &[&job_id, &status, &error],
+      )
+      .await
+      .context("updating blame job status in postgres")?;
+    Ok(())
+  }
+
+  async fn active_github_repos(&self) -> anyhow::Result<Vec<GitHubNodeId>> {
+    let rows = self
+      .client()
+      .await?
+      .query(
+        "SELECT DISTINCT repo_github_node_id FROM commit_github_repo",
+        &[],
+      )
+      .await
+      .context("looking up active github repos in postgres")?;
+    Ok(rows.iter().map(|row| GitHubNodeId(row.get(0))).collect())
+  }
+
+  async fn active_other_repos(&self) -> anyhow::Result<Vec<String>> {
+    let rows = self
+      .client()
+      .await?
+      .query("SELECT DISTINCT repo_id FROM commit_repo", &[])
+      .await
+      .context("looking up active repos in postgres")?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::repo_id::RepoId;
+
+  #[tokio::test]
+  #[ignore = "needs a database with hasura/migrations applied, at TEST_DATABASE_URL"]
+  async fn round_trip() {
+    let database_url = std::env::var("TEST_DATABASE_URL")
+      .expect("TEST_DATABASE_URL should point at a test database");
+    let storage = PostgresStorage::new(
+      &database_url,
+      TokenCipher::new(vec!["k=0123456789abcdef0123456789abcdef".parse().unwrap()]),
+    )
+    .unwrap();
+    // Unique per run, so that we can run this against the same database more than once.
+    let nonce = std::time::SystemTime::now()
+      .duration_since(std::time::UNIX_EPOCH)
+      .unwrap()
+      .as_nanos();
+    let user = GitHubUserId(GitHubNodeId(format!("user-{}", nonce)));
+    let commit_hash = format!("{:040}", nonce);
+    let file_path = "src/\"weird\"\t\\path/日本語.rs";
+
+    storage
+      .upsert_user(
+        &user,
+        (nonce % i32::MAX as u128) as u32,
+        "Name",
+        &format!("username-{}", nonce),
+        Some("user@example.com".to_string()),
+        &format!("token-{}", nonce),
+      )
+      .await
+      .unwrap();
+    let ttl = Duration::days(1);
+    let session_token = storage.start_user_session(&user, ttl).await.unwrap();
+    let (auth, session) = storage
+      .lookup_user_session(&session_token, ttl)
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(auth.github_node_id.0 .0, user.0 .0);
+    assert_eq!(auth.access_token, format!("token-{}", nonce));
+    assert!(session.expires_at > session.last_used_at);
+    async fn stored_token(storage: &PostgresStorage, user: &GitHubUserId) -> String {
+      storage
+        .client()
+        .await
+        .unwrap()
+        .query_one(
+          "SELECT access_token FROM github_users WHERE github_node_id = $1",
+          &[&user.0 .0],
+        )
+        .await
+        .unwrap()
+        .get(0)
+    }
+    assert!(stored_token(&storage, &user)
+      .await
+      .starts_with("k:v2.local."));
+    // Rows from before we encrypted tokens, or that were encrypted with an older key, still work, and get re-encrypted
+    // with the current key.
+    storage
+      .client()
+      .await
+      .unwrap()
+      .execute(
+        "UPDATE github_users SET access_token = $2 WHERE github_node_id = $1",
+        &[&user.0 .0, &format!("token-{}", nonce)],
+      )
+      .await
+      .unwrap();
+    assert_eq!(
+      storage
+        .lookup_user_session(&session_token, ttl)
+        .await
+        .unwrap()
+        .unwrap()
+        .0
+        .access_token,
+      format!("token-{}", nonce)
+    );
+    assert!(storage.reencrypt_access_tokens().await.unwrap() >= 1);
+    assert!(stored_token(&storage, &user)
+      .await
+      .starts_with("k:v2.local."));
+    let rotated_storage = PostgresStorage::new(
+      &database_url,
+      TokenCipher::new(vec![
+        "k2=fedcba9876543210fedcba9876543210".parse().unwrap(),
+        "k=0123456789abcdef0123456789abcdef".parse().unwrap(),
+      ]),
+    )
+    .unwrap();
+    assert!(rotated_storage.reencrypt_access_tokens().await.unwrap() >= 1);
+    assert!(stored_token(&storage, &user)
+      .await
+      .starts_with("k2:v2.local."));
+    assert_eq!(
+      rotated_storage
+        .lookup_user_session(&session_token, ttl)
+        .await
+        .unwrap()
+        .unwrap()
+        .0
+        .access_token,
+      format!("token-{}", nonce)
+    );
+    // The old key can't read the user's token anymore.
+    let storage = rotated_storage;
+    let grace = Duration::minutes(1);
+    let rotated_token = storage
+      .rotate_user_session(&session_token, ttl, grace)
+      .await
+      .unwrap()
+      .unwrap();
+    // The old session lives out its grace period, without getting pushed back or rotated again.
+    let (_, old) = storage
+      .lookup_user_session(&session_token, ttl)
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(old.rotated_at.is_some());
+    assert!(old.expires_at <= chrono::Utc::now() + grace);
+    assert!(storage
+      .rotate_user_session(&session_token, ttl, grace)
+      .await
+      .unwrap()
+      .is_none());
+    storage.end_user_session(&session_token).await.unwrap();
+    assert!(storage
+      .lookup_user_session(&session_token, ttl)
+      .await
+      .unwrap()
+      .is_none());
+    let (_, rotated) = storage
+      .lookup_user_session(&rotated_token, ttl)
+      .await
+      .unwrap()
+      .unwrap();
+    let sessions = storage.user_sessions(&user).await.unwrap();
+    assert_eq!(
+      sessions.iter().map(|s| &s.public_id).collect::<Vec<_>>(),
+      vec![&rotated.public_id]
+    );
+    assert!(storage
+      .lookup_user_session("not-a-uuid", ttl)
+      .await
+      .unwrap()
+      .is_none());
+    assert!(storage
+      .rotate_user_session("not-a-uuid", ttl, ttl)
+      .await
+      .unwrap()
+      .is_none());
+    storage.end_user_session("not-a-uuid").await.unwrap();
+    assert!(!storage
+      .end_user_session_by_public_id(&user, "not-a-uuid")
+      .await
+      .unwrap());
+    assert!(storage
+      .end_user_session_by_public_id(&user, &rotated.public_id)
+      .await
+      .unwrap());
+    assert!(storage
+      .lookup_user_session(&rotated_token, ttl)
+      .await
+      .unwrap()
+      .is_none());
+
+    assert!(!storage
+      .lookup_existing_blamelines(&commit_hash, file_path)
+      .await
+      .unwrap());
+    let blamelines = (1..=3)
+      .map(|i| BlameLine {
+        original_commit: commit_hash.clone(),
+        original_file_path: file_path.to_string(),
+        original_line_number: i,
+      })
+      .collect::<Vec<_>>();
+    storage
+      .insert_blamelines(&commit_hash, file_path, blamelines)
+      .await
+      .unwrap();
+    assert!(storage
+      .lookup_existing_blamelines(&commit_hash, file_path)
+      .await
+      .unwrap());
+
+    let repo = RepoWithCommit::Other(RepoId::GitHubRepo {
+      owner: "owner".into(),
+      name: format!("repo-{}", nonce),
+    });
+    let (thread_id, first_comment_id) = storage
+      .start_thread(
+        &user,
+        &repo,
+        &commit_hash,
+        file_path,
+        &LineRange {
+          start_line: 2,
+          end_line: 2,
+          start_column: None,
+          end_column: None,
+        },
+        "hello",
+      )
+      .await
+      .unwrap();
+    let threads = storage
+      .threads_for_original_lines(vec![commit_hash.clone()], vec![file_path.to_string()])
+      .await
+      .unwrap();
+    assert_eq!(threads.len(), 1);
+    assert_eq!(threads[0].id, thread_id);
+    assert_eq!(threads[0].original_line_number, 2);
+    assert_eq!(threads[0].comments.len(), 1);
+    assert_eq!(threads[0].comments[0].body, "hello");
+    assert_eq!(threads[0].comments[0].id, first_comment_id);
+
+    let mentioned = GitHubUser {
+      login: format!("Mentioned-{}", nonce),
+      node_id: GitHubNodeId(format!("U_mentioned_{}", nonce)),
+      database_id: ((nonce + 1) % i32::MAX as u128) as u32,
+      name: None,
+      email: None,
+    };
+    storage.upsert_mentioned_user(&mentioned).await.unwrap();
+    storage.upsert_mentioned_user(&mentioned).await.unwrap();
+    let mention = MentionRecord {
+      github_node_id: mentioned.node_id.0.clone(),
+      github_username: mentioned.login.clone(),
+    };
+    assert_eq!(
+      storage
+        .lookup_users_by_username(&[mentioned.login.to_uppercase(), "nobody".to_string()])
+        .await
+        .unwrap(),
+      vec![mention.clone()]
+    );
+    storage
+      .add_mentions(&first_comment_id, std::slice::from_ref(&mention))
+      .await
+      .unwrap();
+    storage
+      .add_mentions(&first_comment_id, std::slice::from_ref(&mention))
+      .await
+      .unwrap();
+    let (_, first_comment) = storage
+      .lookup_comment(&first_comment_id)
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(first_comment.mentions, vec![mention]);
+    assert_eq!(
+      storage
+        .lookup_thread(&thread_id)
+        .await
+        .unwrap()
+        .unwrap()
+        .original_file_path,
+      file_path
+    );
+    assert!(storage
+      .active_other_repos()
+      .await
+      .unwrap()
+      .contains(&format!("github-owner!repo-{}", nonce)));
+
+    // Ranges can start on the same line as another thread.
+    let (range_thread_id, _) = storage
+      .start_thread(
+        &user,
+        &repo,
+        &commit_hash,
+        file_path,
+        &LineRange {
+          start_line: 2,
+          end_line: 4,
+          start_column: Some(1),
+          end_column: Some(0),
+        },
+        "a range",
+      )
+      .await
+      .unwrap();
+    let anchor = storage
+      .lookup_thread(&range_thread_id)
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(
+      (
+        anchor.original_line_number,
+        anchor.original_end_line_number(),
+        anchor.original_start_column,
+        anchor.original_end_column
+      ),
+      (2, 4, Some(1), Some(0))
+    );
+
+    let other_repo = RepoWithCommit::Other(RepoId::GitHubRepo {
+      owner: "owner".into(),
+      name: format!("other-repo-{}", nonce),
+    });
+    assert!(storage
+      .threads_for_file_paths(&other_repo, vec![file_path.to_string()])
+      .await
+      .unwrap()
+      .iter()
+      .all(|t| t.id != thread_id));
+
+    assert!(storage.resolve_thread(&thread_id, &user).await.unwrap());
+    assert!(!storage.resolve_thread(&thread_id, &user).await.unwrap());
+    let threads = storage
+      .threads_for_file_paths(&repo, vec![file_path.to_string()])
+      .await
+      .unwrap();
+    let thread = threads.iter().find(|t| t.id == thread_id).unwrap();
+    assert!(thread.resolved_at.is_some());
+    assert_eq!(thread.resolved_by_github_node_id, Some(user.0 .0.clone()));
+    assert!(storage.reopen_thread(&thread_id).await.unwrap());
+    assert!(!storage.reopen_thread(&thread_id).await.unwrap());
+    let threads = storage
+      .threads_for_file_paths(&repo, vec![file_path.to_string()])
+      .await
+      .unwrap();
+    let thread = threads.iter().find(|t| t.id == thread_id).unwrap();
+    assert_eq!(thread.resolved_at, None);
+    assert_eq!(thread.resolved_by_github_node_id, None);
+
+    let reply = storage
+      .add_comment(&user, &thread_id, "first")
+      .await
+      .unwrap();
+    assert_eq!(reply.edited_at, None);
+    let (reply_thread_id, _) = storage.lookup_comment(&reply.id).await.unwrap().unwrap();
+    assert_eq!(reply_thread_id, thread_id);
+    let edited = storage
+      .edit_comment(&reply.id, "second")
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(edited.body, "second");
+    assert!(edited.edited_at.is_some());
+    storage.edit_comment(&reply.id, "third").await.unwrap();
+    assert_eq!(
+      storage
+        .comment_edits(&reply.id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|e| e.body)
+        .collect::<Vec<_>>(),
+      vec!["first", "second"]
+    );
+    storage
+      .add_reaction(&reply.id, &user, "heart")
+      .await
+      .unwrap();
+    storage
+      .add_reaction(&reply.id, &user, "heart")
+      .await
+      .unwrap();
+    storage
+      .add_reaction(&reply.id, &user, "eyes")
+      .await
+      .unwrap();
+    assert!(storage
+      .add_reaction(&reply.id, &user, "party_parrot")
+      .await
+      .is_err());
+    storage
+      .remove_reaction(&reply.id, &user, "eyes")
+      .await
+      .unwrap();
+    let (_, reacted) = storage.lookup_comment(&reply.id).await.unwrap().unwrap();
+    assert_eq!(
+      reacted.reactions,
+      vec![ReactionRecord {
+        reaction: "heart".to_string(),
+        github_node_id: user.0 .0.clone(),
+      }]
+    );
+    assert!(storage.delete_comment(&reply.id).await.unwrap());
+    assert!(!storage.delete_comment(&reply.id).await.unwrap());
+    assert!(storage
+      .edit_comment(&reply.id, "fourth")
+      .await
+      .unwrap()
+      .is_none());
+    let (_, deleted) = storage.lookup_comment(&reply.id).await.unwrap().unwrap();
+    assert!(deleted.deleted_at.is_some());
+    assert_eq!(deleted.body, "third");
+
+    let job = storage
+      .insert_blame_job("github-owner!repo", &commit_hash, file_path)
+      .await
+      .unwrap();
+    assert_eq!(job.status, "queued");
+    assert_eq!(
+      storage
+        .lookup_unfinished_blame_job("github-owner!repo", &commit_hash, file_path)
+        .await
+        .unwrap()
+        .unwrap()
+        .id,
+      job.id
+    );
+    assert!(storage
+      .lookup_unfinished_blame_job("github-someone!else", &commit_hash, file_path)
+      .await
+      .unwrap()
+      .is_none());
+    storage
+      .update_blame_job_status(&job.id, "failed", Some("oops"))
+      .await
+      .unwrap();
+    let job = storage.lookup_blame_job(&job.id).await.unwrap().unwrap();
+    assert_eq!(job.status, "failed");
+    assert_eq!(job.error.as_deref(), Some("oops"));
+    assert!(storage
+      .lookup_unfinished_blame_job("github-owner!repo", &commit_hash, file_path)
+      .await
+      .unwrap()
+      .is_none());
+
+    // An empty file has no line 1, so it's the done job that says we have all of its blamelines.
+    let empty_file = "src/empty.rs";
+    storage
+      .insert_blamelines(&commit_hash, empty_file, vec![])
+      .await
+      .unwrap();
+    let job = storage
+      .insert_blame_job("github-owner!repo", &commit_hash, empty_file)
+      .await
+      .unwrap();
+    assert!(!storage
+      .lookup_existing_blamelines(&commit_hash, empty_file)
+      .await
+      .unwrap());
+    storage
+      .update_blame_job_status(&job.id, "done", None)
+      .await
+      .unwrap();
+    assert!(storage
+      .lookup_existing_blamelines(&commit_hash, empty_file)
+      .await
+      .unwrap());
+  }
+}
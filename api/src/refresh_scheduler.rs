@@ -0,0 +1,186 @@
+// Periodically fetches the mirrors of repos that people have started threads on, so that by the time someone opens a
+// file at a just-pushed commit we usually have it already.
+//
+// Repos that keep turning out to be unchanged get checked less and less often, up to a limit. As soon as a fetch turns
+// up something new, the repo goes back to the base interval.
+use crate::github::GitHub;
+use crate::repo_id::parse_repo_id;
+use crate::repo_id::RepoId;
+use crate::storage::Storage;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How often we wake up to check whether anything is due.
+const TICK: Duration = Duration::from_secs(30);
+
+struct RepoSchedule {
+  interval: Duration,
+  next_due: Instant,
+}
+
+pub struct Schedule {
+  base_interval: Duration,
+  max_interval: Duration,
+  repos: HashMap<RepoId, RepoSchedule>,
+}
+
+impl Schedule {
+  pub fn new(base_interval: Duration, max_interval: Duration) -> Self {
+    Schedule {
+      base_interval,
+      max_interval,
+      repos: HashMap::new(),
+    }
+  }
+
+  /// Replace the set of repos that we're keeping fresh. Repos that we haven't seen before are due right away, the rest
+  /// keep their current schedule.
+  pub fn set_repos(&mut self, repos: impl IntoIterator<Item = RepoId>, now: Instant) {
+    let mut old = std::mem::take(&mut self.repos);
+    for repo_id in repos {
+      let schedule = old.remove(&repo_id).unwrap_or(RepoSchedule {
+        interval: self.base_interval,
+        next_due: now,
+      });
+      self.repos.insert(repo_id, schedule);
+    }
+  }
+
+  /// Repos that are due for a refresh, most overdue first.
+  pub fn due(&self, now: Instant) -> Vec<RepoId> {
+    let mut due = self
+      .repos
+      .iter()
+      .filter(|(_, s)| s.next_due <= now)
+      .collect::<Vec<_>>();
+    due.sort_by_key(|(_, s)| s.next_due);
+    due
+      .into_iter()
+      .map(|(repo_id, _)| repo_id.clone())
+      .collect()
+  }
+
+  /// Record the outcome of a refresh. Failures back off the same way that unchanged repos do, so that a repo that's
+  /// gone missing doesn't get hammered.
+  pub fn record(&mut self, repo_id: &RepoId, changed: bool, now: Instant) {
+    if let Some(s) = self.repos.get_mut(repo_id) {
+      s.interval = if changed {
+        self.base_interval
+      } else {
+        (s.interval * 2).min(self.max_interval)
+      };
+      s.next_due = now + s.interval;
+    }
+  }
+}
+
+/// Every repo that has had a thread started on one of its commits.
+async fn active_repos(storage: &dyn Storage, github: &dyn GitHub) -> anyhow::Result<Vec<RepoId>> {
+  let mut repos = vec![];
+  let github_node_ids = storage.active_github_repos().await?;
+  for (owner, name) in github.lookup_repo_names(&github_node_ids).await? {
+    repos.push(RepoId::GitHubRepo { owner, name });
+  }
+  for repo_id in storage.active_other_repos().await? {
+    match parse_repo_id(&repo_id) {
+      Ok(repo_id) => repos.push(repo_id),
+      Err(e) => log::warn!("ignoring bad repo_id {:?} in commit_repo: {:?}", repo_id, e),
+    }
+  }
+  Ok(repos)
+}
+
+/// Run forever, refreshing repos every `base_interval` and backing off to at most `max_interval`. A zero
+/// `base_interval` turns this off.
+pub async fn run(
+  storage: Arc<dyn Storage>,
+  github: Arc<dyn GitHub>,
+  base_interval: Duration,
+  max_interval: Duration,
+) {
+  if base_interval.is_zero() {
+    log::info!("mirror refresh scheduler is disabled");
+    return;
+  }
+
+  let mut schedule = Schedule::new(base_interval, max_interval);
+  let mut repos_looked_up_at: Option<Instant> = None;
+  loop {
+    // The set of active repos only grows when someone starts a thread on a new repo, so there's no need to keep asking.
+    if repos_looked_up_at.is_none_or(|t| t.elapsed() >= base_interval) {
+      match active_repos(&*storage, &*github).await {
+        Ok(repos) => {
+          log::info!("refreshing mirrors for {} active repos", repos.len());
+          schedule.set_repos(repos, Instant::now());
+          repos_looked_up_at = Some(Instant::now());
+        }
+        Err(e) => log::error!("failed to look up active repos: {:?}", e),
+      }
+    }
+
+    for repo_id in schedule.due(Instant::now()) {
+      let changed = match crate::mirrors().refresh(&repo_id).await {
+        Ok(changed) => {
+          log::trace!("refreshed {}, changed = {}", repo_id, changed);
+          changed
+        }
+        Err(e) => {
+          log::warn!("failed to refresh {}: {:?}", repo_id, e);
+          false
+        }
+      };
+      schedule.record(&repo_id, changed, Instant::now());
+    }
+
+    tokio::time::sleep(TICK).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn repo(name: &str) -> RepoId {
+    RepoId::GitHubRepo {
+      owner: "owner".into(),
+      name: name.into(),
+    }
+  }
+
+  #[test]
+  fn backs_off_until_something_changes() {
+    let minute = Duration::from_secs(60);
+    let mut schedule = Schedule::new(minute, minute * 5);
+    let start = Instant::now();
+    schedule.set_repos([repo("a"), repo("b")], start);
+    assert_eq!(schedule.due(start).len(), 2);
+
+    // a never changes, b changes every time.
+    let mut a_refreshed_at = vec![];
+    let mut now = start;
+    while now < start + minute * 20 {
+      for repo_id in schedule.due(now) {
+        if repo_id == repo("a") {
+          a_refreshed_at.push((now - start).as_secs() / 60);
+        }
+        schedule.record(&repo_id, repo_id == repo("b"), now);
+      }
+      assert!(schedule.due(now).is_empty());
+      now += minute;
+      if now < start + minute * 20 {
+        assert!(schedule.due(now).contains(&repo("b")));
+      }
+    }
+    // Waiting 2, 4, and then capped at 5 minutes.
+    assert_eq!(a_refreshed_at, vec![0, 2, 6, 11, 16]);
+
+    // Repos that are still around keep their schedule, new ones are due right away.
+    schedule.set_repos([repo("a"), repo("c")], now);
+    assert_eq!(schedule.due(now), vec![repo("c")]);
+    schedule.record(&repo("a"), true, now);
+    assert!(!schedule.due(now + minute).contains(&repo("b")));
+    assert!(schedule.due(now + minute).contains(&repo("a")));
+  }
+}
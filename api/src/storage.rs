@@ -0,0 +1,345 @@
+// Everything that the api reads from or writes to the database goes through `Storage`. There are two implementations:
+// `hasura::HasuraStorage`, which goes through Hasura's admin GraphQL API, and `postgres::PostgresStorage`, which talks
+// to the same Postgres database directly. Both assume the schema in hasura/migrations. Which one we use is picked at
+// startup with the STORAGE_BACKEND setting. Tests use `fakes::InMemoryStorage` instead.
+//
+// GraphQL resolvers get their `Storage` from `JuniperContext` rather than the global, so that they can be tested.
+//
+// Hasura still needs to be up either way, since it's what the web client talks to and what sends us events.
+use crate::config::Config;
+use crate::config::StorageBackend;
+use crate::github::GitHubNodeId;
+use crate::github::GitHubUser;
+use crate::token_cipher::TokenCipher;
+use crate::BlameLine;
+use crate::GitHubAuth;
+use crate::GitHubUserId;
+use crate::RepoWithCommit;
+use async_trait::async_trait;
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Where a thread was started.
+#[derive(Clone, Debug)]
+pub struct ThreadAnchor {
+  pub original_commit_hash: String,
+  pub original_file_path: String,
+  /// The first line of the range.
+  pub original_line_number: i64,
+  /// The last line of the range, inclusive. None for threads from before ranges, which are on a single line.
+  pub original_end_line_number: Option<i64>,
+  pub original_start_column: Option<i64>,
+  pub original_end_column: Option<i64>,
+}
+
+impl ThreadAnchor {
+  pub fn original_end_line_number(&self) -> i64 {
+    self
+      .original_end_line_number
+      .unwrap_or(self.original_line_number)
+  }
+}
+
+/// The lines, and optionally the columns, that a new thread is anchored to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineRange {
+  /// 1-indexed and inclusive.
+  pub start_line: u32,
+  pub end_line: u32,
+  /// 0-indexed character offsets into the first and last line, for threads on part of a line. The end is exclusive.
+  pub start_column: Option<u32>,
+  pub end_column: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ThreadWithComments {
+  pub id: String,
+  pub original_commit_hash: String,
+  pub original_file_path: String,
+  pub original_line_number: i64,
+  pub original_end_line_number: Option<i64>,
+  pub original_start_column: Option<i64>,
+  pub original_end_column: Option<i64>,
+  /// Null while the thread is open.
+  pub resolved_at: Option<String>,
+  pub resolved_by_github_node_id: Option<String>,
+  /// Oldest first.
+  pub comments: Vec<CommentRecord>,
+}
+
+impl ThreadWithComments {
+  pub fn anchor(&self) -> ThreadAnchor {
+    ThreadAnchor {
+      original_commit_hash: self.original_commit_hash.clone(),
+      original_file_path: self.original_file_path.clone(),
+      original_line_number: self.original_line_number,
+      original_end_line_number: self.original_end_line_number,
+      original_start_column: self.original_start_column,
+      original_end_column: self.original_end_column,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CommentRecord {
+  pub id: String,
+  pub body: String,
+  pub created_at: String,
+  pub author_github_node_id: Option<String>,
+  pub author_email: Option<String>,
+  /// When the body was last changed, if ever.
+  pub edited_at: Option<String>,
+  /// Deleted comments keep their body, but nobody should see it.
+  pub deleted_at: Option<String>,
+  /// In no particular order.
+  pub reactions: Vec<ReactionRecord>,
+  /// Ordered by username.
+  pub mentions: Vec<MentionRecord>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ReactionRecord {
+  /// One of `comments::Reaction`, as stored in comment_reactions.reaction.
+  pub reaction: String,
+  pub github_node_id: String,
+}
+
+/// A user @mentioned in a comment.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct MentionRecord {
+  pub github_node_id: String,
+  /// As it was when they were mentioned. People can change their username.
+  pub github_username: String,
+}
+
+/// A previous version of a comment.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CommentEditRecord {
+  pub body: String,
+  /// When `body` stopped being the comment's body.
+  pub replaced_at: String,
+}
+
+/// A row in the user_sessions table, minus the session token itself.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SessionRecord {
+  /// Identifies the session to its user. Unlike the token, this is fine to hand out.
+  pub public_id: String,
+  pub created_at: DateTime<Utc>,
+  pub last_used_at: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+  /// When the session was replaced by a new one, if it has been. See `Storage::rotate_user_session`.
+  pub rotated_at: Option<DateTime<Utc>>,
+}
+
+/// A row in the blame_jobs table.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlameJobRecord {
+  pub id: String,
+  pub repo_id: String,
+  pub commit_hash: String,
+  pub file_path: String,
+  pub status: String,
+  pub error: Option<String>,
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+  /// Whether we already have the blamelines for `file_path` as of `commit_hash`, either because line 1 is there or
+  /// because a blame job for the file is done. Empty files have no lines at all, so only the job can say so.
+  async fn lookup_existing_blamelines(
+    &self,
+    commit_hash: &str,
+    file_path: &str,
+  ) -> anyhow::Result<bool>;
+  /// Insert the blamelines for `file_path` as of `commit_hash`. Existing rows are left alone. Once
+  /// `lookup_existing_blamelines` says that they're there, all of them have to be.
+  async fn insert_blamelines(
+    &self,
+    commit_hash: &str,
+    file_path: &str,
+    blamelines: Vec<BlameLine>,
+  ) -> anyhow::Result<()>;
+
+  /// Stores `github_access_token` encrypted, see `token_cipher`.
+  async fn upsert_user(
+    &self,
+    github_node_id: &GitHubUserId,
+    github_database_id: u32,
+    github_name: &str,
+    github_username: &str,
+    email: Option<String>,
+    github_access_token: &str,
+  ) -> anyhow::Result<()>;
+  /// Make sure that there's a github_users row for someone who was mentioned, so that they can be notified even if
+  /// they've never logged in. Users that we already have keep their email and access token, but get `user`'s username
+  /// and name.
+  async fn upsert_mentioned_user(&self, user: &GitHubUser) -> anyhow::Result<()>;
+  /// The users that we have with one of `usernames`, matched case insensitively.
+  async fn lookup_users_by_username(
+    &self,
+    usernames: &[String],
+  ) -> anyhow::Result<Vec<MentionRecord>>;
+  /// Encrypt every stored access token that isn't encrypted with the current key yet, including ones from before we
+  /// encrypted them at all. Returns how many were updated.
+  async fn reencrypt_access_tokens(&self) -> anyhow::Result<usize>;
+  /// Returns the new session token. The session expires once it has gone unused for `ttl`.
+  async fn start_user_session(
+    &self,
+    github_user: &GitHubUserId,
+    ttl: Duration,
+  ) -> anyhow::Result<String>;
+  /// Expired sessions don't count. Looking a session up counts as using it, so this also pushes its expiry back to
+  /// `ttl` from now, unless the session has been rotated.
+  async fn lookup_user_session(
+    &self,
+    session_token: &str,
+    ttl: Duration,
+  ) -> anyhow::Result<Option<(GitHubAuth, SessionRecord)>>;
+  async fn end_user_session(&self, session_token: &str) -> anyhow::Result<()>;
+  /// Start a new session for the same user as `session_token`, and cut the old one off after `grace` so that requests
+  /// already in flight with it still go through. Returns the new session token, or None if `session_token` has
+  /// expired or has already been rotated.
+  async fn rotate_user_session(
+    &self,
+    session_token: &str,
+    ttl: Duration,
+    grace: Duration,
+  ) -> anyhow::Result<Option<String>>;
+  /// Every unexpired session that `github_user` has, most recently used first.
+  async fn user_sessions(&self, github_user: &GitHubUserId) -> anyhow::Result<Vec<SessionRecord>>;
+  /// End `github_user`'s session with `public_id`. Returns whether there was one.
+  async fn end_user_session_by_public_id(
+    &self,
+    github_user: &GitHubUserId,
+    public_id: &str,
+  ) -> anyhow::Result<bool>;
+
+  /// Start a thread with a single comment from `author_github_node_id`, recording that `commit_hash` lives in `repo`
+  /// and every line in `range` along the way. Returns the IDs of the created thread and its comment.
+  async fn start_thread(
+    &self,
+    author_github_node_id: &GitHubUserId,
+    repo: &RepoWithCommit,
+    commit_hash: &str,
+    file_path: &str,
+    range: &LineRange,
+    body: &str,
+  ) -> anyhow::Result<(String, String)>;
+  async fn lookup_thread(&self, thread_id: &str) -> anyhow::Result<Option<ThreadAnchor>>;
+  /// Every thread anchored to one of `commit_hashes` and one of `file_paths`. This is a superset of what you probably
+  /// want, so filter the results.
+  async fn threads_for_original_lines(
+    &self,
+    commit_hashes: Vec<String>,
+    file_paths: Vec<String>,
+  ) -> anyhow::Result<Vec<ThreadWithComments>>;
+  /// Every thread ever started on one of `file_paths` in `repo`, at any commit.
+  async fn threads_for_file_paths(
+    &self,
+    repo: &RepoWithCommit,
+    file_paths: Vec<String>,
+  ) -> anyhow::Result<Vec<ThreadWithComments>>;
+  /// Mark an open thread as resolved by `resolver`. Returns false if the thread doesn't exist or was already resolved,
+  /// in which case whoever resolved it first stays on record.
+  async fn resolve_thread(&self, thread_id: &str, resolver: &GitHubUserId) -> anyhow::Result<bool>;
+  /// Returns false if the thread doesn't exist or wasn't resolved.
+  async fn reopen_thread(&self, thread_id: &str) -> anyhow::Result<bool>;
+
+  /// Add a comment from `author_github_node_id` to the end of an existing thread.
+  async fn add_comment(
+    &self,
+    author_github_node_id: &GitHubUserId,
+    thread_id: &str,
+    body: &str,
+  ) -> anyhow::Result<CommentRecord>;
+  /// The comment along with the id of its thread. Deleted comments are included.
+  async fn lookup_comment(
+    &self,
+    comment_id: &str,
+  ) -> anyhow::Result<Option<(String, CommentRecord)>>;
+  /// Replace a comment's body. The database keeps the old one in comment_edits. Returns None if the comment doesn't
+  /// exist or has been deleted.
+  async fn edit_comment(
+    &self,
+    comment_id: &str,
+    body: &str,
+  ) -> anyhow::Result<Option<CommentRecord>>;
+  /// Mark a comment as deleted. Returns whether there was an undeleted comment to delete.
+  async fn delete_comment(&self, comment_id: &str) -> anyhow::Result<bool>;
+  /// Every previous version of a comment, oldest first.
+  async fn comment_edits(&self, comment_id: &str) -> anyhow::Result<Vec<CommentEditRecord>>;
+  /// Adding a reaction that's already there, or removing one that isn't, does nothing.
+  async fn add_reaction(
+    &self,
+    comment_id: &str,
+    github_user: &GitHubUserId,
+    reaction: &str,
+  ) -> anyhow::Result<()>;
+  async fn remove_reaction(
+    &self,
+    comment_id: &str,
+    github_user: &GitHubUserId,
+    reaction: &str,
+  ) -> anyhow::Result<()>;
+  /// Record that a comment mentions each of `mentions`, who should already be in github_users. Mentions that were
+  /// already recorded are left alone.
+  async fn add_mentions(&self, comment_id: &str, mentions: &[MentionRecord]) -> anyhow::Result<()>;
+
+  /// Insert a new queued blame job and return it.
+  async fn insert_blame_job(
+    &self,
+    repo_id: &str,
+    commit_hash: &str,
+    file_path: &str,
+  ) -> anyhow::Result<BlameJobRecord>;
+  async fn lookup_blame_job(&self, job_id: &str) -> anyhow::Result<Option<BlameJobRecord>>;
+  /// A queued or running job for (repo_id, commit_hash, file_path), if there is one. Jobs for the same commit in another
+  /// repo don't count, since they may well fail where this one wouldn't.
+  async fn lookup_unfinished_blame_job(
+    &self,
+    repo_id: &str,
+    commit_hash: &str,
+    file_path: &str,
+  ) -> anyhow::Result<Option<BlameJobRecord>>;
+  /// Every job that hasn't finished yet, oldest first.
+  async fn unfinished_blame_jobs(&self) -> anyhow::Result<Vec<BlameJobRecord>>;
+  async fn update_blame_job_status(
+    &self,
+    job_id: &str,
+    status: &str,
+    error: Option<&str>,
+  ) -> anyhow::Result<()>;
+
+  /// Every GitHub repo that has had a thread started on one of its commits.
+  async fn active_github_repos(&self) -> anyhow::Result<Vec<GitHubNodeId>>;
+  /// The same as `active_github_repos`, but for every other kind of repo. These are `RepoId` strings.
+  async fn active_other_repos(&self) -> anyhow::Result<Vec<String>>;
+}
+
+/// Session tokens and public ids are uuids. Anything else can't belong to a session, and would be an error rather than a
+/// miss if it made it to the database.
+pub fn looks_like_uuid(s: &str) -> bool {
+  s.len() == 36
+    && s.char_indices().all(|(i, c)| match i {
+      8 | 13 | 18 | 23 => c == '-',
+      _ => c.is_ascii_hexdigit(),
+    })
+}
+
+/// The backend that `config` asks for.
+pub fn from_config(config: &Config) -> anyhow::Result<Arc<dyn Storage>> {
+  Ok(match config.storage_backend {
+    StorageBackend::Hasura => Arc::new(crate::hasura::HasuraStorage::new(config)),
+    StorageBackend::Postgres => Arc::new(crate::postgres::PostgresStorage::new(
+      config
+        .database_url
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("DATABASE_URL not set"))?,
+      TokenCipher::new(config.access_token_keys.clone()),
+    )?),
+  })
+}
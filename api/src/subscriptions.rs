@@ -0,0 +1,525 @@
+// Live updates for threads and comments, served over the graphql-ws websocket protocol.
+//
+// Every new thread/comment gets published on a process-wide broadcast channel. Each subscriber filters that down to the
+// events that are relevant to the (commit, file_path) that they're looking at.
+use crate::config::Config;
+use crate::github::GitHub;
+use crate::juniperify;
+use crate::lookup_session_from_header;
+use crate::parse_repo_id;
+use crate::storage::CommentRecord;
+use crate::storage::Storage;
+use crate::storage::ThreadAnchor;
+use crate::AuthContext;
+use crate::JuniperContext;
+use crate::Schema;
+use anyhow::anyhow;
+use anyhow::ensure;
+use futures::future;
+use futures::SinkExt;
+use futures::Stream;
+use futures::StreamExt;
+use hyper::header;
+use hyper::Body;
+use hyper::Request;
+use hyper::Response;
+use hyper::StatusCode;
+use juniper::FieldResult;
+use juniper::GraphQLEnum;
+use juniper::GraphQLObject;
+use juniper_graphql_ws::ClientMessage;
+use juniper_graphql_ws::ConnectionConfig;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// The subprotocol implemented by juniper_graphql_ws, aka. Apollo's subscriptions-transport-ws.
+const GRAPHQL_WS_PROTOCOL: &str = "graphql-ws";
+
+#[derive(Clone, Copy, Debug, PartialEq, GraphQLEnum)]
+pub enum ThreadEventKind {
+  ThreadStarted,
+  CommentAdded,
+  CommentEdited,
+  /// The event's body is empty, like the comment's.
+  CommentDeleted,
+  /// For these two, the event's author is whoever resolved or reopened the thread, and the body is empty.
+  ThreadResolved,
+  ThreadReopened,
+}
+
+#[derive(Clone, Debug, GraphQLObject)]
+pub struct ThreadEvent {
+  kind: ThreadEventKind,
+  thread_id: String,
+  comment_id: Option<String>,
+  author_github_node_id: Option<String>,
+  body: String,
+  // Where the thread is anchored.
+  original_commit: String,
+  original_file_path: String,
+  original_line_number: i32,
+  original_end_line_number: i32,
+}
+
+lazy_static! {
+  // Subscribers that fall more than this many events behind will start missing events.
+  static ref THREAD_EVENTS: broadcast::Sender<ThreadEvent> = broadcast::channel(1024).0;
+}
+
+fn publish(event: ThreadEvent) {
+  log::trace!("publishing thread event {:?}", event);
+  // An Err here just means that nobody is subscribed at the moment.
+  let _ = THREAD_EVENTS.send(event);
+}
+
+pub fn publish_thread_started(
+  thread_id: &str,
+  author_github_node_id: &str,
+  body: &str,
+  commit_hash: &str,
+  file_path: &str,
+  start_line_number: i32,
+  end_line_number: i32,
+) {
+  publish(ThreadEvent {
+    kind: ThreadEventKind::ThreadStarted,
+    thread_id: thread_id.to_string(),
+    comment_id: None,
+    author_github_node_id: Some(author_github_node_id.to_string()),
+    body: body.to_string(),
+    original_commit: commit_hash.to_string(),
+    original_file_path: file_path.to_string(),
+    original_line_number: start_line_number,
+    original_end_line_number: end_line_number,
+  });
+}
+
+/// Edits and deletes go through our API rather than Hasura, so they're published directly.
+pub fn publish_comment_changed(
+  kind: ThreadEventKind,
+  thread_id: &str,
+  thread: &ThreadAnchor,
+  comment: &CommentRecord,
+) {
+  publish(ThreadEvent {
+    kind,
+    thread_id: thread_id.to_string(),
+    comment_id: Some(comment.id.clone()),
+    author_github_node_id: comment.author_github_node_id.clone(),
+    body: match kind {
+      ThreadEventKind::CommentDeleted => String::new(),
+      _ => comment.body.clone(),
+    },
+    original_commit: thread.original_commit_hash.clone(),
+    original_file_path: thread.original_file_path.clone(),
+    original_line_number: thread.original_line_number as i32,
+    original_end_line_number: thread.original_end_line_number() as i32,
+  });
+}
+
+pub fn publish_thread_resolution(
+  resolved: bool,
+  thread_id: &str,
+  thread: &ThreadAnchor,
+  github_node_id: &str,
+) {
+  publish(ThreadEvent {
+    kind: if resolved {
+      ThreadEventKind::ThreadResolved
+    } else {
+      ThreadEventKind::ThreadReopened
+    },
+    thread_id: thread_id.to_string(),
+    comment_id: None,
+    author_github_node_id: Some(github_node_id.to_string()),
+    body: String::new(),
+    original_commit: thread.original_commit_hash.clone(),
+    original_file_path: thread.original_file_path.clone(),
+    original_line_number: thread.original_line_number as i32,
+    original_end_line_number: thread.original_end_line_number() as i32,
+  });
+}
+
+type ThreadEventStream = Pin<Box<dyn Stream<Item = FieldResult<ThreadEvent>> + Send>>;
+
+pub struct Subscription;
+
+#[juniper::graphql_subscription(context = JuniperContext)]
+impl Subscription {
+  /// New threads and comments on `file_path` as of `commit_hash`. When `repo_id` is provided we also include threads
+  /// that are anchored to older commits but still show up on this version of the file according to git blame.
+  async fn thread_events(
+    context: &JuniperContext,
+    commit_hash: String,
+    file_path: String,
+    repo_id: Option<String>,
+  ) -> FieldResult<ThreadEventStream> {
+    juniperify(thread_events_inner(context, commit_hash, file_path, repo_id).await)
+  }
+}
+
+async fn thread_events_inner(
+  context: &JuniperContext,
+  commit_hash: String,
+  file_path: String,
+  repo_id: Option<String>,
+) -> anyhow::Result<ThreadEventStream> {
+  // Threads and comments are only visible to logged in users in hasura, so we do the same here.
+  ensure!(
+    matches!(context.auth, AuthContext::GitHub(_)),
+    "unauthorized"
+  );
+
+  // The set of original lines that are visible in this version of the file.
+  let visible_lines = match repo_id {
+    Some(repo_id) => crate::git_blame(&parse_repo_id(&repo_id)?, &commit_hash, &file_path)
+      .await?
+      .into_iter()
+      .map(|bl| {
+        (
+          bl.original_commit,
+          bl.original_file_path,
+          bl.original_line_number,
+        )
+      })
+      .collect::<HashSet<_>>(),
+    None => HashSet::new(),
+  };
+
+  // Subscribe before returning so that we can't miss anything that happens between now and when the client starts
+  // polling the stream.
+  let rx = THREAD_EVENTS.subscribe();
+  let events = futures::stream::unfold(rx, |mut rx| async move {
+    loop {
+      match rx.recv().await {
+        Ok(event) => return Some((event, rx)),
+        Err(broadcast::error::RecvError::Lagged(n)) => {
+          log::warn!("thread event subscriber lagged, dropped {} events", n)
+        }
+        Err(broadcast::error::RecvError::Closed) => return None,
+      }
+    }
+  });
+  Ok(
+    events
+      .filter(move |event| {
+        future::ready(
+          (event.original_commit == commit_hash && event.original_file_path == file_path)
+            || (event.original_line_number..=event.original_end_line_number).any(|line_number| {
+              visible_lines.contains(&(
+                event.original_commit.clone(),
+                event.original_file_path.clone(),
+                line_number,
+              ))
+            }),
+        )
+      })
+      .map(Ok)
+      .boxed(),
+  )
+}
+
+// Hasura calls this via an event trigger whenever a row is inserted into the comments table. See
+// https://hasura.io/docs/latest/graphql/core/event-triggers/payload.html for the payload format.
+#[derive(Deserialize)]
+struct HasuraEventPayload {
+  event: HasuraEvent,
+}
+#[derive(Deserialize)]
+struct HasuraEvent {
+  data: HasuraEventData,
+}
+#[derive(Deserialize)]
+struct HasuraEventData {
+  new: CommentRow,
+}
+#[derive(Deserialize)]
+struct CommentRow {
+  id: String,
+  thread_id: String,
+  body: String,
+  author_github_node_id: Option<String>,
+}
+
+async fn insert_comments_event_route_inner(
+  config: &Config,
+  storage: &dyn Storage,
+  req: Request<Body>,
+) -> anyhow::Result<()> {
+  crate::check_admin_secret(config, &req)?;
+
+  let payload: HasuraEventPayload =
+    serde_json::from_slice(&hyper::body::to_bytes(req.into_body()).await?)?;
+  let comment = payload.event.data.new;
+  let thread = storage
+    .lookup_thread(&comment.thread_id)
+    .await?
+    .ok_or_else(|| {
+      anyhow!(
+        "comment {} belongs to a thread that doesn't exist",
+        comment.id
+      )
+    })?;
+  let original_end_line_number = thread.original_end_line_number() as i32;
+  publish(ThreadEvent {
+    kind: ThreadEventKind::CommentAdded,
+    thread_id: comment.thread_id,
+    comment_id: Some(comment.id),
+    author_github_node_id: comment.author_github_node_id,
+    body: comment.body,
+    original_commit: thread.original_commit_hash,
+    original_file_path: thread.original_file_path,
+    original_line_number: thread.original_line_number as i32,
+    original_end_line_number,
+  });
+  Ok(())
+}
+pub async fn insert_comments_event_route(
+  config: &Config,
+  storage: &dyn Storage,
+  req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+  let status = match insert_comments_event_route_inner(config, storage, req).await {
+    Ok(()) => StatusCode::OK,
+    Err(e) => {
+      log::error!("insert_comments event failed: {:?}", e);
+      StatusCode::BAD_REQUEST
+    }
+  };
+  Ok(
+    Response::builder()
+      .status(status)
+      .body(Body::empty())
+      .expect("building response failed"),
+  )
+}
+
+/// Newtype so that we can teach juniper_graphql_ws how to parse websocket text frames.
+struct WsText(String);
+impl TryFrom<WsText> for ClientMessage<juniper::DefaultScalarValue> {
+  type Error = serde_json::Error;
+  fn try_from(msg: WsText) -> Result<Self, Self::Error> {
+    serde_json::from_str(&msg.0)
+  }
+}
+
+/// Figure out who is connecting. Browsers can't set headers on websocket requests, so we also accept
+/// `{"Authorization": "Bearer <token>"}` in the connection_init payload.
+async fn websocket_auth(
+  storage: &dyn Storage,
+  session_ttl: chrono::Duration,
+  header_auth: Option<header::HeaderValue>,
+  init_payload: juniper::Variables,
+) -> anyhow::Result<AuthContext> {
+  let payload_auth = init_payload
+    .get("Authorization")
+    .and_then(|v| v.as_string_value())
+    .map(header::HeaderValue::from_str)
+    .transpose()?;
+  match header_auth.or(payload_auth) {
+    Some(value) => Ok(AuthContext::GitHub(
+      lookup_session_from_header(storage, session_ttl, &value)
+        .await?
+        .auth,
+    )),
+    None => Ok(AuthContext::Anonymous),
+  }
+}
+
+/// Upgrade a request to a graphql-ws websocket connection.
+pub async fn subscriptions_route(
+  root_node: Arc<Schema>,
+  config: &Config,
+  storage: Arc<dyn Storage>,
+  github: Arc<dyn GitHub>,
+  req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+  let bad_request = || {
+    Ok(
+      Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::empty())
+        .expect("building response failed"),
+    )
+  };
+
+  let is_upgrade = req
+    .headers()
+    .get(header::UPGRADE)
+    .and_then(|v| v.to_str().ok())
+    .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+  let accept_key = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
+    Some(key) if is_upgrade => {
+      tokio_tungstenite::tungstenite::handshake::derive_accept_key(key.as_bytes())
+    }
+    _ => return bad_request(),
+  };
+  let speaks_graphql_ws = req
+    .headers()
+    .get(header::SEC_WEBSOCKET_PROTOCOL)
+    .and_then(|v| v.to_str().ok())
+    .is_some_and(|v| v.split(',').any(|p| p.trim() == GRAPHQL_WS_PROTOCOL));
+  if !speaks_graphql_ws {
+    return bad_request();
+  }
+  let header_auth = req.headers().get(header::AUTHORIZATION).cloned();
+  let session_ttl = config.session_ttl;
+
+  tokio::spawn(async move {
+    let upgraded = match hyper::upgrade::on(req).await {
+      Ok(upgraded) => upgraded,
+      Err(e) => {
+        log::error!("websocket upgrade failed: {}", e);
+        return;
+      }
+    };
+    let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+    let (mut ws_tx, mut ws_rx) = ws.split();
+
+    let init = move |params: juniper::Variables| async move {
+      match websocket_auth(&*storage, session_ttl, header_auth, params).await {
+        Ok(auth) => Ok(ConnectionConfig::new(JuniperContext {
+          auth,
+          storage,
+          github,
+        })),
+        Err(e) => Err(WsAuthError(format!("{}", e))),
+      }
+    };
+    let (mut conn_tx, mut conn_rx) =
+      juniper_graphql_ws::Connection::new(juniper_graphql_ws::ArcSchema(root_node), init).split();
+
+    // Client -> server.
+    let incoming = async move {
+      while let Some(msg) = ws_rx.next().await {
+        match msg {
+          Ok(Message::Text(text)) => {
+            if conn_tx.send(WsText(text)).await.is_err() {
+              break;
+            }
+          }
+          Ok(Message::Close(_)) | Err(_) => break,
+          // Pings are answered by tungstenite itself.
+          Ok(_) => {}
+        }
+      }
+      let _ = conn_tx.close().await;
+    };
+    // Server -> client.
+    let outgoing = async move {
+      while let Some(msg) = conn_rx.next().await {
+        let text = serde_json::to_string(&msg).expect("ServerMessage is always serializable");
+        if ws_tx.send(Message::Text(text)).await.is_err() {
+          break;
+        }
+      }
+      let _ = ws_tx.close().await;
+    };
+    future::join(incoming, outgoing).await;
+    log::trace!("websocket connection closed");
+  });
+
+  Ok(
+    Response::builder()
+      .status(StatusCode::SWITCHING_PROTOCOLS)
+      .header(header::CONNECTION, "upgrade")
+      .header(header::UPGRADE, "websocket")
+      .header(header::SEC_WEBSOCKET_ACCEPT, accept_key)
+      .header(header::SEC_WEBSOCKET_PROTOCOL, GRAPHQL_WS_PROTOCOL)
+      .body(Body::empty())
+      .expect("building response failed"),
+  )
+}
+
+#[derive(Debug)]
+struct WsAuthError(String);
+impl std::fmt::Display for WsAuthError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+impl std::error::Error for WsAuthError {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fakes;
+  use crate::fakes::FakeGitHub;
+  use crate::fakes::InMemoryStorage;
+  use std::sync::Arc;
+
+  #[tokio::test]
+  async fn thread_events_are_filtered_by_file() {
+    let context = fakes::context(
+      fakes::github_auth("MDQ6VXNlcjE="),
+      Arc::new(InMemoryStorage::default()),
+      Arc::new(FakeGitHub::default()),
+    );
+    // THREAD_EVENTS is shared with every test that starts a thread, so use commits nobody else does.
+    let nonce = std::time::SystemTime::now()
+      .duration_since(std::time::UNIX_EPOCH)
+      .unwrap()
+      .as_nanos();
+    let commit = format!("filtered-{}", nonce);
+    let other_commit = format!("filtered-other-{}", nonce);
+    let mut events = thread_events_inner(&context, commit.clone(), "src/lib.rs".to_string(), None)
+      .await
+      .unwrap();
+
+    let thread_id = |i: u32| format!("t{}-{}", i, nonce);
+    publish_thread_started(
+      &thread_id(1),
+      "MDQ6VXNlcjE=",
+      "elsewhere",
+      &commit,
+      "src/main.rs",
+      1,
+      1,
+    );
+    publish_thread_started(
+      &thread_id(2),
+      "MDQ6VXNlcjE=",
+      "other commit",
+      &other_commit,
+      "src/lib.rs",
+      1,
+      1,
+    );
+    publish_thread_started(
+      &thread_id(3),
+      "MDQ6VXNlcjE=",
+      "hello",
+      &commit,
+      "src/lib.rs",
+      3,
+      5,
+    );
+
+    let event = events.next().await.unwrap().unwrap();
+    assert_eq!(event.kind, ThreadEventKind::ThreadStarted);
+    assert_eq!(event.thread_id, thread_id(3));
+    assert_eq!(event.original_line_number, 3);
+    assert_eq!(event.original_end_line_number, 5);
+  }
+
+  #[tokio::test]
+  async fn thread_events_require_auth() {
+    let context = fakes::context(
+      AuthContext::Anonymous,
+      Arc::new(InMemoryStorage::default()),
+      Arc::new(FakeGitHub::default()),
+    );
+    assert!(
+      thread_events_inner(&context, "abc".to_string(), "src/lib.rs".to_string(), None)
+        .await
+        .is_err()
+    );
+  }
+}
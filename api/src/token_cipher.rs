@@ -0,0 +1,122 @@
+// GitHub access tokens are encrypted before they go into github_users.access_token, so that a leaked database dump or
+// an overly curious Hasura console doesn't hand out everyone's GitHub access. Only `Storage::lookup_user_session`
+// decrypts them.
+//
+// Each stored token looks like "<key id>:v2.local.<...>", a paseto v2.local token (XChaCha20-Poly1305) whose footer is
+// the GitHub node id of the token's owner. The footer is authenticated, so an encrypted token can't be moved to another
+// user's row either. Rows from before we encrypted anything hold the plaintext token, which we still accept until
+// `Storage::reencrypt_access_tokens` gets to them.
+use crate::config::AccessTokenKey;
+use anyhow::anyhow;
+use anyhow::Context;
+
+const PASETO_PREFIX: &str = "v2.local.";
+
+pub struct TokenCipher {
+  /// The first one encrypts.
+  keys: Vec<AccessTokenKey>,
+}
+
+impl TokenCipher {
+  pub fn new(keys: Vec<AccessTokenKey>) -> Self {
+    assert!(!keys.is_empty(), "need at least one access token key");
+    TokenCipher { keys }
+  }
+
+  /// Encrypt `access_token` for storing in `github_node_id`'s row.
+  pub fn seal(&self, github_node_id: &str, access_token: &str) -> anyhow::Result<String> {
+    let key = &self.keys[0];
+    let token =
+      paseto::v2::local::local_paseto(access_token, Some(github_node_id), key.key.as_bytes())
+        .map_err(|e| anyhow!("encrypting access token: {}", e))?;
+    Ok(format!("{}:{}", key.id, token))
+  }
+
+  /// The access token that `stored` holds for `github_node_id`.
+  pub fn open(&self, github_node_id: &str, stored: &str) -> anyhow::Result<String> {
+    let (key_id, token) = match split(stored) {
+      Some(x) => x,
+      None => return Ok(stored.to_string()),
+    };
+    let key = self
+      .keys
+      .iter()
+      .find(|key| key.id == key_id)
+      .ok_or_else(|| anyhow!("no access token key with id {:?}", key_id))?;
+    paseto::v2::local::decrypt_paseto(token, Some(github_node_id), key.key.as_bytes())
+      .map_err(|e| anyhow!("{}", e))
+      .with_context(|| format!("decrypting access token for {}", github_node_id))
+  }
+
+  /// Whether `stored` should be encrypted again, either because it was encrypted with an older key or because it's
+  /// from before we encrypted tokens at all.
+  pub fn is_stale(&self, stored: &str) -> bool {
+    split(stored).is_none_or(|(key_id, _)| key_id != self.keys[0].id)
+  }
+
+  /// `stored` encrypted with the current key, or None if it already is.
+  pub fn reseal(&self, github_node_id: &str, stored: &str) -> anyhow::Result<Option<String>> {
+    if !self.is_stale(stored) {
+      return Ok(None);
+    }
+    let access_token = self.open(github_node_id, stored)?;
+    Ok(Some(self.seal(github_node_id, &access_token)?))
+  }
+}
+
+/// (key id, paseto token), or None if `stored` is a plaintext token.
+fn split(stored: &str) -> Option<(&str, &str)> {
+  stored
+    .split_once(':')
+    .filter(|(_, token)| token.starts_with(PASETO_PREFIX))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn key(id: &str, key: &str) -> AccessTokenKey {
+    AccessTokenKey {
+      id: id.to_string(),
+      key: key.to_string(),
+    }
+  }
+
+  #[test]
+  fn seal_open_and_rotate() {
+    let old = TokenCipher::new(vec![key("old", "0123456789abcdef0123456789abcdef")]);
+    let sealed = old.seal("U_1", "gho_token").unwrap();
+    assert!(sealed.starts_with("old:v2.local."));
+    assert!(!sealed.contains("gho_token"));
+    assert_eq!(old.open("U_1", &sealed).unwrap(), "gho_token");
+    // Tokens stay with the user that they were sealed for.
+    assert!(old.open("U_2", &sealed).is_err());
+    assert!(!old.is_stale(&sealed));
+
+    let new = TokenCipher::new(vec![
+      key("new", "fedcba9876543210fedcba9876543210"),
+      key("old", "0123456789abcdef0123456789abcdef"),
+    ]);
+    assert_eq!(new.open("U_1", &sealed).unwrap(), "gho_token");
+    assert!(new.is_stale(&sealed));
+    let resealed = new.reseal("U_1", &sealed).unwrap().unwrap();
+    assert!(resealed.starts_with("new:"));
+    assert_eq!(new.open("U_1", &resealed).unwrap(), "gho_token");
+    assert_eq!(new.reseal("U_1", &resealed).unwrap(), None);
+    // Once the old key is gone, so is everything that wasn't resealed.
+    assert!(
+      TokenCipher::new(vec![key("new", "fedcba9876543210fedcba9876543210")])
+        .open("U_1", &sealed)
+        .is_err()
+    );
+  }
+
+  #[test]
+  fn plaintext() {
+    let cipher = TokenCipher::new(vec![key("k", "0123456789abcdef0123456789abcdef")]);
+    assert_eq!(cipher.open("U_1", "gho_plain").unwrap(), "gho_plain");
+    assert!(cipher.is_stale("gho_plain"));
+    let resealed = cipher.reseal("U_1", "gho_plain").unwrap().unwrap();
+    assert_eq!(cipher.open("U_1", &resealed).unwrap(), "gho_plain");
+  }
+}
@@ -0,0 +1 @@
+DROP TABLE "public"."commit_repo";
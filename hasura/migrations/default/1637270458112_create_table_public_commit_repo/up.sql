@@ -0,0 +1,2 @@
+CREATE TABLE "public"."commit_repo" ("commit_hash" text NOT NULL, "repo_id" text NOT NULL, PRIMARY KEY ("commit_hash","repo_id") );
+comment on TABLE "public"."commit_repo" is E'Mapping between commit hashes and the non-GitHub repos (GitLab, Bitbucket, plain git URLs) that contain them. Repos are identified by their api RepoId, eg. "gitlab-gitlab.com!group!project". GitHub repos live in commit_github_repo instead.';
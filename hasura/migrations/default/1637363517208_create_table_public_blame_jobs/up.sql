@@ -0,0 +1,4 @@
+CREATE TABLE "public"."blame_jobs" ("id" uuid NOT NULL DEFAULT gen_random_uuid(), "repo_id" text NOT NULL, "commit_hash" text NOT NULL, "file_path" text NOT NULL, "status" text NOT NULL DEFAULT 'queued', "error" text, "created_at" timestamptz NOT NULL DEFAULT now(), "updated_at" timestamptz NOT NULL DEFAULT now(), PRIMARY KEY ("id"), CONSTRAINT "status_is_valid" CHECK (status IN ('queued', 'running', 'done', 'failed')));
+comment on TABLE "public"."blame_jobs" is E'Background jobs that calculate blamelines for a (commit_hash, file_path). Inserted by the api when CalculateBlameLines is called and updated as the job runs. repo_id is the api RepoId that the commit should be fetched from.';
+CREATE INDEX "blame_jobs_commit_hash_file_path" on "public"."blame_jobs" using btree ("commit_hash", "file_path");
+CREATE INDEX "blame_jobs_status" on "public"."blame_jobs" using btree ("status");
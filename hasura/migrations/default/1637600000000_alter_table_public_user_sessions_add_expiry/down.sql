@@ -0,0 +1,4 @@
+DROP INDEX "public"."user_sessions_user_github_node_id";
+ALTER TABLE "public"."user_sessions" DROP COLUMN "public_id";
+ALTER TABLE "public"."user_sessions" DROP COLUMN "expires_at";
+ALTER TABLE "public"."user_sessions" DROP COLUMN "last_used_at";
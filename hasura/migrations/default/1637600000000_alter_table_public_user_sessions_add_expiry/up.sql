@@ -0,0 +1,7 @@
+ALTER TABLE "public"."user_sessions" ADD COLUMN "last_used_at" timestamptz NOT NULL DEFAULT now();
+ALTER TABLE "public"."user_sessions" ADD COLUMN "expires_at" timestamptz NOT NULL DEFAULT now() + interval '30 days';
+ALTER TABLE "public"."user_sessions" ADD COLUMN "public_id" uuid NOT NULL DEFAULT gen_random_uuid();
+ALTER TABLE "public"."user_sessions" ADD CONSTRAINT "user_sessions_public_id_key" UNIQUE ("public_id");
+CREATE INDEX "user_sessions_user_github_node_id" on "public"."user_sessions" using btree ("user_github_node_id");
+comment on column "public"."user_sessions"."expires_at" is E'Sessions that haven''t been used by this time are dead. Pushed back every time the session is used.';
+comment on column "public"."user_sessions"."public_id" is E'Identifies the session when listing and revoking sessions, since the id is the session token itself and shouldn''t be handed out.';
@@ -0,0 +1,5 @@
+DROP TRIGGER "save_comment_edit" ON "public"."comments";
+DROP FUNCTION "public"."save_comment_edit"();
+DROP TABLE "public"."comment_edits";
+ALTER TABLE "public"."comments" DROP COLUMN "deleted_at";
+ALTER TABLE "public"."comments" DROP COLUMN "edited_at";
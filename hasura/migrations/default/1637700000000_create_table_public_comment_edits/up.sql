@@ -0,0 +1,24 @@
+ALTER TABLE "public"."comments" ADD COLUMN "edited_at" timestamptz;
+ALTER TABLE "public"."comments" ADD COLUMN "deleted_at" timestamptz;
+comment on column "public"."comments"."edited_at" is E'When the body was last changed, or null if it never has been. Set by the save_comment_edit trigger.';
+comment on column "public"."comments"."deleted_at" is E'Deleted comments stay around, along with their body and edit history, but are no longer shown to anyone.';
+CREATE TABLE "public"."comment_edits" ("id" uuid NOT NULL DEFAULT gen_random_uuid(), "comment_id" uuid NOT NULL, "body" text NOT NULL, "replaced_at" timestamptz NOT NULL DEFAULT now(), PRIMARY KEY ("id"), FOREIGN KEY ("comment_id") REFERENCES "public"."comments"("id") ON UPDATE cascade ON DELETE cascade);
+comment on TABLE "public"."comment_edits" is E'Every previous version of every comment. body is what the comment said until replaced_at. Inserted by the save_comment_edit trigger whenever a comment''s body changes.';
+CREATE INDEX "comment_edits_comment_id" on "public"."comment_edits" using btree ("comment_id", "replaced_at");
+
+CREATE OR REPLACE FUNCTION "public"."save_comment_edit"()
+RETURNS TRIGGER AS $$
+BEGIN
+  IF NEW.body IS DISTINCT FROM OLD.body THEN
+    INSERT INTO "public"."comment_edits" ("comment_id", "body") VALUES (OLD.id, OLD.body);
+    NEW.edited_at = now();
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER "save_comment_edit"
+BEFORE UPDATE ON "public"."comments"
+FOR EACH ROW
+EXECUTE PROCEDURE "public"."save_comment_edit"();
+COMMENT ON TRIGGER "save_comment_edit" ON "public"."comments"
+IS 'Saves the old body to comment_edits whenever a comment is edited.';
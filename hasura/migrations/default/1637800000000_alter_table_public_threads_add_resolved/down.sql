@@ -0,0 +1,3 @@
+ALTER TABLE "public"."threads" DROP CONSTRAINT "threads_resolved_by_github_node_id_fkey";
+ALTER TABLE "public"."threads" DROP COLUMN "resolved_by_github_node_id";
+ALTER TABLE "public"."threads" DROP COLUMN "resolved_at";
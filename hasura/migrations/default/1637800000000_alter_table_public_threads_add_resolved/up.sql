@@ -0,0 +1,5 @@
+ALTER TABLE "public"."threads" ADD COLUMN "resolved_at" timestamptz;
+ALTER TABLE "public"."threads" ADD COLUMN "resolved_by_github_node_id" text;
+ALTER TABLE "public"."threads" ADD CONSTRAINT "threads_resolved_by_github_node_id_fkey" FOREIGN KEY ("resolved_by_github_node_id") REFERENCES "public"."github_users"("github_node_id") ON UPDATE cascade ON DELETE set null;
+comment on column "public"."threads"."resolved_at" is E'When the thread was resolved, or null if it''s open. Reopening a thread sets this back to null.';
+comment on column "public"."threads"."resolved_by_github_node_id" is E'Who resolved the thread. Null whenever resolved_at is.';
@@ -0,0 +1,6 @@
+DROP INDEX "public"."threads_original_range_key";
+ALTER TABLE "public"."threads" ADD CONSTRAINT "threads_original_commit_original_file_path_original_line_number" UNIQUE ("original_commit_hash", "original_file_path", "original_line_number");
+ALTER TABLE "public"."threads" DROP CONSTRAINT "threads_original_range";
+ALTER TABLE "public"."threads" DROP COLUMN "original_end_column";
+ALTER TABLE "public"."threads" DROP COLUMN "original_start_column";
+ALTER TABLE "public"."threads" DROP COLUMN "original_end_line_number";
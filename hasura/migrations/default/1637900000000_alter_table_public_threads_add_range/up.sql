@@ -0,0 +1,22 @@
+ALTER TABLE "public"."threads" ADD COLUMN "original_end_line_number" integer;
+ALTER TABLE "public"."threads" ADD COLUMN "original_start_column" integer;
+ALTER TABLE "public"."threads" ADD COLUMN "original_end_column" integer;
+comment on column "public"."threads"."original_end_line_number" is E'The last line of the range that the thread was started on, inclusive. Null means the same as original_line_number, ie. a single line.';
+comment on column "public"."threads"."original_start_column" is E'0-indexed character offset into the first line of the range, for threads on part of a line. Set along with original_end_column.';
+comment on column "public"."threads"."original_end_column" is E'0-indexed character offset into the last line of the range, exclusive. Set along with original_start_column.';
+ALTER TABLE "public"."threads" ADD CONSTRAINT "threads_original_range" CHECK (
+  original_end_line_number >= original_line_number
+  AND original_start_column >= 0
+  AND original_end_column >= 0
+  AND (original_start_column IS NULL) = (original_end_column IS NULL)
+);
+-- Threads used to be unique per line. Now that they can cover ranges, they're unique per range instead.
+ALTER TABLE "public"."threads" DROP CONSTRAINT "threads_original_commit_original_file_path_original_line_number";
+CREATE UNIQUE INDEX "threads_original_range_key" ON "public"."threads" (
+  original_commit_hash,
+  original_file_path,
+  original_line_number,
+  (coalesce(original_end_line_number, original_line_number)),
+  (coalesce(original_start_column, -1)),
+  (coalesce(original_end_column, -1))
+);
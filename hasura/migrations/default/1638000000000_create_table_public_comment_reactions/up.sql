@@ -0,0 +1,2 @@
+CREATE TABLE "public"."comment_reactions" ("comment_id" uuid NOT NULL, "github_node_id" text NOT NULL, "reaction" text NOT NULL, "created_at" timestamptz NOT NULL DEFAULT now(), PRIMARY KEY ("comment_id", "github_node_id", "reaction"), FOREIGN KEY ("comment_id") REFERENCES "public"."comments"("id") ON UPDATE cascade ON DELETE cascade, FOREIGN KEY ("github_node_id") REFERENCES "public"."github_users"("github_node_id") ON UPDATE cascade ON DELETE cascade, CONSTRAINT "comment_reactions_reaction" CHECK (reaction IN ('thumbs_up', 'thumbs_down', 'laugh', 'hooray', 'confused', 'heart', 'rocket', 'eyes')));
+comment on TABLE "public"."comment_reactions" is E'Emoji reactions to comments. Each user can react to a comment at most once with each kind of reaction.';
@@ -0,0 +1,4 @@
+DROP TABLE "public"."comment_mentions";
+comment on column "public"."github_users"."email" is E'User\'s email according to their GitHub account. We assume that all GitHub accounts have an email associated with it. Note that GitHub does not enforce that emails must be unique, eg. @drshrey and @shreyasjag have the same email.';
+-- Fails if anyone has been mentioned without having a public email.
+alter table "public"."github_users" alter column "email" set not null;
@@ -0,0 +1,6 @@
+alter table "public"."github_users" alter column "email" drop not null;
+comment on column "public"."github_users"."email" is E'User\'s email according to their GitHub account. Null for users who have been mentioned but have never logged in, unless their GitHub email is public. Note that GitHub does not enforce that emails must be unique, eg. @drshrey and @shreyasjag have the same email.';
+CREATE TABLE "public"."comment_mentions" ("comment_id" uuid NOT NULL, "github_node_id" text NOT NULL, "github_username" text NOT NULL, "created_at" timestamptz NOT NULL DEFAULT now(), PRIMARY KEY ("comment_id", "github_node_id"), FOREIGN KEY ("comment_id") REFERENCES "public"."comments"("id") ON UPDATE cascade ON DELETE cascade, FOREIGN KEY ("github_node_id") REFERENCES "public"."github_users"("github_node_id") ON UPDATE cascade ON DELETE cascade);
+comment on TABLE "public"."comment_mentions" is E'Users @mentioned in comments. Mentioned users always have a github_users row, even if they have never logged in, so that they can be notified.';
+comment on column "public"."comment_mentions"."github_username" is E'The username as it was when the comment was written.';
+CREATE INDEX "comment_mentions_github_node_id" on "public"."comment_mentions" using btree ("github_node_id");
@@ -0,0 +1,2 @@
+comment on column "public"."user_sessions"."expires_at" is E'Sessions that haven''t been used by this time are dead. Pushed back every time the session is used.';
+ALTER TABLE "public"."user_sessions" DROP COLUMN "rotated_at";
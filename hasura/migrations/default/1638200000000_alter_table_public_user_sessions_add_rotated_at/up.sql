@@ -0,0 +1,3 @@
+ALTER TABLE "public"."user_sessions" ADD COLUMN "rotated_at" timestamptz NULL;
+comment on column "public"."user_sessions"."rotated_at" is E'When the session was replaced by a new one. A rotated session only lives out its grace period: using it no longer pushes expires_at back, and it can''t be rotated again.';
+comment on column "public"."user_sessions"."expires_at" is E'Sessions that haven''t been used by this time are dead. Pushed back every time the session is used, unless it has been rotated.';
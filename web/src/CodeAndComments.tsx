@@ -1,11 +1,18 @@
 import { BorderBox, Box, Flex, Grid } from "@primer/components";
 import React, { Suspense, useEffect, useMemo, useRef, useState } from "react";
-import { graphql, useMutation } from "react-relay/hooks";
+import {
+  fetchQuery,
+  graphql,
+  useMutation,
+  useRelayEnvironment,
+} from "react-relay/hooks";
 import SyntaxHighlighter from "react-syntax-highlighter";
 import createElement from "react-syntax-highlighter/dist/esm/create-element";
 import { githubGist } from "react-syntax-highlighter/dist/esm/styles/hljs";
 import { githubRepoId, internalError } from "./App";
 import Comments from "./Comments";
+import { CodeAndComments_blameJob_Query } from "./__generated__/CodeAndComments_blameJob_Query.graphql";
+import { CodeAndComments_calcblamelines_Mutation } from "./__generated__/CodeAndComments_calcblamelines_Mutation.graphql";
 
 const MyPreTag: React.FC = (props) => (
   <table
@@ -23,7 +30,11 @@ const MyPreTag: React.FC = (props) => (
   </table>
 );
 
+// How long to wait between checks on a blame job that's still running.
+const BLAME_JOB_POLL_INTERVAL_MS = 1000;
+
 // Returns whether or not the blameline info has been successfully calculated and dumped into the blamelines table.
+// CalculateBlameLines only queues up a job to do that, so we keep checking on the job until it's done.
 function useCalcBlameLines(
   repo_owner: string,
   repo_name: string,
@@ -31,30 +42,80 @@ function useCalcBlameLines(
   commitSHA: string
 ) {
   const repoId = githubRepoId(repo_owner, repo_name);
-  const [calcBlameLines, waiting] = useMutation(graphql`
-    mutation CodeAndComments_calcblamelines_Mutation(
-      $repoId: String!
-      $commit: String!
-      $filePath: String!
-    ) {
-      CalculateBlameLines(
-        repoId: $repoId
-        lastCommit: $commit
-        filePath: $filePath
-      )
-    }
-  `);
+  const environment = useRelayEnvironment();
+  const [blameDone, setBlameDone] = useState(false);
+  const [calcBlameLines] = useMutation<CodeAndComments_calcblamelines_Mutation>(
+    graphql`
+      mutation CodeAndComments_calcblamelines_Mutation(
+        $repoId: String!
+        $commit: String!
+        $filePath: String!
+      ) {
+        CalculateBlameLines(
+          repoId: $repoId
+          lastCommit: $commit
+          filePath: $filePath
+        )
+      }
+    `
+  );
   useEffect(() => {
+    setBlameDone(false);
+    let cancelled = false;
+    let pollTimeout: ReturnType<typeof setTimeout> | null = null;
+    const poll = (jobId: string) =>
+      fetchQuery<CodeAndComments_blameJob_Query>(
+        environment,
+        graphql`
+          query CodeAndComments_blameJob_Query($id: String!) {
+            blameJob(id: $id) {
+              status
+              error
+            }
+          }
+        `,
+        { id: jobId }
+      )
+        .toPromise()
+        .then((data) => {
+          if (cancelled) return;
+          const job = data?.blameJob;
+          if (job?.status === "DONE") {
+            setBlameDone(true);
+          } else if (job?.status === "QUEUED" || job?.status === "RUNNING") {
+            pollTimeout = setTimeout(
+              () => poll(jobId),
+              BLAME_JOB_POLL_INTERVAL_MS
+            );
+          } else {
+            const reason = job?.error ?? "no such job";
+            internalError(new Error(`blame job ${jobId} failed: ${reason}`));
+          }
+        }, internalError);
+
     calcBlameLines({
       variables: { repoId, commit: commitSHA, filePath: filePath },
+      onCompleted: ({ CalculateBlameLines: jobId }) => {
+        if (cancelled) return;
+        // No job means that the blamelines were there already.
+        if (jobId === null) {
+          setBlameDone(true);
+        } else {
+          poll(jobId);
+        }
+      },
       onError: internalError,
     });
+    return () => {
+      cancelled = true;
+      if (pollTimeout !== null) clearTimeout(pollTimeout);
+    };
     // TODO: Having calcBlameLines in here actually causes a bunch of redundant calls to the API for the same file. This
     // really sucks. I'm guessing that the RelayEnvProvider reloads too many times and that each time implies a new
     // calcBlameLines closure.
-  }, [repoId, commitSHA, filePath, calcBlameLines]);
+  }, [repoId, commitSHA, filePath, calcBlameLines, environment]);
 
-  return !waiting;
+  return blameDone;
 }
 
 function useFileContents(
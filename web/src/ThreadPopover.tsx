@@ -179,25 +179,10 @@ const ThreadPopover: React.FC<{
   const [submit, isInFlight] = useMutation(graphql`
     mutation ThreadPopover_NewComment_Mutation(
       $body: String!
-      $thread_id: uuid!
-      $author_github_id: Int!
+      $threadId: String!
     ) {
-      insert_comments_one(
-        object: {
-          body: $body
-          thread_id: $thread_id
-          author_github_id: $author_github_id
-        }
-      ) {
-        # Even though they aren't directly used, these fields are important because they control what goes into the
-        # Relay Store.
+      AddComment(threadId: $threadId, body: $body) {
         id
-        created_at
-        body
-        author_github_id
-        author {
-          github_username
-        }
       }
     }
   `);
@@ -234,33 +219,9 @@ const ThreadPopover: React.FC<{
             inputRef={inputRef}
             onSubmit={() => {
               if (authstate.isLoggedIn) {
+                // No updater, the new comment shows up through the threads subscription in Comments.
                 submit({
-                  variables: {
-                    body: message,
-                    thread_id: thread.id,
-                    author_github_id: authstate.user.github_id,
-                  },
-                  updater(store) {
-                    const linkName = 'comments(order_by:{"created_at":"asc"})';
-                    const threadRec = store.get(thread.id as string);
-                    const newCommentRec = store.getRootField(
-                      "insert_comments_one"
-                    );
-                    const existingComments = threadRec?.getLinkedRecords(
-                      linkName
-                    );
-                    if (
-                      existingComments === null ||
-                      existingComments === undefined
-                    )
-                      throw internalError(
-                        Error("no existing comments in relay store")
-                      );
-                    threadRec?.setLinkedRecords(
-                      [...existingComments, newCommentRec],
-                      linkName
-                    );
-                  },
+                  variables: { body: message, threadId: thread.id as string },
                   onCompleted(data) {
                     setMessage("");
                   },